@@ -0,0 +1,70 @@
+/* C interface of lighar, enabled by the `capi` feature. */
+#ifndef LIGHAR_H
+#define LIGHAR_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LGR_OK 0
+#define LGR_INVALID_ARGUMENT -1
+#define LGR_CANCELLED -2
+/* The library failed unexpectedly, i.e., it panicked. */
+#define LGR_INTERNAL_ERROR -3
+
+typedef struct LgrScene LgrScene;
+typedef struct LgrRender LgrRender;
+
+/* Surface properties, in linear RGB. */
+typedef struct LgrMaterial {
+    float albedo[3];
+    float emit[3];
+} LgrMaterial;
+
+/* Transforms are row-major 3x4 matrices of 12 floats, or null for the
+ * identity. */
+
+LgrScene* lgr_scene_new(void);
+void lgr_scene_free(LgrScene* scene);
+/* Scales the emission of `mat` to `nits`, keeping its tint, or white if it
+ * doesn't emit. */
+int lgr_material_set_emit_nits(LgrMaterial* mat, float nits);
+/* Returns the material index, or a negative error code. */
+int lgr_scene_add_material(LgrScene* scene, const LgrMaterial* mat);
+/* `verts` holds `nvert * 3` floats and `idxs` holds `ntri * 3` indices.
+ * `obj2world` must be invertible. Returns the object index, or a negative
+ * error code. */
+int lgr_scene_add_mesh(LgrScene* scene, const float* verts, uint32_t nvert,
+    const uint32_t* idxs, uint32_t ntri, const float* obj2world,
+    uint32_t material);
+/* The camera looks along +z in its local space; `fov` is vertical, in
+ * radians. */
+int lgr_scene_set_camera(LgrScene* scene, const float* cam2world, float fov);
+int lgr_scene_set_ambient(LgrScene* scene, float r, float g, float b);
+/* Maps radiance to pixel values by the exposure of a physical camera, with
+ * the shutter time in seconds. */
+int lgr_scene_set_exposure(LgrScene* scene, float iso, float shutter,
+    float f_number);
+
+/* Blocking render into `w * h * 4` bytes of RGBA8 pixels. Returns
+ * `LGR_INVALID_ARGUMENT` for frames too large for memory. */
+int lgr_render(const LgrScene* scene, uint32_t w, uint32_t h, uint32_t spp,
+    uint8_t* rgba);
+
+/* Background render of a copy of the scene. Every handle must be passed to
+ * `lgr_render_wait` exactly once, which frees it. */
+LgrRender* lgr_render_async(const LgrScene* scene, uint32_t w, uint32_t h,
+    uint32_t spp);
+uint32_t lgr_render_progress(const LgrRender* render);
+void lgr_render_cancel(const LgrRender* render);
+/* `rgba` may be null to discard the pixels. Returns `LGR_INTERNAL_ERROR` if
+ * the render failed. */
+int lgr_render_wait(LgrRender* render, uint8_t* rgba);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LIGHAR_H */
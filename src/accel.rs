@@ -0,0 +1,316 @@
+use crate::geom::{Real, Ray, Triangle, Barycentric, Precision, ray_cast_tri_with};
+use crate::rt::Intersection;
+use crate::scene::Scene;
+use crate::bvh::{Bvh, TriRef};
+use crate::kdtree::KdTree;
+use crate::qbvh::QuantizedBvh;
+
+/// Size and quality of an acceleration structure.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccelStats {
+    pub nnode: usize,
+    /// Number of nodes from the root to the deepest leaf, inclusive.
+    pub depth: usize,
+    /// Memory taken by the nodes and the triangles.
+    pub bytes: usize,
+    /// Expected cost of tracing a ray by the surface area heuristic, roughly
+    /// the number of nodes visited plus triangles tested.
+    pub cost: Real,
+}
+
+/// Work done by an acceleration structure to find the closest hit of a ray.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraversalCost {
+    /// Number of nodes visited.
+    pub nnode: usize,
+    /// Number of triangles tested.
+    pub ntri: usize,
+}
+
+/// Acceleration structures over the triangles of a scene. Only `traverse` is
+/// needed to plug a structure into `RayTracer`; `closest` and `any` are
+/// geometric queries built on it.
+pub trait Accel : Send + Sync {
+    /// Build the structure over triangles `tris` of `scene`.
+    fn build<M: Sync>(scene: &Scene<M>, tris: &[TriRef]) -> Self where Self: Sized;
+    /// Invoke `f` with every triangle `ray` might hit, until `f` returns
+    /// false. Triangles that certainly miss may be skipped.
+    fn traverse(&self, ray: &Ray, f: &mut dyn FnMut(TriRef, &Triangle) -> bool);
+    fn stats(&self) -> AccelStats;
+    /// Same as `traverse` but triangles `ray` can only hit beyond parametric
+    /// distance `tmax` may be skipped. `f` can lower `tmax`, e.g., to the
+    /// closest hit so far, so that farther parts of the structure are skipped
+    /// altogether. By default nothing is skipped.
+    fn traverse_within(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+    ) {
+        let mut tmax = tmax;
+        self.traverse(ray, &mut |r, tri| f(r, tri, &mut tmax))
+    }
+
+    /// Same as `traverse_within` but the nodes visited are counted into
+    /// `nnode`. By default no node is counted, which is only right for
+    /// structures without nodes like `BruteForce`; others override it.
+    fn traverse_counted(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+        _nnode: &mut usize,
+    ) {
+        self.traverse_within(ray, tmax, f)
+    }
+
+    /// The closest triangle hit by `ray` from either side.
+    fn closest(&self, ray: &Ray) -> Option<(TriRef, Intersection<Barycentric>)> {
+        let mut closest = None;
+        self.traverse_within(ray, Real::INFINITY, &mut |r, tri, tmax| {
+            let precision = Precision { max_t: *tmax, ..Precision::default() };
+            if let Some(x) = ray_cast_tri_with(ray, tri, &precision) {
+                if x.t < *tmax {
+                    *tmax = x.t;
+                    closest = Some((r, x));
+                }
+            }
+            true
+        });
+        closest
+    }
+    /// Work done to find the closest hit of `ray` like `closest`, but with
+    /// hits tested at `precision`, e.g., `Scene::precision`.
+    fn closest_cost(&self, ray: &Ray, precision: &Precision) -> TraversalCost {
+        let mut cost = TraversalCost::default();
+        let mut ntri = 0;
+        self.traverse_counted(ray, precision.max_t, &mut |_, tri, tmax| {
+            ntri += 1;
+            let precision = Precision { max_t: *tmax, ..*precision };
+            if let Some(x) = ray_cast_tri_with(ray, tri, &precision) {
+                *tmax = tmax.min(x.t);
+            }
+            true
+        }, &mut cost.nnode);
+        cost.ntri = ntri;
+        cost
+    }
+    /// Whether `ray` hits any triangle, with hits tested at `precision`,
+    /// e.g., `Scene::precision`.
+    fn any(&self, ray: &Ray, precision: &Precision) -> bool {
+        let mut hit = false;
+        self.traverse_within(ray, precision.max_t, &mut |_, tri, _| {
+            hit = ray_cast_tri_with(ray, tri, precision).is_some();
+            !hit
+        });
+        hit
+    }
+}
+impl Accel for Bvh {
+    fn build<M: Sync>(scene: &Scene<M>, tris: &[TriRef]) -> Bvh {
+        Bvh::with_tris(scene, tris)
+    }
+    fn traverse(&self, ray: &Ray, f: &mut dyn FnMut(TriRef, &Triangle) -> bool) {
+        Bvh::traverse(self, ray, f)
+    }
+    fn stats(&self) -> AccelStats {
+        Bvh::stats(self)
+    }
+    fn traverse_within(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+    ) {
+        Bvh::traverse_within(self, ray, tmax, f)
+    }
+    fn traverse_counted(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+        nnode: &mut usize,
+    ) {
+        Bvh::traverse_counted(self, ray, tmax, f, nnode)
+    }
+}
+impl Accel for KdTree {
+    fn build<M: Sync>(scene: &Scene<M>, tris: &[TriRef]) -> KdTree {
+        KdTree::with_tris(scene, tris)
+    }
+    fn traverse(&self, ray: &Ray, f: &mut dyn FnMut(TriRef, &Triangle) -> bool) {
+        KdTree::traverse(self, ray, f)
+    }
+    fn stats(&self) -> AccelStats {
+        KdTree::stats(self)
+    }
+    fn traverse_within(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+    ) {
+        KdTree::traverse_within(self, ray, tmax, f)
+    }
+    fn traverse_counted(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+        nnode: &mut usize,
+    ) {
+        KdTree::traverse_counted(self, ray, tmax, f, nnode)
+    }
+}
+impl Accel for QuantizedBvh {
+    fn build<M: Sync>(scene: &Scene<M>, tris: &[TriRef]) -> QuantizedBvh {
+        QuantizedBvh::from_bvh(Bvh::with_tris(scene, tris))
+    }
+    fn traverse(&self, ray: &Ray, f: &mut dyn FnMut(TriRef, &Triangle) -> bool) {
+        QuantizedBvh::traverse(self, ray, f)
+    }
+    fn stats(&self) -> AccelStats {
+        QuantizedBvh::stats(self)
+    }
+    fn traverse_within(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+    ) {
+        QuantizedBvh::traverse_within(self, ray, tmax, f)
+    }
+    fn traverse_counted(
+        &self,
+        ray: &Ray,
+        tmax: Real,
+        f: &mut dyn FnMut(TriRef, &Triangle, &mut Real) -> bool,
+        nnode: &mut usize,
+    ) {
+        QuantizedBvh::traverse_counted(self, ray, tmax, f, nnode)
+    }
+}
+
+/// No acceleration at all, every triangle is tested. Useful as a reference.
+#[derive(Debug, Clone)]
+pub struct BruteForce {
+    prims: Vec<(TriRef, Triangle)>,
+}
+impl Accel for BruteForce {
+    fn build<M: Sync>(scene: &Scene<M>, tris: &[TriRef]) -> BruteForce {
+        let prims = tris.iter()
+            .map(|&r| (r, r.resolve(scene)))
+            .collect();
+        BruteForce { prims }
+    }
+    fn traverse(&self, _ray: &Ray, f: &mut dyn FnMut(TriRef, &Triangle) -> bool) {
+        for (r, tri) in self.prims.iter() {
+            if !f(*r, tri) { return }
+        }
+    }
+    fn stats(&self) -> AccelStats {
+        AccelStats {
+            nnode: 0,
+            depth: 0,
+            bytes: std::mem::size_of_val(self.prims.as_slice()),
+            cost: self.prims.len() as Real,
+        }
+    }
+}
+
+/// Available acceleration structures.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccelKind {
+    BruteForce,
+    #[default]
+    Bvh,
+    /// Wide BVH with quantized bounds, smaller in memory than `Bvh`.
+    QuantizedBvh,
+    KdTree,
+}
+impl AccelKind {
+    /// Kind named `name`, one of `brute_force`, `bvh`, `qbvh` and `kdtree`.
+    pub fn from_name(name: &str) -> Option<AccelKind> {
+        match name {
+            "brute_force" => Some(AccelKind::BruteForce),
+            "bvh" => Some(AccelKind::Bvh),
+            "qbvh" => Some(AccelKind::QuantizedBvh),
+            "kdtree" => Some(AccelKind::KdTree),
+            _ => None,
+        }
+    }
+    /// Build an acceleration structure of this kind over all triangles of
+    /// `scene`.
+    pub fn build<M: Sync>(self, scene: &Scene<M>) -> Box<dyn Accel> {
+        let tris = TriRef::all(scene);
+        match self {
+            AccelKind::BruteForce => Box::new(BruteForce::build(scene, &tris)),
+            AccelKind::Bvh => Box::new(<Bvh as Accel>::build(scene, &tris)),
+            AccelKind::QuantizedBvh => {
+                Box::new(<QuantizedBvh as Accel>::build(scene, &tris))
+            },
+            AccelKind::KdTree => Box::new(<KdTree as Accel>::build(scene, &tris)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{Point, Vector, Transform};
+    use crate::model::{make_cube, make_sphere};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// The closest triangle of `accel` hit by `ray` and the distance to it.
+    fn closest(accel: &dyn Accel, ray: &Ray) -> Option<(TriRef, Real)> {
+        let precision = Precision::default();
+        let mut closest = None;
+        accel.traverse_within(ray, Real::INFINITY, &mut |r, tri, tmax| {
+            if let Some(x) = ray_cast_tri_with(ray, tri, &precision).filter(|x| x.t < *tmax) {
+                *tmax = x.t;
+                closest = Some((r, x.t));
+            }
+            true
+        });
+        closest
+    }
+
+    #[test]
+    fn accels_agree_with_brute_force() {
+        let mut rng = StdRng::seed_from_u64(0x6163_6365);
+        let mut vector = |scale: Real| {
+            let mut x = || rng.gen_range(-scale, scale);
+            Vector(x(), x(), x())
+        };
+        let objs = (0..60)
+            .map(|i| {
+                let trans = Transform::eye()
+                    .scale(vector(0.5) + Vector(0.6, 0.6, 0.6))
+                    .rotate(vector(3.0).0, vector(1.0) + Vector(0.0, 0.0, 1.1))
+                    .translate(vector(5.0));
+                if i % 2 == 0 { make_cube((), trans) } else { make_sphere((), trans, 6, 12) }
+            })
+            .collect::<Vec<_>>();
+        let scene = Scene::new(objs);
+        let reference = AccelKind::BruteForce.build(&scene);
+        let kinds = [AccelKind::Bvh, AccelKind::QuantizedBvh, AccelKind::KdTree];
+        let accels = kinds.iter().map(|kind| kind.build(&scene)).collect::<Vec<_>>();
+        for _ in 0..1000 {
+            let o = Point(0.0, 0.0, 0.0).affine_add(vector(8.0));
+            // Aimed into the scene so that most rays hit something.
+            let ray = Ray { o, v: vector(5.0) - o.rel_from(Point(0.0, 0.0, 0.0)) };
+            let expected = closest(&*reference, &ray);
+            for (kind, accel) in kinds.iter().zip(accels.iter()) {
+                let hit = closest(&**accel, &ray);
+                match (hit, expected) {
+                    (Some((r, t)), Some((r0, t0))) => {
+                        assert!(r == r0 || (t - t0).abs() <= 1e-5 * t0, "{:?} {:?}", kind, ray);
+                    },
+                    (None, None) => {},
+                    _ => panic!("{:?} {:?} {:?} {:?}", kind, ray, hit, expected),
+                }
+            }
+        }
+    }
+}
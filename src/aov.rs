@@ -0,0 +1,225 @@
+//! Geometric AOVs (arbitrary output variables) rendered alongside the beauty
+//! pass for compositing: positions, normals and depths of the surfaces seen
+//! by each pixel and how far they moved on screen since the previous frame,
+//! e.g., for external denoisers, outlines, depth of field and motion blur in
+//! post. Save them as full-float layers with `img::save_exr`.
+use crate::geom::{Real, Point, Ray, Transform, Color, Barycentric, narrow};
+use crate::rt::RayTracer;
+use crate::accel::TraversalCost;
+use crate::post::false_color;
+use crate::scene::RayKind;
+use crate::camera::Camera;
+use crate::img::Image;
+use crate::par::*;
+
+/// Camera and object transforms of the previous frame, to compute motion
+/// vectors against.
+#[derive(Debug, Clone, Copy)]
+pub struct PrevFrame<'a> {
+    pub cam: &'a Camera,
+    /// `Object::world2obj` in the previous frame of the objects that moved,
+    /// by index in `Scene::objs`. Other objects haven't moved.
+    pub moved: &'a [(usize, Transform)],
+}
+
+/// AOVs rendered by `render_geometry_aovs`. Pixels seeing no surface are
+/// transparent in every image, and alpha is 1 elsewhere.
+pub struct GeometryAovs {
+    /// World space positions in `R`, `G` and `B`.
+    pub p: Image,
+    /// Object space positions, which stick to moving and deforming objects,
+    /// e.g., to pin textures in compositing.
+    pub p_obj: Image,
+    /// World space unit normals facing the camera in `R`, `G` and `B`.
+    pub n: Image,
+    /// Distances from the camera in `R`, `G` and `B`.
+    pub depth: Image,
+    /// Screen space motion in pixels from the previous frame to this one in
+    /// `R` and `G`, with `G` pointing down the image like pixel rows.
+    pub motion: Option<Image>,
+}
+
+/// Screen point of `cam` in pixel coordinates of a `w` by `h` frame.
+fn to_pixel(cam: &Camera, p: Point, w: u32, h: u32) -> Option<(Real, Real)> {
+    let (x, y) = cam.project(p)?;
+    // Screen y points down and camera y points up.
+    Some(((x + 1.0) * 0.5 * w as Real, (1.0 - y) * 0.5 * h as Real))
+}
+
+/// Render the positions seen through the pixel centers of a `w` by `h` frame
+/// of `cam` into `rt`, and with `prev` their motion vectors. Pixels are
+/// rendered in parallel.
+pub fn render_geometry_aovs<T>(
+    rt: &T,
+    cam: &Camera,
+    prev: Option<&PrevFrame>,
+    w: u32,
+    h: u32,
+) -> GeometryAovs
+    where T: RayTracer<Ray = Ray, RayAttr = Barycentric>,
+          T::Payload: Default,
+{
+    let pxs = (0..w * h).into_par_iter()
+        .map(|i| {
+            let (x, y) = ((i % w) as Real + 0.5, (i / w) as Real + 0.5);
+            let sx = x / w as Real * 2.0 - 1.0;
+            let sy = y / h as Real * 2.0 - 1.0;
+            let ray = cam.ray(sx, -sy);
+            let hit = rt.closest(&ray, RayKind::Camera, &mut T::Payload::default())?;
+            let bary = hit.intersect.attr;
+            let p = hit.tri.o.affine_add(bary.u * hit.tri.x + bary.v * hit.tri.y);
+            let obj = &rt.scene().objs[hit.obj];
+            let p_obj = obj.obj2world * p;
+            let n = if hit.tri.n.dot(ray.v) > 0.0 { -hit.tri.n } else { hit.tri.n };
+            let depth = hit.intersect.t * ray.v.mag();
+            let motion = prev.and_then(|prev| {
+                let p_prev = match prev.moved.iter().find(|(i, _)| *i == hit.obj) {
+                    Some((_, world2obj)) => *world2obj * p_obj,
+                    None => p,
+                };
+                let (px, py) = to_pixel(prev.cam, p_prev, w, h)?;
+                Some((x - px, y - py))
+            });
+            Some((p, p_obj, n, depth, motion))
+        })
+        .collect::<Vec<_>>();
+    let point = |p: Point| Color(narrow(p.0), narrow(p.1), narrow(p.2), 1.0);
+    let (w, h) = (w as usize, h as usize);
+    let mut rv = GeometryAovs {
+        p: Image::new(w, h),
+        p_obj: Image::new(w, h),
+        n: Image::new(w, h),
+        depth: Image::new(w, h),
+        motion: prev.map(|_| Image::new(w, h)),
+    };
+    for (i, px) in pxs.into_iter().enumerate() {
+        let (p, p_obj, n, depth, motion) = match px {
+            Some(x) => x,
+            None => continue,
+        };
+        rv.p.store_px(i % w, i / w, point(p));
+        rv.p_obj.store_px(i % w, i / w, point(p_obj));
+        rv.n.store_px(i % w, i / w, Color(narrow(n.0), narrow(n.1), narrow(n.2), 1.0));
+        let depth = narrow(depth);
+        rv.depth.store_px(i % w, i / w, Color(depth, depth, depth, 1.0));
+        if let (Some(img), Some((dx, dy))) = (rv.motion.as_mut(), motion) {
+            img.store_px(i % w, i / w, Color(narrow(dx), narrow(dy), 0.0, 1.0));
+        }
+    }
+    rv
+}
+
+/// Per-pixel work of finding the surfaces seen through the pixel centers, in
+/// row-major order, to tell where traversal is expensive and compare the
+/// acceleration structures of different builders.
+#[derive(Debug, Clone)]
+pub struct TraversalStats {
+    pub w: u32,
+    pub h: u32,
+    pub costs: Vec<TraversalCost>,
+}
+impl TraversalStats {
+    /// The largest node and triangle counts of any pixel.
+    pub fn max(&self) -> TraversalCost {
+        self.costs.iter().fold(TraversalCost::default(), |a, b| TraversalCost {
+            nnode: a.nnode.max(b.nnode),
+            ntri: a.ntri.max(b.ntri),
+        })
+    }
+    /// False color heatmap of the nodes visited, from none in blue to `max`
+    /// in red, by default the largest count in the frame. Pass the same `max`
+    /// to heatmaps of different structures to compare them.
+    pub fn node_heatmap(&self, max: Option<usize>) -> Image {
+        let max = max.unwrap_or_else(|| self.max().nnode).max(1) as f32;
+        self.heatmap(|x| x.nnode as f32 / max)
+    }
+    /// False color heatmap of the triangles tested like `node_heatmap`.
+    pub fn tri_heatmap(&self, max: Option<usize>) -> Image {
+        let max = max.unwrap_or_else(|| self.max().ntri).max(1) as f32;
+        self.heatmap(|x| x.ntri as f32 / max)
+    }
+    fn heatmap<F: Fn(&TraversalCost) -> f32>(&self, f: F) -> Image {
+        let (w, h) = (self.w as usize, self.h as usize);
+        let mut img = Image::new(w, h);
+        for (i, cost) in self.costs.iter().enumerate() {
+            img.store_px(i % w, i / w, false_color(f(cost)));
+        }
+        img
+    }
+}
+
+/// Count the work of tracing a ray through each pixel center of a `w` by `h`
+/// frame of `cam` into `rt`. Without an acceleration structure every
+/// triangle is tested. Pixels are traced in parallel.
+pub fn render_traversal_costs<T>(rt: &T, cam: &Camera, w: u32, h: u32) -> TraversalStats
+    where T: RayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    let ntri_total = rt.scene().objs.iter().map(|x| x.idxs.len()).sum::<usize>();
+    let costs = (0..w * h).into_par_iter()
+        .map(|i| {
+            let sx = ((i % w) as Real + 0.5) / w as Real * 2.0 - 1.0;
+            let sy = ((i / w) as Real + 0.5) / h as Real * 2.0 - 1.0;
+            let ray = cam.ray(sx, -sy);
+            match rt.accel(&ray) {
+                Some((accel, ray)) => accel.closest_cost(&ray, &rt.scene().precision),
+                None => TraversalCost { nnode: 0, ntri: ntri_total },
+            }
+        })
+        .collect::<Vec<_>>();
+    TraversalStats { w, h, costs }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{Vector, Transform};
+    use crate::desc::DiffuseMaterial;
+    use crate::furnace::furnace_scene;
+
+    const SIZE: u32 = 16;
+
+    #[test]
+    fn geometry_aovs_of_a_sphere() {
+        // A sphere of radius 0.9 at the origin seen from 3 units away.
+        let rt = furnace_scene(DiffuseMaterial::default());
+        let aovs = render_geometry_aovs(&rt, &rt.cam, None, SIZE, SIZE);
+        assert!(aovs.motion.is_none());
+        let c = (SIZE / 2) as usize;
+        let p = aovs.p.load_px(c, c);
+        let n = aovs.n.load_px(c, c);
+        let depth = aovs.depth.load_px(c, c);
+        assert_eq!(p.3, 1.0);
+        let r = (p.0 * p.0 + p.1 * p.1 + p.2 * p.2).sqrt();
+        assert!((r - 0.9).abs() < 0.05, "{:?}", p);
+        assert!(n.2 < -0.95, "{:?}", n);
+        assert!((depth.0 - 2.1).abs() < 0.05, "{:?}", depth);
+        // The sphere of diameter 1 in object space is scaled by 1.8.
+        let p_obj = aovs.p_obj.load_px(c, c);
+        assert!((p_obj.0 * 1.8 - p.0).abs() < 1e-3 && (p_obj.2 * 1.8 - p.2).abs() < 1e-3,
+            "{:?}", p_obj);
+        // Corners see nothing.
+        assert_eq!(aovs.p.load_px(0, 0).3, 0.0);
+        assert_eq!(aovs.depth.load_px(0, SIZE as usize - 1).3, 0.0);
+    }
+
+    #[test]
+    fn motion_vectors_of_a_moving_camera() {
+        let rt = furnace_scene(DiffuseMaterial::default());
+        let cam = &rt.cam;
+        let prev = PrevFrame { cam, moved: &[] };
+        let aovs = render_geometry_aovs(&rt, cam, Some(&prev), SIZE, SIZE);
+        let motion = aovs.motion.unwrap();
+        let c = (SIZE / 2) as usize;
+        let still = motion.load_px(c, c);
+        assert!(still.0.abs() < 1e-3 && still.1.abs() < 1e-3, "{:?}", still);
+
+        // The camera panned to the right since the previous frame, so the
+        // sphere moved to the left on screen.
+        let mut prev_cam = cam.clone();
+        prev_cam.cam2world = Transform::eye().translate(Vector(-0.1, 0.0, -3.0));
+        let prev = PrevFrame { cam: &prev_cam, moved: &[] };
+        let aovs = render_geometry_aovs(&rt, cam, Some(&prev), SIZE, SIZE);
+        let m = aovs.motion.unwrap().load_px(c, c);
+        assert!(m.0 < -0.1 && m.1.abs() < 1e-2, "{:?}", m);
+    }
+}
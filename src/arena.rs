@@ -0,0 +1,42 @@
+use std::cell::RefCell;
+use crate::geom::Point;
+
+/// A pool of buffers for transient data. A buffer is lent out for the
+/// duration of a closure and is returned to the pool afterwards with its
+/// capacity kept, so once the pool is warmed up, borrowing a buffer doesn't
+/// allocate. Nested borrows, e.g., by a trace recursively invoked by a shader,
+/// get distinct buffers.
+pub struct Arena<T> {
+    free: RefCell<Vec<Vec<T>>>,
+}
+impl<T> Arena<T> {
+    pub const fn new() -> Arena<T> {
+        Arena { free: RefCell::new(Vec::new()) }
+    }
+    /// Lend an empty buffer to `f`.
+    pub fn with<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut Vec<T>) -> R
+    {
+        let mut buf = self.free.borrow_mut().pop().unwrap_or_default();
+        buf.clear();
+        let rv = f(&mut buf);
+        self.free.borrow_mut().push(buf);
+        rv
+    }
+}
+impl<T> Default for Arena<T> {
+    fn default() -> Arena<T> {
+        Arena::new()
+    }
+}
+
+thread_local! {
+    static VERTS: Arena<Point> = const { Arena::new() };
+}
+
+/// Lend an empty vertex buffer of the current thread to `f`.
+pub fn with_verts<R, F>(f: F) -> R
+    where F: FnOnce(&mut Vec<Point>) -> R
+{
+    VERTS.with(|arena| arena.with(f))
+}
@@ -0,0 +1,311 @@
+use std::io::Write;
+use crate::geom::{Real, Point, Vector, Ray, Color, Triangle, disk, offset_ray_origin, narrow};
+use crate::rt::RayTracer;
+use crate::scene::{Object, RayKind};
+use crate::img::{Image, ColorSpace};
+use crate::par::*;
+use crate::rng;
+
+/// Vertex positions of `obj` in world space.
+fn world_verts<M>(obj: &Object<M>) -> Vec<Point> {
+    obj.verts.iter().map(|&x| obj.world2obj * x).collect()
+}
+
+/// Unit vertex normals of `obj` in world space, averaged from the normals of
+/// the triangles around each vertex weighted by their angles at the vertex,
+/// so that normals don't depend on how faces are triangulated. Vertices not
+/// used by any triangle get zero normals.
+///
+/// See: Grit Thürmer and Charles A. Wüthrich, Computing Vertex Normals from
+/// Polygonal Facets.
+pub fn vertex_normals<M>(obj: &Object<M>) -> Vec<Vector> {
+    let verts = world_verts(obj);
+    let mut normals = vec![Vector::default(); verts.len()];
+    for &(x, y, z) in obj.idxs.iter() {
+        let n = Triangle::new(verts[x], verts[y], verts[z]).n;
+        for &(i, j, k) in [(x, y, z), (y, z, x), (z, x, y)].iter() {
+            let a = verts[j].rel_from(verts[i]);
+            let b = verts[k].rel_from(verts[i]);
+            let cos = a.dot(b) / (a.mag() * b.mag());
+            if cos.is_nan() { continue }
+            normals[i] = normals[i] + n * cos.clamp(-1.0, 1.0).acos();
+        }
+    }
+    normals.into_iter()
+        .map(|n| if n.mag() > 0.0 { n.normalize() } else { n })
+        .collect()
+}
+
+/// Occlusion of a point by the scene around it, as consumed by game engines
+/// to shade baked surfaces. See `bake_occlusion`.
+#[derive(Debug, Clone, Copy)]
+pub struct Occlusion {
+    /// Ambient occlusion, from 1 where nothing is in sight to 0 where the
+    /// point is entirely enclosed by occluders within the distance baked.
+    pub ao: f32,
+    /// Fraction of cosine weighted directions the environment is seen in,
+    /// i.e., ambient occlusion by occluders at any distance. Interiors seen
+    /// through a window are dark here even if their walls are far away.
+    pub sky: f32,
+    /// Unit mean of the unoccluded directions, which points towards where
+    /// most light comes from. It's the surface normal where nothing is in
+    /// sight, and also where everything is.
+    pub bent_normal: Vector,
+}
+
+/// Ambient occlusion of every vertex of the `iobj`-th object of the scene of
+/// `rt`, from 1 where nothing is in sight to 0 where the vertex is entirely
+/// enclosed. `nray` cosine distributed occlusion rays are traced over the
+/// hemisphere of each vertex, and only occluders within `max_dist` count.
+/// Vertices are baked in parallel.
+pub fn bake_ao<T>(rt: &T, iobj: usize, nray: u32, max_dist: Real) -> Vec<f32>
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    bake_occlusion(rt, iobj, nray, max_dist).into_iter()
+        .map(|x| x.ao)
+        .collect()
+}
+/// Ambient occlusion of every vertex like `bake_ao`, along with the sky
+/// visibility and bent normals from the same rays.
+pub fn bake_occlusion<T>(rt: &T, iobj: usize, nray: u32, max_dist: Real) -> Vec<Occlusion>
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    let obj = &rt.scene().objs[iobj];
+    let verts = world_verts(obj);
+    let normals = vertex_normals(obj);
+    verts.par_iter()
+        .zip(normals.par_iter())
+        .map(|(&p, &n)| occlusion_at(rt, p, n, nray, max_dist))
+        .collect()
+}
+fn occlusion_at<T>(rt: &T, p: Point, n: Vector, nray: u32, max_dist: Real) -> Occlusion
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    if n.mag() == 0.0 || nray == 0 {
+        return Occlusion { ao: 1.0, sky: 1.0, bent_normal: n };
+    }
+    let up = if n.0.abs() < 0.9 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+    let u = up.cross(n).normalize();
+    let v = n.cross(u);
+    let o = offset_ray_origin(p, n);
+    let (mut nocc, mut nsky) = (0, 0);
+    let mut bent = Vector::default();
+    for _ in 0..nray {
+        let (x, y) = disk(rng::random::<Real>(), rng::random::<Real>());
+        let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+        let ray = Ray { o, v: Vector(x, y, z).in_basis(u, v, n) };
+        // The closest hit tells both whether the ray escapes the scene and
+        // whether it's blocked within `max_dist`.
+        match rt.closest(&ray, RayKind::Shadow, &mut T::Payload::default()) {
+            None => nsky += 1,
+            Some(hit) if hit.intersect.t < max_dist => {
+                nocc += 1;
+                continue;
+            },
+            Some(_) => {},
+        }
+        bent = bent + ray.v;
+    }
+    let bent_normal = if bent.mag() > 0.0 { bent.normalize() } else { n };
+    Occlusion {
+        ao: 1.0 - nocc as f32 / nray as f32,
+        sky: nsky as f32 / nray as f32,
+        bent_normal,
+    }
+}
+
+/// Signed mean curvature of every vertex of `obj`, estimated from how far the
+/// neighboring vertices fall below the tangent plane. It's positive on convex
+/// features like edges and bumps, negative in concave ones like creases and
+/// pits, and zero on flat surfaces. Curvatures are in inverse world units.
+pub fn vertex_curvature<M>(obj: &Object<M>) -> Vec<Real> {
+    let verts = world_verts(obj);
+    let normals = vertex_normals(obj);
+    let mut sum = vec![0.0; verts.len()];
+    let mut count = vec![0u32; verts.len()];
+    for &(x, y, z) in obj.idxs.iter() {
+        for &(i, j) in [(x, y), (y, z), (z, x), (y, x), (z, y), (x, z)].iter() {
+            let d = verts[j].rel_from(verts[i]);
+            let len2 = d.dot(d);
+            if len2 == 0.0 { continue }
+            // Curvature of the circle through both vertices, tangent at `i`.
+            sum[i] += -2.0 * d.dot(normals[i]) / len2;
+            count[i] += 1;
+        }
+    }
+    sum.into_iter()
+        .zip(count)
+        .map(|(x, n)| if n > 0 { x / n as Real } else { 0.0 })
+        .collect()
+}
+/// Cavity of every vertex of `obj` for darkening crevices, from
+/// `vertex_curvature`. Curvatures of `-scale` and below map to 0 and flat or
+/// convex vertices map to 1.
+pub fn bake_cavity<M>(obj: &Object<M>, scale: Real) -> Vec<f32> {
+    vertex_curvature(obj).into_iter()
+        .map(|k| narrow((1.0 + k / scale).clamp(0.0, 1.0)))
+        .collect()
+}
+
+/// Surface under the center of a texel, see `rasterize_uv`.
+#[derive(Debug, Clone, Copy)]
+pub struct Texel {
+    /// Point in world space.
+    pub p: Point,
+    /// Unit normal in world space, interpolated from `vertex_normals`.
+    pub n: Vector,
+    /// Index of the triangle in `Object::idxs`.
+    pub tri: usize,
+}
+
+/// Surfaces under the texels of a texture mapped onto a mesh. Texels not
+/// covered by any triangle of the UV layout are `None`.
+#[derive(Debug, Clone)]
+pub struct TexelBuffer {
+    pub w: usize,
+    pub h: usize,
+    /// Row-major texels, from the top-left corner.
+    pub texels: Vec<Option<Texel>>,
+}
+impl TexelBuffer {
+    pub fn get(&self, x: usize, y: usize) -> Option<&Texel> {
+        self.texels[y * self.w + x].as_ref()
+    }
+}
+
+/// Rasterize the UV layout of `obj` into a `w` by `h` texture, recording the
+/// world space position and normal seen by each texel, for baking integrators
+/// to shade. `uvs` are the texture coordinates of `obj.verts` where (0, 0) is
+/// the bottom-left corner of the texture. A texel is covered by a triangle if
+/// its center is; where triangles overlap in UV space the last one wins.
+pub fn rasterize_uv<M>(obj: &Object<M>, uvs: &[(Real, Real)], w: usize, h: usize) -> TexelBuffer {
+    assert_eq!(uvs.len(), obj.verts.len(), "each vertex needs a texture coordinate");
+    let verts = world_verts(obj);
+    let normals = vertex_normals(obj);
+    // Texel space coordinates, where texel centers are at half integers.
+    let texel_coords = |i: usize| (uvs[i].0 * w as Real, (1.0 - uvs[i].1) * h as Real);
+    let mut texels = vec![None; w * h];
+    for (itri, &(a, b, c)) in obj.idxs.iter().enumerate() {
+        let (pa, pb, pc) = (texel_coords(a), texel_coords(b), texel_coords(c));
+        let edge = |p: (Real, Real), q: (Real, Real), x: Real, y: Real| {
+            (q.0 - p.0) * (y - p.1) - (q.1 - p.1) * (x - p.0)
+        };
+        let area = edge(pa, pb, pc.0, pc.1);
+        if area == 0.0 { continue }
+        let xmin = pa.0.min(pb.0).min(pc.0).floor().max(0.0) as usize;
+        let ymin = pa.1.min(pb.1).min(pc.1).floor().max(0.0) as usize;
+        let xmax = (pa.0.max(pb.0).max(pc.0).ceil().max(0.0) as usize).min(w);
+        let ymax = (pa.1.max(pb.1).max(pc.1).ceil().max(0.0) as usize).min(h);
+        for y in ymin..ymax {
+            for x in xmin..xmax {
+                let (cx, cy) = (x as Real + 0.5, y as Real + 0.5);
+                // Barycentric weights of the texel center, of either winding.
+                let wa = edge(pb, pc, cx, cy) / area;
+                let wb = edge(pc, pa, cx, cy) / area;
+                let wc = edge(pa, pb, cx, cy) / area;
+                if wa < 0.0 || wb < 0.0 || wc < 0.0 { continue }
+                let p = verts[a].affine_add(
+                    verts[b].rel_from(verts[a]) * wb + verts[c].rel_from(verts[a]) * wc);
+                let n = normals[a] * wa + normals[b] * wb + normals[c] * wc;
+                let n = if n.mag() > 0.0 { n.normalize() } else { n };
+                texels[y * w + x] = Some(Texel { p, n, tri: itri });
+            }
+        }
+    }
+    TexelBuffer { w, h, texels }
+}
+
+/// Ambient occlusion like `bake_ao` but baked into a `w` by `h` texture over
+/// the UV layout `uvs` of the `iobj`-th object, see `rasterize_uv`. Texels
+/// outside the layout are transparent.
+pub fn bake_ao_texture<T>(
+    rt: &T,
+    iobj: usize,
+    uvs: &[(Real, Real)],
+    w: usize,
+    h: usize,
+    nray: u32,
+    max_dist: Real,
+) -> Image
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    bake_occlusion_textures(rt, iobj, uvs, w, h, nray, max_dist).ao
+}
+
+/// Textures baked by `bake_occlusion_textures`.
+pub struct OcclusionTextures {
+    pub ao: Image,
+    pub sky: Image,
+    /// Bent normals in world space, stored like normal maps, see
+    /// `ColorSpace::Normal`.
+    pub bent_normal: Image,
+}
+
+/// Occlusion like `bake_occlusion` but baked into `w` by `h` textures like
+/// `bake_ao_texture`.
+pub fn bake_occlusion_textures<T>(
+    rt: &T,
+    iobj: usize,
+    uvs: &[(Real, Real)],
+    w: usize,
+    h: usize,
+    nray: u32,
+    max_dist: Real,
+) -> OcclusionTextures
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    let texels = rasterize_uv(&rt.scene().objs[iobj], uvs, w, h);
+    let occlusions = texels.texels.par_iter()
+        .map(|texel| texel.map(|x| occlusion_at(rt, x.p, x.n, nray, max_dist)))
+        .collect::<Vec<_>>();
+    let mut rv = OcclusionTextures {
+        ao: Image::new(w, h),
+        sky: Image::new(w, h),
+        bent_normal: Image::new(w, h).with_color_space(ColorSpace::Normal),
+    };
+    for (i, x) in occlusions.into_iter().enumerate() {
+        let x = match x {
+            Some(x) => x,
+            None => continue,
+        };
+        let n = x.bent_normal;
+        rv.ao.store_px(i % w, i / w, Color(x.ao, x.ao, x.ao, 1.0));
+        rv.sky.store_px(i % w, i / w, Color(x.sky, x.sky, x.sky, 1.0));
+        rv.bent_normal.store_px(
+            i % w,
+            i / w,
+            Color(narrow(n.0), narrow(n.1), narrow(n.2), 1.0),
+        );
+    }
+    rv
+}
+
+/// Write `obj` in world space as a Wavefront OBJ mesh. With `colors`, each
+/// vertex is followed by its color, the vertex color extension understood by
+/// common tools like Blender and MeshLab; combine baked values into colors
+/// like `Color(ao, ao, ao, 1.0)`.
+pub fn write_obj<M, W: Write>(
+    w: &mut W,
+    obj: &Object<M>,
+    colors: Option<&[Color]>,
+) -> std::io::Result<()> {
+    if let Some(name) = &obj.name {
+        writeln!(w, "o {}", name)?;
+    }
+    for (i, p) in world_verts(obj).into_iter().enumerate() {
+        match colors.and_then(|x| x.get(i)) {
+            Some(c) => writeln!(w, "v {} {} {} {} {} {}", p.0, p.1, p.2, c.0, c.1, c.2)?,
+            None => writeln!(w, "v {} {} {}", p.0, p.1, p.2)?,
+        }
+    }
+    for &(x, y, z) in obj.idxs.iter() {
+        // Indices are 1-based.
+        writeln!(w, "f {} {} {}", x + 1, y + 1, z + 1)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,283 @@
+//! Render the jobs of a job file one after another:
+//!
+//! ```text
+//! render-batch jobs.txt
+//! ```
+//!
+//! Each line of the job file is a render of a scene description in the format
+//! of `lighar::desc`; `#` starts a comment:
+//!
+//! ```text
+//! render scene=room.txt out=room.png w=1920 h=1080 spp=256
+//! render scene=room.txt camera=top out=room_top.png spp=64
+//! render scene=garden.txt out=garden.png
+//! ```
+//!
+//! `camera` names a camera of the scene, the first one by default. `w` and `h`
+//! default to 256 and `spp` to 16. `clay=all` renders every surface in gray
+//! and `clay=surfaces` keeps the emissive ones, for lighting checks.
+//! `toon=true` renders the scene cel shaded instead, see `lighar::toon`. With
+//! `noise`, pixels are sampled adaptively up to `spp` times until their
+//! relative noise falls below it, and heatmaps of the sample counts and the
+//! variances are saved next to the image, e.g., `room.spp.png` and
+//! `room.variance.png`. With `cost=true`, heatmaps of the acceleration
+//! structure nodes visited and the triangles tested to find the surface seen
+//! by each pixel are saved next to the image, e.g., `room.nodes.png` and
+//! `room.tris.png`. `outline` draws black outlines of the given width in
+//! pixels over the image, see `lighar::post::Outline`. With `aovs=true`, the
+//! image is also saved with the world and object space positions, the
+//! normals and the depths as layers `P`, `Pobj`, `N` and `Z` of a float
+//! OpenEXR file next to it, e.g., `room.aovs.exr`. With `journal=true`,
+//! finished tiles are streamed to a journal next to the image, e.g.,
+//! `room.journal`, which holds the render so far if the job is interrupted
+//! and can be loaded with `lighar::journal::load_journal`. It's removed once
+//! the image is saved. `accel` overrides the acceleration structure of the
+//! scene, see `lighar::accel::AccelKind::from_name`. Paths are relative to the
+//! job file. Images referred to by several scenes are loaded only once.
+//! Failed jobs are reported and skipped, and the exit code is non-zero if
+//! any job failed.
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use lighar::geom::*;
+use lighar::rt::*;
+use lighar::img::*;
+use lighar::desc::{parse_scene, DiffuseMaterial};
+use lighar::aov::{render_geometry_aovs, render_traversal_costs};
+use lighar::post::Outline;
+use lighar::journal::TileJournal;
+use lighar::integrator::{ClayRayTracer, ClayMode};
+use lighar::toon::ToonRayTracer;
+use lighar::accel::AccelKind;
+use lighar::trace::{self, Level, StderrSubscriber};
+
+struct Job {
+    scene: PathBuf,
+    camera: Option<String>,
+    out: PathBuf,
+    w: u32,
+    h: u32,
+    spp: u32,
+    clay: Option<ClayMode>,
+    toon: bool,
+    noise: Option<f32>,
+    cost: bool,
+    outline: Option<f32>,
+    aovs: bool,
+    journal: bool,
+    accel: Option<AccelKind>,
+}
+
+fn parse_jobs(desc: &str, base: &Path) -> Result<Vec<Job>, String> {
+    let mut jobs = Vec::new();
+    for (iline, line) in desc.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+        let err = |e: &str| format!("line {}: {}", iline + 1, e);
+        match words.next() {
+            Some("render") => {},
+            Some(x) => return Err(err(&format!("unknown command `{}`", x))),
+            None => continue,
+        }
+        let args = words
+            .filter_map(|x| {
+                let mut kv = x.splitn(2, '=');
+                Some((kv.next()?, kv.next()?))
+            })
+            .collect::<Vec<_>>();
+        let arg = |key: &str| args.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let num = |key: &str, default: u32| match arg(key) {
+            Some(x) => x.parse::<u32>()
+                .ok()
+                .filter(|&x| x > 0)
+                .ok_or_else(|| err(&format!("invalid `{}`", key))),
+            None => Ok(default),
+        };
+        jobs.push(Job {
+            scene: base.join(arg("scene").ok_or_else(|| err("missing `scene`"))?),
+            camera: arg("camera").map(str::to_owned),
+            out: base.join(arg("out").ok_or_else(|| err("missing `out`"))?),
+            w: num("w", 256)?,
+            h: num("h", 256)?,
+            spp: num("spp", 16)?,
+            clay: match arg("clay") {
+                Some("all") => Some(ClayMode::All),
+                Some("surfaces") => Some(ClayMode::KeepEmissive),
+                Some(_) => return Err(err("invalid `clay`")),
+                None => None,
+            },
+            toon: match arg("toon") {
+                Some(x) => x.parse::<bool>().map_err(|_| err("invalid `toon`"))?,
+                None => false,
+            },
+            noise: match arg("noise") {
+                Some(x) => Some(x.parse::<f32>()
+                    .ok()
+                    .filter(|&x| x > 0.0)
+                    .ok_or_else(|| err("invalid `noise`"))?),
+                None => None,
+            },
+            cost: match arg("cost") {
+                Some(x) => x.parse::<bool>().map_err(|_| err("invalid `cost`"))?,
+                None => false,
+            },
+            outline: match arg("outline") {
+                Some(x) => Some(x.parse::<f32>()
+                    .ok()
+                    .filter(|&x| x > 0.0)
+                    .ok_or_else(|| err("invalid `outline`"))?),
+                None => None,
+            },
+            aovs: match arg("aovs") {
+                Some(x) => x.parse::<bool>().map_err(|_| err("invalid `aovs`"))?,
+                None => false,
+            },
+            journal: match arg("journal") {
+                Some(x) => x.parse::<bool>().map_err(|_| err("invalid `journal`"))?,
+                None => false,
+            },
+            accel: match arg("accel") {
+                Some(x) => Some(AccelKind::from_name(x).ok_or_else(|| err("invalid `accel`"))?),
+                None => None,
+            },
+        });
+    }
+    Ok(jobs)
+}
+
+/// Framebuffer storing the mean of all samples of each pixel into `inner`.
+struct Accumulation<'a, FB: Framebuffer> {
+    inner: &'a mut FB,
+    sums: Vec<(Color, u32)>,
+}
+impl<FB: Framebuffer> Framebuffer for Accumulation<'_, FB> {
+    fn width(&self) -> u32 { self.inner.width() }
+    fn height(&self) -> u32 { self.inner.height() }
+    fn store(&mut self, x: u32, y: u32, color: Color) {
+        let (sum, n) = &mut self.sums[(y * self.inner.width() + x) as usize];
+        *sum = *sum + color;
+        *n += 1;
+        let mean = *sum * (*n as f32).recip();
+        self.inner.store(x, y, mean);
+    }
+    fn end_tile(&mut self, pixels: &[(u32, u32)]) {
+        self.inner.end_tile(pixels);
+    }
+}
+
+/// Draw `job.spp` samples per pixel, or up to that many adaptively, and
+/// return the samples per pixel actually taken, on average.
+fn accumulate<T: RayTracer, FB: Framebuffer>(rt: &T, job: &Job, framebuf: &mut FB) -> Result<u32, SaveError> {
+    if let Some(threshold) = job.noise {
+        let settings = AdaptiveSampling {
+            min_spp: AdaptiveSampling::default().min_spp.min(job.spp),
+            max_spp: job.spp,
+            threshold,
+        };
+        let stats = rt.draw_adaptive(framebuf, &settings);
+        let meta = RenderMetadata::default();
+        save_image(&stats.count_heatmap(&settings), job.out.with_extension("spp.png"), &meta)?;
+        save_image(&stats.variance_heatmap(), job.out.with_extension("variance.png"), &meta)?;
+        return Ok(stats.mean_spp());
+    }
+    let npx = job.w as usize * job.h as usize;
+    let mut accum = Accumulation { inner: framebuf, sums: vec![(Color::default(), 0); npx] };
+    for _ in 0..job.spp {
+        rt.draw(&mut accum);
+    }
+    Ok(job.spp)
+}
+
+/// The render of `job` and the samples per pixel it took, see `accumulate`.
+fn draw<T: RayTracer>(rt: &T, job: &Job) -> Result<(Image, u32), Box<dyn std::error::Error>> {
+    if !job.journal {
+        let mut img = Image::new(job.w as usize, job.h as usize);
+        let spp = accumulate(rt, job, &mut img)?;
+        return Ok((img, spp));
+    }
+    let mut journal = TileJournal::create(job.out.with_extension("journal"), job.w, job.h)?;
+    let spp = accumulate(rt, job, &mut journal)?;
+    Ok((journal.finish()?, spp))
+}
+
+fn run(job: &Job, assets: &mut AssetCache) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let desc = std::fs::read_to_string(&job.scene)?;
+    let base = job.scene.parent().unwrap_or_else(|| Path::new("."));
+    let settings = RenderSettings { accel: job.accel, ..Default::default() };
+    let rt = parse_scene(&desc, base, Some(assets))?
+        .into_tracer_with(job.camera.as_deref(), job.w, job.h, &settings)?;
+    let aovs = if job.aovs || job.outline.is_some() {
+        Some(render_geometry_aovs(&rt, &rt.cam, None, job.w, job.h))
+    } else {
+        None
+    };
+    let costs = if job.cost {
+        Some(render_traversal_costs(&rt, &rt.cam, job.w, job.h))
+    } else {
+        None
+    };
+    let (mut img, spp) = match job.clay {
+        Some(mode) => draw(&ClayRayTracer::new(rt, mode), job)?,
+        None if job.toon => draw(&ToonRayTracer::new(rt, |mat: &DiffuseMaterial| mat.albedo), job)?,
+        None => draw(&rt, job)?,
+    };
+    if let (Some(width), Some(aovs)) = (job.outline, aovs.as_ref()) {
+        let outline = Outline { width, ..Default::default() };
+        outline.apply_img(&mut img, &aovs.depth, &aovs.n);
+    }
+    let meta = RenderMetadata {
+        scene_hash: Some(hash_file(&job.scene)?),
+        spp: Some(spp),
+        seed: None,
+        render_time: Some(start.elapsed()),
+    };
+    save_image(&img, &job.out, &meta)?;
+    if let Some(costs) = costs {
+        let meta = RenderMetadata::default();
+        save_image(&costs.node_heatmap(None), job.out.with_extension("nodes.png"), &meta)?;
+        save_image(&costs.tri_heatmap(None), job.out.with_extension("tris.png"), &meta)?;
+    }
+    if let Some(aovs) = aovs.filter(|_| job.aovs) {
+        let layers = [
+            ("", &img), ("P", &aovs.p), ("Pobj", &aovs.p_obj), ("N", &aovs.n), ("Z", &aovs.depth),
+        ];
+        save_exr(&layers, job.out.with_extension("aovs.exr"), &meta)?;
+    }
+    if job.journal {
+        std::fs::remove_file(job.out.with_extension("journal"))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    trace::set_subscriber(StderrSubscriber::default());
+    let path = match std::env::args().nth(1) {
+        Some(x) => PathBuf::from(x),
+        None => {
+            eprintln!("usage: render-batch <job file>");
+            std::process::exit(2);
+        },
+    };
+    let desc = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path.display(), e);
+        std::process::exit(2);
+    });
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+    let jobs = parse_jobs(&desc, base).unwrap_or_else(|e| {
+        eprintln!("invalid job file: {}", e);
+        std::process::exit(2);
+    });
+    let mut assets = AssetCache::new();
+    let mut nfail = 0;
+    for (i, job) in jobs.iter().enumerate() {
+        trace::event(Level::Info, &format!("job {}/{}: {}", i + 1, jobs.len(), job.out.display()));
+        if let Err(e) = run(job, &mut assets) {
+            trace::event(Level::Warn, &format!("job {} failed: {}", i + 1, e));
+            nfail += 1;
+        }
+    }
+    if nfail > 0 {
+        eprintln!("{} of {} jobs failed", nfail, jobs.len());
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,196 @@
+//! Headless render service.
+//!
+//! `POST /render?w=256&h=256&spp=16` with a scene description as the body,
+//! in the format of `lighar::desc`. `camera` names the camera of the scene to
+//! render from, the first one by default. Descriptions can't refer to files
+//! on the server. The response is a `multipart/x-mixed-replace` stream of PNG images,
+//! one after each sample per pixel, the last of which is the final image.
+//! Browsers show the stream as a progressively refined image.
+//!
+//! Requests are served by a fixed pool of workers, each render using every
+//! core, and connections beyond those queued for the workers are turned away
+//! with `503 Service Unavailable`. Clients stalling for longer than
+//! `TIMEOUT` or sending oversized headers are dropped.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
+use lighar::geom::*;
+use lighar::rt::*;
+use lighar::img::*;
+use lighar::desc::parse_scene;
+use lighar::trace::{self, Level, StderrSubscriber};
+
+/// Largest accepted frame, in pixels along each side.
+const MAX_SIZE: u32 = 4096;
+const MAX_SPP: u32 = 4096;
+/// Largest accepted scene description, in bytes.
+const MAX_BODY: usize = 1 << 20;
+/// Largest accepted request or header line, in bytes.
+const MAX_HEADER_LINE: usize = 8 << 10;
+/// Largest accepted request line and headers altogether, in bytes.
+const MAX_HEADER: usize = 64 << 10;
+/// Time a client can take to send or receive anything before its connection
+/// is dropped, so that stalled clients don't hold workers.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of requests served at the same time.
+const NWORKER: usize = 2;
+/// Number of connections waiting for a worker.
+const MAX_QUEUE: usize = 16;
+
+/// Encode the average of `passes` accumulated samples as a PNG image.
+fn encode_png(accum: &[Color], w: u32, h: u32, passes: u32) -> Vec<u8> {
+    let rn = (passes as f32).recip();
+    let rgb = accum.iter()
+        .flat_map(|&x| {
+            let x: [u8; 3] = (x * rn).into();
+            x.to_vec()
+        })
+        .collect::<Vec<_>>();
+    let mut png = Vec::new();
+    image::png::PNGEncoder::new(&mut png)
+        .encode(&rgb, w, h, image::ColorType::Rgb8)
+        .expect("encoding into memory cannot fail");
+    png
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    write!(stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)
+}
+
+/// Read a line of at most `limit` bytes, or `None` if it's longer.
+fn read_line_within<R: BufRead>(reader: &mut R, limit: usize) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    let n = reader.take(limit as u64).read_line(&mut line)?;
+    if n == limit && !line.ends_with('\n') { return Ok(None) }
+    Ok(Some(line))
+}
+
+fn handle(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut budget = MAX_HEADER;
+    let mut next_line = |reader: &mut BufReader<TcpStream>| {
+        let line = read_line_within(reader, MAX_HEADER_LINE.min(budget))?;
+        budget -= line.as_ref().map_or(0, |x| x.len());
+        Ok::<_, std::io::Error>(line)
+    };
+    let too_large = "431 Request Header Fields Too Large";
+    let request_line = match next_line(&mut reader)? {
+        Some(x) => x,
+        None => return respond(&mut stream, too_large, "request line too long\n"),
+    };
+    let mut content_len = 0;
+    loop {
+        let line = match next_line(&mut reader)? {
+            Some(x) => x,
+            None => return respond(&mut stream, too_large, "headers too large\n"),
+        };
+        let line = line.trim();
+        if line.is_empty() { break }
+        let mut kv = line.splitn(2, ':');
+        let (k, v) = (kv.next().unwrap_or(""), kv.next().unwrap_or("").trim());
+        if k.eq_ignore_ascii_case("content-length") {
+            content_len = match v.parse::<usize>() {
+                Ok(x) => x,
+                Err(_) => return respond(&mut stream, "400 Bad Request", "invalid content length\n"),
+            };
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let mut target = target.splitn(2, '?');
+    let (path, query) = (target.next().unwrap_or(""), target.next().unwrap_or(""));
+    if method != "POST" || path != "/render" {
+        return respond(&mut stream, "404 Not Found", "POST a scene to /render\n");
+    }
+    if content_len > MAX_BODY {
+        let msg = format!("scene descriptions are limited to {} bytes\n", MAX_BODY);
+        return respond(&mut stream, "413 Payload Too Large", &msg);
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body)?;
+    let desc = String::from_utf8_lossy(&body);
+
+    let arg = |key: &str| {
+        query.split('&')
+            .filter_map(|x| {
+                let mut kv = x.splitn(2, '=');
+                Some((kv.next()?, kv.next()?))
+            })
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    };
+    let param = |key: &str, default: u32| {
+        arg(key).and_then(|v| v.parse::<u32>().ok()).unwrap_or(default)
+    };
+    let w = param("w", 256).clamp(1, MAX_SIZE);
+    let h = param("h", 256).clamp(1, MAX_SIZE);
+    let spp = param("spp", 16).clamp(1, MAX_SPP);
+    let camera = arg("camera");
+    let rt = parse_scene(&desc, Path::new("."), None)
+        .and_then(|x| x.into_tracer(camera, w, h));
+    let rt = match rt {
+        Ok(x) => x,
+        Err(e) => return respond(&mut stream, "400 Bad Request", &format!("{}\n", e)),
+    };
+    trace::event(Level::Info, &format!("rendering {}x{} at {} spp", w, h, spp));
+
+    write!(stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary=frame\r\n\
+        Connection: close\r\n\r\n")?;
+    let mut accum = vec![Color::default(); (w * h) as usize];
+    let mut pass = Image::new(w as usize, h as usize);
+    for ipass in 1..=spp {
+        rt.draw(&mut pass);
+        for (i, x) in accum.iter_mut().enumerate() {
+            *x = *x + pass.load_px(i % w as usize, i / w as usize);
+        }
+        let png = encode_png(&accum, w, h, ipass);
+        // Clients that went away fail the write and abort the render.
+        write!(stream,
+            "--frame\r\nContent-Type: image/png\r\nContent-Length: {}\r\nX-Pass: {}\r\n\r\n",
+            png.len(), ipass)?;
+        stream.write_all(&png)?;
+        stream.write_all(b"\r\n")?;
+    }
+    stream.write_all(b"--frame--\r\n")
+}
+
+fn main() {
+    trace::set_subscriber(StderrSubscriber::default());
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    let listener = TcpListener::bind(&addr).expect("failed to bind the server address");
+    trace::event(Level::Info, &format!("listening on {}", addr));
+    let (tx, rx) = mpsc::sync_channel::<TcpStream>(MAX_QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..NWORKER {
+        let rx = rx.clone();
+        std::thread::spawn(move || loop {
+            // Release the queue before serving, so other workers can wait on
+            // it meanwhile.
+            let stream = match rx.lock().unwrap().recv() {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            if let Err(e) = handle(stream) {
+                trace::event(Level::Warn, &format!("request failed: {}", e));
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(mpsc::TrySendError::Full(mut stream)) = tx.try_send(stream) {
+                    trace::event(Level::Warn, "too many requests, connection refused");
+                    let _ = respond(&mut stream, "503 Service Unavailable", "the server is busy\n");
+                }
+            },
+            Err(e) => trace::event(Level::Warn, &format!("connection failed: {}", e)),
+        }
+    }
+}
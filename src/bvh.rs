@@ -0,0 +1,514 @@
+use crate::geom::{Real, Point, Ray, Triangle};
+use crate::scene::Scene;
+use crate::accel::AccelStats;
+use crate::trace;
+use crate::par;
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+impl Aabb {
+    /// A box containing nothing, which any point grows it to.
+    pub fn empty() -> Aabb {
+        const INF: Real = Real::INFINITY;
+        Aabb {
+            min: Point(INF, INF, INF),
+            max: Point(-INF, -INF, -INF),
+        }
+    }
+    pub fn grow(self, p: Point) -> Aabb {
+        Aabb {
+            min: Point(self.min.0.min(p.0), self.min.1.min(p.1), self.min.2.min(p.2)),
+            max: Point(self.max.0.max(p.0), self.max.1.max(p.1), self.max.2.max(p.2)),
+        }
+    }
+    pub fn union(self, rhs: Aabb) -> Aabb {
+        self.grow(rhs.min).grow(rhs.max)
+    }
+    pub fn of_tri(tri: &Triangle) -> Aabb {
+        Aabb::empty()
+            .grow(tri.o)
+            .grow(tri.o.affine_add(tri.x))
+            .grow(tri.o.affine_add(tri.y))
+    }
+    pub fn centroid(&self) -> Point {
+        Point(
+            0.5 * (self.min.0 + self.max.0),
+            0.5 * (self.min.1 + self.max.1),
+            0.5 * (self.min.2 + self.max.2),
+        )
+    }
+    pub fn surface_area(&self) -> Real {
+        let d = self.max.rel_from(self.min);
+        if d.0 < 0.0 || d.1 < 0.0 || d.2 < 0.0 { return 0.0 }
+        2.0 * (d.0 * d.1 + d.1 * d.2 + d.2 * d.0)
+    }
+    /// Parametric distances at which `ray` enters and leaves the box, if it
+    /// passes through the box ahead of its origin. The ray direction doesn't
+    /// have to be normalized.
+    pub fn clip(&self, ray: &Ray) -> Option<(Real, Real)> {
+        let slab = |o: Real, v: Real, min: Real, max: Real| {
+            let rv = v.recip();
+            let t0 = (min - o) * rv;
+            let t1 = (max - o) * rv;
+            if t0 < t1 { (t0, t1) } else { (t1, t0) }
+        };
+        let (tx0, tx1) = slab(ray.o.0, ray.v.0, self.min.0, self.max.0);
+        let (ty0, ty1) = slab(ray.o.1, ray.v.1, self.min.1, self.max.1);
+        let (tz0, tz1) = slab(ray.o.2, ray.v.2, self.min.2, self.max.2);
+        // `max` and `min` drop the NaNs of rays parallel to a slab with the
+        // origin on its boundary.
+        let tmin = tx0.max(ty0).max(tz0).max(0.0);
+        // Be conservative about rounding errors, or rays grazing the edges
+        // of triangles might slip through their boxes.
+        let tmax = tx1.min(ty1).min(tz1) * (1.0 + 4.0 * Real::EPSILON);
+        if tmin <= tmax { Some((tmin, tmax)) } else { None }
+    }
+    /// Whether `ray` passes through the box ahead of its origin.
+    pub fn hit(&self, ray: &Ray) -> bool {
+        self.clip(ray).is_some()
+    }
+}
+
+/// Reference to the `tri`-th triangle of the `obj`-th object in a scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TriRef {
+    pub obj: usize,
+    pub tri: usize,
+}
+impl TriRef {
+    /// References to all triangles in `scene`.
+    pub fn all<M>(scene: &Scene<M>) -> Vec<TriRef> {
+        scene.objs.iter()
+            .enumerate()
+            .flat_map(|(iobj, obj)| {
+                (0..obj.idxs.len()).map(move |itri| TriRef { obj: iobj, tri: itri })
+            })
+            .collect()
+    }
+    /// The referenced triangle, transformed the same way as in `RayTracer`.
+    pub fn resolve<M>(self, scene: &Scene<M>) -> Triangle {
+        let obj = &scene.objs[self.obj];
+        let (x, y, z) = obj.idxs[self.tri];
+        Triangle::new(
+            obj.world2obj * obj.verts[x],
+            obj.world2obj * obj.verts[y],
+            obj.world2obj * obj.verts[z],
+        )
+    }
+}
+
+/// Maximum number of triangles in a leaf.
+const MAX_LEAF_SIZE: usize = 4;
+/// Maximum depth of the tree, which bounds the traversal stack.
+pub(crate) const MAX_DEPTH: usize = 48;
+/// Relative costs of visiting a node and intersecting a triangle.
+const TRAVERSAL_COST: Real = 1.0;
+const INTERSECT_COST: Real = 1.0;
+/// Number of bins the surface area heuristic is evaluated at.
+const NBIN: usize = 16;
+/// Nodes with at least this many triangles are built in parallel.
+const PAR_THRESHOLD: usize = 4096;
+/// Rebuild on `update` once refits made the tree this much more costly than it
+/// was when built.
+const REBUILD_RATIO: Real = 1.5;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Node {
+    pub(crate) bounds: Aabb,
+    /// Index of the first of the two consecutive children of an inner node,
+    /// or of the first triangle of a leaf.
+    pub(crate) start: usize,
+    /// Number of triangles in a leaf, 0 for inner nodes.
+    pub(crate) ntri: usize,
+}
+
+/// Bounding volume hierarchy over all the triangles of a scene. Children are
+/// always stored after their parents.
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) prims: Vec<(TriRef, Triangle)>,
+    build_cost: Real,
+}
+impl Bvh {
+    /// Build a BVH by the binned surface area heuristic. Large nodes are built
+    /// in parallel.
+    pub fn build<M: Sync>(scene: &Scene<M>) -> Bvh {
+        Bvh::with_tris(scene, &TriRef::all(scene))
+    }
+    /// Same as `build` but only over the triangles `tris`.
+    pub fn with_tris<M: Sync>(scene: &Scene<M>, tris: &[TriRef]) -> Bvh {
+        use crate::par::*;
+        let _span = trace::span_with("bvh_build", || format!("{} triangles", tris.len()));
+        let mut prims = tris.par_iter()
+            .map(|&r| (r, r.resolve(scene)))
+            .collect::<Vec<_>>();
+        let nodes = if prims.is_empty() {
+            Vec::new()
+        } else {
+            build_subtree(&mut prims, 0, 0)
+        };
+        let mut bvh = Bvh { nodes, prims, build_cost: 0.0 };
+        bvh.build_cost = bvh.cost();
+        bvh
+    }
+    fn bounds_of(&self, start: usize, ntri: usize) -> Aabb {
+        bounds_of(&self.prims[start..start + ntri])
+    }
+
+    /// Update the triangles and node bounds after objects moved or deformed,
+    /// without changing the tree topology. The objects and their index buffers
+    /// must be the same as when the BVH was built.
+    pub fn refit<M>(&mut self, scene: &Scene<M>) {
+        for (r, tri) in self.prims.iter_mut() {
+            *tri = r.resolve(scene);
+        }
+        for i in (0..self.nodes.len()).rev() {
+            let Node { start, ntri, .. } = self.nodes[i];
+            self.nodes[i].bounds = if ntri > 0 {
+                self.bounds_of(start, ntri)
+            } else {
+                self.nodes[start].bounds.union(self.nodes[start + 1].bounds)
+            };
+        }
+    }
+    /// Expected cost of tracing a ray by the surface area heuristic. It grows
+    /// as refits make the bounds overlap.
+    pub fn cost(&self) -> Real {
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bounds.surface_area(),
+            None => return 0.0,
+        };
+        if root_area == 0.0 { return 0.0 }
+        self.nodes.iter()
+            .map(|node| {
+                let p = node.bounds.surface_area() / root_area;
+                if node.ntri > 0 {
+                    p * INTERSECT_COST * node.ntri as Real
+                } else {
+                    p * TRAVERSAL_COST
+                }
+            })
+            .sum()
+    }
+    /// Number of nodes from the root to the deepest leaf.
+    pub fn depth(&self) -> usize {
+        if self.nodes.is_empty() { return 0 }
+        // Children are stored after their parents.
+        let mut depths = vec![0; self.nodes.len()];
+        depths[0] = 1;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.ntri == 0 {
+                depths[node.start] = depths[i] + 1;
+                depths[node.start + 1] = depths[i] + 1;
+            }
+        }
+        depths.into_iter().max().unwrap_or(0)
+    }
+    pub fn stats(&self) -> AccelStats {
+        AccelStats {
+            nnode: self.nodes.len(),
+            depth: self.depth(),
+            bytes: std::mem::size_of_val(self.nodes.as_slice()) +
+                std::mem::size_of_val(self.prims.as_slice()),
+            cost: self.cost(),
+        }
+    }
+    /// Whether refits have degraded the tree enough to be worth a rebuild.
+    pub fn degraded(&self) -> bool {
+        self.cost() > REBUILD_RATIO * self.build_cost
+    }
+    /// Refit to the scene, or rebuild if the refitted tree is degraded.
+    /// Returns whether the BVH has been rebuilt.
+    pub fn update<M: Sync>(&mut self, scene: &Scene<M>) -> bool {
+        self.refit(scene);
+        if self.degraded() {
+            let tris = self.prims.iter().map(|(r, _)| *r).collect::<Vec<_>>();
+            *self = Bvh::with_tris(scene, &tris);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Invoke `f` with the triangles in the leaves `ray` passes through, in no
+    /// particular order, until `f` returns false.
+    pub fn traverse<F>(&self, ray: &Ray, mut f: F)
+        where F: FnMut(TriRef, &Triangle) -> bool
+    {
+        self.traverse_within(ray, Real::INFINITY, |r, tri, _| f(r, tri))
+    }
+    /// Same as `traverse` but only into nodes `ray` enters within parametric
+    /// distance `tmax`, which `f` can lower, e.g., to the closest hit so far.
+    /// Nearer children are visited first so that farther ones are more likely
+    /// to be skipped.
+    pub fn traverse_within<F>(&self, ray: &Ray, tmax: Real, f: F)
+        where F: FnMut(TriRef, &Triangle, &mut Real) -> bool
+    {
+        self.traverse_counted(ray, tmax, f, &mut 0)
+    }
+    /// Same as `traverse_within` but the nodes visited are counted into
+    /// `nnode`.
+    pub fn traverse_counted<F>(&self, ray: &Ray, tmax: Real, mut f: F, nnode: &mut usize)
+        where F: FnMut(TriRef, &Triangle, &mut Real) -> bool
+    {
+        if self.nodes.is_empty() { return }
+        let mut tmax = tmax;
+        let tenter = match self.nodes[0].bounds.clip(ray) {
+            Some((t0, _)) => t0,
+            None => return,
+        };
+        let mut stack = [(0, 0.0); MAX_DEPTH + 1];
+        stack[0] = (0, tenter);
+        let mut nstack = 1;
+        while nstack > 0 {
+            nstack -= 1;
+            let (inode, tenter) = stack[nstack];
+            if tenter > tmax { continue }
+            let node = &self.nodes[inode];
+            *nnode += 1;
+            if node.ntri > 0 {
+                for (r, tri) in self.prims[node.start..node.start + node.ntri].iter() {
+                    if !f(*r, tri, &mut tmax) { return }
+                }
+                continue;
+            }
+            let enter = |i: usize| {
+                self.nodes[i].bounds.clip(ray)
+                    .map(|(t0, _)| (i, t0))
+                    .filter(|&(_, t0)| t0 <= tmax)
+            };
+            match (enter(node.start), enter(node.start + 1)) {
+                (Some(a), Some(b)) => {
+                    // The nearer child is popped first.
+                    let (near, far) = if a.1 <= b.1 { (a, b) } else { (b, a) };
+                    stack[nstack] = far;
+                    stack[nstack + 1] = near;
+                    nstack += 2;
+                },
+                (Some(x), None) | (None, Some(x)) => {
+                    stack[nstack] = x;
+                    nstack += 1;
+                },
+                (None, None) => {},
+            }
+        }
+    }
+}
+
+/// Bounding volume hierarchy over the boxes of arbitrary primitives, e.g., of
+/// the curves and splats of a `Shape`, split at the median centroid along the
+/// longest axis. It's cheaper to build than `Bvh`, and primitives are only
+/// known by their indices into the boxes it's built over.
+#[derive(Debug, Clone, Default)]
+pub struct BoxBvh {
+    nodes: Vec<Node>,
+    /// Primitive indices referred to by the leaves.
+    prims: Vec<usize>,
+}
+impl BoxBvh {
+    pub fn new(boxes: &[Aabb]) -> BoxBvh {
+        let mut prims = (0..boxes.len()).collect::<Vec<_>>();
+        let mut nodes = Vec::new();
+        if !boxes.is_empty() {
+            nodes.push(Node { bounds: Aabb::empty(), start: 0, ntri: 0 });
+            build_boxes(boxes, &mut prims, 0, 0, 0, &mut nodes);
+        }
+        BoxBvh { nodes, prims }
+    }
+    /// Bounds of all the primitives, empty if there are none.
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or_else(Aabb::empty, |x| x.bounds)
+    }
+    /// Invoke `f` with the indices of the primitives in the leaves `ray`
+    /// enters within parametric distance `tmax`, which `f` can lower, e.g.,
+    /// to the closest hit so far.
+    pub fn traverse_within<F>(&self, ray: &Ray, tmax: Real, mut f: F)
+        where F: FnMut(usize, &mut Real)
+    {
+        if self.nodes.is_empty() { return }
+        let mut tmax = tmax;
+        let mut stack = [0; MAX_DEPTH + 2];
+        let mut nstack = 1;
+        while nstack > 0 {
+            nstack -= 1;
+            let node = &self.nodes[stack[nstack]];
+            match node.bounds.clip(ray) {
+                Some((t0, _)) if t0 <= tmax => {},
+                _ => continue,
+            }
+            if node.ntri > 0 {
+                for &i in self.prims[node.start..node.start + node.ntri].iter() {
+                    f(i, &mut tmax);
+                }
+            } else {
+                stack[nstack] = node.start;
+                stack[nstack + 1] = node.start + 1;
+                nstack += 2;
+            }
+        }
+    }
+}
+/// Build node `inode` over `prims`, which start at `offset` in all primitive
+/// indices, appending the nodes below it to `nodes`.
+fn build_boxes(
+    boxes: &[Aabb],
+    prims: &mut [usize],
+    offset: usize,
+    inode: usize,
+    depth: usize,
+    nodes: &mut Vec<Node>,
+) {
+    let bounds = prims.iter().fold(Aabb::empty(), |seed, &i| seed.union(boxes[i]));
+    if prims.len() <= MAX_LEAF_SIZE || depth >= MAX_DEPTH {
+        nodes[inode] = Node { bounds, start: offset, ntri: prims.len() };
+        return;
+    }
+    let cbounds = prims.iter().fold(Aabb::empty(), |seed, &i| seed.grow(boxes[i].centroid()));
+    let d = cbounds.max.rel_from(cbounds.min);
+    let axis = if d.0 >= d.1 && d.0 >= d.2 { 0 } else if d.1 >= d.2 { 1 } else { 2 };
+    let key = |i: usize| {
+        let c = boxes[i].centroid();
+        match axis { 0 => c.0, 1 => c.1, _ => c.2 }
+    };
+    let mid = prims.len() / 2;
+    prims.select_nth_unstable_by(mid, |&a, &b| key(a).total_cmp(&key(b)));
+    let child = nodes.len();
+    nodes.push(Node { bounds: Aabb::empty(), start: 0, ntri: 0 });
+    nodes.push(Node { bounds: Aabb::empty(), start: 0, ntri: 0 });
+    nodes[inode] = Node { bounds, start: child, ntri: 0 };
+    let (left, right) = prims.split_at_mut(mid);
+    build_boxes(boxes, left, offset, child, depth + 1, nodes);
+    build_boxes(boxes, right, offset + mid, child + 1, depth + 1, nodes);
+}
+
+type Prim = (TriRef, Triangle);
+
+fn bounds_of(prims: &[Prim]) -> Aabb {
+    let f = |seed: Aabb, (_, tri): &Prim| seed.union(Aabb::of_tri(tri));
+    if prims.len() >= PAR_THRESHOLD {
+        par::fold_reduce(prims, Aabb::empty, f, Aabb::union)
+    } else {
+        prims.iter().fold(Aabb::empty(), f)
+    }
+}
+fn centroid_of(tri: &Triangle) -> Point {
+    Aabb::of_tri(tri).centroid()
+}
+
+/// Build the subtree over `prims`, which start at `offset` in all primitives.
+/// The root of the subtree is its first node, and inner nodes refer to their
+/// children by indices relative to the subtree.
+fn build_subtree(prims: &mut [Prim], offset: usize, depth: usize) -> Vec<Node> {
+    let bounds = bounds_of(prims);
+    let ntri = prims.len();
+    if ntri <= MAX_LEAF_SIZE || depth >= MAX_DEPTH {
+        return vec![Node { bounds, start: offset, ntri }];
+    }
+    let mid = sah_split(prims);
+    let (left, right) = prims.split_at_mut(mid);
+    let (left, right) = if ntri >= PAR_THRESHOLD {
+        par::join(
+            || build_subtree(left, offset, depth + 1),
+            || build_subtree(right, offset + mid, depth + 1),
+        )
+    } else {
+        (
+            build_subtree(left, offset, depth + 1),
+            build_subtree(right, offset + mid, depth + 1),
+        )
+    };
+    // Lay out as the root, the roots of both subtrees, and then the rest of
+    // the left and right subtrees, so that children stay consecutive.
+    let relocate = |node: &Node, shift: usize| {
+        if node.ntri > 0 { *node } else { Node { start: node.start + shift, ..*node } }
+    };
+    let lshift = 2;
+    let rshift = left.len() + 1;
+    let mut nodes = Vec::with_capacity(1 + left.len() + right.len());
+    nodes.push(Node { bounds, start: 1, ntri: 0 });
+    nodes.push(relocate(&left[0], lshift));
+    nodes.push(relocate(&right[0], rshift));
+    nodes.extend(left[1..].iter().map(|x| relocate(x, lshift)));
+    nodes.extend(right[1..].iter().map(|x| relocate(x, rshift)));
+    nodes
+}
+
+/// Partition `prims` at the cheapest of the bin boundaries along the longest
+/// axis of their centroids, by the surface area heuristic. Returns the number
+/// of primitives in the first part, which is never 0 or all of them.
+fn sah_split(prims: &mut [Prim]) -> usize {
+    let ntri = prims.len();
+    let f = |seed: Aabb, (_, tri): &Prim| seed.grow(centroid_of(tri));
+    let cbounds = if ntri >= PAR_THRESHOLD {
+        par::fold_reduce(prims, Aabb::empty, f, Aabb::union)
+    } else {
+        prims.iter().fold(Aabb::empty(), f)
+    };
+    let d = cbounds.max.rel_from(cbounds.min);
+    let axis = if d.0 >= d.1 && d.0 >= d.2 { 0 } else if d.1 >= d.2 { 1 } else { 2 };
+    let (min, extent) = match axis {
+        0 => (cbounds.min.0, d.0),
+        1 => (cbounds.min.1, d.1),
+        _ => (cbounds.min.2, d.2),
+    };
+    if extent <= 0.0 {
+        // All centroids coincide, any split is as good as another.
+        return ntri / 2;
+    }
+    let bin_of = |tri: &Triangle| {
+        let c = centroid_of(tri);
+        let c = match axis { 0 => c.0, 1 => c.1, _ => c.2 };
+        (((c - min) / extent * NBIN as Real) as usize).min(NBIN - 1)
+    };
+
+    type Bins = [(Aabb, usize); NBIN];
+    let empty = || [(Aabb::empty(), 0); NBIN];
+    let add = |mut bins: Bins, (_, tri): &Prim| {
+        let bin = &mut bins[bin_of(tri)];
+        *bin = (bin.0.union(Aabb::of_tri(tri)), bin.1 + 1);
+        bins
+    };
+    let merge = |mut a: Bins, b: Bins| {
+        for (x, y) in a.iter_mut().zip(b.iter()) {
+            *x = (x.0.union(y.0), x.1 + y.1);
+        }
+        a
+    };
+    let bins = if ntri >= PAR_THRESHOLD {
+        par::fold_reduce(prims, empty, add, merge)
+    } else {
+        prims.iter().fold(empty(), add)
+    };
+
+    // Cost of splitting after each bin, sweeping from the right first.
+    let mut rcost = [0.0; NBIN];
+    let mut acc = (Aabb::empty(), 0);
+    for i in (1..NBIN).rev() {
+        acc = (acc.0.union(bins[i].0), acc.1 + bins[i].1);
+        rcost[i - 1] = acc.0.surface_area() * acc.1 as Real;
+    }
+    let mut best = (Real::INFINITY, 0);
+    let mut acc = (Aabb::empty(), 0);
+    for i in 0..NBIN - 1 {
+        acc = (acc.0.union(bins[i].0), acc.1 + bins[i].1);
+        if acc.1 == 0 || acc.1 == ntri { continue }
+        let cost = acc.0.surface_area() * acc.1 as Real + rcost[i];
+        if cost < best.0 {
+            best = (cost, i);
+        }
+    }
+
+    let mut mid = 0;
+    for i in 0..ntri {
+        if bin_of(&prims[i].1) <= best.1 {
+            prims.swap(mid, i);
+            mid += 1;
+        }
+    }
+    mid
+}
@@ -0,0 +1,473 @@
+use std::sync::Arc;
+use crate::geom::{
+    Real, Point, Vector, Ray, Color, Transform, Triangle, Barycentric, Handedness, ray_cast_tri_with,
+    disk, narrow,
+};
+use crate::scene::Scene;
+use crate::arena::with_verts;
+use crate::img::PixelSource;
+use crate::sampler::TexelDistribution;
+use crate::rt::Region;
+use crate::bvh::Aabb;
+use crate::units::CameraExposure;
+
+/// Imperfections of a physical lens.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lens {
+    /// Darken the image towards its edges by the cosine-fourth law.
+    pub vignetting: bool,
+    /// Radial distortion coefficient. Positive values give barrel distortion
+    /// and negative values give pincushion distortion.
+    pub distortion: Real,
+    /// Extra distortion of the blue channel, and the opposite for the red
+    /// channel. The green channel is not affected.
+    pub chromatic_aberration: Real,
+}
+
+/// Shape of the lens opening, which out-of-focus highlights take.
+#[derive(Debug, Clone, Default)]
+pub enum Aperture {
+    #[default]
+    Circle,
+    /// Regular polygon formed by `blades` straight blades, with a corner at
+    /// angle `rotation` in radians.
+    Polygon { blades: u32, rotation: Real },
+    /// Any shape given by a mask.
+    Mask(Arc<ApertureMask>),
+}
+impl Aperture {
+    /// Map `a` and `b` in [0..1) to a point in the unit aperture, uniformly
+    /// distributed over the opening.
+    pub fn sample(&self, a: Real, b: Real) -> (Real, Real) {
+        match self {
+            Aperture::Circle => disk(a, b),
+            Aperture::Polygon { blades, rotation } => {
+                use std::f64::consts::PI;
+                let n = (*blades).max(3);
+                // Pick one of the triangles fanning out from the center, then
+                // a point in it.
+                let i = ((a * n as Real) as u32).min(n - 1);
+                let a = a * n as Real - i as Real;
+                let corner = |i: u32| {
+                    let theta = *rotation + i as Real * (2.0 * PI as Real) / n as Real;
+                    theta.sin_cos()
+                };
+                let (s0, c0) = corner(i);
+                let (s1, c1) = corner(i + 1);
+                let r = a.sqrt();
+                (r * ((1.0 - b) * c0 + b * c1), r * ((1.0 - b) * s0 + b * s1))
+            },
+            Aperture::Mask(mask) => mask.sample(a, b),
+        }
+    }
+}
+
+/// Transmittance mask of an aperture. The mask spans the square bounding the
+/// unit aperture, and the luminance of a texel is the fraction of light it
+/// lets through.
+#[derive(Debug, Clone)]
+pub struct ApertureMask {
+    dist: TexelDistribution,
+}
+impl ApertureMask {
+    /// Make a mask of the luminance of `img`, or `None` if `img` is empty or
+    /// black all over, so that no light gets through.
+    pub fn new<I: PixelSource>(img: &I) -> Option<ApertureMask> {
+        let dist = TexelDistribution::new(img);
+        if dist.is_empty() { return None }
+        Some(ApertureMask { dist })
+    }
+    /// Map `a` and `b` in [0..1) to a point in `[-1, 1]` squared distributed
+    /// proportionally to the transmittance.
+    pub fn sample(&self, a: Real, b: Real) -> (Real, Real) {
+        let (u, v) = self.dist.sample(narrow(a), narrow(b));
+        ((u * 2.0 - 1.0) as Real, (v * 2.0 - 1.0) as Real)
+    }
+}
+
+/// Arrangement of the views of both eyes in a stereo frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// Left eye in the top half and right eye in the bottom half, as usual
+    /// for 360 degree videos.
+    TopBottom,
+    /// Left eye in the left half and right eye in the right half, as usual
+    /// for VR180 videos.
+    SideBySide,
+}
+
+/// How screen points map to rays leaving the camera.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// Perspective through the thin lens, see `Camera::fov`.
+    Perspective,
+    /// Latitude-longitude panorama around the camera, spanning `h_fov`
+    /// radians horizontally and half a turn vertically, e.g., 2 pi for a full
+    /// 360 degree panorama at an aspect ratio of 2 or pi for VR180.
+    Equirect { h_fov: Real },
+    /// Omnidirectional stereo panorama for VR headsets: an `Equirect`
+    /// panorama for each eye arranged by `layout`. Each column is seen from
+    /// eyes `ipd` apart on a circle, perpendicular to the view direction of
+    /// the column, so that parallax is right wherever the viewer turns to.
+    ///
+    /// See: Google, Rendering Omni-directional Stereo Content.
+    Stereo { h_fov: Real, ipd: Real, layout: StereoLayout },
+}
+
+/// A thin-lens camera looking down the positive z-axis of its local space.
+/// Screen coordinates `x` and `y` in [-1, 1] are mapped to the local x and y
+/// axes respectively, see `handedness`.
+#[derive(Debug, Clone)]
+pub struct Camera {
+    /// Camera local space to world space.
+    pub cam2world: Transform,
+    /// Vertical field of view in radians.
+    pub fov: Real,
+    /// Width divided by height.
+    pub aspect: Real,
+    /// Radius of the lens. The camera is a pinhole camera if it's zero.
+    pub aperture: Real,
+    /// Distance from the lens to the plane in focus, along the view axis.
+    pub focal_dist: Real,
+    /// Shape of the lens opening of radius `aperture`.
+    pub shape: Aperture,
+    pub lens: Lens,
+    /// Extra border beyond the frame, as a fraction of the frame size on each
+    /// side, see `overscan_frame`. The framing of the original frame is kept.
+    pub overscan: Real,
+    /// Panoramas ignore `fov`, the lens and its aperture.
+    pub projection: Projection,
+    /// Handedness of the local space seen with screen x to the right and
+    /// screen y up. It's left-handed by default, which sees the right-handed
+    /// scene space mirrored; right-handed cameras map screen x to the local
+    /// negative x-axis instead, so that assets look as in their authoring
+    /// tools.
+    pub handedness: Handedness,
+    /// Sensor settings mapping radiance to pixel values, see
+    /// `exposure_scale`. Radiance is taken as it is if `None`.
+    pub exposure: Option<CameraExposure>,
+}
+impl Camera {
+    pub fn new(cam2world: Transform, fov: Real, aspect: Real) -> Camera {
+        Camera {
+            cam2world,
+            fov,
+            aspect,
+            aperture: 0.0,
+            focal_dist: 1.0,
+            shape: Aperture::default(),
+            lens: Lens::default(),
+            overscan: 0.0,
+            projection: Projection::Perspective,
+            handedness: Handedness::Left,
+            exposure: None,
+        }
+    }
+    /// Size of the frame to render for a `w` by `h` frame with overscan, and
+    /// the region of the original frame in it, e.g., to crop the render back
+    /// with `CropFramebuffer`. Borders are rounded to whole pixels, so the
+    /// framing is exact only if `overscan` times `w` and `h` are integers.
+    pub fn overscan_frame(&self, w: u32, h: u32) -> (u32, u32, Region) {
+        let px = (w as Real * self.overscan).round() as u32;
+        let py = (h as Real * self.overscan).round() as u32;
+        let region = Region { x0: px, y0: py, x1: px + w, y1: py + h };
+        (w + 2 * px, h + 2 * py, region)
+    }
+
+    /// Direction in local space through screen point `(x, y)` distorted by
+    /// radial coefficient `k`. The z component is always 1.
+    #[inline]
+    fn local_dir(&self, x: Real, y: Real, k: Real) -> Vector {
+        // Screen coordinates span the frame with borders.
+        let (x, y) = (x * (1.0 + 2.0 * self.overscan), y * (1.0 + 2.0 * self.overscan));
+        let tan = (self.fov * 0.5).tan();
+        let d = 1.0 + k * (x * x + y * y);
+        Vector(x * d * tan * self.aspect, y * d * tan, 1.0)
+    }
+    /// Ray in world space of `ray` in the local space of a left-handed
+    /// camera.
+    #[inline]
+    fn to_world(&self, ray: Ray) -> Ray {
+        let ray = match self.handedness {
+            Handedness::Left => ray,
+            Handedness::Right => Ray {
+                o: Point(-ray.o.0, ray.o.1, ray.o.2),
+                v: Vector(-ray.v.0, ray.v.1, ray.v.2),
+            },
+        };
+        self.cam2world * ray
+    }
+    /// Ray in local space of a panorama through screen point `(x, y)`.
+    fn panorama_ray(&self, x: Real, y: Real) -> Ray {
+        const FRAC_PI_2: Real = std::f64::consts::FRAC_PI_2 as Real;
+        let (h_fov, x, y, eye) = match self.projection {
+            Projection::Perspective => unreachable!(),
+            Projection::Equirect { h_fov } => (h_fov, x, y, 0.0),
+            // Remap the half of each eye to the whole screen.
+            Projection::Stereo { h_fov, ipd, layout } => match layout {
+                StereoLayout::TopBottom if y >= 0.0 => (h_fov, x, 2.0 * y - 1.0, -0.5 * ipd),
+                StereoLayout::TopBottom => (h_fov, x, 2.0 * y + 1.0, 0.5 * ipd),
+                StereoLayout::SideBySide if x < 0.0 => (h_fov, 2.0 * x + 1.0, y, -0.5 * ipd),
+                StereoLayout::SideBySide => (h_fov, 2.0 * x - 1.0, y, 0.5 * ipd),
+            },
+        };
+        let (sin_phi, cos_phi) = (x * 0.5 * h_fov).sin_cos();
+        let (sin_theta, cos_theta) = (y * FRAC_PI_2).sin_cos();
+        let v = Vector(cos_theta * sin_phi, sin_theta, cos_theta * cos_phi);
+        // Eyes are offset along the right of the column, negative for the
+        // left eye.
+        let o = Point(cos_phi * eye, 0.0, -sin_phi * eye);
+        Ray { o, v }
+    }
+    /// Generate a ray from the lens center through screen point `(x, y)`.
+    pub fn ray(&self, x: Real, y: Real) -> Ray {
+        if self.projection != Projection::Perspective {
+            return self.to_world(self.panorama_ray(x, y));
+        }
+        let ray = Ray {
+            o: Point(0.0, 0.0, 0.0),
+            v: self.local_dir(x, y, self.lens.distortion),
+        };
+        self.to_world(ray)
+    }
+    /// Generate a ray through screen point `(x, y)` leaving the lens at a
+    /// position decided by the lens sample `(a, b)` in [0..1).
+    pub fn ray_dof(&self, x: Real, y: Real, a: Real, b: Real) -> Ray {
+        self.lens_ray(x, y, a, b, self.lens.distortion)
+    }
+    /// Same as `ray_dof` but the ray is only meant to carry one color
+    /// channel, 0 for red, 1 for green and 2 for blue, so that chromatic
+    /// aberration can be simulated.
+    pub fn ray_channel(&self, x: Real, y: Real, a: Real, b: Real, channel: usize) -> Ray {
+        let ca = self.lens.chromatic_aberration * (channel as Real - 1.0);
+        self.lens_ray(x, y, a, b, self.lens.distortion + ca)
+    }
+    fn lens_ray(&self, x: Real, y: Real, a: Real, b: Real, k: Real) -> Ray {
+        if self.projection != Projection::Perspective {
+            return self.to_world(self.panorama_ray(x, y));
+        }
+        let dir = self.local_dir(x, y, k);
+        if self.aperture <= 0.0 {
+            let ray = Ray { o: Point(0.0, 0.0, 0.0), v: dir };
+            return self.to_world(ray);
+        }
+        let (lx, ly) = self.shape.sample(a, b);
+        let o = Point(lx * self.aperture, ly * self.aperture, 0.0);
+        let focus = Point(0.0, 0.0, 0.0).affine_add(dir * self.focal_dist);
+        let ray = Ray { o, v: focus.rel_from(o) };
+        self.to_world(ray)
+    }
+    /// Screen point `(x, y)` the world space point `p` is seen at, like the
+    /// inverse of `ray` without lens distortion, or `None` if `p` is out of
+    /// sight, e.g., behind the camera. Stereo panoramas see points in two
+    /// places, and none is returned. `cam2world` is expected to be free of
+    /// shear.
+    pub fn project(&self, p: Point) -> Option<(Real, Real)> {
+        const FRAC_PI_2: Real = std::f64::consts::FRAC_PI_2 as Real;
+        let axis = |v: Vector| {
+            let v = self.cam2world * v;
+            v / v.dot(v)
+        };
+        let d = p.rel_from(self.cam2world * Point(0.0, 0.0, 0.0));
+        let right = match self.handedness {
+            Handedness::Left => Vector(1.0, 0.0, 0.0),
+            Handedness::Right => Vector(-1.0, 0.0, 0.0),
+        };
+        let x = d.dot(axis(right));
+        let y = d.dot(axis(Vector(0.0, 1.0, 0.0)));
+        let z = d.dot(axis(Vector(0.0, 0.0, 1.0)));
+        match self.projection {
+            Projection::Perspective => {
+                if z <= 0.0 { return None }
+                let tan = (self.fov * 0.5).tan();
+                let border = 1.0 + 2.0 * self.overscan;
+                Some((x / z / (tan * self.aspect * border), y / z / (tan * border)))
+            },
+            Projection::Equirect { h_fov } => {
+                let sx = x.atan2(z) / (0.5 * h_fov);
+                let sy = y.atan2((x * x + z * z).sqrt()) / FRAC_PI_2;
+                if sx.abs() > 1.0 { return None }
+                Some((sx, sy))
+            },
+            Projection::Stereo { .. } => None,
+        }
+    }
+    /// Move the camera along its view axis so that `bounds`, e.g.,
+    /// `Scene::bounds`, is seen whole with a margin of a tenth of the frame
+    /// on each side, and focus it on the center of `bounds`. The view
+    /// direction is kept, and `aspect` should be set beforehand. Empty bounds
+    /// leave the camera unchanged.
+    pub fn frame(&mut self, bounds: &Aabb) {
+        const MARGIN: Real = 0.1;
+        const FRAC_PI_2: Real = std::f64::consts::FRAC_PI_2 as Real;
+        let d = bounds.max.rel_from(bounds.min);
+        if d.0 < 0.0 || d.1 < 0.0 || d.2 < 0.0 { return }
+        // Fit the bounding sphere in the cone of the narrower of the
+        // vertical and the horizontal fields of view.
+        let r = 0.5 * d.mag();
+        let half_fov = match self.projection {
+            Projection::Perspective => {
+                let tan = (self.fov * 0.5).tan() * (1.0 - 2.0 * MARGIN);
+                tan.min(tan * self.aspect).atan()
+            },
+            Projection::Equirect { h_fov } | Projection::Stereo { h_fov, .. } => {
+                (0.5 * h_fov).min(FRAC_PI_2) * (1.0 - 2.0 * MARGIN)
+            },
+        };
+        let dist = r / half_fov.sin();
+        let forward = (self.cam2world * Vector(0.0, 0.0, 1.0)).normalize();
+        let eye = bounds.centroid().affine_add(forward * -dist);
+        self.cam2world.af = eye.rel_from(Point(0.0, 0.0, 0.0));
+        self.focal_dist = dist;
+    }
+    /// Factor of `exposure` scaling the radiance seen by the camera.
+    pub fn exposure_scale(&self) -> f32 {
+        self.exposure.map_or(1.0, |x| x.scale())
+    }
+    /// Sample of the camera through screen point `(x, y)`: a ray leaving the
+    /// lens at a position decided by `(a, b)` in [0..1) like `ray_dof`, and
+    /// the weight of the radiance it carries, i.e., `vignette` and
+    /// `exposure_scale`. With chromatic aberration the ray only carries the
+    /// color channel picked by `c` in [0..1), see `ray_channel`, which is
+    /// weighted by 3 for the others, so that the channels average out over
+    /// samples. Alpha is always weighted by 1.
+    pub fn sample(&self, x: Real, y: Real, a: Real, b: Real, c: Real) -> (Ray, Color) {
+        let k = narrow(self.vignette(x, y)) * self.exposure_scale();
+        if self.lens.chromatic_aberration == 0.0 {
+            return (self.ray_dof(x, y, a, b), Color(k, k, k, 1.0));
+        }
+        let channel = ((c * 3.0) as usize).min(2);
+        let mut weight = Color(0.0, 0.0, 0.0, 1.0);
+        match channel {
+            0 => weight.0 = 3.0 * k,
+            1 => weight.1 = 3.0 * k,
+            _ => weight.2 = 3.0 * k,
+        }
+        (self.ray_channel(x, y, a, b, channel), weight)
+    }
+    /// Fraction of light reaching screen point `(x, y)` due to vignetting.
+    pub fn vignette(&self, x: Real, y: Real) -> Real {
+        if !self.lens.vignetting || self.projection != Projection::Perspective {
+            return 1.0;
+        }
+        let dir = self.local_dir(x, y, self.lens.distortion);
+        let cos_theta = dir.mag().recip();
+        let cos_theta2 = cos_theta * cos_theta;
+        cos_theta2 * cos_theta2
+    }
+    /// Trace a ray through screen point `(x, y)` and focus the camera on the
+    /// closest surface it hits. The new focal distance is returned, or `None`
+    /// if nothing is hit, in which case the camera is left unchanged.
+    pub fn focus_at<M>(&mut self, x: Real, y: Real, scene: &Scene<M>) -> Option<Real> {
+        let ray = self.ray(x, y);
+        let p = closest_hit_pos(&ray, scene)?;
+        let forward = (self.cam2world * Vector(0.0, 0.0, 1.0)).normalize();
+        let eye = self.cam2world * Point(0.0, 0.0, 0.0);
+        self.focal_dist = p.rel_from(eye).dot(forward);
+        Some(self.focal_dist)
+    }
+}
+
+/// Position of the closest intersection of `ray` with either face of any
+/// triangle visible to the camera.
+fn closest_hit_pos<M>(ray: &Ray, scene: &Scene<M>) -> Option<Point> {
+    let mut closest: Option<(Real, Point)> = None;
+    with_verts(|verts| {
+        for obj in scene.objs.iter() {
+            if !obj.visibility.camera { continue }
+            verts.clear();
+            verts.extend(obj.verts.iter().map(|&x| obj.world2obj * x));
+            for (x, y, z) in obj.idxs.iter() {
+                let tri = Triangle::new(verts[*x], verts[*y], verts[*z]);
+                if let Some(intersect) = ray_cast_tri_with(ray, &tri, &scene.precision) {
+                    let Barycentric { u, v } = intersect.attr;
+                    let p = tri.o.affine_add(u * tri.x + v * tri.y);
+                    let t = p.rel_from(ray.o).mag();
+                    if closest.map(|(tmax, _)| t < tmax).unwrap_or(true) {
+                        closest = Some((t, p));
+                    }
+                }
+            }
+        }
+    });
+    closest.map(|(_, p)| p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: [(Real, Real); 5] = [(0.0, 0.0), (0.5, 0.25), (-0.75, 0.5), (0.9, -0.9), (-0.3, -0.6)];
+
+    /// Check that points along the rays through `SCREEN` project back onto
+    /// the screen points they were generated from.
+    fn check_inverse(cam: &Camera) {
+        for &(x, y) in SCREEN.iter() {
+            let ray = cam.ray(x, y);
+            let p = ray.o.affine_add(ray.v * 2.5);
+            let (px, py) = cam.project(p).unwrap();
+            assert!((px - x).abs() < 1e-3 && (py - y).abs() < 1e-3,
+                "({}, {}) projected to ({}, {})", x, y, px, py);
+        }
+    }
+
+    fn camera() -> Camera {
+        let cam2world = Transform::eye()
+            .rotate(0.3, Vector(0.0, 1.0, 0.0))
+            .rotate(-0.2, Vector(1.0, 0.0, 0.0))
+            .translate(Vector(1.0, 2.0, -3.0));
+        Camera::new(cam2world, 50.0_f64.to_radians() as Real, 1.5)
+    }
+
+    #[test]
+    fn project_perspective() {
+        let mut cam = camera();
+        check_inverse(&cam);
+        cam.overscan = 0.1;
+        check_inverse(&cam);
+        cam.handedness = Handedness::Right;
+        check_inverse(&cam);
+    }
+
+    #[test]
+    fn project_equirect() {
+        let mut cam = camera();
+        cam.projection = Projection::Equirect { h_fov: 2.0 * std::f64::consts::PI as Real };
+        check_inverse(&cam);
+    }
+
+    #[test]
+    fn project_out_of_sight() {
+        let cam = camera();
+        let ray = cam.ray(0.2, 0.1);
+        assert!(cam.project(ray.o.affine_add(ray.v * -1.0)).is_none());
+        let mut cam = cam;
+        cam.projection = Projection::Stereo {
+            h_fov: 2.0 * std::f64::consts::PI as Real,
+            ipd: 0.065,
+            layout: StereoLayout::TopBottom,
+        };
+        assert!(cam.project(Point(0.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn sample_weights() {
+        let mut cam = camera();
+        let (_, center) = cam.sample(0.0, 0.0, 0.5, 0.5, 0.5);
+        assert_eq!((center.0, center.1, center.2, center.3), (1.0, 1.0, 1.0, 1.0));
+        cam.lens.vignetting = true;
+        // Corners are seen at the angle whose tangent spans both half fields.
+        let tan = (cam.fov * 0.5).tan();
+        let cos = (1.0 + tan * tan * (1.0 + cam.aspect * cam.aspect)).sqrt().recip();
+        let (_, corner) = cam.sample(1.0, -1.0, 0.5, 0.5, 0.5);
+        assert!((corner.0 as Real - cos.powi(4)).abs() < 1e-5);
+        assert_eq!(corner.3, 1.0);
+        // Channels picked apart average to the weight of all of them.
+        cam.lens.chromatic_aberration = 0.05;
+        let sum = [0.1, 0.5, 0.9].iter()
+            .map(|&c| cam.sample(1.0, -1.0, 0.5, 0.5, c).1)
+            .fold(Color::default(), |a, b| a + b);
+        let k = 3.0 * corner.0;
+        assert!((sum.0 - k).abs() < 1e-5 && (sum.1 - k).abs() < 1e-5 && (sum.2 - k).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,499 @@
+//! C ABI for embedding the renderer, declared in `include/lighar.h`. Build a
+//! linkable library with, e.g.,
+//! `cargo rustc --release --features capi --lib --crate-type cdylib`.
+//!
+//! Scenes are diffuse triangle meshes lit by emissive materials and a
+//! constant ambient light, rendered by path tracing. Handles are opaque and
+//! must be freed by their owners. Panics never unwind into the caller; the
+//! functions fail with `LGR_INTERNAL_ERROR` or null instead.
+use std::os::raw::c_int;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread::JoinHandle;
+use crate::geom::*;
+use crate::rt::*;
+use crate::scene::*;
+use crate::camera::Camera;
+use crate::img::Image;
+use crate::desc::{DiffuseMaterial, DiffuseRayTracer};
+use crate::units::{CameraExposure, radiance_from_nits};
+
+pub const LGR_OK: c_int = 0;
+pub const LGR_INVALID_ARGUMENT: c_int = -1;
+pub const LGR_CANCELLED: c_int = -2;
+/// The library failed unexpectedly, i.e., it panicked.
+pub const LGR_INTERNAL_ERROR: c_int = -3;
+
+/// Run `f` catching panics, which must not unwind across the C ABI, and
+/// return `on_panic` for them.
+fn guard<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+/// Number of bytes of RGBA8 pixels of a `w` by `h` frame, `None` if it's
+/// empty or doesn't fit in memory.
+fn frame_bytes(w: u32, h: u32) -> Option<usize> {
+    let npx = (w as usize).checked_mul(h as usize)?;
+    // Passes are accumulated in `Color`s.
+    npx.checked_mul(std::mem::size_of::<Color>())
+        .filter(|&x| x > 0 && x <= isize::MAX as usize)?;
+    npx.checked_mul(4)
+}
+
+/// Surface properties, in linear RGB.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LgrMaterial {
+    pub albedo: [f32; 3],
+    pub emit: [f32; 3],
+}
+
+/// Scene under construction.
+pub struct LgrScene {
+    /// Meshes with material indices.
+    objs: Vec<Object<u32>>,
+    mats: Vec<LgrMaterial>,
+    cam2world: Transform,
+    fov: Real,
+    ambient: Color,
+    exposure: Option<CameraExposure>,
+}
+impl LgrScene {
+    /// Freeze into a tracer for a `w` by `h` frame.
+    fn to_tracer(&self, w: u32, h: u32) -> DiffuseRayTracer {
+        let to_color = |x: [f32; 3]| Color(x[0], x[1], x[2], 1.0);
+        let objs = self.objs.iter()
+            .map(|obj| {
+                let mat = self.mats[obj.mat as usize];
+                Object {
+                    verts: obj.verts.clone(),
+                    idxs: obj.idxs.clone(),
+                    mat: DiffuseMaterial {
+                        albedo: to_color(mat.albedo),
+                        emit: to_color(mat.emit),
+                        ..Default::default()
+                    },
+                    obj2world: obj.obj2world,
+                    world2obj: obj.world2obj,
+                    visibility: obj.visibility,
+                    cull_backfaces: obj.cull_backfaces,
+                    normals: None,
+                    colors: None,
+                    name: None,
+                    shape: None,
+                }
+            })
+            .collect();
+        let mut cam = Camera::new(self.cam2world, self.fov, w as Real / h as Real);
+        cam.exposure = self.exposure;
+        DiffuseRayTracer::new(Scene::new(objs), cam, self.ambient)
+    }
+}
+
+/// Render `spp` passes of `rt` and return the average as RGBA8 pixels. The
+/// frame must fit in memory, see `frame_bytes`.
+fn render_rgba(
+    rt: &DiffuseRayTracer,
+    w: u32,
+    h: u32,
+    spp: u32,
+    cancel: &CancelToken,
+    progress: &AtomicU32,
+) -> Result<Vec<u8>, Cancelled> {
+    let mut accum = vec![Color::default(); w as usize * h as usize];
+    let mut pass = Image::new(w as usize, h as usize);
+    for _ in 0..spp {
+        rt.draw_cancellable(&mut pass, cancel)?;
+        for (i, x) in accum.iter_mut().enumerate() {
+            *x = *x + pass.load_px(i % w as usize, i / w as usize);
+        }
+        progress.fetch_add(1, Ordering::Relaxed);
+    }
+    let rn = (spp as f32).recip();
+    let rgba = accum.into_iter()
+        .flat_map(|x| {
+            let x: [u8; 4] = Color(x.0 * rn, x.1 * rn, x.2 * rn, 1.0).into();
+            x.to_vec()
+        })
+        .collect();
+    Ok(rgba)
+}
+
+/// Transform from 12 floats of a row-major 3x4 matrix, the identity if null.
+unsafe fn read_transform(x: *const f32) -> Transform {
+    if x.is_null() { return Transform::eye() }
+    let x = std::slice::from_raw_parts(x, 12);
+    let row = |i: usize| Vector(x[i] as Real, x[i + 1] as Real, x[i + 2] as Real);
+    let mut rv = Transform::eye();
+    rv.r1 = row(0);
+    rv.r2 = row(4);
+    rv.r3 = row(8);
+    rv.af = Vector(x[3] as Real, x[7] as Real, x[11] as Real);
+    rv
+}
+
+/// Create an empty scene, with a camera at the origin looking along +z.
+#[no_mangle]
+pub extern "C" fn lgr_scene_new() -> *mut LgrScene {
+    guard(std::ptr::null_mut(), || {
+        let scene = LgrScene {
+            objs: Vec::new(),
+            mats: Vec::new(),
+            cam2world: Transform::eye(),
+            fov: std::f64::consts::FRAC_PI_3 as Real,
+            ambient: Color::default(),
+            exposure: None,
+        };
+        Box::into_raw(Box::new(scene))
+    })
+}
+/// # Safety
+///
+/// `scene` must be null or a scene from `lgr_scene_new` not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_scene_free(scene: *mut LgrScene) {
+    guard((), || {
+        if !scene.is_null() {
+            drop(Box::from_raw(scene));
+        }
+    })
+}
+/// Add a material and return its index, or a negative error code.
+///
+/// # Safety
+///
+/// `scene` must be a live scene and `mat` must point to a material.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_scene_add_material(
+    scene: *mut LgrScene,
+    mat: *const LgrMaterial,
+) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        let (scene, mat) = match (scene.as_mut(), mat.as_ref()) {
+            (Some(scene), Some(mat)) => (scene, mat),
+            _ => return LGR_INVALID_ARGUMENT,
+        };
+        scene.mats.push(*mat);
+        (scene.mats.len() - 1) as c_int
+    })
+}
+/// Scale the emission of `mat` to a luminance of `nits` candela per square
+/// meter, keeping its tint, or white if it doesn't emit.
+///
+/// # Safety
+///
+/// `mat` must point to a material.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_material_set_emit_nits(mat: *mut LgrMaterial, nits: f32) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        let mat = match mat.as_mut() {
+            Some(x) => x,
+            None => return LGR_INVALID_ARGUMENT,
+        };
+        if nits.is_nan() || nits < 0.0 { return LGR_INVALID_ARGUMENT }
+        let tint = match mat.emit {
+            [r, g, b] if r > 0.0 || g > 0.0 || b > 0.0 => Color(r, g, b, 1.0),
+            _ => Color(1.0, 1.0, 1.0, 1.0),
+        };
+        let emit = radiance_from_nits(tint, nits);
+        mat.emit = [emit.0, emit.1, emit.2];
+        LGR_OK
+    })
+}
+/// Add a triangle mesh of `nvert` vertices of three floats each, and `ntri`
+/// triangles of three vertex indices each. `obj2world` is a row-major 3x4
+/// matrix or null for the identity, and must be invertible. Returns the index
+/// of the object, or a negative error code.
+///
+/// # Safety
+///
+/// `scene` must be a live scene, and the arrays must be as long as given.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_scene_add_mesh(
+    scene: *mut LgrScene,
+    verts: *const f32,
+    nvert: u32,
+    idxs: *const u32,
+    ntri: u32,
+    obj2world: *const f32,
+    material: u32,
+) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        let scene = match scene.as_mut() {
+            Some(x) => x,
+            None => return LGR_INVALID_ARGUMENT,
+        };
+        if verts.is_null() || idxs.is_null() || material as usize >= scene.mats.len() {
+            return LGR_INVALID_ARGUMENT;
+        }
+        let verts = std::slice::from_raw_parts(verts, nvert as usize * 3)
+            .chunks_exact(3)
+            .map(|x| Point(x[0] as Real, x[1] as Real, x[2] as Real))
+            .collect::<Vec<_>>();
+        let idxs = std::slice::from_raw_parts(idxs, ntri as usize * 3)
+            .chunks_exact(3)
+            .map(|x| (x[0] as usize, x[1] as usize, x[2] as usize))
+            .collect::<Vec<_>>();
+        if idxs.iter().any(|&(a, b, c)| a.max(b).max(c) >= verts.len()) {
+            return LGR_INVALID_ARGUMENT;
+        }
+        // Like `make_cube`, `world2obj` is the transform applied to the vertices.
+        let trans = read_transform(obj2world);
+        if !trans.is_invertible() { return LGR_INVALID_ARGUMENT }
+        scene.objs.push(Object {
+            verts,
+            idxs,
+            mat: material,
+            obj2world: trans.inverse(),
+            world2obj: trans,
+            visibility: Visibility::default(),
+            cull_backfaces: false,
+            normals: None,
+            colors: None,
+            name: None,
+            shape: None,
+        });
+        (scene.objs.len() - 1) as c_int
+    })
+}
+/// Place the camera by `cam2world`, a row-major 3x4 matrix or null for the
+/// identity, with vertical field of view `fov` in radians. The camera looks
+/// along +z in its local space.
+///
+/// # Safety
+///
+/// `scene` must be a live scene and `cam2world` null or 12 floats.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_scene_set_camera(
+    scene: *mut LgrScene,
+    cam2world: *const f32,
+    fov: f32,
+) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        let scene = match scene.as_mut() {
+            Some(x) => x,
+            None => return LGR_INVALID_ARGUMENT,
+        };
+        scene.cam2world = read_transform(cam2world);
+        scene.fov = fov as Real;
+        LGR_OK
+    })
+}
+/// Set the light coming from every direction where no surface is hit.
+///
+/// # Safety
+///
+/// `scene` must be a live scene.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_scene_set_ambient(
+    scene: *mut LgrScene,
+    r: f32,
+    g: f32,
+    b: f32,
+) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        match scene.as_mut() {
+            Some(scene) => {
+                scene.ambient = Color(r, g, b, 1.0);
+                LGR_OK
+            },
+            None => LGR_INVALID_ARGUMENT,
+        }
+    })
+}
+/// Map radiance to pixel values by the exposure of a physical camera of
+/// sensitivity `iso`, `shutter` time in seconds and relative aperture
+/// `f_number`, e.g., for materials emitting in nits. All of them must be
+/// positive. Radiance is taken as it is by default.
+///
+/// # Safety
+///
+/// `scene` must be a live scene.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_scene_set_exposure(
+    scene: *mut LgrScene,
+    iso: f32,
+    shutter: f32,
+    f_number: f32,
+) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        let scene = match scene.as_mut() {
+            Some(x) => x,
+            None => return LGR_INVALID_ARGUMENT,
+        };
+        if [iso, shutter, f_number].iter().any(|x| x.is_nan() || *x <= 0.0) {
+            return LGR_INVALID_ARGUMENT;
+        }
+        scene.exposure = Some(CameraExposure { iso, shutter, f_number });
+        LGR_OK
+    })
+}
+
+/// Render `spp` samples per pixel of a `w` by `h` frame into `rgba`, which
+/// receives `w * h * 4` bytes of RGBA8 pixels from the top-left corner. Blocks
+/// until done. Empty frames and frames too large for memory are invalid.
+///
+/// # Safety
+///
+/// `scene` must be a live scene and `rgba` must have room for the pixels.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_render(
+    scene: *const LgrScene,
+    w: u32,
+    h: u32,
+    spp: u32,
+    rgba: *mut u8,
+) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        let scene = match scene.as_ref() {
+            Some(x) => x,
+            None => return LGR_INVALID_ARGUMENT,
+        };
+        if rgba.is_null() || frame_bytes(w, h).is_none() { return LGR_INVALID_ARGUMENT }
+        let rt = scene.to_tracer(w, h);
+        let progress = AtomicU32::new(0);
+        match render_rgba(&rt, w, h, spp.max(1), &CancelToken::new(), &progress) {
+            Ok(pixels) => {
+                std::ptr::copy_nonoverlapping(pixels.as_ptr(), rgba, pixels.len());
+                LGR_OK
+            },
+            Err(Cancelled) => LGR_CANCELLED,
+        }
+    })
+}
+
+/// Render running on a background thread.
+pub struct LgrRender {
+    cancel: CancelToken,
+    progress: Arc<AtomicU32>,
+    thread: JoinHandle<Result<Vec<u8>, Cancelled>>,
+}
+/// Start rendering like `lgr_render` on a background thread and return
+/// immediately. The scene is copied, so it can be changed or freed meanwhile.
+/// The returned handle must be passed to `lgr_render_wait` once.
+///
+/// # Safety
+///
+/// `scene` must be a live scene.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_render_async(
+    scene: *const LgrScene,
+    w: u32,
+    h: u32,
+    spp: u32,
+) -> *mut LgrRender {
+    guard(std::ptr::null_mut(), || {
+        let scene = match scene.as_ref() {
+            Some(x) => x,
+            None => return std::ptr::null_mut(),
+        };
+        if frame_bytes(w, h).is_none() { return std::ptr::null_mut() }
+        let rt = scene.to_tracer(w, h);
+        let cancel = CancelToken::new();
+        let progress = Arc::new(AtomicU32::new(0));
+        let thread = {
+            let (cancel, progress) = (cancel.clone(), progress.clone());
+            std::thread::spawn(move || render_rgba(&rt, w, h, spp.max(1), &cancel, &progress))
+        };
+        Box::into_raw(Box::new(LgrRender { cancel, progress, thread }))
+    })
+}
+/// Number of samples per pixel finished so far.
+///
+/// # Safety
+///
+/// `render` must be a handle from `lgr_render_async` not waited yet.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_render_progress(render: *const LgrRender) -> u32 {
+    guard(0, || {
+        render.as_ref().map_or(0, |x| x.progress.load(Ordering::Relaxed))
+    })
+}
+/// Ask the render to stop early. `lgr_render_wait` then returns
+/// `LGR_CANCELLED`.
+///
+/// # Safety
+///
+/// `render` must be a handle from `lgr_render_async` not waited yet.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_render_cancel(render: *const LgrRender) {
+    guard((), || {
+        if let Some(render) = render.as_ref() {
+            render.cancel.cancel();
+        }
+    })
+}
+/// Wait for the render to finish, write its pixels into `rgba` like
+/// `lgr_render`, and free the handle. `rgba` may be null to discard the
+/// pixels.
+///
+/// # Safety
+///
+/// `render` must be a handle from `lgr_render_async` not waited yet, and
+/// `rgba` must be null or have room for the pixels.
+#[no_mangle]
+pub unsafe extern "C" fn lgr_render_wait(render: *mut LgrRender, rgba: *mut u8) -> c_int {
+    guard(LGR_INTERNAL_ERROR, || {
+        if render.is_null() { return LGR_INVALID_ARGUMENT }
+        let render = Box::from_raw(render);
+        match render.thread.join() {
+            Ok(Ok(pixels)) => {
+                if !rgba.is_null() {
+                    std::ptr::copy_nonoverlapping(pixels.as_ptr(), rgba, pixels.len());
+                }
+                LGR_OK
+            },
+            Ok(Err(Cancelled)) => LGR_CANCELLED,
+            // The render thread panicked.
+            Err(_) => LGR_INTERNAL_ERROR,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD_VERTS: [f32; 12] = [-1.0, -1.0, 2.0, 1.0, -1.0, 2.0, 1.0, 1.0, 2.0, -1.0, 1.0, 2.0];
+    const QUAD_IDXS: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    #[test]
+    fn frame_sizes() {
+        assert_eq!(frame_bytes(4, 3), Some(48));
+        assert_eq!(frame_bytes(0, 3), None);
+        assert_eq!(frame_bytes(u32::MAX, u32::MAX), None);
+    }
+
+    #[test]
+    fn render_round_trip() {
+        unsafe {
+            let scene = lgr_scene_new();
+            assert!(!scene.is_null());
+            let mat = LgrMaterial { albedo: [0.0; 3], emit: [1.0, 0.5, 0.25] };
+            let imat = lgr_scene_add_material(scene, &mat);
+            assert_eq!(imat, 0);
+            let iobj = lgr_scene_add_mesh(
+                scene, QUAD_VERTS.as_ptr(), 4, QUAD_IDXS.as_ptr(), 2, std::ptr::null(), 0);
+            assert_eq!(iobj, 0);
+            let (w, h) = (4, 3);
+            let mut rgba = vec![0; frame_bytes(w, h).unwrap()];
+            assert_eq!(lgr_render(scene, w, h, 2, rgba.as_mut_ptr()), LGR_OK);
+            // The quad covers the center of the frame.
+            let i = (w as usize + 2) * 4;
+            assert_eq!(&rgba[i..i + 4], &[255, 128, 64, 255]);
+            lgr_scene_free(scene);
+        }
+    }
+
+    #[test]
+    fn singular_transforms_are_rejected() {
+        unsafe {
+            let scene = lgr_scene_new();
+            lgr_scene_add_material(scene, &LgrMaterial::default());
+            let flat = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+            let rv = lgr_scene_add_mesh(
+                scene, QUAD_VERTS.as_ptr(), 4, QUAD_IDXS.as_ptr(), 2, flat.as_ptr(), 0);
+            assert_eq!(rv, LGR_INVALID_ARGUMENT);
+            lgr_scene_free(scene);
+        }
+    }
+}
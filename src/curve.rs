@@ -0,0 +1,359 @@
+use crate::geom::{Real, Point, Vector, Ray, Color, Transform, Precision, narrow};
+use crate::rt::{Intersection, HitKind};
+use crate::bvh::{Aabb, BoxBvh};
+use crate::scene::{Object, Shape, ShapeHit};
+
+/// Maximum number of times a curve is halved before its segments are treated
+/// as straight.
+const MAX_SUBDIV: u32 = 10;
+
+/// Cubic Bézier curve swept by a disk facing the ray, i.e., a flat ribbon that
+/// always turns to the viewer. It's the usual primitive of hair and fur, which
+/// are too thin to be seen as anything else.
+#[derive(Debug, Clone)]
+pub struct Curve {
+    /// Control points.
+    pub cps: [Point; 4],
+    /// Radius at the start of the curve.
+    pub r0: Real,
+    /// Radius at the end of the curve.
+    pub r1: Real,
+}
+impl Curve {
+    /// Point on the curve at parameter `u` in [0, 1].
+    pub fn eval(&self, u: Real) -> Point {
+        split(&self.cps, u).0[3]
+    }
+    /// Unnormalized tangent at parameter `u` in [0, 1].
+    pub fn tangent(&self, u: Real) -> Vector {
+        let [_, _, c, d] = split(&self.cps, u).0;
+        let t = d.rel_from(c);
+        if t.dot(t) > 0.0 {
+            t
+        } else {
+            // Degenerated end, fall back to the chord.
+            self.cps[3].rel_from(self.cps[0])
+        }
+    }
+    pub fn bounds(&self) -> Aabb {
+        let r = self.r0.max(self.r1);
+        let pad = Vector(r, r, r);
+        let hull = self.cps.iter()
+            .fold(Aabb::empty(), |seed, &p| seed.grow(p));
+        Aabb { min: hull.min.affine_sub(pad), max: hull.max.affine_add(pad) }
+    }
+}
+
+/// Attributes of a ray hitting a curve.
+#[derive(Debug, Clone, Copy)]
+pub struct CurveHit {
+    /// Parameter along the curve in [0, 1].
+    pub u: Real,
+    /// Position across the ribbon in [0, 1], from one edge to the other.
+    pub v: Real,
+}
+
+/// Split Bézier curve `cps` at parameter `u` into two halves.
+fn split(cps: &[Point; 4], u: Real) -> ([Point; 4], [Point; 4]) {
+    let lerp = |a: Point, b: Point| a.affine_add(u * b.rel_from(a));
+    let (p01, p12, p23) = (lerp(cps[0], cps[1]), lerp(cps[1], cps[2]), lerp(cps[2], cps[3]));
+    let (p012, p123) = (lerp(p01, p12), lerp(p12, p23));
+    let p0123 = lerp(p012, p123);
+    ([cps[0], p01, p012, p0123], [p0123, p123, p23, cps[3]])
+}
+
+/// Cast a ray to the curve and return where the ray hits it, if it does. The
+/// curve is projected onto the plane perpendicular to the ray, and recursively
+/// halved until its pieces are flat enough to be tested as line segments.
+/// Hits closer to the ray origin than the radius of the curve are ignored, so
+/// that rays leaving a strand don't hit it again.
+///
+/// See: Koji Nakamaru and Yoshio Ohno, Ray Tracing for Curves Primitive.
+pub fn ray_cast_curve(ray: &Ray, curve: &Curve) -> Option<Intersection<CurveHit>> {
+    let len = ray.v.mag();
+    let z = ray.v / len;
+    // Any vector not parallel to the ray gives the basis of the plane.
+    let up = if z.0.abs() < 0.9 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+    let x = up.cross(z).normalize();
+    let y = z.cross(x);
+    let to_ray = |p: Point| {
+        let d = p.rel_from(ray.o);
+        Point(d.dot(x), d.dot(y), d.dot(z))
+    };
+    let cps = [
+        to_ray(curve.cps[0]),
+        to_ray(curve.cps[1]),
+        to_ray(curve.cps[2]),
+        to_ray(curve.cps[3]),
+    ];
+    // Subdivide until the projected curve deviates from its chord by a small
+    // fraction of its width.
+    let l0 = (0..2)
+        .map(|i| {
+            let d = |p: Point, q: Point, r: Point| {
+                let v = p.rel_from(q) + r.rel_from(q);
+                v.0.abs().max(v.1.abs())
+            };
+            d(cps[i], cps[i + 1], cps[i + 2])
+        })
+        .fold(0.0, Real::max);
+    let eps = 0.05 * curve.r0.max(curve.r1).max(Real::EPSILON);
+    let sqrt2 = std::f64::consts::SQRT_2 as Real;
+    let depth = (sqrt2 * 6.0 * l0 / (8.0 * eps)).max(1.0).log2() * 0.5;
+    let depth = (depth.ceil() as u32).min(MAX_SUBDIV);
+    let mut tmax = Real::INFINITY;
+    let mut closest = None;
+    recurse(curve, &cps, 0.0, 1.0, depth, &mut tmax, &mut closest);
+    closest.map(|(zhit, attr)| {
+        Intersection { attr, kind: HitKind::Front, t: zhit / len, prim: 0 }
+    })
+}
+fn recurse(
+    curve: &Curve,
+    cps: &[Point; 4],
+    u0: Real,
+    u1: Real,
+    depth: u32,
+    zmax: &mut Real,
+    closest: &mut Option<(Real, CurveHit)>,
+) {
+    let r = curve.r0.max(curve.r1);
+    let (lo, hi) = cps.iter().fold(
+        ((Real::INFINITY, Real::INFINITY, Real::INFINITY),
+        (-Real::INFINITY, -Real::INFINITY, -Real::INFINITY)),
+        |(lo, hi), p| {
+            ((lo.0.min(p.0), lo.1.min(p.1), lo.2.min(p.2)),
+            (hi.0.max(p.0), hi.1.max(p.1), hi.2.max(p.2)))
+        });
+    // The ray runs along the z-axis through the origin of the projection.
+    if lo.0 - r > 0.0 || hi.0 + r < 0.0 || lo.1 - r > 0.0 || hi.1 + r < 0.0 ||
+        hi.2 + r < 0.0 || lo.2 - r > *zmax
+    {
+        return;
+    }
+    if depth > 0 {
+        let (a, b) = split(cps, 0.5);
+        let umid = 0.5 * (u0 + u1);
+        recurse(curve, &a, u0, umid, depth - 1, zmax, closest);
+        recurse(curve, &b, umid, u1, depth - 1, zmax, closest);
+        return;
+    }
+    // The origin must lie between the planes perpendicular to the tangents at
+    // both ends, otherwise the neighboring segments handle it.
+    let edge0 = (cps[1].1 - cps[0].1) * -cps[0].1 + cps[0].0 * (cps[0].0 - cps[1].0);
+    let edge1 = (cps[2].1 - cps[3].1) * -cps[3].1 + cps[3].0 * (cps[3].0 - cps[2].0);
+    if edge0 < 0.0 || edge1 < 0.0 { return }
+    let (dx, dy) = (cps[3].0 - cps[0].0, cps[3].1 - cps[0].1);
+    let denom = dx * dx + dy * dy;
+    if denom == 0.0 { return }
+    let w = ((-cps[0].0 * dx - cps[0].1 * dy) / denom).clamp(0.0, 1.0);
+    let u = u0 + (u1 - u0) * w;
+    let radius = curve.r0 + (curve.r1 - curve.r0) * u;
+    let (head, _) = split(cps, w);
+    let (pc, tangent) = (head[3], head[3].rel_from(head[2]));
+    let dist2 = pc.0 * pc.0 + pc.1 * pc.1;
+    if dist2 > radius * radius || pc.2 < radius || pc.2 > *zmax { return }
+    // Which side of the center line the ray passes by.
+    let side = tangent.0 * -pc.1 + pc.0 * tangent.1;
+    let offset = 0.5 * dist2.sqrt() / radius;
+    let v = if side > 0.0 { 0.5 + offset } else { 0.5 - offset };
+    *zmax = pc.2;
+    *closest = Some((pc.2, CurveHit { u, v }));
+}
+
+/// A collection of curves, e.g., the strands of a hair cut, traced natively as
+/// a `Shape` rather than meshed. Curves are culled by a `BoxBvh` over their
+/// bounding boxes before the exact test.
+#[derive(Debug, Clone, Default)]
+pub struct Curves {
+    curves: Vec<Curve>,
+    bvh: BoxBvh,
+}
+impl Curves {
+    pub fn new(curves: Vec<Curve>) -> Curves {
+        let bounds = curves.iter().map(Curve::bounds).collect::<Vec<_>>();
+        Curves { curves, bvh: BoxBvh::new(&bounds) }
+    }
+    pub fn curves(&self) -> &[Curve] {
+        &self.curves
+    }
+    /// The closest curve hit by `ray` and its index.
+    pub fn closest(&self, ray: &Ray) -> Option<(usize, Intersection<CurveHit>)> {
+        self.closest_within(ray, &Precision::default())
+    }
+    /// Same as `closest` but within the tolerances of `precision`.
+    pub fn closest_within(
+        &self,
+        ray: &Ray,
+        precision: &Precision,
+    ) -> Option<(usize, Intersection<CurveHit>)> {
+        let mut closest = None;
+        self.bvh.traverse_within(ray, precision.max_t, |i, tmax| {
+            if let Some(x) = ray_cast_curve(ray, &self.curves[i]) {
+                if x.t < *tmax && x.t >= precision.ray_epsilon {
+                    *tmax = x.t;
+                    closest = Some((i, x));
+                }
+            }
+        });
+        closest
+    }
+    /// Object of the curves placed in the world by `world2obj`. Strands can
+    /// be shaded with `KajiyaKay` along the tangents of their hits, see
+    /// `Shape`.
+    pub fn into_object<M>(self, mat: M, world2obj: Transform) -> Object<M> {
+        Object::from_shape(self, mat, world2obj)
+    }
+}
+impl Shape for Curves {
+    fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+    /// Hits are on the front of ribbons facing the ray, whose normals point
+    /// back along the ray and whose tangents run along the curves.
+    fn ray_cast(&self, ray: &Ray, precision: &Precision) -> Option<ShapeHit> {
+        let (i, x) = self.closest_within(ray, precision)?;
+        let tangent = self.curves[i].tangent(x.attr.u).normalize();
+        let v = ray.v.normalize();
+        let n = tangent * tangent.dot(v) - v;
+        let n = if n.mag() > 0.0 {
+            n.normalize()
+        } else {
+            // The ray runs along the strand, any normal across it will do.
+            let up = if tangent.0.abs() < 0.9 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+            up.cross(tangent).normalize()
+        };
+        Some(ShapeHit { t: x.t, kind: HitKind::Front, prim: i, n, tangent })
+    }
+}
+
+/// A simple hair BSDF modeling strands as thin opaque cylinders.
+///
+/// See: James T. Kajiya and Timothy L. Kay, Rendering Fur with Three
+/// Dimensional Textures.
+#[derive(Debug, Clone, Copy)]
+pub struct KajiyaKay {
+    pub diffuse: Color,
+    pub specular: Color,
+    /// Exponent of the specular lobe. Higher values give sharper highlights.
+    pub shininess: Real,
+}
+impl KajiyaKay {
+    /// Light reflected towards `wo` of light coming from `wi` by a strand of
+    /// tangent `tangent`. All the vectors MUST be normalized and `wi` and `wo`
+    /// point away from the strand.
+    pub fn eval(&self, tangent: Vector, wi: Vector, wo: Vector) -> Color {
+        let cos_i = tangent.dot(wi);
+        let cos_o = tangent.dot(wo);
+        let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+        // The specular cone is centered around the mirror direction about the
+        // strand.
+        let spec = (sin_i * sin_o - cos_i * cos_o).max(0.0).powf(self.shininess);
+        self.diffuse * narrow(sin_i) + self.specular * narrow(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Scene;
+    use crate::camera::Camera;
+    use crate::desc::{DiffuseMaterial, DiffuseRayTracer};
+    use crate::integrator::PathTracer;
+
+    fn straight(r0: Real, r1: Real) -> Curve {
+        Curve {
+            cps: [
+                Point(0.0, 0.0, 0.0),
+                Point(1.0, 0.0, 0.0),
+                Point(2.0, 0.0, 0.0),
+                Point(3.0, 0.0, 0.0),
+            ],
+            r0,
+            r1,
+        }
+    }
+    fn down(x: Real, z: Real) -> Ray {
+        Ray { o: Point(x, 5.0, z), v: Vector(0.0, -1.0, 0.0) }
+    }
+
+    #[test]
+    fn ray_cast_curve_width() {
+        let curve = straight(0.5, 0.5);
+        let hit = ray_cast_curve(&down(1.5, 0.0), &curve).unwrap();
+        assert!((hit.t - 5.0).abs() < 1e-3);
+        assert!((hit.attr.u - 0.5).abs() < 1e-2);
+        assert!((hit.attr.v - 0.5).abs() < 1e-2);
+        let edge = ray_cast_curve(&down(1.5, 0.4), &curve).unwrap();
+        assert!((edge.attr.v - 0.5).abs() > 0.35);
+        assert!(ray_cast_curve(&down(1.5, 0.6), &curve).is_none());
+        // `t` is parametric.
+        let ray = Ray { o: Point(1.5, 5.0, 0.0), v: Vector(0.0, -2.0, 0.0) };
+        assert!((ray_cast_curve(&ray, &curve).unwrap().t - 2.5).abs() < 1e-3);
+        // The radius at u = 0.9 of a tapered curve is 0.14.
+        let tapered = straight(0.5, 0.1);
+        assert!(ray_cast_curve(&down(2.7, 0.1), &tapered).is_some());
+        assert!(ray_cast_curve(&down(2.7, 0.2), &tapered).is_none());
+        // Rays leaving a strand don't hit it again.
+        let up = Ray { o: Point(1.5, 0.0, 0.0), v: Vector(0.0, 1.0, 0.0) };
+        assert!(ray_cast_curve(&up, &curve).is_none());
+    }
+
+    #[test]
+    fn shape_hit_tangent() {
+        let curve = Curve {
+            cps: [
+                Point(0.0, 0.0, 0.0),
+                Point(1.0, 2.0, 0.0),
+                Point(2.0, 2.0, 0.0),
+                Point(3.0, 0.0, 0.0),
+            ],
+            r0: 0.05,
+            r1: 0.05,
+        };
+        let curves = Curves::new(vec![curve.clone()]);
+        let ray = down(0.75, 0.0);
+        let hit = curves.ray_cast(&ray, &Precision::default()).unwrap();
+        let (i, x) = curves.closest(&ray).unwrap();
+        assert_eq!((hit.prim, i), (0, 0));
+        let p = curve.eval(x.attr.u);
+        assert!((p.0 - 0.75).abs() < 0.05);
+        assert!((hit.t - (5.0 - p.1)).abs() < 0.05);
+        let h = 1e-3;
+        let d = curve.eval(x.attr.u + h).rel_from(curve.eval(x.attr.u - h)).normalize();
+        assert!(hit.tangent.dot(d) > 0.999);
+        assert!(hit.n.dot(hit.tangent).abs() < 1e-4);
+        assert!(hit.n.dot(ray.v) < 0.0);
+    }
+
+    #[test]
+    fn curves_in_scene() {
+        let curves = Curves::new(vec![straight(0.5, 0.5)]);
+        let world2obj = Transform::eye().translate(Vector(0.0, 1.0, 0.0));
+        let scene = Scene::new(vec![curves.into_object((), world2obj)]);
+        let hit = scene.raycast(&down(1.5, 0.0)).unwrap();
+        assert!(hit.front);
+        assert!((hit.t - 4.0).abs() < 1e-3);
+        assert!(hit.n.dot(Vector(0.0, 1.0, 0.0)) > 0.99);
+        assert!(scene.raycast(&down(1.5, 0.6)).is_none());
+    }
+
+    #[test]
+    fn hair_furnace() {
+        // A white strand reflects all the light of a uniform environment
+        // diffusely, but never back onto itself.
+        let hair = KajiyaKay {
+            diffuse: Color(1.0, 1.0, 1.0, 1.0),
+            specular: Color::default(),
+            shininess: 1.0,
+        };
+        let mat = DiffuseMaterial { hair: Some(hair), ..Default::default() };
+        let obj = Curves::new(vec![straight(0.5, 0.5)]).into_object(mat, Transform::eye());
+        let cam = Camera::new(Transform::eye(), 1.0, 1.0);
+        let rt = DiffuseRayTracer::new(Scene::new(vec![obj]), cam, Color(1.0, 1.0, 1.0, 1.0));
+        let n = 20000;
+        let sum = (0..n).map(|_| rt.trace_path(down(1.5, 0.2), &mut ()).0).sum::<f32>();
+        assert!((sum / n as f32 - 1.0).abs() < 0.03, "{}", sum / n as f32);
+    }
+}
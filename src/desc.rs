@@ -0,0 +1,1212 @@
+//! Line-based scene descriptions of diffuse meshes, as accepted by the render
+//! server and batch jobs. `#` starts a comment:
+//!
+//! ```text
+//! ambient 0.2 0.2 0.2
+//! background alpha=0
+//! environment sky.hdr intensity=1.5
+//! sky sun=-1,0.5,-1 clouds=0.4
+//! precision epsilon=0.0001 max_t=1000
+//! accel kdtree
+//! fog color=0.6,0.65,0.7 density=0.05 falloff=0.5
+//! volume smoke.nvdb sigma_t=4 albedo=0.9,0.9,0.9 g=0.3 translate=0,1,1
+//! camera fov=60 translate=0,0,-3
+//! camera name=top rotate=90,1,0,0 translate=0,5,0
+//! cube name=box albedo=0.9,0.8,0.1 rotate=30,0,1,0 translate=0,0,1
+//! plane albedo=1,1,1 scale=10,10,10 translate=0,-0.5,0
+//! points scan.ply radius=0.5 unit=cm albedo=1,1,1 translate=0,0,2
+//! voxels castle.vox size=0.05 albedo=1,1,1 rotate=-90,1,0,0 translate=2,0,2
+//! cube emit=4,4,4 scale=0.5,0.5,0.5 translate=0,2,1
+//! light point pos=0,2,0 intensity=2,2,2 far=4,6
+//! light spot pos=0,3,1 dir=0,-1,0 angle=30 penumbra=5 intensity=10,10,10
+//! light directional dir=1,-1,1 intensity=3,3,3 angle=0.5 shadow_except=box
+//! light rect pos=0,2,1 x=0.5,0,0 y=0,0,0.5 intensity=5,5,5
+//! light disk pos=0,2,3 dir=0,-1,0 radius=0.2 nits=2000
+//! ```
+//!
+//! Scenes are y-up, so the example lights the box from above. Transforms are
+//! applied in the order of `scale`, `rotate` (degrees followed by an axis)
+//! and then `translate`. Light not blocked by any object comes from the
+//! environment map if any, which is equirectangular, or the ambient color
+//! otherwise. `sky` bakes a procedural `Sky` into the environment map
+//! instead, with the sun towards `sun` and a `CloudLayer` covering `clouds`
+//! of the sky, 0 by default, `resolution` pixels wide, 512 by default.
+//! `background alpha=0` renders the background seen by the camera
+//! transparent, for compositing over other backgrounds, while it still
+//! lights the scene.
+//! Surfaces take `two_sided=false` to absorb light hitting their back faces
+//! and `emit_back=false` to only emit from their front faces, see `Sides`.
+//! `emit_map` names an image emitting light over the surface, mapped like
+//! `EmissionTexture`, and `emit_intensity` scales it. Emission maps of planes
+//! are sampled directly as lights by their bright texels. Images other than
+//! Radiance `.hdr` and OpenEXR files are decoded from sRGB unless
+//! `color_space` is `linear`, `srgb` or `normal`, for the emission map or the
+//! environment map of the line, see `ColorSpace`. `transparency` lets
+//! that fraction of light through the surface, e.g., for leaves. `water`
+//! shades the surface as animated `Water` of that index of refraction
+//! instead, with waves up to `wave_height` high and `wavelength` long, 0.02
+//! and 1 by default, travelling along `wind` and seen at `time` in seconds.
+//! Cameras take `projection=equirect` for panoramas spanning `h_fov` degrees
+//! horizontally, 360 by default, and `projection=stereo` for omnidirectional
+//! stereo panoramas of both eyes `ipd` apart, 0.064 by default, laid out by
+//! `layout=top_bottom` or `layout=side_by_side`; see `Projection`. They are
+//! left-handed unless `handedness=right`, see `Camera::handedness`.
+//! `fog` fills the scene with `HeightFog` of `color`, `density`, 0.1 by
+//! default, thinning out by `falloff` per unit height above `height` along
+//! `up`, 0,1,0 by default.
+//! `volume` fills the scene with smoke or clouds, a `HeterogeneousMedium`
+//! loaded from an uncompressed NanoVDB file by `load_nanovdb`, extinguishing
+//! `sigma_t` per unit length at unit density, 1 by default, and scattering
+//! `albedo` of it, white by default, with phase asymmetry `g` in (-1, 1).
+//! Volumes can be scaled and translated but not rotated.
+//! `points` loads the vertices of a ply file as a `PointCloud` of disks, or
+//! spheres for points without normals, of `radius`, 0.01 by default, unless
+//! the file gives radii. Positions and radii are in `unit` of `m`, `cm`,
+//! `mm`, `in` or `ft`, meters by default. Point colors multiply the albedo,
+//! and points take the materials of cubes and planes.
+//! `voxels` likewise loads the first model of a MagicaVoxel `.vox` file by
+//! `load_vox` as a `VoxelGrid` of voxels of edge `size`, 0.1 by default,
+//! from the origin, with their palette colors multiplying the albedo. Vox
+//! files are z-up, so models are usually rotated up.
+//! `precision` sets the fields of `Precision` named `epsilon`, `min_area` and
+//! `max_t`, for scenes far from unit scale. `accel` picks the acceleration
+//! structure by `AccelKind::from_name`, a BVH by default.
+//!
+//! Point and spot lights are `PunctualLight`s. `radius` softens their
+//! shadows, and `exponent`, `near` and `far` set their `Falloff`, where
+//! `near` and `far` are pairs of distances. Spot lights take `angle` and
+//! `penumbra` in degrees, and `barn` crops their beam with `BarnDoors` at the
+//! left, right, top and bottom angles in degrees, softened over
+//! `barn_softness` degrees. Directional lights take the irradiance as
+//! `intensity` and their angular diameter as `angle` in degrees. `rect` and
+//! `disk` are `AreaLight`s taking the radiance as `intensity`; rectangles
+//! span the half edges `x` and `y` around `pos`, and disks of `radius` face
+//! `dir`. They only emit from the front unless `two_sided=true`, and are
+//! seen by the camera and by bounces without blocking them. A light
+//! only lights the objects named in `only`, or all but those in `except`,
+//! and likewise only the objects in `shadow_only`, or all but those in
+//! `shadow_except`, cast its shadows; see `LightLinks`. Objects are named
+//! with `name`.
+//!
+//! Point and spot lights also take photometric amounts as `candela` or
+//! `lumens`, and `rect` and `disk` lights as `nits` or `lumens`, in which
+//! case `intensity` only tints them, white by default; see `units`. Cameras
+//! take the `iso`, `shutter` time in seconds and `f_number` of a
+//! `CameraExposure` to map such amounts to pixel values, defaulting to the
+//! sunny 16 rule for the settings left out; radiance is taken as it is
+//! without any of them. Cameras also take the `Lens` imperfections
+//! `vignetting=true`, `distortion` and `chromatic_aberration`.
+use std::path::Path;
+use std::sync::Arc;
+use crate::geom::*;
+use crate::rt::*;
+use crate::scene::*;
+use crate::model::*;
+use crate::img::{Image, AssetCache, ColorSpace, LoadError, to_rgba8};
+use crate::camera::{Camera, Lens, Projection, StereoLayout};
+use crate::sky::{Sky, CloudLayer};
+use crate::accel::{Accel, AccelKind};
+use crate::integrator::{
+    PathTracer, Scatter, Sides, LpeRadiance, Bounce, scatter_diffuse, direct_diffuse,
+    hit_area_lights, scatter_medium, scatter_hair, power_heuristic, facing_surface, DIFFUSE_PDF,
+};
+use crate::curve::KajiyaKay;
+use crate::medium::{HeightFog, HeterogeneousMedium, VdbError, load_nanovdb};
+use crate::points::{PlyError, load_ply};
+use crate::voxel::{VoxError, load_vox};
+use crate::units::{LengthUnit, CameraExposure};
+use crate::water::Water;
+use crate::light::{
+    Light, LightLink, PunctualLight, DirectionalLight, AreaLight, Falloff, BarnDoors,
+    EmissionTexture,
+};
+use crate::sampler::{Sampler, EquirectSampler, FilterMode};
+use crate::rng;
+
+/// Lambertian material that might emit light.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffuseMaterial {
+    pub albedo: Color,
+    pub emit: Color,
+    /// Index into `DiffuseRayTracer::emission_textures` of a texture emitting
+    /// in addition to `emit`.
+    pub emit_texture: Option<usize>,
+    pub sides: Sides,
+    /// Fraction of light passing through the surface, by which rays pass
+    /// through it at random, see `stochastic_pass`. Opaque at 0.
+    pub transparency: f32,
+    /// Shade the surface as animated water instead, reflecting and
+    /// refracting light.
+    pub water: Option<Water>,
+    /// Shade hits on `Curves` as hair instead, see `scatter_hair`.
+    pub hair: Option<KajiyaKay>,
+}
+
+/// Error reading scene descriptions.
+#[derive(Debug)]
+pub enum DescError {
+    /// The description is malformed.
+    Parse(String),
+    /// A referenced asset failed to load.
+    Asset(LoadError),
+    /// A referenced volume failed to load.
+    Volume(VdbError),
+    /// A referenced point cloud failed to load.
+    Points(PlyError),
+    /// A referenced voxel model failed to load.
+    Voxels(VoxError),
+}
+impl std::fmt::Display for DescError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DescError::Parse(msg) => write!(f, "{}", msg),
+            DescError::Asset(e) => write!(f, "failed to load asset: {}", e),
+            DescError::Volume(e) => write!(f, "failed to load volume: {}", e),
+            DescError::Points(e) => write!(f, "failed to load point cloud: {}", e),
+            DescError::Voxels(e) => write!(f, "failed to load voxels: {}", e),
+        }
+    }
+}
+impl std::error::Error for DescError {}
+impl From<LoadError> for DescError {
+    fn from(e: LoadError) -> DescError {
+        DescError::Asset(e)
+    }
+}
+impl From<VdbError> for DescError {
+    fn from(e: VdbError) -> DescError {
+        DescError::Volume(e)
+    }
+}
+impl From<PlyError> for DescError {
+    fn from(e: PlyError) -> DescError {
+        DescError::Points(e)
+    }
+}
+impl From<VoxError> for DescError {
+    fn from(e: VoxError) -> DescError {
+        DescError::Voxels(e)
+    }
+}
+
+/// A parsed scene description.
+pub struct SceneDesc {
+    pub scene: Scene<DiffuseMaterial>,
+    /// Cameras in the order of declaration with their names, if any. The
+    /// aspect ratio is decided at render time.
+    pub cameras: Vec<(Option<String>, Camera)>,
+    pub ambient: Color,
+    /// Environment map and its intensity.
+    pub environment: Option<(Arc<Image>, f32)>,
+    pub lights: Vec<Light>,
+    pub emission_textures: Vec<EmissionTexture>,
+    pub fog: Option<HeightFog>,
+    pub medium: Option<HeterogeneousMedium>,
+    pub background_alpha: f32,
+    /// Acceleration structure tracers are built with.
+    pub accel: AccelKind,
+}
+impl SceneDesc {
+    /// Build a tracer for a `w` by `h` frame seen by the camera named
+    /// `camera`, or the first camera if `None`. Descriptions without cameras
+    /// are seen from the origin towards +z.
+    pub fn into_tracer(self, camera: Option<&str>, w: u32, h: u32) -> Result<DiffuseRayTracer, DescError> {
+        self.into_tracer_with(camera, w, h, &RenderSettings::default())
+    }
+    /// Same as `into_tracer` but with the acceleration structure of
+    /// `settings` if it overrides the one of the description.
+    pub fn into_tracer_with(
+        self,
+        camera: Option<&str>,
+        w: u32,
+        h: u32,
+        settings: &RenderSettings,
+    ) -> Result<DiffuseRayTracer, DescError> {
+        let cam = self.camera(camera, w, h)?;
+        let accel = settings.accel.unwrap_or(self.accel).build(&self.scene);
+        let pixel_spread = cam.fov / h.max(1) as Real;
+        Ok(DiffuseRayTracer {
+            s: self.scene,
+            cam,
+            ambient: self.ambient,
+            environment: self.environment,
+            lights: self.lights,
+            emission_textures: self.emission_textures,
+            fog: self.fog,
+            medium: self.medium,
+            background_alpha: self.background_alpha,
+            pixel_spread,
+            accel,
+        })
+    }
+    /// The camera named `name` like `into_tracer`, fit to a `w` by `h` frame.
+    pub fn camera(&self, name: Option<&str>, w: u32, h: u32) -> Result<Camera, DescError> {
+        let mut cam = match name {
+            Some(name) => {
+                self.cameras.iter()
+                    .find(|(x, _)| x.as_deref() == Some(name))
+                    .map(|(_, cam)| cam.clone())
+                    .ok_or_else(|| DescError::Parse(format!("no camera named `{}`", name)))?
+            },
+            None => self.cameras.first()
+                .map(|(_, cam)| cam.clone())
+                .unwrap_or_else(default_camera),
+        };
+        cam.aspect = w as Real / h as Real;
+        Ok(cam)
+    }
+}
+
+/// Render `spp` samples per pixel of a `w` by `h` frame of description
+/// `desc` seen by its first camera, and return its 8-bit straight RGBA pixels
+/// row by row from the top-left corner, in linear color. Descriptions
+/// referring to files are rejected. Meant for targets without files or
+/// threads like `wasm32-unknown-unknown`, with the `parallel` feature
+/// disabled, where bindings hand the pixels over to a canvas.
+pub fn render_rgba(desc: &str, w: u32, h: u32, spp: u32) -> Result<Vec<u8>, DescError> {
+    let rt = parse_scene(desc, Path::new("."), None)?.into_tracer(None, w, h)?;
+    let mut img = Image::new(w as usize, h as usize);
+    let settings = RenderSettings { spp: spp.max(1), ..Default::default() };
+    rt.render(&mut img, &settings).expect("the render is never cancelled");
+    Ok(to_rgba8(&img))
+}
+
+fn default_camera() -> Camera {
+    Camera::new(Transform::eye(), 60.0_f64.to_radians() as Real, 1.0)
+}
+fn parse_reals(x: &str, n: usize) -> Result<Vec<Real>, String> {
+    let rv = x.split(',')
+        .map(|x| x.trim().parse::<Real>().map_err(|_| format!("invalid number `{}`", x)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if rv.len() != n {
+        return Err(format!("expected {} numbers, got `{}`", n, x));
+    }
+    Ok(rv)
+}
+fn parse_transform(args: &[(&str, &str)]) -> Result<Transform, String> {
+    let mut rv = Transform::eye();
+    for key in ["scale", "rotate", "translate"].iter() {
+        let val = match args.iter().find(|(k, _)| k == key) {
+            Some((_, val)) => *val,
+            None => continue,
+        };
+        rv = match *key {
+            "scale" => {
+                let x = parse_reals(val, 3)?;
+                rv.scale(Vector(x[0], x[1], x[2]))
+            },
+            "rotate" => {
+                let x = parse_reals(val, 4)?;
+                rv.rotate(x[0].to_radians(), Vector(x[1], x[2], x[3]).normalize())
+            },
+            _ => {
+                let x = parse_reals(val, 3)?;
+                rv.translate(Vector(x[0], x[1], x[2]))
+            },
+        };
+    }
+    Ok(rv)
+}
+fn parse_color(args: &[(&str, &str)], key: &str) -> Result<Color, String> {
+    match args.iter().find(|(k, _)| *k == key) {
+        Some((_, val)) => {
+            let x = parse_reals(val, 3)?;
+            Ok(Color(narrow(x[0]), narrow(x[1]), narrow(x[2]), 1.0))
+        },
+        None => Ok(Color::default()),
+    }
+}
+fn parse_lens(args: &[(&str, &str)]) -> Result<Lens, String> {
+    let real = |key: &str| -> Result<Real, String> {
+        match args.iter().find(|(k, _)| *k == key) {
+            Some((_, x)) => Ok(parse_reals(x, 1)?[0]),
+            None => Ok(0.0),
+        }
+    };
+    Ok(Lens {
+        vignetting: parse_bool(args, "vignetting", false)?,
+        distortion: real("distortion")?,
+        chromatic_aberration: real("chromatic_aberration")?,
+    })
+}
+fn parse_exposure(args: &[(&str, &str)]) -> Result<Option<CameraExposure>, String> {
+    let mut rv = CameraExposure::default();
+    let mut any = false;
+    let fields = [("iso", &mut rv.iso), ("shutter", &mut rv.shutter), ("f_number", &mut rv.f_number)];
+    for (key, field) in fields {
+        if let Some((_, x)) = args.iter().find(|(k, _)| *k == key) {
+            let x = narrow(parse_reals(x, 1)?[0]);
+            if x.is_nan() || x <= 0.0 { return Err(format!("`{}` must be positive", key)) }
+            *field = x;
+            any = true;
+        }
+    }
+    Ok(if any { Some(rv) } else { None })
+}
+fn parse_water(args: &[(&str, &str)]) -> Result<Option<Water>, String> {
+    let real = |key: &str, default: Real| -> Result<Real, String> {
+        match args.iter().find(|(k, _)| *k == key) {
+            Some((_, x)) => Ok(parse_reals(x, 1)?[0]),
+            None => Ok(default),
+        }
+    };
+    let ior = match args.iter().find(|(k, _)| *k == "water") {
+        Some((_, x)) => parse_reals(x, 1)?[0],
+        None => return Ok(None),
+    };
+    if ior <= 0.0 {
+        return Err("water index of refraction must be positive".to_owned());
+    }
+    let default = Water::default();
+    let wind = match args.iter().find(|(k, _)| *k == "wind") {
+        Some((_, x)) => {
+            let x = parse_reals(x, 3)?;
+            Vector(x[0], x[1], x[2])
+        },
+        None => default.wind,
+    };
+    let water = Water {
+        ior,
+        height: real("wave_height", default.height)?,
+        wavelength: real("wavelength", default.wavelength)?,
+        wind,
+        time: real("time", default.time)?,
+        ..default
+    };
+    if water.wavelength <= 0.0 {
+        return Err("wavelength must be positive".to_owned());
+    }
+    Ok(Some(water))
+}
+fn parse_unit(args: &[(&str, &str)]) -> Result<LengthUnit, String> {
+    match args.iter().find(|(k, _)| *k == "unit") {
+        None | Some((_, "m")) => Ok(LengthUnit::Meters),
+        Some((_, "cm")) => Ok(LengthUnit::Centimeters),
+        Some((_, "mm")) => Ok(LengthUnit::Millimeters),
+        Some((_, "in")) => Ok(LengthUnit::Inches),
+        Some((_, "ft")) => Ok(LengthUnit::Feet),
+        Some((_, x)) => Err(format!("unknown unit `{}`", x)),
+    }
+}
+fn parse_bool(args: &[(&str, &str)], key: &str, default: bool) -> Result<bool, String> {
+    match args.iter().find(|(k, _)| *k == key) {
+        Some((_, val)) => val.parse::<bool>().map_err(|_| format!("invalid `{}`", key)),
+        None => Ok(default),
+    }
+}
+fn parse_color_space(args: &[(&str, &str)]) -> Result<Option<ColorSpace>, String> {
+    match args.iter().find(|(k, _)| *k == "color_space") {
+        Some((_, "linear")) => Ok(Some(ColorSpace::Linear)),
+        Some((_, "srgb")) => Ok(Some(ColorSpace::Srgb)),
+        Some((_, "normal")) => Ok(Some(ColorSpace::Normal)),
+        Some((_, x)) => Err(format!("unknown color space `{}`", x)),
+        None => Ok(None),
+    }
+}
+/// The image at `path` from `assets`, taken as encoded in `space` if given.
+fn load_texture(
+    assets: &mut AssetCache,
+    path: &Path,
+    space: Option<ColorSpace>,
+) -> Result<std::sync::Arc<Image>, LoadError> {
+    match space {
+        Some(space) => assets.image_as(path, space),
+        None => assets.image(path),
+    }
+}
+fn parse_projection(args: &[(&str, &str)]) -> Result<Projection, String> {
+    let arg = |key: &str| args.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    let real = |key: &str, default: Real| -> Result<Real, String> {
+        arg(key).map(|x| Ok(parse_reals(x, 1)?[0])).unwrap_or(Ok(default))
+    };
+    let h_fov = real("h_fov", 360.0)?.to_radians();
+    match arg("projection") {
+        None | Some("perspective") => Ok(Projection::Perspective),
+        Some("equirect") => Ok(Projection::Equirect { h_fov }),
+        Some("stereo") => {
+            let layout = match arg("layout") {
+                None | Some("top_bottom") => StereoLayout::TopBottom,
+                Some("side_by_side") => StereoLayout::SideBySide,
+                Some(x) => return Err(format!("unknown stereo layout `{}`", x)),
+            };
+            Ok(Projection::Stereo { h_fov, ipd: real("ipd", 0.064)?, layout })
+        },
+        Some(x) => Err(format!("unknown projection `{}`", x)),
+    }
+}
+const LINK_KEYS: [&str; 4] = ["only", "except", "shadow_only", "shadow_except"];
+fn parse_link(key: &str, val: &str, objs: &[Object<DiffuseMaterial>]) -> Result<LightLink, String> {
+    let idxs = val.split(',')
+        .map(|name| {
+            objs.iter()
+                .position(|x| x.name.as_deref() == Some(name))
+                .ok_or_else(|| format!("no object named `{}`", name))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(if key.ends_with("only") { LightLink::Only(idxs) } else { LightLink::Except(idxs) })
+}
+fn parse_light(args: &[(&str, &str)], kind: &str) -> Result<Light, String> {
+    let arg = |key: &str| args.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    let real = |key: &str| -> Result<Option<Real>, String> {
+        arg(key).map(|x| Ok(parse_reals(x, 1)?[0])).transpose()
+    };
+    let pair = |key: &str| -> Result<Option<(Real, Real)>, String> {
+        arg(key).map(|x| parse_reals(x, 2).map(|x| (x[0], x[1]))).transpose()
+    };
+    let real32 = |key: &str| real(key).map(|x| x.map(narrow));
+    let (candela, lumens, nits) = (real32("candela")?, real32("lumens")?, real32("nits")?);
+    // Photometric amounts tint white lights unless a color is given.
+    let intensity = match arg("intensity") {
+        None if candela.is_some() || lumens.is_some() || nits.is_some() => Color(1.0, 1.0, 1.0, 1.0),
+        _ => parse_color(args, "intensity")?,
+    };
+    if kind == "directional" {
+        let dir = parse_reals(arg("dir").ok_or("missing `dir`")?, 3)?;
+        let angle = real("angle")?.unwrap_or(0.0).to_radians();
+        let light = DirectionalLight::new(Vector(dir[0], dir[1], dir[2]), intensity);
+        return Ok(light.with_angle(angle).into());
+    }
+    let pos = parse_reals(arg("pos").ok_or("missing `pos`")?, 3)?;
+    let p = Point(pos[0], pos[1], pos[2]);
+    if kind == "rect" || kind == "disk" {
+        let vector = |key: &str| -> Result<Vector, String> {
+            let x = parse_reals(arg(key).ok_or(format!("missing `{}`", key))?, 3)?;
+            Ok(Vector(x[0], x[1], x[2]))
+        };
+        let light = if kind == "rect" {
+            AreaLight::rect(p, vector("x")?, vector("y")?, intensity)
+        } else {
+            let radius = real("radius")?.ok_or("missing `radius`")?;
+            AreaLight::disk(p, vector("dir")?, radius, intensity)
+        };
+        let mut light = light.with_two_sided(parse_bool(args, "two_sided", false)?);
+        if let Some(nits) = nits { light = light.with_nits(nits) }
+        if let Some(lumens) = lumens { light = light.with_lumens(lumens) }
+        return Ok(light.into());
+    }
+    let mut light = match kind {
+        "point" => PunctualLight::point(p, intensity),
+        "spot" => {
+            let dir = parse_reals(arg("dir").ok_or("missing `dir`")?, 3)?;
+            let angle = real("angle")?.ok_or("missing `angle`")?.to_radians();
+            let mut light = PunctualLight::spot(p, Vector(dir[0], dir[1], dir[2]), angle, intensity);
+            let spot = light.spot.as_mut().unwrap();
+            spot.penumbra = real("penumbra")?.unwrap_or(0.0).to_radians();
+            if let Some(x) = arg("barn") {
+                let x = parse_reals(x, 4)?;
+                spot.barn_doors = Some(BarnDoors {
+                    left: x[0].to_radians(),
+                    right: x[1].to_radians(),
+                    top: x[2].to_radians(),
+                    bottom: x[3].to_radians(),
+                    softness: real("barn_softness")?.unwrap_or(0.0).to_radians(),
+                });
+            }
+            light
+        },
+        _ => return Err(format!("unknown light `{}`", kind)),
+    };
+    light.falloff = Falloff {
+        exponent: real("exponent")?.unwrap_or(light.falloff.exponent),
+        near: pair("near")?,
+        far: pair("far")?,
+    };
+    light.radius = real("radius")?.unwrap_or(0.0);
+    if let Some(candela) = candela { light = light.with_candela(candela) }
+    if let Some(lumens) = lumens { light = light.with_lumens(lumens) }
+    Ok(light.into())
+}
+
+/// Parse a scene description. Assets are loaded through `assets` with paths
+/// relative to `base`; without `assets`, descriptions referring to files are
+/// rejected, e.g., when they come from untrusted clients.
+pub fn parse_scene(
+    desc: &str,
+    base: &Path,
+    mut assets: Option<&mut AssetCache>,
+) -> Result<SceneDesc, DescError> {
+    let mut objs = Vec::new();
+    let mut cameras = Vec::new();
+    let mut ambient = Color::default();
+    let mut environment = None;
+    let mut precision = Precision::default();
+    let mut lights = Vec::new();
+    let mut emission_textures = Vec::new();
+    let mut fog = None;
+    let mut medium = None;
+    let mut background_alpha = 1.0;
+    let mut accel = AccelKind::default();
+    for (iline, line) in desc.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some(x) => x,
+            None => continue,
+        };
+        let err = |e: String| DescError::Parse(format!("line {}: {}", iline + 1, e));
+        let rest = words.collect::<Vec<_>>();
+        let args = rest.iter()
+            .filter_map(|x| {
+                let mut kv = x.splitn(2, '=');
+                Some((kv.next()?, kv.next()?))
+            })
+            .collect::<Vec<_>>();
+        match cmd {
+            "ambient" => {
+                let x = parse_reals(&rest.join(","), 3).map_err(err)?;
+                ambient = Color(narrow(x[0]), narrow(x[1]), narrow(x[2]), 1.0);
+            },
+            "background" => {
+                let alpha = match args.iter().find(|(k, _)| *k == "alpha") {
+                    Some((_, x)) => parse_reals(x, 1).map_err(err)?[0],
+                    None => return Err(err("missing background `alpha`".to_owned())),
+                };
+                if !(0.0..=1.0).contains(&alpha) {
+                    return Err(err(format!("background alpha {} out of [0, 1]", alpha)));
+                }
+                background_alpha = narrow(alpha);
+            },
+            "environment" => {
+                let path = rest.first()
+                    .filter(|x| !x.contains('='))
+                    .ok_or_else(|| err("missing environment map path".to_owned()))?;
+                let assets = assets.as_deref_mut()
+                    .ok_or_else(|| err("assets are not available".to_owned()))?;
+                let intensity = match args.iter().find(|(k, _)| *k == "intensity") {
+                    Some((_, x)) => narrow(parse_reals(x, 1).map_err(err)?[0]),
+                    None => 1.0,
+                };
+                let space = parse_color_space(&args).map_err(err)?;
+                environment = Some((load_texture(assets, &base.join(path), space)?, intensity));
+            },
+            "sky" => {
+                let real = |key: &str, default: Real| -> Result<Real, DescError> {
+                    match args.iter().find(|(k, _)| *k == key) {
+                        Some((_, x)) => Ok(parse_reals(x, 1).map_err(err)?[0]),
+                        None => Ok(default),
+                    }
+                };
+                let mut sky = Sky::default();
+                if let Some((_, x)) = args.iter().find(|(k, _)| *k == "sun") {
+                    let x = parse_reals(x, 3).map_err(err)?;
+                    sky.sun = Vector(x[0], x[1], x[2]).normalize();
+                }
+                let coverage = real("clouds", 0.0)?;
+                if !(0.0..=1.0).contains(&coverage) {
+                    return Err(err(format!("cloud coverage {} out of [0, 1]", coverage)));
+                }
+                if coverage > 0.0 {
+                    sky.clouds = Some(CloudLayer { coverage: narrow(coverage), ..Default::default() });
+                }
+                let w = real("resolution", 512.0)? as usize;
+                if !(2..=8192).contains(&w) {
+                    return Err(err(format!("sky resolution {} out of [2, 8192]", w)));
+                }
+                let img = sky.to_equirect(w, w / 2);
+                environment = Some((Arc::new(img), narrow(real("intensity", 1.0)?)));
+            },
+            "fog" => {
+                let real = |key: &str, default: Real| -> Result<Real, DescError> {
+                    match args.iter().find(|(k, _)| *k == key) {
+                        Some((_, x)) => Ok(parse_reals(x, 1).map_err(err)?[0]),
+                        None => Ok(default),
+                    }
+                };
+                let up = match args.iter().find(|(k, _)| *k == "up") {
+                    Some((_, x)) => {
+                        let x = parse_reals(x, 3).map_err(err)?;
+                        Vector(x[0], x[1], x[2]).normalize()
+                    },
+                    None => Vector(0.0, 1.0, 0.0),
+                };
+                fog = Some(HeightFog {
+                    color: parse_color(&args, "color").map_err(err)?,
+                    density: real("density", 0.1)?,
+                    falloff: real("falloff", 0.0)?,
+                    height: real("height", 0.0)?,
+                    up,
+                });
+            },
+            "volume" => {
+                let path = rest.first()
+                    .filter(|x| !x.contains('='))
+                    .ok_or_else(|| err("missing volume path".to_owned()))?;
+                if assets.is_none() {
+                    return Err(err("assets are not available".to_owned()));
+                }
+                // Density grids are axis aligned and their samples are ordered
+                // along the axes.
+                if args.iter().any(|(k, _)| *k == "rotate") {
+                    return Err(err("volumes can't rotate".to_owned()));
+                }
+                if let Some((_, x)) = args.iter().find(|(k, _)| *k == "scale") {
+                    if parse_reals(x, 3).map_err(err)?.iter().any(|&x| x <= 0.0) {
+                        return Err(err("volume scale must be positive".to_owned()));
+                    }
+                }
+                let real = |key: &str, default: Real| -> Result<Real, DescError> {
+                    match args.iter().find(|(k, _)| *k == key) {
+                        Some((_, x)) => Ok(parse_reals(x, 1).map_err(err)?[0]),
+                        None => Ok(default),
+                    }
+                };
+                let g = real("g", 0.0)?;
+                if !(-1.0 < g && g < 1.0) {
+                    return Err(err(format!("phase asymmetry {} out of (-1, 1)", g)));
+                }
+                let albedo = match args.iter().find(|(k, _)| *k == "albedo") {
+                    Some(_) => parse_color(&args, "albedo").map_err(err)?,
+                    None => Color(1.0, 1.0, 1.0, 1.0),
+                };
+                let sigma_t = real("sigma_t", 1.0)?;
+                if sigma_t < 0.0 {
+                    return Err(err("extinction must not be negative".to_owned()));
+                }
+                let mut density = load_nanovdb(base.join(path))?;
+                let trans = parse_transform(&args).map_err(err)?;
+                density.bounds.min = trans * density.bounds.min;
+                density.bounds.max = trans * density.bounds.max;
+                medium = Some(HeterogeneousMedium {
+                    density,
+                    sigma_t: narrow(sigma_t),
+                    albedo,
+                    g,
+                });
+            },
+            "precision" => {
+                for (key, val) in args.iter() {
+                    let x = parse_reals(val, 1).map_err(err)?[0];
+                    match *key {
+                        "epsilon" => precision.ray_epsilon = x,
+                        "min_area" => precision.min_tri_area = x,
+                        "max_t" => precision.max_t = x,
+                        _ => return Err(err(format!("unknown precision `{}`", key))),
+                    }
+                }
+            },
+            "accel" => {
+                let name = rest.first().ok_or_else(|| err("missing accel kind".to_owned()))?;
+                accel = AccelKind::from_name(name)
+                    .ok_or_else(|| err(format!("unknown accel `{}`", name)))?;
+            },
+            "camera" => {
+                let mut cam = default_camera();
+                cam.cam2world = parse_transform(&args).map_err(err)?;
+                if let Some((_, fov)) = args.iter().find(|(k, _)| *k == "fov") {
+                    let fov = parse_reals(fov, 1).map_err(err)?[0];
+                    cam.fov = fov.to_radians();
+                }
+                cam.projection = parse_projection(&args).map_err(err)?;
+                cam.handedness = match args.iter().find(|(k, _)| *k == "handedness") {
+                    None | Some((_, "left")) => Handedness::Left,
+                    Some((_, "right")) => Handedness::Right,
+                    Some((_, x)) => return Err(err(format!("unknown handedness `{}`", x))),
+                };
+                cam.exposure = parse_exposure(&args).map_err(err)?;
+                cam.lens = parse_lens(&args).map_err(err)?;
+                let name = args.iter()
+                    .find(|(k, _)| *k == "name")
+                    .map(|(_, x)| x.to_string());
+                cameras.push((name, cam));
+            },
+            "light" => {
+                let kind = rest.first()
+                    .filter(|x| !x.contains('='))
+                    .ok_or_else(|| err("missing light kind".to_owned()))?;
+                let light = parse_light(&args, kind).map_err(err)?;
+                // Linked objects might be declared later.
+                let links = args.iter()
+                    .filter(|(k, _)| LINK_KEYS.contains(k))
+                    .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect::<Vec<_>>();
+                lights.push((light, iline, links));
+            },
+            "cube" | "plane" | "points" | "voxels" => {
+                let emit_texture = match args.iter().find(|(k, _)| *k == "emit_map") {
+                    Some((_, path)) => {
+                        let assets = assets.as_deref_mut()
+                            .ok_or_else(|| err("assets are not available".to_owned()))?;
+                        let intensity = match args.iter().find(|(k, _)| *k == "emit_intensity") {
+                            Some((_, x)) => narrow(parse_reals(x, 1).map_err(err)?[0]),
+                            None => 1.0,
+                        };
+                        let space = parse_color_space(&args).map_err(err)?;
+                        let img = load_texture(assets, &base.join(path), space)?;
+                        emission_textures.push(EmissionTexture::new(img, intensity));
+                        Some(emission_textures.len() - 1)
+                    },
+                    None => None,
+                };
+                let mat = DiffuseMaterial {
+                    albedo: parse_color(&args, "albedo").map_err(err)?,
+                    emit: parse_color(&args, "emit").map_err(err)?,
+                    emit_texture,
+                    sides: Sides {
+                        two_sided: parse_bool(&args, "two_sided", true).map_err(err)?,
+                        emit_back: parse_bool(&args, "emit_back", true).map_err(err)?,
+                    },
+                    transparency: match args.iter().find(|(k, _)| *k == "transparency") {
+                        Some((_, x)) => {
+                            let x = narrow(parse_reals(x, 1).map_err(err)?[0]);
+                            if !(0.0..=1.0).contains(&x) {
+                                return Err(err("transparency must be in [0, 1]".to_owned()));
+                            }
+                            x
+                        },
+                        None => 0.0,
+                    },
+                    water: parse_water(&args).map_err(err)?,
+                    hair: None,
+                };
+                let trans = parse_transform(&args).map_err(err)?;
+                let obj = match cmd {
+                    "cube" => make_cube(mat, trans),
+                    "plane" => make_pln(mat, trans),
+                    _ => {
+                        let path = rest.first()
+                            .filter(|x| !x.contains('='))
+                            .ok_or_else(|| err(format!("missing {} path", cmd)))?;
+                        if assets.is_none() {
+                            return Err(err("assets are not available".to_owned()));
+                        }
+                        let real = |key: &str, default: Real| -> Result<Real, DescError> {
+                            match args.iter().find(|(k, _)| *k == key) {
+                                Some((_, x)) => Ok(parse_reals(x, 1).map_err(err)?[0]),
+                                None => Ok(default),
+                            }
+                        };
+                        if cmd == "voxels" {
+                            let size = real("size", 0.1)?;
+                            if size <= 0.0 {
+                                return Err(err("voxel size must be positive".to_owned()));
+                            }
+                            load_vox(base.join(path), Point(0.0, 0.0, 0.0), size)?
+                                .into_object(mat, trans)
+                        } else {
+                            let radius = real("radius", 0.01)?;
+                            if radius <= 0.0 {
+                                return Err(err("point radius must be positive".to_owned()));
+                            }
+                            let unit = parse_unit(&args).map_err(err)?;
+                            load_ply(base.join(path), radius, unit)?.into_object(mat, trans)
+                        }
+                    },
+                };
+                objs.push(match args.iter().find(|(k, _)| *k == "name") {
+                    Some((_, name)) => obj.with_name(name),
+                    None => obj,
+                });
+            },
+            _ => return Err(err(format!("unknown command `{}`", cmd))),
+        }
+    }
+    let lights = lights.into_iter()
+        .map(|(mut light, iline, links)| {
+            for (key, val) in links.iter() {
+                let link = parse_link(key, val, &objs)
+                    .map_err(|e| DescError::Parse(format!("line {}: {}", iline + 1, e)))?;
+                let links = light.links_mut();
+                if key.starts_with("shadow_") {
+                    links.shadow = link;
+                } else {
+                    links.illumination = link;
+                }
+            }
+            Ok(light)
+        })
+        .collect::<Result<Vec<_>, DescError>>()?;
+    let scene = Scene::new(objs).with_precision(precision);
+    Ok(SceneDesc {
+        scene, cameras, ambient, environment, lights, emission_textures, fog, medium,
+        background_alpha, accel,
+    })
+}
+
+/// Path tracer of scenes of diffuse materials.
+pub struct DiffuseRayTracer {
+    pub s: Scene<DiffuseMaterial>,
+    pub cam: Camera,
+    pub ambient: Color,
+    pub environment: Option<(Arc<Image>, f32)>,
+    pub lights: Vec<Light>,
+    pub emission_textures: Vec<EmissionTexture>,
+    /// Fog over the scene seen by the camera.
+    pub fog: Option<HeightFog>,
+    /// Smoke or clouds paths scatter in.
+    pub medium: Option<HeterogeneousMedium>,
+    /// Alpha of the background seen by the camera, 0 for transparent
+    /// backgrounds.
+    pub background_alpha: f32,
+    /// Angle in radians between the camera rays of neighboring pixels, from
+    /// which the footprints of rays on textures are estimated to pick their
+    /// mip levels. Bounces are taken to spread no further than camera rays.
+    /// Textures are sampled at full resolution at 0.
+    pub pixel_spread: Real,
+    accel: Box<dyn Accel>,
+}
+impl DiffuseRayTracer {
+    pub fn new(s: Scene<DiffuseMaterial>, cam: Camera, ambient: Color) -> DiffuseRayTracer {
+        let accel = AccelKind::default().build(&s);
+        DiffuseRayTracer {
+            s,
+            cam,
+            ambient,
+            environment: None,
+            lights: Vec::new(),
+            emission_textures: Vec::new(),
+            fog: None,
+            medium: None,
+            background_alpha: 1.0,
+            pixel_spread: 0.0,
+            accel,
+        }
+    }
+    /// Light emitted by `mat` from either face where `ray` hit `tri` of the
+    /// `obj`-th object.
+    fn emission(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        mat: &DiffuseMaterial,
+    ) -> Color {
+        let mut emit = mat.emit;
+        if let Some(tex) = mat.emit_texture.and_then(|i| self.emission_textures.get(i)) {
+            let bary = intersect.attr;
+            let p = tri.o.affine_add(bary.u * tri.x + bary.v * tri.y);
+            let obj2world = self.s.objs[obj].obj2world;
+            let (u, v) = EmissionTexture::uv(obj2world * p);
+            // Texture coordinates are in object space.
+            let width = intersect.t * ray.v.mag() * self.pixel_spread;
+            let width = (obj2world * (width * tri.x.normalize())).mag();
+            emit = emit + tex.eval_footprint(u, v, narrow(width));
+        }
+        emit
+    }
+    /// Emission texture of the `obj`-th object if it's sampled as a light,
+    /// i.e., if the object is flat on the xz-plane like planes.
+    fn sampled_emission(&self, obj: usize) -> Option<&EmissionTexture> {
+        let obj = &self.s.objs[obj];
+        if obj.shape.is_some() || obj.verts.iter().any(|x| x.1 != 0.0) { return None }
+        obj.mat.emit_texture.and_then(|i| self.emission_textures.get(i))
+    }
+    /// Light of the emission textures sampled as lights reflected towards
+    /// `ray` by a Lambertian surface of `albedo`, sampled and weighed like
+    /// `direct_diffuse`.
+    fn direct_emission(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut (),
+        albedo: Color,
+    ) -> Color {
+        const FRAC_1_PI: f32 = std::f32::consts::FRAC_1_PI;
+        let shading = self.s.objs[obj].shading_normal(intersect.prim, intersect.attr);
+        let (p, ng, n) = facing_surface(ray, tri, intersect, shading);
+        let o = offset_ray_origin(p, ng);
+        let mut rv = Color::default();
+        for (i, emitter) in self.s.objs.iter().enumerate() {
+            if i == obj { continue }
+            let tex = match self.sampled_emission(i) {
+                Some(x) => x,
+                None => continue,
+            };
+            let sample = match tex.illuminate_plane(emitter.world2obj, p, rng::random(), rng::random()) {
+                Some(x) => x,
+                None => continue,
+            };
+            let cos = sample.wi.dot(n);
+            if cos <= 0.0 || sample.wi.dot(ng) <= 0.0 { continue }
+            let radiance = emitter.mat.sides.emit(sample.kind, sample.radiance);
+            // Stop short of the emitter itself.
+            let shadow = Ray { o, v: sample.wi };
+            let dist = sample.dist * (1.0 - 1e-4);
+            if self.occluded_by(shadow, dist, payload, |_| true) { continue }
+            let w = power_heuristic(sample.pdf, DIFFUSE_PDF);
+            let tr = self.transmittance(&shadow, dist) * (1.0 - emitter.mat.transparency as Real);
+            rv = rv + radiance * narrow(cos * w * tr / sample.pdf);
+        }
+        albedo * rv * FRAC_1_PI
+    }
+}
+impl RayTracer for DiffuseRayTracer {
+    type Material = DiffuseMaterial;
+    type Payload = ();
+    type Ray = Ray;
+    type RayAttr = Barycentric;
+
+    fn ray_gen(&self, x: u32, y: u32, w: u32, h: u32) -> Color {
+        let (ray, mut payload, weight) = self.primary_sample(x, y, w, h);
+        self.trace_path(ray, &mut payload) * weight
+    }
+    fn intersect(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        _mat: &DiffuseMaterial,
+    ) -> Option<Intersection<Barycentric>> {
+        ray_cast_tri_with(ray, tri, &self.s.precision)
+    }
+    fn intersect_within(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        _mat: &DiffuseMaterial,
+        tmax: Real,
+    ) -> Option<Intersection<Barycentric>> {
+        let precision = Precision { max_t: tmax.min(self.s.precision.max_t), ..self.s.precision };
+        ray_cast_tri_with(ray, tri, &precision)
+    }
+    fn intersect_shape(
+        &self,
+        ray: &Ray,
+        obj: &Object<DiffuseMaterial>,
+        tmax: Real,
+    ) -> Option<(Triangle, Intersection<Barycentric>)> {
+        let precision = Precision { max_t: tmax.min(self.s.precision.max_t), ..self.s.precision };
+        obj.ray_cast_shape(ray, &precision)
+    }
+    fn any_hit(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        _intersect: &Intersection<Barycentric>,
+        _payload: &mut (),
+        mat: &DiffuseMaterial,
+    ) -> bool {
+        !stochastic_pass(ray, tri, mat.transparency)
+    }
+    fn miss(&self, ray: &Ray, _payload: &mut ()) -> Color {
+        let c = match &self.environment {
+            Some((img, intensity)) => {
+                let samp = EquirectSampler { filter: FilterMode::Linear };
+                samp.sample(std::slice::from_ref(&**img), ray.v.normalize()) * *intensity
+            },
+            None => self.ambient,
+        };
+        Color(c.0, c.1, c.2, self.background_alpha)
+    }
+    /// Continue the path from the hit, e.g., for `draw_wavefront`.
+    fn closest_hit(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut (),
+        mat: &DiffuseMaterial,
+    ) -> Color {
+        self.trace_path_hit(ray, obj, tri, intersect, payload, mat)
+    }
+    fn scene(&self) -> &Scene<DiffuseMaterial> {
+        &self.s
+    }
+    fn accel(&self, ray: &Ray) -> Option<(&dyn Accel, Ray)> {
+        Some((&*self.accel, *ray))
+    }
+}
+impl PathTracer for DiffuseRayTracer {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut (),
+        mat: &DiffuseMaterial,
+    ) -> Scatter<Ray> {
+        // Textures sampled as lights are added by `hit_lights` instead.
+        let emit = if self.sampled_emission(obj).is_some() {
+            mat.emit
+        } else {
+            self.emission(ray, obj, tri, intersect, mat)
+        };
+        if let Some(x) = mat.sides.absorb(intersect.kind, emit) {
+            return x;
+        }
+        let emit = mat.sides.emit(intersect.kind, emit);
+        if let Some(water) = &mat.water {
+            return water.scatter(ray, tri, intersect, emit);
+        }
+        if let Some(hair) = &mat.hair {
+            return scatter_hair(self, ray, obj, tri, intersect, payload, hair, emit);
+        }
+        let object = &self.s.objs[obj];
+        let shading = object.shading_normal(intersect.prim, intersect.attr);
+        let albedo = match object.color(intersect.prim) {
+            Some(color) => mat.albedo * color,
+            None => mat.albedo,
+        };
+        let mut scatter = scatter_diffuse(ray, tri, intersect, shading, albedo, emit);
+        scatter.direct = direct_diffuse(self, ray, obj, tri, intersect, payload, albedo) +
+            self.direct_emission(ray, obj, tri, intersect, payload, albedo);
+        scatter
+    }
+    fn emitted(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        mat: &DiffuseMaterial,
+    ) -> Color {
+        mat.sides.emit(intersect.kind, self.emission(ray, obj, tri, intersect, mat))
+    }
+    fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+    fn hit_lights(&self, ray: &Ray, t: Real, from: Option<Bounce>) -> Color {
+        let mut rv = hit_area_lights(&self.lights, ray, t, from);
+        let len = ray.v.mag();
+        if len == 0.0 { return rv }
+        let v = ray.v / len;
+        let kind = if from.is_some() { RayKind::Reflection } else { RayKind::Camera };
+        for (i, emitter) in self.s.objs.iter().enumerate() {
+            if !emitter.visibility.visible_to(kind) { continue }
+            let tex = match self.sampled_emission(i) {
+                Some(x) => x,
+                None => continue,
+            };
+            // The emitter is the surface hit at `t` unless it's behind it.
+            match tex.hit_plane(emitter.world2obj, ray.o, v) {
+                Some(x) if x.dist <= t * len * (1.0 + 1e-4) + self.s.precision.ray_epsilon => {
+                    let w = match from {
+                        Some(Bounce { pdf, .. }) if pdf > 0.0 => power_heuristic(pdf, x.pdf),
+                        _ => 1.0,
+                    };
+                    // Rays pass through transparent emitters at random, but
+                    // they are counted in every path.
+                    let w = w * (1.0 - emitter.mat.transparency as Real);
+                    rv = rv + emitter.mat.sides.emit(x.kind, x.radiance) * narrow(w);
+                },
+                _ => {},
+            }
+        }
+        rv
+    }
+    fn collide(&self, ray: &Ray, t: Real, payload: &mut ()) -> Option<(Real, Scatter<Ray>)> {
+        let medium = self.medium.as_ref()?;
+        let t = medium.sample_distance(ray, t, &mut || rng::random())?;
+        Some((t, scatter_medium(self, medium, ray, t, payload)))
+    }
+    fn transmittance(&self, ray: &Ray, t: Real) -> Real {
+        match &self.medium {
+            Some(medium) => medium.transmittance(ray, t, &mut || rng::random()),
+            None => 1.0,
+        }
+    }
+    fn camera_fog(&self, ray: &Ray, t: Real, radiance: &mut LpeRadiance) {
+        let fog = match &self.fog {
+            Some(x) => x,
+            None => return,
+        };
+        let tr = narrow(fog.transmittance(ray, t));
+        radiance.emission = radiance.emission * tr + fog.color * (1.0 - tr);
+        radiance.direct_diffuse = radiance.direct_diffuse * tr;
+        radiance.indirect_diffuse = radiance.indirect_diffuse * tr;
+        radiance.specular = radiance.specular * tr;
+    }
+}
+impl WavefrontRayTracer for DiffuseRayTracer {
+    fn primary_ray(&self, x: u32, y: u32, w: u32, h: u32) -> (Ray, ()) {
+        let (ray, payload, _) = self.primary_sample(x, y, w, h);
+        (ray, payload)
+    }
+    /// Lens effects of the camera and its exposure are applied, see
+    /// `Camera::sample`.
+    fn primary_sample(&self, x: u32, y: u32, w: u32, h: u32) -> (Ray, (), Color) {
+        let sx = (x as Real + rng::random::<Real>()) / w as Real * 2.0 - 1.0;
+        let sy = (y as Real + rng::random::<Real>()) / h as Real * 2.0 - 1.0;
+        let (a, b, c) = (rng::random(), rng::random(), rng::random());
+        // Screen y points down and camera y points up.
+        let (ray, weight) = self.cam.sample(sx, -sy, a, b, c);
+        (ray, (), weight)
+    }
+    /// Whole paths are traced in wavefront order, see
+    /// `PathTracer::draw_wavefront_paths`.
+    fn draw_wavefront<FB>(&self, framebuf: &mut FB)
+        where FB: Framebuffer,
+              Self::Ray: Send,
+              Self::Payload: Send,
+              Self::RayAttr: Send,
+              Self::Material: Sync,
+    {
+        self.draw_wavefront_paths(framebuf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emission_maps_take_color_spaces() {
+        let dir = std::env::temp_dir().join(format!("lighar-desc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut img = Image::with_format(1, 1, crate::img::Format::Rgba8)
+            .with_color_space(ColorSpace::Srgb);
+        img.store_px(0, 0, Color(0.5, 0.5, 0.5, 1.0));
+        crate::img::save_image(&img, dir.join("emit.png"), &Default::default()).unwrap();
+
+        let mut assets = AssetCache::new();
+        let emit = |assets: &mut AssetCache, desc: &str| {
+            let scene = parse_scene(desc, &dir, Some(assets)).unwrap();
+            let img = scene.emission_textures[0].img.clone();
+            (img.color_space(), img.load_px(0, 0).0)
+        };
+        // Mid-gray is stored as 188 in sRGB.
+        let (space, srgb) = emit(&mut assets, "cube emit_map=emit.png");
+        assert_eq!(space, ColorSpace::Srgb);
+        assert!((srgb - 0.5).abs() < 5e-3);
+        let (space, linear) = emit(&mut assets, "cube emit_map=emit.png color_space=linear");
+        assert_eq!(space, ColorSpace::Linear);
+        assert!((linear - 188.0 / 255.0).abs() < 1e-6);
+
+        let desc = "cube emit_map=emit.png color_space=xyz";
+        let e = parse_scene(desc, &dir, Some(&mut assets)).err().unwrap();
+        assert_eq!(e.to_string(), "line 1: unknown color space `xyz`");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn render_rgba_without_files() {
+        let desc = "camera translate=0,0,-3\ncube emit=1,1,1\n";
+        let rgba = render_rgba(desc, 8, 6, 1).unwrap();
+        assert_eq!(rgba.len(), 8 * 6 * 4);
+        // The cube fills the center of the frame.
+        let center = &rgba[(3 * 8 + 4) * 4..][..4];
+        assert_eq!(center, &[255, 255, 255, 255]);
+        assert!(render_rgba("environment sky.hdr\n", 8, 6, 1).is_err());
+    }
+    #[test]
+    fn wavefront_paths_match_emission() {
+        let desc = "ambient 0.25 0.25 0.25\ncamera translate=0,0,-3\ncube emit=1,1,1 albedo=0,0,0\n";
+        let rt = parse_scene(desc, Path::new("."), None).unwrap().into_tracer(None, 8, 6).unwrap();
+        let mut img = Image::new(8, 6);
+        rt.draw_wavefront(&mut img);
+        // The black cube only emits, and the background is the ambient light.
+        let center = img.load_px(4, 3);
+        let corner = img.load_px(0, 0);
+        assert_eq!((center.0, center.1, center.2, center.3), (1.0, 1.0, 1.0, 1.0));
+        assert_eq!((corner.0, corner.1, corner.2, corner.3), (0.25, 0.25, 0.25, 1.0));
+    }
+
+    #[test]
+    fn flat_field_corner_falloff() {
+        let desc = "ambient 1 1 1\ncamera fov=90 vignetting=true\n";
+        let rt = parse_scene(desc, Path::new("."), None).unwrap().into_tracer(None, 64, 64).unwrap();
+        let mut img = Image::new(64, 64);
+        rt.draw(&mut img);
+        // The corners are seen at an angle of tangent sqrt(2).
+        let expected = 1.0 / 9.0;
+        for &(x, y) in &[(0, 0), (63, 0), (0, 63), (63, 63)] {
+            let c = img.load_px(x, y);
+            assert!((c.0 - expected).abs() < 0.01, "corner ({}, {}) is {}", x, y, c.0);
+        }
+        let center = img.load_px(32, 32);
+        assert!(center.0 > 0.99);
+    }
+}
@@ -0,0 +1,142 @@
+/// Shape of a pixel reconstruction filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    /// Plain average over the pixel.
+    Box,
+    /// Linear falloff reaching zero at one pixel from the center.
+    Tent,
+    /// Truncated Gaussian with standard deviation `sigma` in pixels. It's a
+    /// `Box` if `sigma` isn't positive.
+    Gaussian { sigma: f32 },
+    /// Four-term Blackman-Harris window, sharper than the Gaussian with less
+    /// aliasing.
+    BlackmanHarris,
+}
+
+/// Number of bins in the tabulated CDF.
+const NBIN: usize = 256;
+
+/// A separable pixel reconstruction filter. Sub-pixel samples are distributed
+/// by the filter (filter importance sampling), so every sample has the same
+/// weight and a pixel is simply the average of its samples.
+#[derive(Debug, Clone)]
+pub struct PixelFilter {
+    kind: FilterKind,
+    radius: f32,
+    /// CDF of the 1D filter over `[-radius, radius]`, `NBIN + 1` entries.
+    cdf: Vec<f32>,
+}
+impl PixelFilter {
+    pub fn new(kind: FilterKind) -> PixelFilter {
+        // A Gaussian this narrow has no extent to normalize over.
+        let kind = match kind {
+            FilterKind::Gaussian { sigma } if sigma.is_nan() || sigma <= 0.0 => FilterKind::Box,
+            x => x,
+        };
+        let radius = match kind {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian { sigma } => 3.0 * sigma,
+            FilterKind::BlackmanHarris => 1.5,
+        };
+        let mut filter = PixelFilter { kind, radius, cdf: Vec::new() };
+        let dx = 2.0 * radius / NBIN as f32;
+        let mut acc = 0.0;
+        filter.cdf.push(0.0);
+        for i in 0..NBIN {
+            let x = -radius + (i as f32 + 0.5) * dx;
+            acc += filter.eval_1d(x) * dx;
+            filter.cdf.push(acc);
+        }
+        for x in filter.cdf.iter_mut() {
+            *x /= acc;
+        }
+        filter
+    }
+    pub fn kind(&self) -> FilterKind { self.kind }
+    /// Extent of the filter from the pixel center, in pixels.
+    pub fn radius(&self) -> f32 { self.radius }
+
+    fn eval_1d(&self, x: f32) -> f32 {
+        use std::f32::consts::PI;
+        let x = x.abs();
+        if x > self.radius { return 0.0 }
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => 1.0 - x,
+            FilterKind::Gaussian { sigma } => {
+                // Offset so that the filter falls to zero at its radius.
+                let g = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (g(x) - g(self.radius)).max(0.0)
+            },
+            FilterKind::BlackmanHarris => {
+                let t = 2.0 * PI * (0.5 + 0.5 * x / self.radius);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() -
+                    0.01168 * (3.0 * t).cos()
+            },
+        }
+    }
+    /// Unnormalized filter weight at offset `(dx, dy)` from the pixel center.
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    fn sample_1d(&self, a: f32) -> f32 {
+        // Find the bin `a` falls in and interpolate linearly inside.
+        let i = match self.cdf.binary_search_by(|x| {
+            x.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Less)
+        }) {
+            Ok(i) => i.min(NBIN - 1),
+            Err(i) => i.clamp(1, NBIN) - 1,
+        };
+        let lo = self.cdf[i];
+        let hi = self.cdf[i + 1];
+        let frac = if hi > lo { (a - lo) / (hi - lo) } else { 0.5 };
+        let dx = 2.0 * self.radius / NBIN as f32;
+        -self.radius + (i as f32 + frac) * dx
+    }
+    /// Map `a` and `b` in [0..1) to an offset from the pixel center, in
+    /// pixels, distributed proportionally to the filter.
+    pub fn sample(&self, a: f32, b: f32) -> (f32, f32) {
+        (self.sample_1d(a), self.sample_1d(b))
+    }
+}
+impl Default for PixelFilter {
+    fn default() -> PixelFilter {
+        PixelFilter::new(FilterKind::Box)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_integrate_to_one() {
+        let kinds = [
+            FilterKind::Box,
+            FilterKind::Tent,
+            FilterKind::Gaussian { sigma: 0.5 },
+            FilterKind::Gaussian { sigma: 0.0 },
+            FilterKind::Gaussian { sigma: -1.0 },
+            FilterKind::BlackmanHarris,
+        ];
+        for kind in kinds {
+            let filter = PixelFilter::new(kind);
+            // Midpoint rule over the extent, normalized by the CDF.
+            let n = 1000;
+            let dx = 2.0 * filter.radius() / n as f32;
+            let total = (0..n)
+                .map(|i| filter.eval_1d(-filter.radius() + (i as f32 + 0.5) * dx) * dx)
+                .sum::<f32>();
+            let cdf = &filter.cdf;
+            assert!(total.is_finite() && total > 0.0, "{:?}", kind);
+            assert!(cdf.windows(2).all(|x| x[0] <= x[1]), "{:?}", kind);
+            assert!((cdf[NBIN] - 1.0).abs() < 1e-5, "{:?}", kind);
+            let (x, y) = filter.sample(0.99, 0.01);
+            assert!(x.abs() <= filter.radius() && y.abs() <= filter.radius(), "{:?}", kind);
+        }
+        let narrow = PixelFilter::new(FilterKind::Gaussian { sigma: 0.0 });
+        assert_eq!(narrow.radius(), 0.5);
+    }
+}
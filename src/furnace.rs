@@ -0,0 +1,88 @@
+//! White furnace tests to catch BSDFs and samplers gaining or losing energy.
+//! A white object lit by a uniform white environment can't be told apart
+//! from the environment, so every pixel of a render converges to 1. Deviation
+//! of the mean beyond noise points to a bug.
+use crate::geom::*;
+use crate::scene::Scene;
+use crate::model::make_sphere;
+use crate::camera::Camera;
+use crate::desc::{DiffuseMaterial, DiffuseRayTracer};
+use crate::rt::RayTracer;
+use crate::par::*;
+
+/// Outcome of `furnace`.
+#[derive(Debug, Clone, Copy)]
+pub struct FurnaceResult {
+    /// Mean color of all the samples, which should be 1 in every channel.
+    pub mean: Color,
+    /// Largest difference of a pixel averaged over its samples from 1.
+    pub max_error: f32,
+}
+impl FurnaceResult {
+    /// Largest difference of `mean` from 1 over the color channels.
+    pub fn mean_error(&self) -> f32 {
+        let x = self.mean;
+        (x.0 - 1.0).abs().max((x.1 - 1.0).abs()).max((x.2 - 1.0).abs())
+    }
+}
+
+/// A white furnace of `DiffuseRayTracer`: a sphere of `mat` in an ambient
+/// environment of 1, filling most of the frame.
+pub fn furnace_scene(mat: DiffuseMaterial) -> DiffuseRayTracer {
+    let sphere = make_sphere(mat, Transform::eye().scale(Vector(1.8, 1.8, 1.8)), 24, 48);
+    let cam2world = Transform::eye().translate(Vector(0.0, 0.0, -3.0));
+    let cam = Camera::new(cam2world, 40.0_f64.to_radians() as Real, 1.0);
+    DiffuseRayTracer::new(Scene::new(vec![sphere]), cam, Color(1.0, 1.0, 1.0, 1.0))
+}
+
+/// Render a `size` by `size` white furnace of `rt` with `spp` samples per
+/// pixel. `rt` is expected to only contain white objects lit by a uniform
+/// environment of 1, e.g., `furnace_scene`.
+pub fn furnace<T: RayTracer>(rt: &T, size: u32, spp: u32) -> FurnaceResult {
+    let rn = (spp.max(1) as f32).recip();
+    let pxs = (0..size * size).into_par_iter()
+        .map(|i| {
+            let sum = (0..spp.max(1))
+                .map(|_| rt.ray_gen(i % size, i / size, size, size))
+                .fold(Color::default(), |a, b| a + b);
+            sum * rn
+        })
+        .collect::<Vec<_>>();
+    let mean = pxs.iter().fold(Color::default(), |a, &b| a + b) * (pxs.len() as f32).recip();
+    let max_error = pxs.iter()
+        .map(|x| (x.0 - 1.0).abs().max((x.1 - 1.0).abs()).max((x.2 - 1.0).abs()))
+        .fold(0.0, f32::max);
+    FurnaceResult { mean, max_error }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::{ClayRayTracer, ClayMode};
+
+    const WHITE: Color = Color(1.0, 1.0, 1.0, 1.0);
+
+    #[test]
+    fn diffuse_conserves_energy() {
+        let rt = furnace_scene(DiffuseMaterial { albedo: WHITE, ..Default::default() });
+        let result = furnace(&rt, 32, 64);
+        assert!(result.mean_error() < 0.01, "{:?}", result);
+    }
+
+    #[test]
+    fn clay_conserves_energy() {
+        let mut rt = ClayRayTracer::new(furnace_scene(DiffuseMaterial::default()), ClayMode::All);
+        rt.albedo = WHITE;
+        let result = furnace(&rt, 32, 64);
+        assert!(result.mean_error() < 0.01, "{:?}", result);
+    }
+
+    #[test]
+    fn gray_loses_energy() {
+        // The test must be able to fail.
+        let gray = Color(0.5, 0.5, 0.5, 1.0);
+        let rt = furnace_scene(DiffuseMaterial { albedo: gray, ..Default::default() });
+        let result = furnace(&rt, 32, 64);
+        assert!(result.mean_error() > 0.1, "{:?}", result);
+    }
+}
@@ -1,8 +1,88 @@
-use std::ops::{Add, Sub, Mul, Div, Neg};
-use crate::rt::{Intersection, HitKind};
+use core::ops::{Add, Sub, Mul, Div, Neg};
+
+/// Scalar type of geometry. It's `f64` with feature `f64`, for scenes with
+/// large coordinate extents where single precision causes self-intersection
+/// and banding.
+#[cfg(not(feature = "f64"))]
+pub type Real = f32;
+#[cfg(feature = "f64")]
+pub type Real = f64;
+
+/// Narrow a geometric scalar to `f32`, e.g., to combine it with colors.
+#[inline]
+#[allow(clippy::unnecessary_cast)]
+pub fn narrow(x: Real) -> f32 {
+    x as f32
+}
+
+/// Software fallbacks of the float functions only available with `std`. They
+/// are accurate to a few ulps for the arguments met in ray tracing, but lose
+/// precision for huge angles. Test builds link `std` regardless, whose
+/// inherent methods take over.
+#[cfg(not(any(feature = "std", test)))]
+trait CoreFloat : Sized {
+    fn sqrt(self) -> Self;
+    fn sin_cos(self) -> (Self, Self);
+}
+#[cfg(not(any(feature = "std", test)))]
+#[allow(clippy::unnecessary_cast)]
+impl CoreFloat for Real {
+    fn sqrt(self) -> Real {
+        let x = self as f64;
+        if x.is_nan() || x < 0.0 { return Real::NAN }
+        if x == 0.0 || x.is_infinite() { return self }
+        // Halving the exponent gives an estimate good enough for Newton's
+        // method to converge to full precision in a few iterations.
+        let mut y = f64::from_bits((x.to_bits() >> 1) + (1023 << 51));
+        for _ in 0..6 {
+            y = 0.5 * (y + x / y);
+        }
+        y as Real
+    }
+    fn sin_cos(self) -> (Real, Real) {
+        use core::f64::consts::{FRAC_2_PI, FRAC_PI_2};
+        let x = self as f64;
+        // Reduce to [-pi/4, pi/4] around the nearest multiple of pi/2.
+        let k = (x * FRAC_2_PI + if x < 0.0 { -0.5 } else { 0.5 }) as i64;
+        let r = x - k as f64 * FRAC_PI_2;
+        let r2 = r * r;
+        let (mut s, mut c) = (0.0, 0.0);
+        let (mut sterm, mut cterm) = (r, 1.0);
+        for i in 1..=9 {
+            s += sterm;
+            c += cterm;
+            sterm *= -r2 / ((2 * i) * (2 * i + 1)) as f64;
+            cterm *= -r2 / ((2 * i - 1) * (2 * i)) as f64;
+        }
+        let (s, c) = match k & 3 {
+            0 => (s, c),
+            1 => (c, -s),
+            2 => (-s, -c),
+            _ => (-c, s),
+        };
+        (s as Real, c as Real)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitKind {
+    Front, Back
+}
+pub struct Intersection<RayAttr> {
+    /// Data used to describe a hit, say, the position of intersection.
+    pub attr: RayAttr,
+    /// Front face or back face.
+    pub kind: HitKind,
+    /// Parametric distance from the ray origin along the ray direction, i.e.,
+    /// the distance for unit length directions.
+    pub t: Real,
+    /// Index of the primitive hit in its object, e.g., of the triangle in
+    /// `Object::idxs`, filled in by scene traversal and 0 otherwise.
+    pub prim: usize,
+}
 
 #[derive(Debug, Default, Clone, Copy)]
-pub struct Point(pub f32, pub f32, pub f32);
+pub struct Point(pub Real, pub Real, pub Real);
 impl Point {
     #[inline]
     pub fn affine_add(self, rhs: Vector) -> Point {
@@ -19,19 +99,19 @@ impl Point {
     }
     /// Only used to calculate barycentric coordinates.
     #[inline]
-    fn to_vec(&self) -> Vector {
+    fn to_vec(self) -> Vector {
         Vector(self.0, self.1, self.2)
     }
 }
-impl From<Point> for (f32, f32, f32) {
-    fn from(x: Point) -> (f32, f32, f32) {
+impl From<Point> for (Real, Real, Real) {
+    fn from(x: Point) -> (Real, Real, Real) {
         (x.0, x.1, x.2)
     }
 }
 
 
 #[derive(Debug, Default, Clone, Copy)]
-pub struct Vector(pub f32, pub f32, pub f32);
+pub struct Vector(pub Real, pub Real, pub Real);
 impl Vector {
     #[inline]
     pub fn normalize(self) -> Vector {
@@ -39,7 +119,7 @@ impl Vector {
         Vector(self.0 / l, self.1 / l, self.2 / l)
     }
     #[inline]
-    pub fn dot(self, rhs: Vector) -> f32 {
+    pub fn dot(self, rhs: Vector) -> Real {
         self.0 * rhs.0 + self.1 * rhs.1 + self.2 * rhs.2
     }
     #[inline]
@@ -50,7 +130,7 @@ impl Vector {
         Vector(n1, n2, n3)
     }
     #[inline]
-    pub fn mag(self) -> f32 {
+    pub fn mag(self) -> Real {
         self.dot(self).sqrt()
     }
     #[inline]
@@ -72,24 +152,24 @@ impl Sub<Vector> for Vector {
         Vector(self.0 - rhs.0, self.1 - rhs.1, self.2 - rhs.2)
     }
 }
-impl Mul<f32> for Vector {
+impl Mul<Real> for Vector {
     type Output = Vector;
     #[inline]
-    fn mul(self, rhs: f32) -> Self::Output {
+    fn mul(self, rhs: Real) -> Self::Output {
         Vector(self.0 * rhs, self.1 * rhs, self.2 * rhs)
     }
 }
-impl Mul<Vector> for f32 {
+impl Mul<Vector> for Real {
     type Output = Vector;
     #[inline]
     fn mul(self, rhs: Vector) -> Self::Output {
         Vector(self * rhs.0, self * rhs.1, self * rhs.2)
     }
 }
-impl Div<f32> for Vector {
+impl Div<Real> for Vector {
     type Output = Vector;
     #[inline]
-    fn div(self, rhs: f32) -> Self::Output {
+    fn div(self, rhs: Real) -> Self::Output {
         Vector(self.0 / rhs, self.1 / rhs, self.2 / rhs)
     }
 }
@@ -100,8 +180,8 @@ impl Neg for Vector {
         Vector(-self.0, -self.1, -self.2)
     }
 }
-impl From<Vector> for (f32, f32, f32) {
-    fn from(x: Vector) -> (f32, f32, f32) {
+impl From<Vector> for (Real, Real, Real) {
+    fn from(x: Vector) -> (Real, Real, Real) {
         (x.0, x.1, x.2)
     }
 }
@@ -141,22 +221,36 @@ impl Mul<Color> for Color {
         Color(self.0 * rhs.0, self.1 * rhs.1, self.2 * rhs.2, self.3 * rhs.3)
     }
 }
+impl Color {
+    /// Color of straight alpha with the color channels premultiplied by
+    /// alpha instead, as colors are blended and filtered.
+    pub fn premultiply(self) -> Color {
+        Color(self.0 * self.3, self.1 * self.3, self.2 * self.3, self.3)
+    }
+    /// Color premultiplied by alpha with straight alpha instead, as most
+    /// 8-bit image files store colors. Fully transparent colors are left as
+    /// they are.
+    pub fn unpremultiply(self) -> Color {
+        if self.3 <= 0.0 || self.3 == 1.0 { return self }
+        Color(self.0 / self.3, self.1 / self.3, self.2 / self.3, self.3)
+    }
+}
 impl From<Color> for [u8; 3] {
     fn from(x: Color) -> [u8; 3] {
         [
-            (x.0.max(0.0).min(1.0) * 255.0) as u8,
-            (x.1.max(0.0).min(1.0) * 255.0) as u8,
-            (x.2.max(0.0).min(1.0) * 255.0) as u8,
+            (x.0.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+            (x.1.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+            (x.2.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
         ]
     }
 }
 impl From<Color> for [u8; 4] {
     fn from(x: Color) -> [u8; 4] {
         [
-            (x.0.max(0.0).min(1.0) * 255.0) as u8,
-            (x.1.max(0.0).min(1.0) * 255.0) as u8,
-            (x.2.max(0.0).min(1.0) * 255.0) as u8,
-            (x.3.max(0.0).min(1.0) * 255.0) as u8,
+            (x.0.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+            (x.1.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+            (x.2.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+            (x.3.clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
         ]
     }
 }
@@ -206,8 +300,8 @@ impl Triangle {
 // A general purpose ray attribute.
 #[derive(Debug, Clone, Copy)]
 pub struct Barycentric {
-    pub u: f32,
-    pub v: f32,
+    pub u: Real,
+    pub v: Real,
 }
 impl Barycentric {
     pub fn new(p: &Point, tri: &Triangle) -> Option<Barycentric> {
@@ -238,7 +332,7 @@ pub struct Sphere {
     /// Center.
     pub c: Point,
     /// Radius.
-    pub r: f32,
+    pub r: Real,
 }
 
 #[derive(Debug, Clone)]
@@ -289,7 +383,7 @@ impl Transform {
         let r3 = self.r3 * scale.2;
         Transform { r1, r2, r3, af: self.af }
     }
-    pub fn rotate(self, angle: f32, axis: Vector) -> Self {
+    pub fn rotate(self, angle: Real, axis: Vector) -> Self {
         let (x, y, z) = axis.into();
         let (sin, cos) = angle.sin_cos();
         let rcos = 1.0 - cos;
@@ -313,36 +407,86 @@ impl Transform {
         };
         trans * self
     }
+    /// Determinant of the linear part, negative if the transform mirrors.
+    pub fn det(&self) -> Real {
+        self.r1.dot(self.r2.cross(self.r3))
+    }
+    /// Whether the transform can be inverted, i.e., it doesn't collapse space
+    /// onto a plane, a line or a point. The determinant is compared relative
+    /// to the scale of the transform.
+    pub fn is_invertible(&self) -> bool {
+        let det = self.det();
+        let scale = self.r1.mag() * self.r2.mag() * self.r3.mag();
+        det.is_finite() && det.abs() > scale * Real::EPSILON * 16.0
+    }
+    /// Inverse of the transform, including the translation, which is undone
+    /// after the linear part. The transform should be invertible, see
+    /// `is_invertible`.
     pub fn inverse(&self) -> Transform {
-        let det = {
-            self.r1.0 * self.r2.1 * self.r3.2 -
-            self.r1.0 * self.r2.2 * self.r3.1 -
-            self.r2.0 * self.r1.1 * self.r3.2 +
-            self.r2.0 * self.r1.2 * self.r3.1 +
-            self.r3.0 * self.r1.1 * self.r2.2 -
-            self.r3.0 * self.r1.2 * self.r2.1
-
-            //+ 4.0 * self.r1.0 * self.r2.1 * self.r3.2 -
-            //self.r1.2 * self.r2.1 * self.r3.0
-        };
-        let r1 = Vector(
-            (self.r2.1 * self.r3.2 - self.r3.1 * self.r2.2) / det,
-            (self.r1.2 * self.r3.1 - self.r1.1 * self.r3.2) / det,
-            (self.r1.1 * self.r2.2 - self.r1.2 * self.r2.1) / det,
-        );
-        let r2 = Vector(
-            (self.r2.2 * self.r3.0 - self.r2.0 * self.r3.2) / det,
-            (self.r1.0 * self.r3.2 - self.r1.2 * self.r3.0) / det,
-            (self.r2.0 * self.r1.2 - self.r1.0 * self.r2.2) / det,
-        );
-        let r3 = Vector(
-            (self.r2.0 * self.r3.1 - self.r2.1 * self.r3.0) / det,
-            (self.r1.1 * self.r3.0 - self.r1.0 * self.r3.1) / det,
-            (self.r1.0 * self.r2.1 - self.r2.0 * self.r1.1) / det,
-        );
-        let af = -self.af;
+        // The columns of the inverse of the linear part are the cross
+        // products of its rows over the determinant.
+        let det = self.det();
+        let c1 = self.r2.cross(self.r3) / det;
+        let c2 = self.r3.cross(self.r1) / det;
+        let c3 = self.r1.cross(self.r2) / det;
+        let r1 = Vector(c1.0, c2.0, c3.0);
+        let r2 = Vector(c1.1, c2.1, c3.1);
+        let r3 = Vector(c1.2, c2.2, c3.2);
+        let af = -Vector(r1.dot(self.af), r2.dot(self.af), r3.dot(self.af));
         Transform { r1, r2, r3, af }
     }
+    /// The closest transform of the same translation whose axes are
+    /// orthonormal, i.e., without scale and shear, e.g., to clean up the
+    /// drift of rotations accumulated over many frames. Mirroring is kept.
+    /// The axes are straightened in the order of x, y and z by Gram-Schmidt,
+    /// so the x-axis keeps its direction. The transform should be
+    /// invertible.
+    pub fn orthonormalize(&self) -> Transform {
+        let (x, y, z) = self.to_cols();
+        let x = x.normalize();
+        let y = (y - x * x.dot(y)).normalize();
+        let z = (z - x * x.dot(z) - y * y.dot(z)).normalize();
+        Transform::from_cols(x, y, z, self.af)
+    }
+    /// Decompose the transform into the translation, rotation, shear and
+    /// scale it applies, or `None` if it isn't invertible. Mirroring is taken
+    /// as a negative scale along z.
+    pub fn decompose(&self) -> Option<Decomposition> {
+        if !self.is_invertible() { return None }
+        // QR decomposition of the linear part by Gram-Schmidt, where the
+        // upper triangular factor is split into shear and scale.
+        let (c1, c2, c3) = self.to_cols();
+        let sx = c1.mag();
+        let q1 = c1 / sx;
+        let xy = q1.dot(c2);
+        let c2 = c2 - q1 * xy;
+        let sy = c2.mag();
+        let q2 = c2 / sy;
+        let (xz, yz) = (q1.dot(c3), q2.dot(c3));
+        let c3 = c3 - q1 * xz - q2 * yz;
+        let mut sz = c3.mag();
+        let mut q3 = c3 / sz;
+        if q1.cross(q2).dot(q3) < 0.0 {
+            q3 = -q3;
+            sz = -sz;
+        }
+        let zero = Vector(0.0, 0.0, 0.0);
+        Some(Decomposition {
+            translation: self.af,
+            rotation: Transform::from_cols(q1, q2, q3, zero),
+            shear: Vector(xy / sy, xz / sz, yz / sz),
+            scale: Vector(sx, sy, sz),
+        })
+    }
+    /// Transform of axes `x`, `y` and `z` translated by `af`.
+    pub fn from_cols(x: Vector, y: Vector, z: Vector, af: Vector) -> Transform {
+        Transform {
+            r1: Vector(x.0, y.0, z.0),
+            r2: Vector(x.1, y.1, z.1),
+            r3: Vector(x.2, y.2, z.2),
+            af,
+        }
+    }
 
     pub fn to_cols(&self) -> (Vector, Vector, Vector) {
         let c1 = Vector(
@@ -417,10 +561,155 @@ impl Mul<Transform> for Transform {
 }
 
 
+/// Parts of an invertible transform, see `Transform::decompose`.
+#[derive(Debug, Clone, Copy)]
+pub struct Decomposition {
+    pub translation: Vector,
+    /// Proper rotation, without mirroring.
+    pub rotation: Transform,
+    /// Shear of x along y and z and of y along z, i.e., how much x grows
+    /// per unit of y and z and y per unit of z, applied after scale.
+    pub shear: Vector,
+    pub scale: Vector,
+}
+impl Decomposition {
+    /// The transform of the parts, i.e., scale, shear, rotation and
+    /// translation in this order.
+    pub fn to_transform(&self) -> Transform {
+        let Vector(xy, xz, yz) = self.shear;
+        let shear = Transform {
+            r1: Vector(1.0, xy, xz),
+            r2: Vector(0.0, 1.0, yz),
+            r3: Vector(0.0, 0.0, 1.0),
+            af: Vector(0.0, 0.0, 0.0),
+        };
+        (self.rotation * shear * Transform::eye().scale(self.scale)).translate(self.translation)
+    }
+}
+
+/// Handedness of a coordinate system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handedness {
+    Right,
+    Left,
+}
+/// Axis pointing up in a coordinate system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+/// Order of the vertices of front faces seen from the front, in the
+/// coordinates they are given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Winding {
+    Clockwise,
+    CounterClockwise,
+}
+
+/// Coordinate conventions of assets. Scenes are right-handed with the y-axis
+/// up, and the front faces of triangles are clockwise, i.e., the normal of
+/// triangle `abc` is `(c - a) x (b - a)` as in `Triangle::new`; assets of
+/// other conventions are converted on import so that they aren't mirrored or
+/// inside-out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoordinateSystem {
+    pub handedness: Handedness,
+    pub up: UpAxis,
+    pub winding: Winding,
+}
+impl CoordinateSystem {
+    /// Conventions of scenes.
+    pub const SCENE: CoordinateSystem = CoordinateSystem {
+        handedness: Handedness::Right,
+        up: UpAxis::Y,
+        winding: Winding::Clockwise,
+    };
+    /// Conventions of obj files and glTF: right-handed, y up, and
+    /// counter-clockwise.
+    pub const OBJ: CoordinateSystem = CoordinateSystem {
+        handedness: Handedness::Right,
+        up: UpAxis::Y,
+        winding: Winding::CounterClockwise,
+    };
+
+    /// Transform from these coordinates to scene coordinates. Left-handed
+    /// coordinates are mirrored along the axis facing forward, z if y is up
+    /// or y if z is up, and z up is rotated to y up.
+    pub fn to_scene(&self) -> Transform {
+        let mirror = match (self.handedness, self.up) {
+            (Handedness::Right, _) => Vector(1.0, 1.0, 1.0),
+            (Handedness::Left, UpAxis::Y) => Vector(1.0, 1.0, -1.0),
+            (Handedness::Left, UpAxis::Z) => Vector(1.0, -1.0, 1.0),
+        };
+        let rv = Transform::eye().scale(mirror);
+        match self.up {
+            UpAxis::Y => rv,
+            UpAxis::Z => Transform {
+                r1: Vector(1.0, 0.0, 0.0),
+                r2: Vector(0.0, 0.0, 1.0),
+                r3: Vector(0.0, -1.0, 0.0),
+                af: Vector(0.0, 0.0, 0.0),
+            } * rv,
+        }
+    }
+    /// Whether the vertices of each triangle have to be reordered after
+    /// `to_scene`, to keep its front face in front.
+    pub fn flips_winding(&self) -> bool {
+        // The winding seen from the front doesn't depend on the handedness
+        // of the coordinates the vertices are given in, so mirroring keeps
+        // it.
+        self.winding == Winding::CounterClockwise
+    }
+}
+
+/// Numeric tolerances of ray-triangle intersection. Appropriate values depend
+/// on the scale of the scene, so they are configured per scene, see
+/// `Scene::with_precision`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Precision {
+    /// Hits closer than this to the ray origin are ignored, so that rays
+    /// leaving a surface don't hit it again. Like `max_t`, it's compared with
+    /// `Intersection::t`.
+    pub ray_epsilon: Real,
+    /// Triangles of at most this area are degenerate and never hit.
+    pub min_tri_area: Real,
+    /// Hits farther than this from the ray origin are ignored. It's checked
+    /// before the rest of the intersection is computed, so intersectors can
+    /// lower it to the closest hit so far to reject farther triangles early.
+    pub max_t: Real,
+}
+impl Default for Precision {
+    /// No tolerance at all, which suits scenes of about unit scale whose
+    /// secondary rays are lifted off surfaces by `offset_ray_origin`.
+    fn default() -> Precision {
+        Precision { ray_epsilon: 0.0, min_tri_area: 0.0, max_t: Real::INFINITY }
+    }
+}
+
+/// Whether `ray` can only hit the back face of `tri`, if anything, which is
+/// much cheaper to tell than intersecting them, e.g., for backface culling.
+#[inline]
+pub fn faces_away(ray: &Ray, tri: &Triangle) -> bool {
+    ray.v.dot(tri.n) > 0.0
+}
+
 /// Cast a ray to the triangle and return the bary centric coordinate of the
 /// intersection if such point exists.
 #[inline]
 pub fn ray_cast_tri(ray: &Ray, tri: &Triangle) -> Option<Intersection<Barycentric>> {
+    ray_cast_tri_with(ray, tri, &Precision::default())
+}
+/// Same as `ray_cast_tri` with the tolerances of `precision`.
+#[inline]
+pub fn ray_cast_tri_with(
+    ray: &Ray,
+    tri: &Triangle,
+    precision: &Precision,
+) -> Option<Intersection<Barycentric>> {
+    if precision.min_tri_area > 0.0 && tri.x.cross(tri.y).mag() * 0.5 <= precision.min_tri_area {
+        return None
+    }
     // Relative position from the origin of triangle to the origin of the ray.
     let dtriray = ray.o.rel_from(tri.o);
     // Displacement from the triangle plane to the ray origin (in normal unit).
@@ -439,14 +728,18 @@ pub fn ray_cast_tri(ray: &Ray, tri: &Triangle) -> Option<Intersection<Barycentri
         return None
     }
 
+    // Parametric distance from the ray origin to the triangle plane, positive
+    // as `r1` and `r2` have opposite signs.
+    let t = -r1 / r2;
+    if t < precision.ray_epsilon || t > precision.max_t {
+        return None
+    }
     // Intersection position.
-    let pos = ray.o.affine_sub(ray.v * r1 / r2);
+    let pos = ray.o.affine_add(ray.v * t);
     // Barycentric coords.
     if let Some(bary) = Barycentric::new(&pos, tri) {
-        // Distance from the ray origin to the triangle.
-        let t = r1.abs();
         let kind = if r2 < 0.0 { HitKind::Front } else { HitKind::Back };
-        let res = Intersection { attr: bary, kind, t };
+        let res = Intersection { attr: bary, kind, t, prim: 0 };
         Some(res)
     } else {
         None
@@ -472,14 +765,14 @@ pub fn ray_cast_sph(ray: &Ray, sph: &Sphere) -> Option<Intersection<Point>> {
         let t = l_mag * cos_theta - (r2 - l2_sin_theta2).sqrt();
         let attr = ray.o.affine_add(t * ray.v);
         let kind = HitKind::Front;
-        Intersection { attr, kind, t }
+        Intersection { attr, kind, t, prim: 0 }
     } else {
         // The ray sourced inside of the sphere, hitting the back (inner) face
         // of it.
         let t = (r2 - l2_sin_theta2).sqrt() - l_mag * cos_theta;
         let attr = ray.o.affine_add(t * ray.v);
         let kind = HitKind::Back;
-        Intersection { attr, kind, t }
+        Intersection { attr, kind, t, prim: 0 }
     };
     Some(intersect)
 }
@@ -489,7 +782,6 @@ pub fn ray_cast_sph(ray: &Ray, sph: &Sphere) -> Option<Intersection<Point>> {
 #[inline]
 pub fn ray_cast_pln(ray: &Ray, pln: &Plane) -> Option<Intersection<Point>> {
     let dplnray = ray.o.rel_from(pln.o);
-    let dplnray_mag = dplnray.dot(dplnray);
     let t = dplnray.dot(pln.n);
     let cos_theta = pln.n.dot(ray.v);
     if t * cos_theta >= 0.0 {
@@ -500,7 +792,7 @@ pub fn ray_cast_pln(ray: &Ray, pln: &Plane) -> Option<Intersection<Point>> {
     // `t` cannot never be zero here. See previous code.
     let kind = if t < 0.0 { HitKind::Front } else { HitKind::Back };
     let attr = ray.o.affine_sub(ray.v * t / cos_theta);
-    let intersect = Intersection { attr, kind, t };
+    let intersect = Intersection { attr, kind, t, prim: 0 };
     Some(intersect)
 }
 
@@ -513,13 +805,163 @@ pub fn reflect(i: Vector, n: Vector) -> Vector {
     2.0 * n * n.dot(i) - i
 }
 
+/// Offset `p` on a surface along its geometric normal `n` so that rays spawned
+/// from it don't hit the same surface again. `n` must point to the side the ray
+/// leaves. Far from the origin the offset is a few ULPs of each coordinate so
+/// that it scales with the floating-point error of `p`; near the origin a small
+/// fixed offset is used instead.
+///
+/// See: Carsten Wächter and Nikolaus Binder, A Fast and Robust Method for
+/// Avoiding Self-Intersection, Ray Tracing Gems.
+#[inline]
+pub fn offset_ray_origin(p: Point, n: Vector) -> Point {
+    const ORIGIN: Real = 1.0 / 32.0;
+    const FLOAT_SCALE: Real = 1.0 / 65536.0;
+    const INT_SCALE: Real = 256.0;
+    let offset = |p: Real, n: Real| {
+        if p.abs() < ORIGIN {
+            return p + FLOAT_SCALE * n;
+        }
+        // Moving the bits of a negative float up moves it towards -inf.
+        let ulps = (INT_SCALE * n) as i64;
+        let ulps = if p < 0.0 { -ulps } else { ulps };
+        Real::from_bits((p.to_bits() as i64 + ulps) as _)
+    };
+    Point(offset(p.0, n.0), offset(p.1, n.1), offset(p.2, n.2))
+}
+
 /// Calculate a unit direction vector shooting out of the north hemisphere based
 /// on height `a` and angular fraction `b` in [0..1).
 #[inline]
-pub fn hemisphere(a: f32, b: f32) -> Vector {
-    const TWO_PI: f32 = std::f32::consts::PI * 2.0;
+pub fn hemisphere(a: Real, b: Real) -> Vector {
+    const TWO_PI: Real = core::f64::consts::PI as Real * 2.0;
     let r = (1.0 - a * a).sqrt();
     let theta = b * TWO_PI;
     let (sin_theta, cos_theta) = theta.sin_cos();
     Vector(r * sin_theta, r * cos_theta, a)
 }
+
+/// Map `a` and `b` in [0..1) to a point on the unit disk. The concentric
+/// mapping is used so that stratified samples stay well distributed.
+#[inline]
+pub fn disk(a: Real, b: Real) -> (Real, Real) {
+    const FRAC_PI_4: Real = core::f64::consts::FRAC_PI_4 as Real;
+    let a = 2.0 * a - 1.0;
+    let b = 2.0 * b - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, FRAC_PI_4 * (b / a))
+    } else {
+        (b, 2.0 * FRAC_PI_4 - FRAC_PI_4 * (a / b))
+    };
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    (r * cos_theta, r * sin_theta)
+}
+
+/// Calculate a unit direction vector uniformly distributed over the sphere
+/// based on height fraction `a` and angular fraction `b` in [0..1).
+#[inline]
+pub fn sphere(a: Real, b: Real) -> Vector {
+    hemisphere(1.0 - 2.0 * a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u8_colors_round_trip() {
+        for i in 0..=255u8 {
+            let rgba: [u8; 4] = Color::from([i, i, i, i]).into();
+            assert_eq!(rgba, [i; 4]);
+        }
+        let rgb: [u8; 3] = Color(0.5, 0.25, 1.5, 1.0).into();
+        assert_eq!(rgb, [128, 64, 255]);
+    }
+
+    const EPS: Real = 1e-4;
+
+    fn assert_close(a: Transform, b: Transform) {
+        let rows = [(a.r1, b.r1), (a.r2, b.r2), (a.r3, b.r3), (a.af, b.af)];
+        for (x, y) in rows.iter() {
+            assert!((*x - *y).mag() < EPS, "{:?} != {:?}", a, b);
+        }
+    }
+    fn composed() -> Transform {
+        Transform::eye()
+            .scale(Vector(2.0, 0.5, 3.0))
+            .rotate(0.7, Vector(1.0, 2.0, -1.0).normalize())
+            .translate(Vector(1.0, -2.0, 5.0))
+            .rotate(-1.2, Vector(0.0, 1.0, 0.0))
+            .translate(Vector(-3.0, 0.5, 0.25))
+    }
+
+    #[test]
+    fn inverse_undoes_composed_transforms() {
+        let t = composed();
+        assert!(t.is_invertible());
+        assert_close(t * t.inverse(), Transform::eye());
+        assert_close(t.inverse() * t, Transform::eye());
+        let p = Point(0.3, -1.5, 2.0);
+        let q = t.inverse() * (t * p);
+        assert!(q.rel_from(p).mag() < EPS, "{:?} != {:?}", q, p);
+    }
+
+    #[test]
+    fn inverse_of_translation_through_rotation() {
+        let t = Transform::eye()
+            .rotate(core::f64::consts::FRAC_PI_2 as Real, Vector(0.0, 0.0, 1.0))
+            .translate(Vector(1.0, 0.0, 0.0));
+        // x maps to y and then moves along x.
+        let p = t * Point(1.0, 0.0, 0.0);
+        assert!(p.rel_from(Point(1.0, 1.0, 0.0)).mag() < EPS, "{:?}", p);
+        let o = t.inverse() * Point(1.0, 1.0, 0.0);
+        assert!(o.rel_from(Point(1.0, 0.0, 0.0)).mag() < EPS, "{:?}", o);
+    }
+
+    #[test]
+    fn singular_transforms_are_not_invertible() {
+        let flat = Transform::eye().scale(Vector(1.0, 0.0, 1.0));
+        assert!(!flat.is_invertible());
+        assert!(flat.decompose().is_none());
+        let tiny = Transform::eye().scale(Vector(1e-3, 1e-3, 1e-3));
+        assert!(tiny.is_invertible());
+    }
+
+    #[test]
+    fn orthonormalize_removes_scale_and_shear() {
+        let t = composed();
+        let o = t.orthonormalize();
+        let (x, y, z) = o.to_cols();
+        for (a, b) in [(x, y), (y, z), (z, x)].iter() {
+            assert!(a.dot(*b).abs() < EPS);
+        }
+        for a in [x, y, z].iter() {
+            assert!((a.mag() - 1.0).abs() < EPS);
+        }
+        assert!((o.det() - 1.0).abs() < EPS);
+        assert!((o.af - t.af).mag() < EPS);
+        let mirror = Transform::eye().scale(Vector(1.0, 1.0, -2.0)).orthonormalize();
+        assert!((mirror.det() + 1.0).abs() < EPS);
+    }
+
+    #[test]
+    fn decompose_recomposes() {
+        let shear = Transform {
+            r1: Vector(1.0, 0.5, 0.0),
+            r2: Vector(0.0, 1.0, -0.25),
+            r3: Vector(0.0, 0.0, 1.0),
+            af: Vector(0.0, 0.0, 0.0),
+        };
+        let mirror = Transform::eye().scale(Vector(-1.0, 1.0, 1.0));
+        for t in [composed(), composed() * shear, composed() * mirror].iter() {
+            let d = t.decompose().unwrap();
+            assert!((d.rotation.det() - 1.0).abs() < EPS);
+            assert_close(d.to_transform(), *t);
+        }
+        let d = composed().decompose().unwrap();
+        assert!(d.shear.mag() < EPS, "{:?}", d.shear);
+    }
+}
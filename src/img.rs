@@ -1,16 +1,169 @@
-use crate::geom::{Color, Vector};
+use std::convert::TryFrom;
+use std::path::Path;
+use crate::geom::Color;
+use crate::rt::{Framebuffer, Region};
 
+/// Pixel storage format of an `Image`. Pixels are always loaded and stored as
+/// `Color`s, and are converted on the fly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Four 32-bit floats. Lossless.
+    Rgba32f,
+    /// Four 16-bit floats.
+    Rgba16f,
+    /// Four 8-bit unsigned normalized integers. Values are clamped to [0, 1].
+    Rgba8,
+    /// RGB with 9-bit mantissas sharing a 5-bit exponent. Negative values are
+    /// clamped to 0 and alpha is always 1.
+    Rgb9e5,
+}
+impl Format {
+    pub fn bytes_per_px(self) -> usize {
+        match self {
+            Format::Rgba32f => 16,
+            Format::Rgba16f => 8,
+            Format::Rgba8 => 4,
+            Format::Rgb9e5 => 4,
+        }
+    }
+}
+
+/// How the colors of an `Image` relate to their alpha.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alpha {
+    /// Color channels are independent of alpha, as in most 8-bit image
+    /// files.
+    Straight,
+    /// Color channels are multiplied by alpha, as in renders and OpenEXR
+    /// files. Such colors are filtered and composited by plain arithmetic.
+    Premultiplied,
+}
+
+/// How the stored values of an `Image` encode the values it's loaded and
+/// stored as, so that textures of colors and of data both decode right.
+/// Alpha is never encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    /// Values as they are, e.g., of renders, HDR images and data textures
+    /// like roughness maps.
+    Linear,
+    /// Colors encoded by the sRGB transfer function, as in most 8-bit color
+    /// images like albedo textures, which spends precision on dark shades.
+    Srgb,
+    /// Unit vectors mapped from [-1, 1] to [0, 1], as in normal maps.
+    Normal,
+}
+impl ColorSpace {
+    /// Values of stored color `c`.
+    pub fn decode(self, c: Color) -> Color {
+        match self {
+            ColorSpace::Linear => c,
+            ColorSpace::Srgb => Color(srgb_to_linear(c.0), srgb_to_linear(c.1), srgb_to_linear(c.2), c.3),
+            ColorSpace::Normal => Color(c.0 * 2.0 - 1.0, c.1 * 2.0 - 1.0, c.2 * 2.0 - 1.0, c.3),
+        }
+    }
+    /// Stored color of values `c`.
+    pub fn encode(self, c: Color) -> Color {
+        match self {
+            ColorSpace::Linear => c,
+            ColorSpace::Srgb => Color(linear_to_srgb(c.0), linear_to_srgb(c.1), linear_to_srgb(c.2), c.3),
+            ColorSpace::Normal => Color(c.0 * 0.5 + 0.5, c.1 * 0.5 + 0.5, c.2 * 0.5 + 0.5, c.3),
+        }
+    }
+}
+/// Linear value of sRGB encoded `x`.
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.040_45 { x / 12.92 } else { ((x + 0.055) / 1.055).powf(2.4) }
+}
+/// sRGB encoding of linear value `x`.
+fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.003_130_8 { x * 12.92 } else { 1.055 * x.powf(2.4_f32.recip()) - 0.055 }
+}
+
+enum Storage {
+    Rgba32f(Vec<Color>),
+    Rgba16f(Vec<[u16; 4]>),
+    Rgba8(Vec<[u8; 4]>),
+    Rgb9e5(Vec<u32>),
+}
+
+/// Anything pixels can be loaded from.
+pub trait PixelSource {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn load_px(&self, x: usize, y: usize) -> Color;
+    /// Same as `load_px` but premultiplied by alpha, e.g., to be filtered.
+    /// Pixels are taken as premultiplied already by default.
+    fn load_px_premultiplied(&self, x: usize, y: usize) -> Color {
+        self.load_px(x, y)
+    }
+}
+
+/// An image of `Color`s, either of straight or of premultiplied alpha, see
+/// `Alpha`, and stored in a `ColorSpace`. Pixels are decoded from the color
+/// space as they are loaded and encoded as they are stored, while alpha is
+/// converted explicitly with `to_alpha` or `load_px_premultiplied`.
 pub struct Image {
-    buf: Vec<Color>,
+    buf: Storage,
     w: usize,
     h: usize,
+    alpha: Alpha,
+    space: ColorSpace,
+    /// Lower resolution levels of the mip chain, each half the size of the
+    /// previous one. Empty for images without mips.
+    mips: Vec<Image>,
 }
 impl Image {
+    /// Linear image of premultiplied alpha, e.g., for renders.
     pub fn new(w: usize, h: usize) -> Image {
-        let buf = std::iter::repeat(Color::default())
-            .take(w * h)
-            .collect();
-        Image { buf, w, h }
+        Image::with_format(w, h, Format::Rgba32f)
+    }
+    /// Linear image of premultiplied alpha like `new` in storage format
+    /// `fmt`.
+    pub fn with_format(w: usize, h: usize, fmt: Format) -> Image {
+        let n = w * h;
+        let buf = match fmt {
+            Format::Rgba32f => Storage::Rgba32f(vec![Color::default(); n]),
+            Format::Rgba16f => Storage::Rgba16f(vec![[0; 4]; n]),
+            Format::Rgba8 => Storage::Rgba8(vec![[0; 4]; n]),
+            Format::Rgb9e5 => Storage::Rgb9e5(vec![0; n]),
+        };
+        Image {
+            buf,
+            w,
+            h,
+            alpha: Alpha::Premultiplied,
+            space: ColorSpace::Linear,
+            mips: Vec::new(),
+        }
+    }
+    /// Copy the image into another storage format, in the same color space.
+    pub fn convert(&self, fmt: Format) -> Image {
+        let mut rv = Image::with_format(self.w, self.h, fmt)
+            .with_alpha(self.alpha)
+            .with_color_space(self.space);
+        for y in 0..self.h {
+            for x in 0..self.w {
+                rv.store_raw(x, y, self.load_raw(x, y));
+            }
+        }
+        rv.mips = self.mips.iter().map(|x| x.convert(fmt)).collect();
+        rv
+    }
+    /// The image with `mips` as the lower resolution levels of its mip
+    /// chain, each half the size of the previous one, e.g., of DDS and KTX2
+    /// textures. Mips are converted along with the image, and sampled by
+    /// `Sampler2D::sample_footprint`.
+    pub fn with_mips(self, mips: Vec<Image>) -> Image {
+        Image { mips, ..self }
+    }
+    /// Number of levels of the mip chain, the image itself included.
+    pub fn nlevel(&self) -> usize {
+        1 + self.mips.len()
+    }
+    /// Level `i` of the mip chain, the image itself at 0.
+    pub fn level(&self, i: usize) -> &Image {
+        if i == 0 { self } else { &self.mips[i - 1] }
     }
 
     // The dimension data are seldom used directly but quite frequently
@@ -20,32 +173,238 @@ impl Image {
     pub fn width(&self) -> usize { self.w }
     #[inline]
     pub fn height(&self) -> usize { self.h }
+    /// Memory taken by the pixels.
+    pub fn byte_size(&self) -> usize {
+        self.w * self.h * self.format().bytes_per_px()
+    }
+    pub fn alpha(&self) -> Alpha {
+        self.alpha
+    }
+    /// The image with its pixels taken as of alpha `alpha`, as they are.
+    pub fn with_alpha(self, alpha: Alpha) -> Image {
+        let mips = self.mips.into_iter().map(|x| x.with_alpha(alpha)).collect();
+        Image { alpha, mips, ..self }
+    }
+    /// Copy the image converting its pixels to alpha `alpha`.
+    pub fn to_alpha(&self, alpha: Alpha) -> Image {
+        let mut rv = Image::with_format(self.w, self.h, self.format())
+            .with_alpha(alpha)
+            .with_color_space(self.space);
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let c = self.load_px(x, y);
+                let c = match (self.alpha, alpha) {
+                    (Alpha::Straight, Alpha::Premultiplied) => c.premultiply(),
+                    (Alpha::Premultiplied, Alpha::Straight) => c.unpremultiply(),
+                    _ => c,
+                };
+                rv.store_px(x, y, c);
+            }
+        }
+        rv.mips = self.mips.iter().map(|x| x.to_alpha(alpha)).collect();
+        rv
+    }
+    pub fn color_space(&self) -> ColorSpace {
+        self.space
+    }
+    /// The image with its stored values taken as encoded in `space`, as they
+    /// are, e.g., to tag textures of data loaded as sRGB colors.
+    pub fn with_color_space(self, space: ColorSpace) -> Image {
+        let mips = self.mips.into_iter().map(|x| x.with_color_space(space)).collect();
+        Image { space, mips, ..self }
+    }
+    /// Copy the image re-encoding its pixels in `space`.
+    pub fn to_color_space(&self, space: ColorSpace) -> Image {
+        let mut rv = Image::with_format(self.w, self.h, self.format())
+            .with_alpha(self.alpha)
+            .with_color_space(space);
+        for y in 0..self.h {
+            for x in 0..self.w {
+                rv.store_px(x, y, self.load_px(x, y));
+            }
+        }
+        rv.mips = self.mips.iter().map(|x| x.to_color_space(space)).collect();
+        rv
+    }
+    pub fn format(&self) -> Format {
+        match self.buf {
+            Storage::Rgba32f(_) => Format::Rgba32f,
+            Storage::Rgba16f(_) => Format::Rgba16f,
+            Storage::Rgba8(_) => Format::Rgba8,
+            Storage::Rgb9e5(_) => Format::Rgb9e5,
+        }
+    }
+
+    /// Copy the `w` by `h` region at `(x, y)` into a new image of the same
+    /// format, alpha and color space.
+    pub fn crop(&self, x: usize, y: usize, w: usize, h: usize) -> Image {
+        let mut rv = Image::with_format(w, h, self.format())
+            .with_alpha(self.alpha)
+            .with_color_space(self.space);
+        for j in 0..h {
+            for i in 0..w {
+                rv.store_raw(i, j, self.load_raw(x + i, y + j));
+            }
+        }
+        rv
+    }
 
     #[inline]
     fn coords2offset(&self, x: usize, y: usize) -> usize {
         x + self.w * y
     }
+    /// Pixel `(x, y)` decoded from the color space.
     #[inline]
     pub fn load_px(&self, x: usize, y: usize) -> Color {
-        let i = self.coords2offset(x, y);
-        self.buf[i]
+        self.space.decode(self.load_raw(x, y))
     }
+    /// Store pixel `(x, y)` encoded in the color space.
     #[inline]
     pub fn store_px(&mut self, x: usize, y: usize, c: Color) {
+        let c = self.space.encode(c);
+        self.store_raw(x, y, c);
+    }
+    #[inline]
+    fn load_raw(&self, x: usize, y: usize) -> Color {
         let i = self.coords2offset(x, y);
-        self.buf[i] = c;
+        match &self.buf {
+            Storage::Rgba32f(buf) => buf[i],
+            Storage::Rgba16f(buf) => {
+                let [r, g, b, a] = buf[i];
+                Color(f16_to_f32(r), f16_to_f32(g), f16_to_f32(b), f16_to_f32(a))
+            },
+            Storage::Rgba8(buf) => buf[i].into(),
+            Storage::Rgb9e5(buf) => rgb9e5_to_color(buf[i]),
+        }
+    }
+    #[inline]
+    fn store_raw(&mut self, x: usize, y: usize, c: Color) {
+        let i = self.coords2offset(x, y);
+        match &mut self.buf {
+            Storage::Rgba32f(buf) => buf[i] = c,
+            Storage::Rgba16f(buf) => {
+                buf[i] = [f32_to_f16(c.0), f32_to_f16(c.1), f32_to_f16(c.2), f32_to_f16(c.3)];
+            },
+            Storage::Rgba8(buf) => buf[i] = c.into(),
+            Storage::Rgb9e5(buf) => buf[i] = color_to_rgb9e5(c),
+        }
     }
 }
+impl PixelSource for Image {
+    fn width(&self) -> usize { self.w }
+    fn height(&self) -> usize { self.h }
+    fn load_px(&self, x: usize, y: usize) -> Color { Image::load_px(self, x, y) }
+    fn load_px_premultiplied(&self, x: usize, y: usize) -> Color {
+        let c = Image::load_px(self, x, y);
+        match self.alpha {
+            Alpha::Straight => c.premultiply(),
+            Alpha::Premultiplied => c,
+        }
+    }
+}
+impl Framebuffer for Image {
+    fn width(&self) -> u32 { self.w as u32 }
+    fn height(&self) -> u32 { self.h as u32 }
+    fn store(&mut self, x: u32, y: u32, color: Color) {
+        self.store_px(x as usize, y as usize, color);
+    }
+}
+
+/// Framebuffer of 8-bit RGBA pixels in row-major order from the top-left
+/// corner, which is the layout of `ImageData` of an HTML canvas. Channels are
+/// clamped to [0, 1], and colors premultiplied by alpha are stored straight
+/// like in `ImageData`. The crate doesn't bind to JavaScript itself; embedders
+/// hand `data` to canvases through their own bindings.
+pub struct RgbaFramebuffer {
+    w: u32,
+    h: u32,
+    data: Vec<u8>,
+}
+impl RgbaFramebuffer {
+    /// Panics if the frame doesn't fit in the address space, e.g., of
+    /// 32-bit wasm.
+    pub fn new(w: u32, h: u32) -> RgbaFramebuffer {
+        let len = (w as usize).checked_mul(h as usize)
+            .and_then(|x| x.checked_mul(4))
+            .expect("frame too large");
+        RgbaFramebuffer { w, h, data: vec![0; len] }
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+impl Framebuffer for RgbaFramebuffer {
+    fn width(&self) -> u32 { self.w }
+    fn height(&self) -> u32 { self.h }
+    fn store(&mut self, x: u32, y: u32, color: Color) {
+        let i = (y as usize * self.w as usize + x as usize) * 4;
+        let rgba: [u8; 4] = color.unpremultiply().into();
+        self.data[i..i + 4].copy_from_slice(&rgba);
+    }
+}
+/// Framebuffer of a `w` by `h` frame passing only the pixels in `region` on to
+/// `inner`, whose top-left corner receives the corner of the region, e.g., to
+/// crop the borders of an overscanned render. Set the same region in
+/// `RenderSettings` so pixels out of it aren't traced at all.
+pub struct CropFramebuffer<'a, FB: Framebuffer> {
+    inner: &'a mut FB,
+    w: u32,
+    h: u32,
+    region: Region,
+}
+impl<'a, FB: Framebuffer> CropFramebuffer<'a, FB> {
+    pub fn new(inner: &'a mut FB, w: u32, h: u32, region: Region) -> CropFramebuffer<'a, FB> {
+        let region = region.clamp(w, h);
+        assert!(region.x1 - region.x0 <= inner.width() && region.y1 - region.y0 <= inner.height(),
+            "crop region is larger than the framebuffer");
+        CropFramebuffer { inner, w, h, region }
+    }
+}
+impl<FB: Framebuffer> Framebuffer for CropFramebuffer<'_, FB> {
+    fn width(&self) -> u32 { self.w }
+    fn height(&self) -> u32 { self.h }
+    fn store(&mut self, x: u32, y: u32, color: Color) {
+        let r = self.region;
+        if (r.x0..r.x1).contains(&x) && (r.y0..r.y1).contains(&y) {
+            self.inner.store(x - r.x0, y - r.y0, color);
+        }
+    }
+    fn end_tile(&mut self, pixels: &[(u32, u32)]) {
+        let r = self.region;
+        let pixels = pixels.iter()
+            .filter(|&&(x, y)| (r.x0..r.x1).contains(&x) && (r.y0..r.y1).contains(&y))
+            .map(|&(x, y)| (x - r.x0, y - r.y0))
+            .collect::<Vec<_>>();
+        if !pixels.is_empty() {
+            self.inner.end_tile(&pixels);
+        }
+    }
+}
+/// Pixels of `img` as 8-bit straight RGBA in row-major order, encoded in the
+/// color space of `img`.
+pub(crate) fn to_rgba8(img: &Image) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 * img.w * img.h);
+    for y in 0..img.height() {
+        for x in 0..img.width() {
+            let c = img.load_px(x, y);
+            let c = match img.alpha {
+                Alpha::Straight => c,
+                Alpha::Premultiplied => c.unpremultiply(),
+            };
+            let c: [u8; 4] = img.space.encode(c).into();
+            buf.extend(&c);
+        }
+    }
+    buf
+}
 impl From<Image> for image::RgbaImage {
     fn from(img: Image) -> image::RgbaImage {
-        let mut buf = Vec::with_capacity(4 * img.buf.len());
         let w = img.width() as u32;
         let h = img.height() as u32;
-        for c in img.buf.into_iter() {
-            let c: [u8; 4] = c.into();
-            buf.extend(&c);
-        }
-        image::RgbaImage::from_raw(w, h, buf)
+        image::RgbaImage::from_raw(w, h, to_rgba8(&img))
             .unwrap()
     }
 }
@@ -54,10 +413,897 @@ impl From<image::DynamicImage> for Image {
         use image::GenericImageView;
         let w = img.width() as usize;
         let h = img.height() as usize;
+        // 8-bit sources lose nothing in 8-bit storage.
         let buf = img.into_rgba()
             .chunks_exact(4)
-            .map(|x| [x[0], x[1], x[2], x[3]].into())
-            .collect::<Vec<Color>>();
-        Image { buf, w, h }
+            .map(|x| [x[0], x[1], x[2], x[3]])
+            .collect::<Vec<_>>();
+        Image {
+            buf: Storage::Rgba8(buf),
+            w,
+            h,
+            alpha: Alpha::Straight,
+            space: ColorSpace::Srgb,
+            mips: Vec::new(),
+        }
+    }
+}
+
+/// Error loading images from files.
+#[derive(Debug)]
+pub enum LoadError {
+    Image(image::ImageError),
+    /// The images don't form a valid cube map.
+    Cubemap(String),
+    /// The images don't form a valid UDIM set.
+    Udim(String),
+    /// The file format is not supported.
+    Unsupported(String),
+    /// The texture container, DDS, KTX2 or OpenEXR, is malformed.
+    Container(String),
+}
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Image(e) => write!(f, "failed to load image: {}", e),
+            LoadError::Cubemap(msg) => write!(f, "invalid cube map: {}", msg),
+            LoadError::Udim(msg) => write!(f, "invalid UDIM set: {}", msg),
+            LoadError::Unsupported(fmt) => write!(f, "unsupported image format: {}", fmt),
+            LoadError::Container(msg) => write!(f, "malformed texture container: {}", msg),
+        }
+    }
+}
+impl std::error::Error for LoadError {}
+impl From<image::ImageError> for LoadError {
+    fn from(e: image::ImageError) -> LoadError {
+        LoadError::Image(e)
+    }
+}
+
+/// Images loaded once and shared by path, e.g., environment maps used by
+/// several scenes of a batch.
+#[derive(Default)]
+pub struct AssetCache {
+    /// Images by path and the color space they were loaded as, if given.
+    images: std::collections::HashMap<(std::path::PathBuf, Option<ColorSpace>), std::sync::Arc<Image>>,
+}
+impl AssetCache {
+    pub fn new() -> AssetCache {
+        AssetCache::default()
+    }
+    /// The image at `path`, loaded with `load_image` on first use.
+    pub fn image<P: AsRef<Path>>(&mut self, path: P) -> Result<std::sync::Arc<Image>, LoadError> {
+        self.load(path.as_ref(), None)
+    }
+    /// The image at `path`, loaded with `load_image_as` on first use.
+    pub fn image_as<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        space: ColorSpace,
+    ) -> Result<std::sync::Arc<Image>, LoadError> {
+        self.load(path.as_ref(), Some(space))
+    }
+    fn load(&mut self, path: &Path, space: Option<ColorSpace>) -> Result<std::sync::Arc<Image>, LoadError> {
+        let key = (path.to_owned(), space);
+        if let Some(img) = self.images.get(&key) {
+            return Ok(img.clone());
+        }
+        let img = match space {
+            Some(space) => load_image_as(path, space)?,
+            None => load_image(path)?,
+        };
+        let img = std::sync::Arc::new(img);
+        self.images.insert(key, img.clone());
+        Ok(img)
+    }
+}
+
+/// Load an image file of colors. Radiance `.hdr` and OpenEXR files are loaded
+/// into linear `Rgba32f` images with their full range, see `decode_exr` for
+/// the OpenEXR files supported; other formats are loaded into sRGB `Rgba8`
+/// images of straight alpha. DDS and KTX2 textures are loaded with their mip
+/// chains, see `texfile::load_texture_levels` and `Image::with_mips`.
+pub fn load_image<P: AsRef<Path>>(path: P) -> Result<Image, LoadError> {
+    let path = path.as_ref();
+    let ext = path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("hdr") => load_hdr(path),
+        Some("dds") | Some("ktx2") => {
+            let mut levels = crate::texfile::load_texture_levels(path)?.into_iter();
+            let base = levels.next()
+                .ok_or_else(|| LoadError::Unsupported("texture without levels".to_owned()))?;
+            Ok(base.with_mips(levels.collect()))
+        },
+        Some("exr") => decode_exr(&std::fs::read(path).map_err(image::ImageError::from)?),
+        _ => Ok(image::open(path)?.into()),
+    }
+}
+/// Load an image file like `load_image` but of values encoded in `space`,
+/// e.g., `ColorSpace::Normal` for normal maps and `ColorSpace::Linear` for
+/// data textures like roughness maps.
+pub fn load_image_as<P: AsRef<Path>>(path: P, space: ColorSpace) -> Result<Image, LoadError> {
+    Ok(load_image(path)?.with_color_space(space))
+}
+fn load_hdr(path: &Path) -> Result<Image, LoadError> {
+    let file = std::fs::File::open(path).map_err(image::ImageError::from)?;
+    let decoder = image::hdr::HdrDecoder::new(std::io::BufReader::new(file))?;
+    let meta = decoder.metadata();
+    let (w, h) = (meta.width as usize, meta.height as usize);
+    let buf = decoder.read_image_hdr()?
+        .into_iter()
+        .map(|image::Rgb([r, g, b])| Color(r, g, b, 1.0))
+        .collect();
+    Ok(Image {
+        buf: Storage::Rgba32f(buf),
+        w,
+        h,
+        alpha: Alpha::Premultiplied,
+        space: ColorSpace::Linear,
+        mips: Vec::new(),
+    })
+}
+
+/// File stems of cube map faces in the order used by `CubeSampler`.
+const CUBEMAP_FACE_NAMES: [&str; 6] = ["pos-x", "neg-x", "pos-y", "neg-y", "pos-z", "neg-z"];
+
+/// Load a cube map for `CubeSampler`. `path` is either:
+///
+/// - a directory of six images named `pos-x`, `neg-x`, `pos-y`, `neg-y`,
+///   `pos-z` and `neg-z`, with any extension supported; or
+/// - a single image of the faces in a horizontal (4:3) or vertical (3:4)
+///   cross. The horizontal cross has `pos-y` on top, `neg-x`, `pos-z`,
+///   `pos-x` and `neg-z` in the middle row and `neg-y` at the bottom; the
+///   vertical cross moves `neg-z` below `neg-y`, turned half a turn.
+///
+/// Faces are used as is, in the orientation `CubeSampler` samples them.
+pub fn load_cubemap<P: AsRef<Path>>(path: P) -> Result<Vec<Image>, LoadError> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return load_cubemap_cross(path);
+    }
+    let mut paths = Vec::with_capacity(6);
+    for name in CUBEMAP_FACE_NAMES.iter() {
+        let face = std::fs::read_dir(path)
+            .map_err(|e| LoadError::Image(e.into()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|p| p.file_stem().and_then(|x| x.to_str()) == Some(name))
+            .ok_or_else(|| {
+                LoadError::Cubemap(format!("face `{}` is missing in {}", name, path.display()))
+            })?;
+        paths.push(face);
+    }
+    load_cubemap_faces(&paths)
+}
+/// Load a cube map from six images in the order used by `CubeSampler`.
+pub fn load_cubemap_faces<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<Image>, LoadError> {
+    if paths.len() != 6 {
+        return Err(LoadError::Cubemap(format!("expected 6 faces, got {}", paths.len())));
+    }
+    let faces = paths.iter()
+        .map(load_image)
+        .collect::<Result<Vec<_>, _>>()?;
+    let size = faces[0].width();
+    for (face, name) in faces.iter().zip(CUBEMAP_FACE_NAMES.iter()) {
+        if face.width() != size || face.height() != size {
+            return Err(LoadError::Cubemap(format!(
+                "face `{}` is {}x{} but faces must all be {}x{}",
+                name, face.width(), face.height(), size, size)));
+        }
+    }
+    Ok(faces)
+}
+fn load_cubemap_cross(path: &Path) -> Result<Vec<Image>, LoadError> {
+    let img = load_image(path)?;
+    cross_faces(&img).ok_or_else(|| LoadError::Cubemap(format!(
+        "{} is {}x{}, which is neither a 4:3 nor a 3:4 cross",
+        path.display(), img.width(), img.height())))
+}
+/// Faces of cube map cross `img` in the order used by `CubeSampler`, `None` if
+/// it's neither a 4:3 nor a 3:4 cross. Vertical crosses store `neg-z` turned
+/// half a turn, as seen from the bottom of the cross, so it's turned back to
+/// the orientation of the horizontal cross.
+fn cross_faces(img: &Image) -> Option<Vec<Image>> {
+    let (w, h) = (img.width(), img.height());
+    // Cells of the faces in the order used by `CubeSampler`.
+    let (size, cells, vertical) = if w * 3 == h * 4 && w % 4 == 0 {
+        (w / 4, [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (3, 1)], false)
+    } else if w * 4 == h * 3 && w % 3 == 0 {
+        (w / 3, [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (1, 3)], true)
+    } else {
+        return None;
+    };
+    let mut faces = cells.iter()
+        .map(|&(x, y)| img.crop(x * size, y * size, size, size))
+        .collect::<Vec<_>>();
+    if vertical {
+        let neg_z = &mut faces[5];
+        let n = size * size;
+        for i in 0..n / 2 {
+            let (x0, y0) = (i % size, i / size);
+            let (x1, y1) = (size - 1 - x0, size - 1 - y0);
+            let (a, b) = (neg_z.load_raw(x0, y0), neg_z.load_raw(x1, y1));
+            neg_z.store_raw(x0, y0, b);
+            neg_z.store_raw(x1, y1, a);
+        }
+    }
+    Some(faces)
+}
+
+/// Convert a 32-bit float to the bits of the nearest 16-bit float.
+fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let man = bits & 0x007f_ffff;
+    if exp == 0xff {
+        // Infinity or NaN.
+        let nan = if man != 0 { 0x0200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    // Round to nearest, ties to even, dropping the lowest `shift` bits.
+    let round = |man: u32, shift: u32| {
+        let rv = man >> shift;
+        let rem = man & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if rem > halfway || (rem == halfway && rv & 1 == 1) { rv + 1 } else { rv }
+    };
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        // Too large, saturate to infinity.
+        sign | 0x7c00
+    } else if exp <= 0 {
+        // Subnormal or too small to be represented.
+        if exp < -10 { return sign }
+        sign | round(man | 0x0080_0000, (14 - exp) as u32) as u16
+    } else {
+        // A carry out of the mantissa correctly bumps the exponent.
+        sign | round(((exp as u32) << 23) | man, 13) as u16
+    }
+}
+/// Convert the bits of a 16-bit float to a 32-bit float.
+pub(crate) fn f16_to_f32(x: u16) -> f32 {
+    let sign = ((x & 0x8000) as u32) << 16;
+    let exp = ((x >> 10) & 0x1f) as u32;
+    let man = (x & 0x03ff) as u32;
+    let bits = match exp {
+        0 => {
+            // Zero or subnormal.
+            let mag = man as f32 * (-24.0_f32).exp2();
+            return if sign != 0 { -mag } else { mag };
+        },
+        0x1f => sign | 0x7f80_0000 | (man << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (man << 13),
+    };
+    f32::from_bits(bits)
+}
+
+const RGB9E5_MANTISSA_BITS: i32 = 9;
+const RGB9E5_EXP_BIAS: i32 = 15;
+const RGB9E5_MAX_EXP: i32 = 31;
+/// Pack a color into the shared-exponent format.
+///
+/// See: https://www.khronos.org/registry/OpenGL/extensions/EXT/EXT_texture_shared_exponent.txt
+fn color_to_rgb9e5(c: Color) -> u32 {
+    let max_val = (511.0 / 512.0) *
+        ((RGB9E5_MAX_EXP - RGB9E5_EXP_BIAS) as f32).exp2();
+    // Also maps NaN to 0.
+    let clamp = |x: f32| if x > 0.0 { x.min(max_val) } else { 0.0 };
+    let (r, g, b) = (clamp(c.0), clamp(c.1), clamp(c.2));
+    let maxc = r.max(g).max(b);
+    let mut exp = (-RGB9E5_EXP_BIAS - 1).max(maxc.log2().floor() as i32) +
+        1 + RGB9E5_EXP_BIAS;
+    let mut denom = ((exp - RGB9E5_EXP_BIAS - RGB9E5_MANTISSA_BITS) as f32).exp2();
+    if (maxc / denom + 0.5).floor() as i32 == 1 << RGB9E5_MANTISSA_BITS {
+        denom *= 2.0;
+        exp += 1;
+    }
+    let quantize = |x: f32| (x / denom + 0.5).floor() as u32;
+    quantize(r) | (quantize(g) << 9) | (quantize(b) << 18) | ((exp as u32) << 27)
+}
+/// Unpack a color from the shared-exponent format.
+fn rgb9e5_to_color(x: u32) -> Color {
+    let exp = (x >> 27) as i32;
+    let scale = ((exp - RGB9E5_EXP_BIAS - RGB9E5_MANTISSA_BITS) as f32).exp2();
+    let dequantize = |m: u32| (m & 0x1ff) as f32 * scale;
+    Color(dequantize(x), dequantize(x >> 9), dequantize(x >> 18), 1.0)
+}
+
+/// Provenance of a render, embedded into saved images by `save_image`.
+#[derive(Debug, Clone, Default)]
+pub struct RenderMetadata {
+    /// Hash of the scene description, see `hash_file`.
+    pub scene_hash: Option<u64>,
+    /// Samples per pixel.
+    pub spp: Option<u32>,
+    pub seed: Option<u64>,
+    pub render_time: Option<std::time::Duration>,
+}
+impl RenderMetadata {
+    /// Key-value pairs of the metadata, including the crate version.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut rv = vec![("Software", format!("lighar {}", env!("CARGO_PKG_VERSION")))];
+        if let Some(x) = self.scene_hash {
+            rv.push(("lighar:scene_hash", format!("{:016x}", x)));
+        }
+        if let Some(x) = self.spp {
+            rv.push(("lighar:spp", x.to_string()));
+        }
+        if let Some(x) = self.seed {
+            rv.push(("lighar:seed", x.to_string()));
+        }
+        if let Some(x) = self.render_time {
+            rv.push(("lighar:render_time", format!("{:.3}s", x.as_secs_f64())));
+        }
+        rv
+    }
+}
+
+/// 64-bit FNV-1a hash of the content of a file, e.g., of a scene description
+/// for `RenderMetadata::scene_hash`. Unlike `std` hashers it's stable across
+/// builds.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> std::io::Result<u64> {
+    let data = std::fs::read(path)?;
+    Ok(data.iter().fold(0xcbf29ce484222325, |h, &x| (h ^ x as u64).wrapping_mul(0x100000001b3)))
+}
+
+/// Error saving images to files.
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    /// The file format is not supported.
+    Unsupported(String),
+}
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "failed to write image: {}", e),
+            SaveError::Image(e) => write!(f, "failed to encode image: {}", e),
+            SaveError::Unsupported(fmt) => write!(f, "unsupported image format: {}", fmt),
+        }
+    }
+}
+impl std::error::Error for SaveError {}
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> SaveError {
+        SaveError::Io(e)
+    }
+}
+impl From<image::ImageError> for SaveError {
+    fn from(e: image::ImageError) -> SaveError {
+        SaveError::Image(e)
+    }
+}
+
+/// Save `img` with 8 bits per channel, or 32-bit floats for OpenEXR files.
+/// PNG and OpenEXR files get `meta` as text chunks and string attributes;
+/// other formats supported by the `image` crate are saved without metadata.
+/// Colors are stored premultiplied by alpha in OpenEXR files and with
+/// straight alpha otherwise, as the formats expect, whatever `Alpha` of
+/// `img`.
+pub fn save_image<P: AsRef<Path>>(img: &Image, path: P, meta: &RenderMetadata) -> Result<(), SaveError> {
+    let path = path.as_ref();
+    let ext = path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("png") => {
+            std::fs::write(path, encode_png(img, meta)?)?;
+            Ok(())
+        },
+        Some("exr") => {
+            std::fs::write(path, encode_exr(&[("", img)], meta)?)?;
+            Ok(())
+        },
+        _ => {
+            let (w, h) = (img.width() as u32, img.height() as u32);
+            image::save_buffer(path, &to_rgba8(img), w, h, image::ColorType::Rgba8)?;
+            Ok(())
+        },
+    }
+}
+/// Encode `img` as a PNG file with `meta` in `tEXt` chunks.
+pub fn encode_png(img: &Image, meta: &RenderMetadata) -> Result<Vec<u8>, SaveError> {
+    let mut png = Vec::new();
+    image::png::PNGEncoder::new(&mut png)
+        .encode(&to_rgba8(img), img.width() as u32, img.height() as u32, image::ColorType::Rgba8)?;
+    let mut chunks = Vec::new();
+    for (key, val) in meta.entries() {
+        let mut data = key.as_bytes().to_vec();
+        data.push(0);
+        data.extend(val.bytes());
+        chunks.extend(&(data.len() as u32).to_be_bytes());
+        let start = chunks.len();
+        chunks.extend(b"tEXt");
+        chunks.extend(&data);
+        let crc = crc32(&chunks[start..]);
+        chunks.extend(&crc.to_be_bytes());
+    }
+    // Text chunks follow the 8-byte signature and the 25-byte `IHDR` chunk,
+    // which always come first.
+    const IHDR_END: usize = 8 + 25;
+    png.splice(IHDR_END..IHDR_END, chunks);
+    Ok(png)
+}
+/// CRC-32 of PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &x in data {
+        crc ^= x as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Save `layers` of the same size into a single OpenEXR file, see
+/// `encode_exr`.
+pub fn save_exr<P: AsRef<Path>>(
+    layers: &[(&str, &Image)],
+    path: P,
+    meta: &RenderMetadata,
+) -> Result<(), SaveError> {
+    std::fs::write(path, encode_exr(layers, meta)?)?;
+    Ok(())
+}
+/// Encode named `layers` of the same size as an uncompressed scanline
+/// OpenEXR file of 32-bit float channels, e.g., a beauty pass with AOVs for
+/// compositing. Each layer has the channels `R`, `G`, `B` and `A` prefixed
+/// by the layer name and a dot, like `P.R`; the layer named `""` is the
+/// main one, without prefixes. `meta` is written as string attributes.
+pub fn encode_exr(layers: &[(&str, &Image)], meta: &RenderMetadata) -> Result<Vec<u8>, SaveError> {
+    let (w, h) = match layers.first() {
+        Some((_, img)) => (img.width(), img.height()),
+        None => return Err(SaveError::Unsupported("OpenEXR without layers".to_owned())),
+    };
+    if layers.iter().any(|(_, img)| img.width() != w || img.height() != h) {
+        return Err(SaveError::Unsupported("OpenEXR layers of different sizes".to_owned()));
+    }
+    // Data windows are inclusive and can't be empty.
+    if w == 0 || h == 0 {
+        return Err(SaveError::Unsupported("empty OpenEXR images".to_owned()));
+    }
+    // Channels must be sorted by name, and pixels are stored in that order.
+    let mut channels = layers.iter()
+        .enumerate()
+        .flat_map(|(i, (name, _))| {
+            ["R", "G", "B", "A"].iter().enumerate().map(move |(c, x)| {
+                let name = if name.is_empty() { x.to_string() } else { format!("{}.{}", name, x) };
+                (name, i, c)
+            })
+        })
+        .collect::<Vec<_>>();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    fn attr(buf: &mut Vec<u8>, name: &str, ty: &str, value: &[u8]) {
+        buf.extend(name.as_bytes());
+        buf.push(0);
+        buf.extend(ty.as_bytes());
+        buf.push(0);
+        buf.extend(&(value.len() as i32).to_le_bytes());
+        buf.extend(value);
+    }
+    // Magic number and version 2 of single-part scanline files.
+    let mut exr = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut chlist = Vec::new();
+    for (name, _, _) in channels.iter() {
+        chlist.extend(name.as_bytes());
+        chlist.push(0);
+        // 32-bit float, not perceptually linear, reserved bytes, and no
+        // subsampling in x and y.
+        chlist.extend(&2i32.to_le_bytes());
+        chlist.extend(&[0, 0, 0, 0]);
+        chlist.extend(&1i32.to_le_bytes());
+        chlist.extend(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    attr(&mut exr, "channels", "chlist", &chlist);
+    attr(&mut exr, "compression", "compression", &[0]);
+    let window = [0, 0, w as i32 - 1, h as i32 - 1].iter()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    attr(&mut exr, "dataWindow", "box2i", &window);
+    attr(&mut exr, "displayWindow", "box2i", &window);
+    // Increasing y.
+    attr(&mut exr, "lineOrder", "lineOrder", &[0]);
+    attr(&mut exr, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attr(&mut exr, "screenWindowCenter", "v2f", &[0; 8]);
+    attr(&mut exr, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    for (key, val) in meta.entries() {
+        attr(&mut exr, key, "string", val.as_bytes());
+    }
+    exr.push(0);
+
+    // One scanline per chunk, located by a table of offsets from the start
+    // of the file.
+    let chunk_size = 8 + channels.len() * w * 4;
+    let table_end = exr.len() + h * 8;
+    for y in 0..h {
+        exr.extend(&((table_end + y * chunk_size) as u64).to_le_bytes());
+    }
+    for y in 0..h {
+        exr.extend(&(y as i32).to_le_bytes());
+        exr.extend(&((chunk_size - 8) as i32).to_le_bytes());
+        for &(_, i, c) in channels.iter() {
+            let img = layers[i].1;
+            for x in 0..w {
+                let px = img.load_px_premultiplied(x, y);
+                let val = [px.0, px.1, px.2, px.3][c];
+                exr.extend(&val.to_le_bytes());
+            }
+        }
+    }
+    Ok(exr)
+}
+/// Decode the main layer of a scanline OpenEXR file, like those of
+/// `encode_exr`, into a linear `Rgba32f` image of premultiplied alpha.
+/// Channels of any pixel type are read; missing color channels are 0 and
+/// missing alpha is 1. Files may be uncompressed or ZIP compressed, as most
+/// tools write them by default; other compressions, and tiled, deep and
+/// multi-part files are not supported.
+pub fn decode_exr(data: &[u8]) -> Result<Image, LoadError> {
+    fn malformed(msg: &str) -> LoadError {
+        LoadError::Container(format!("OpenEXR {}", msg))
+    }
+    fn bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], LoadError> {
+        offset.checked_add(len)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| malformed("file is truncated"))
+    }
+    fn read_i32(data: &[u8], offset: usize) -> Result<i32, LoadError> {
+        let x = bytes(data, offset, 4)?;
+        Ok(i32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+    }
+    /// Null-terminated string at `*offset`, which is moved past it.
+    fn read_str<'a>(data: &'a [u8], offset: &mut usize) -> Result<&'a [u8], LoadError> {
+        let rest = data.get(*offset..).unwrap_or_default();
+        let len = rest.iter()
+            .position(|&x| x == 0)
+            .ok_or_else(|| malformed("file is truncated"))?;
+        *offset += len + 1;
+        Ok(&rest[..len])
+    }
+    /// Inflate the zlib stream of ZIP compressed pixels into `out`, undoing
+    /// the delta predictor and the byte interleaving applied before.
+    fn unzip(zipped: &[u8], out: &mut [u8]) -> Result<(), LoadError> {
+        use miniz_oxide::inflate::{TINFLStatus, core::{decompress, DecompressorOxide, inflate_flags}};
+        let flags = inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER
+            | inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        let mut tmp = vec![0; out.len()];
+        let mut cursor = std::io::Cursor::new(&mut tmp[..]);
+        let (status, _, nout) = decompress(&mut DecompressorOxide::new(), zipped, &mut cursor, flags);
+        if status != TINFLStatus::Done || nout != out.len() {
+            return Err(malformed("ZIP data is corrupt"));
+        }
+        for i in 1..tmp.len() {
+            tmp[i] = tmp[i - 1].wrapping_add(tmp[i]).wrapping_sub(128);
+        }
+        let (even, odd) = tmp.split_at(tmp.len().div_ceil(2));
+        for (i, x) in out.iter_mut().enumerate() {
+            *x = if i % 2 == 0 { even[i / 2] } else { odd[i / 2] };
+        }
+        Ok(())
+    }
+
+    if !data.starts_with(&[0x76, 0x2f, 0x31, 0x01]) {
+        return Err(malformed("magic number is missing"));
+    }
+    // Tiled, deep and multi-part files.
+    let version = read_i32(data, 4)?;
+    if version & 0xff != 2 || version & (0x200 | 0x800 | 0x1000) != 0 {
+        return Err(LoadError::Unsupported(format!("OpenEXR files of version {:#x}", version)));
+    }
+    // Channels of pixel types 0 for `u32`, 1 for `f16` and 2 for `f32`, in
+    // the order their pixels are stored.
+    let mut channels = Vec::new();
+    let mut window = None;
+    // 0 for none, 2 for ZIP of single scanlines and 3 for ZIP of 16.
+    let mut compression = 0;
+    let mut offset = 8;
+    loop {
+        let name = read_str(data, &mut offset)?;
+        if name.is_empty() { break }
+        let ty = read_str(data, &mut offset)?;
+        let len = read_i32(data, offset)?;
+        let len = usize::try_from(len).map_err(|_| malformed("attribute size is negative"))?;
+        let value = bytes(data, offset + 4, len)?;
+        offset += 4 + len;
+        match (name, ty) {
+            (b"channels", b"chlist") => {
+                let mut i = 0;
+                loop {
+                    let name = read_str(value, &mut i)?;
+                    if name.is_empty() { break }
+                    let pixel_type = read_i32(value, i)?;
+                    if !(0..=2).contains(&pixel_type) {
+                        return Err(malformed("pixel type is unknown"));
+                    }
+                    if read_i32(value, i + 8)? != 1 || read_i32(value, i + 12)? != 1 {
+                        return Err(LoadError::Unsupported("subsampled OpenEXR channels".to_owned()));
+                    }
+                    channels.push((name.to_vec(), pixel_type));
+                    i += 16;
+                }
+            },
+            (b"compression", _) => {
+                compression = value.first().copied().unwrap_or_default();
+                if ![0, 2, 3].contains(&compression) {
+                    let msg = format!("OpenEXR files of compression {}", compression);
+                    return Err(LoadError::Unsupported(msg));
+                }
+            },
+            (b"dataWindow", b"box2i") => {
+                window = Some([
+                    read_i32(value, 0)?,
+                    read_i32(value, 4)?,
+                    read_i32(value, 8)?,
+                    read_i32(value, 12)?,
+                ]);
+            },
+            _ => {},
+        }
+    }
+    let [xmin, ymin, xmax, ymax] = window.ok_or_else(|| malformed("data window is missing"))?;
+    let size = |min: i32, max: i32| {
+        usize::try_from(max as i64 - min as i64 + 1)
+            .ok()
+            .filter(|&x| x > 0)
+            .ok_or_else(|| malformed("data window is empty"))
+    };
+    let (w, h) = (size(xmin, xmax)?, size(ymin, ymax)?);
+    // Bytes of a pixel of all channels, and the channels of the main layer
+    // making up the colors.
+    let px_size = channels.iter()
+        .map(|&(_, ty)| if ty == 1 { 2 } else { 4 })
+        .sum::<usize>();
+    let line_size = w.checked_mul(px_size)
+        .ok_or_else(|| malformed("data window is too large"))?;
+    let npx = w.checked_mul(h)
+        .filter(|&x| x.checked_mul(16).is_some())
+        .ok_or_else(|| malformed("data window is too large"))?;
+    let component = |name: &[u8]| match name {
+        b"R" => Some(0),
+        b"G" => Some(1),
+        b"B" => Some(2),
+        b"A" => Some(3),
+        _ => None,
+    };
+
+    let mut buf = vec![Color(0.0, 0.0, 0.0, 1.0); npx];
+    let mut unzipped = Vec::new();
+    // Chunks of scanlines located by the table of offsets following the
+    // header. Chunks compressing to no less than their size are stored as
+    // they are.
+    let nline = if compression == 3 { 16 } else { 1 };
+    for ichunk in 0..h.div_ceil(nline) {
+        let chunk = bytes(data, offset + ichunk * 8, 8)?;
+        let chunk = u64::from_le_bytes([
+            chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7],
+        ]);
+        let chunk = usize::try_from(chunk).map_err(|_| malformed("file is truncated"))?;
+        let y0 = read_i32(data, chunk)? as i64 - ymin as i64;
+        let y0 = usize::try_from(y0).ok()
+            .filter(|&y| y < h)
+            .ok_or_else(|| malformed("scanline is out of the data window"))?;
+        let size = line_size.checked_mul(nline.min(h - y0))
+            .ok_or_else(|| malformed("data window is too large"))?;
+        let chunk_size = usize::try_from(read_i32(data, chunk + 4)?)
+            .map_err(|_| malformed("chunk size is negative"))?;
+        let lines = bytes(data, chunk + 8, chunk_size)?;
+        let lines = if chunk_size == size {
+            lines
+        } else if compression != 0 && chunk_size < size {
+            unzipped.resize(size, 0);
+            unzip(lines, &mut unzipped)?;
+            &unzipped[..]
+        } else {
+            return Err(malformed("scanline size mismatches its channels"));
+        };
+        for (y, mut line) in (y0..).zip(lines.chunks(line_size)) {
+            for (name, ty) in channels.iter() {
+                let (vals, rest) = line.split_at(w * if *ty == 1 { 2 } else { 4 });
+                line = rest;
+                let c = match component(name) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                for x in 0..w {
+                    let val = match ty {
+                        0 => u32::from_le_bytes([vals[x * 4], vals[x * 4 + 1], vals[x * 4 + 2], vals[x * 4 + 3]]) as f32,
+                        1 => f16_to_f32(u16::from_le_bytes([vals[x * 2], vals[x * 2 + 1]])),
+                        _ => f32::from_le_bytes([vals[x * 4], vals[x * 4 + 1], vals[x * 4 + 2], vals[x * 4 + 3]]),
+                    };
+                    let px = &mut buf[y * w + x];
+                    match c {
+                        0 => px.0 = val,
+                        1 => px.1 = val,
+                        2 => px.2 = val,
+                        _ => px.3 = val,
+                    }
+                }
+            }
+        }
+    }
+    Ok(Image {
+        buf: Storage::Rgba32f(buf),
+        w,
+        h,
+        alpha: Alpha::Premultiplied,
+        space: ColorSpace::Linear,
+        mips: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_round_trip() {
+        let mut img = Image::with_format(256, 1, Format::Rgba8).with_color_space(ColorSpace::Srgb);
+        for x in 0..256 {
+            let v = x as u8;
+            img.store_raw(x, 0, Color::from([v, v, v, 255]));
+        }
+        let linear = img.convert(Format::Rgba32f).to_color_space(ColorSpace::Linear);
+        let srgb = linear.to_color_space(ColorSpace::Srgb).convert(Format::Rgba8);
+        for x in 0..256 {
+            let c: [u8; 4] = srgb.load_raw(x, 0).into();
+            assert_eq!(c, [x as u8, x as u8, x as u8, 255]);
+        }
+    }
+
+    #[test]
+    fn vertical_cross_seams() {
+        // A gradient continuous across every seam within the image, including
+        // the one between `neg-y` and `neg-z` at the bottom.
+        let size = 8;
+        let mut img = Image::new(3 * size, 4 * size);
+        for y in 0..4 * size {
+            for x in 0..3 * size {
+                img.store_px(x, y, Color(x as f32, y as f32, 0.0, 1.0));
+            }
+        }
+        let faces = cross_faces(&img).unwrap();
+        let (neg_y, neg_z) = (&faces[3], &faces[5]);
+        // In the orientation of the horizontal cross, the bottom edges of
+        // `neg-y` and `neg-z` meet in reverse.
+        for x in 0..size {
+            let a = neg_y.load_px(x, size - 1);
+            let b = neg_z.load_px(size - 1 - x, size - 1);
+            assert_eq!((a.0, a.1 + 1.0), (b.0, b.1));
+        }
+        // The other faces are cut as they are.
+        let pos_z = &faces[4];
+        assert_eq!(pos_z.load_px(0, 0).0, size as f32);
+        assert_eq!(pos_z.load_px(0, 0).1, size as f32);
+    }
+
+    #[test]
+    fn mips_follow_conversions() {
+        let img = Image::new(4, 4).with_mips(vec![Image::new(2, 2), Image::new(1, 1)]);
+        let img = img.with_color_space(ColorSpace::Srgb).to_alpha(Alpha::Straight);
+        let img = img.convert(Format::Rgba8);
+        assert_eq!(img.nlevel(), 3);
+        let level = img.level(2);
+        assert_eq!((level.width(), level.format()), (1, Format::Rgba8));
+        assert_eq!((level.color_space(), level.alpha()), (ColorSpace::Srgb, Alpha::Straight));
+    }
+
+    #[test]
+    fn exr_round_trip() {
+        let mut img = Image::new(3, 2);
+        let mut p = Image::new(3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                img.store_px(x, y, Color(x as f32, y as f32, -0.5, 0.25));
+                p.store_px(x, y, Color(9.0, 9.0, 9.0, 1.0));
+            }
+        }
+        let meta = RenderMetadata { spp: Some(16), seed: Some(7), ..Default::default() };
+        let exr = encode_exr(&[("", &img), ("P", &p)], &meta).unwrap();
+        let decoded = decode_exr(&exr).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (3, 2));
+        for y in 0..2 {
+            for x in 0..3 {
+                let (a, b) = (decoded.load_px(x, y), img.load_px(x, y));
+                assert_eq!((a.0, a.1, a.2, a.3), (b.0, b.1, b.2, b.3));
+            }
+        }
+    }
+
+    /// Recompress uncompressed scanline OpenEXR file `exr` of `w` by `h`
+    /// RGBA f32 pixels as ZIP of `nline` scanlines per chunk.
+    fn zip_exr(exr: &[u8], w: usize, h: usize, nline: usize) -> Vec<u8> {
+        let line_size = w * 16;
+        let table = exr.len() - h * (16 + line_size);
+        let mut rv = exr[..table].to_vec();
+        let i = rv.windows(24).position(|x| x == b"compression\0compression\0").unwrap();
+        rv[i + 28] = if nline == 1 { 2 } else { 3 };
+        let chunks = (0..h).step_by(nline)
+            .map(|y0| {
+                let mut raw = Vec::new();
+                for y in y0..h.min(y0 + nline) {
+                    let line = table + h * 8 + y * (8 + line_size) + 8;
+                    raw.extend(&exr[line..line + line_size]);
+                }
+                // Interleave the bytes and predict each from the previous.
+                let mut tmp = raw.iter().step_by(2).chain(raw.iter().skip(1).step_by(2))
+                    .cloned()
+                    .collect::<Vec<u8>>();
+                for i in (1..tmp.len()).rev() {
+                    tmp[i] = tmp[i].wrapping_sub(tmp[i - 1]).wrapping_add(128);
+                }
+                let zipped = miniz_oxide::deflate::compress_to_vec_zlib(&tmp, 6);
+                let mut chunk = (y0 as i32).to_le_bytes().to_vec();
+                chunk.extend(&(zipped.len() as i32).to_le_bytes());
+                chunk.extend(zipped);
+                chunk
+            })
+            .collect::<Vec<_>>();
+        let mut offset = rv.len() + chunks.len() * 8;
+        for chunk in chunks.iter() {
+            rv.extend(&(offset as u64).to_le_bytes());
+            offset += chunk.len();
+        }
+        rv.extend(chunks.concat());
+        rv
+    }
+
+    #[test]
+    fn exr_zip_round_trip() {
+        let (w, h) = (5, 20);
+        let mut img = Image::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                img.store_px(x, y, Color(x as f32, y as f32 * 0.5, 1.0, 1.0));
+            }
+        }
+        let exr = encode_exr(&[("", &img)], &RenderMetadata::default()).unwrap();
+        for nline in [1, 16] {
+            let zipped = zip_exr(&exr, w, h, nline);
+            assert!(zipped.len() < exr.len());
+            let decoded = decode_exr(&zipped).unwrap();
+            for y in 0..h {
+                for x in 0..w {
+                    let (a, b) = (decoded.load_px(x, y), img.load_px(x, y));
+                    assert_eq!((a.0, a.1, a.2, a.3), (b.0, b.1, b.2, b.3));
+                }
+            }
+            let mut corrupt = zipped.clone();
+            let n = corrupt.len();
+            corrupt[n - 8] ^= 0xff;
+            assert!(decode_exr(&corrupt).is_err());
+        }
+    }
+
+    #[test]
+    fn exr_without_alpha_is_opaque() {
+        let mut img = Image::new(1, 1);
+        img.store_px(0, 0, Color(0.5, 0.5, 0.5, 0.5));
+        // Drop the `A` channel from the channel list.
+        let mut exr = encode_exr(&[("", &img)], &RenderMetadata::default()).unwrap();
+        let i = exr.windows(2).position(|x| x == b"A\0").unwrap();
+        exr[i] = b'X';
+        let decoded = decode_exr(&exr).unwrap();
+        assert_eq!(decoded.load_px(0, 0).3, 1.0);
+    }
+
+    #[test]
+    fn exr_rejects_empty_images() {
+        let img = Image::new(0, 4);
+        assert!(encode_exr(&[("", &img)], &RenderMetadata::default()).is_err());
+        assert!(encode_exr(&[], &RenderMetadata::default()).is_err());
+    }
+
+    #[test]
+    fn exr_rejects_truncated_files() {
+        let exr = encode_exr(&[("", &Image::new(2, 2))], &RenderMetadata::default()).unwrap();
+        assert!(decode_exr(&exr[..exr.len() - 1]).is_err());
+        assert!(decode_exr(&exr[..20]).is_err());
     }
 }
@@ -0,0 +1,948 @@
+use crate::geom::{Real, Point, Vector, Ray, Triangle, Color, Barycentric, hemisphere, sphere, offset_ray_origin, narrow};
+use crate::rt::{
+    RayTracer, WavefrontRayTracer, Intersection, HitKind, TracePayload, Framebuffer, morton_order,
+    WAVEFRONT_BATCH,
+};
+use crate::scene::{Scene, Object, RayKind};
+use crate::accel::Accel;
+use crate::img::Image;
+use crate::light::Light;
+use crate::medium::{HeterogeneousMedium, henyey_greenstein};
+use crate::curve::KajiyaKay;
+use crate::rng;
+use crate::trace;
+
+/// Kind of BSDF lobe a path scattered by, which tells light path expressions
+/// apart.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Lobe {
+    #[default]
+    Diffuse,
+    /// Mirror-like reflection and refraction, including glossy ones.
+    Specular,
+}
+
+/// How the two faces of a surface shade, where the front face is the one
+/// the triangle normal points out of. By default both faces shade and emit
+/// alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sides {
+    /// Whether the back face scatters light like the front face. Otherwise
+    /// it absorbs all light, e.g., the inside of single sided meshes.
+    pub two_sided: bool,
+    /// Whether emissive surfaces emit from the back face too, e.g., not for
+    /// area lights shining in one direction.
+    pub emit_back: bool,
+}
+impl Default for Sides {
+    fn default() -> Sides {
+        Sides { two_sided: true, emit_back: true }
+    }
+}
+impl Sides {
+    /// Whether light hitting the `kind` face scatters.
+    pub fn scatters(&self, kind: HitKind) -> bool {
+        kind == HitKind::Front || self.two_sided
+    }
+    /// Light emitted from the `kind` face of a surface emitting `emit`.
+    pub fn emit(&self, kind: HitKind, emit: Color) -> Color {
+        if kind == HitKind::Front || self.emit_back { emit } else { Color::default() }
+    }
+    /// Outcome of a path hitting the `kind` face of a surface emitting
+    /// `emit`, if the face absorbs all light.
+    pub fn absorb<Ray>(&self, kind: HitKind, emit: Color) -> Option<Scatter<Ray>> {
+        if self.scatters(kind) { return None }
+        Some(Scatter {
+            emit: self.emit(kind, emit),
+            direct: Color::default(),
+            next: None,
+            lobe: Lobe::Diffuse,
+            pdf: 0.0,
+        })
+    }
+}
+
+/// Outcome of a path hitting a surface.
+pub struct Scatter<Ray> {
+    /// Light emitted by the surface towards the incoming ray.
+    pub emit: Color,
+    /// Light of `PathTracer::lights` reflected towards the incoming ray,
+    /// sampled directly at the surface.
+    pub direct: Color,
+    /// The ray to continue the path with and the weight it carries, i.e., BSDF
+    /// times cosine divided by the sampling PDF. `None` if the path is
+    /// absorbed.
+    pub next: Option<(Ray, Color)>,
+    /// Lobe `next` is sampled from.
+    pub lobe: Lobe,
+    /// PDF in solid angle of the direction of `next` if `direct` sampled the
+    /// lights `next` might hit too, for weighing both by multiple importance
+    /// sampling. 0 if it didn't, e.g., for specular lobes, so that lights hit
+    /// by `next` count in full.
+    pub pdf: Real,
+}
+
+/// Where a path bounced before a ray, for `PathTracer::hit_lights`.
+#[derive(Debug, Clone, Copy)]
+pub struct Bounce {
+    /// Index of the object bounced off, `None` in participating media, which
+    /// light links don't apply to.
+    pub obj: Option<usize>,
+    /// `Scatter::pdf` of the bounce.
+    pub pdf: Real,
+}
+
+/// Sample a bounce off a Lambertian surface of `albedo` emitting `emit`, where
+/// `ray` hit `tri`. Both sides of the triangle are diffuse. Bounces are
+/// sampled around the unit `shading` normal if any, e.g., from
+/// `Object::shading_normal`, and absorbed below the triangle.
+pub fn scatter_diffuse(
+    ray: &Ray,
+    tri: &Triangle,
+    intersect: &Intersection<Barycentric>,
+    shading: Option<Vector>,
+    albedo: Color,
+    emit: Color,
+) -> Scatter<Ray> {
+    let (p, ng, n) = facing_surface(ray, tri, intersect, shading);
+    let u = (tri.y - tri.y.dot(n) * n).normalize();
+    let v = n.cross(u);
+    // Sampled uniformly over the hemisphere, the weight is
+    // `albedo / PI * cos / (1 / (2 * PI))`.
+    let cos = rng::random::<Real>();
+    let dir = hemisphere(cos, rng::random::<Real>()).in_basis(u, v, n);
+    let next = if dir.dot(ng) > 0.0 {
+        let ray = Ray { o: offset_ray_origin(p, ng), v: dir };
+        Some((ray, albedo * (2.0 * narrow(cos))))
+    } else {
+        None
+    };
+    Scatter {
+        emit,
+        direct: Color::default(),
+        next,
+        lobe: Lobe::Diffuse,
+        pdf: DIFFUSE_PDF,
+    }
+}
+
+/// Point where `ray` hit `tri`, the unit geometric normal of the side it hit
+/// and the unit `shading` normal flipped to the same side, or the geometric
+/// normal without one.
+pub(crate) fn facing_surface(
+    ray: &Ray,
+    tri: &Triangle,
+    intersect: &Intersection<Barycentric>,
+    shading: Option<Vector>,
+) -> (Point, Vector, Vector) {
+    let bary = intersect.attr;
+    let p = tri.o.affine_add(bary.u * tri.x + bary.v * tri.y);
+    let ng = if ray.v.dot(tri.n) > 0.0 { -tri.n } else { tri.n };
+    let n = match shading {
+        Some(n) if n.dot(tri.n) * ng.dot(tri.n) < 0.0 => -n,
+        Some(n) => n,
+        None => ng,
+    };
+    (p, ng, n)
+}
+
+/// PDF in solid angle of the bounces sampled by `scatter_diffuse`.
+pub(crate) const DIFFUSE_PDF: Real = 0.5 * std::f64::consts::FRAC_1_PI as Real;
+
+/// Weight of a sample of PDF `pdf` combined with another strategy sampling
+/// it with PDF `other`, by the power heuristic.
+///
+/// See: Eric Veach and Leonidas J. Guibas, Optimally Combining Sampling
+/// Techniques for Monte Carlo Rendering.
+pub fn power_heuristic(pdf: Real, other: Real) -> Real {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+/// Light of `rt.lights()` reflected towards `ray` by a Lambertian surface of
+/// `albedo` where `ray` hit `tri` of the `obj`-th object, for
+/// `Scatter::direct`. Each light linked to the object is sampled once with a
+/// shadow ray towards a random point of it, which is only blocked by objects
+/// shadow linked to the light. Lights that rays can hit are weighed against
+/// the bounces of `scatter_diffuse`, see `PathTracer::hit_lights`.
+pub fn direct_diffuse<T>(
+    rt: &T,
+    ray: &Ray,
+    obj: usize,
+    tri: &Triangle,
+    intersect: &Intersection<Barycentric>,
+    payload: &mut T::Payload,
+    albedo: Color,
+) -> Color
+    where T: PathTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    const FRAC_1_PI: f32 = std::f32::consts::FRAC_1_PI;
+    albedo * sample_lights(rt, ray, obj, tri, intersect, payload, DIFFUSE_PDF) * FRAC_1_PI
+}
+
+/// Irradiance of `rt.lights()` where `ray` hit `tri` of the `obj`-th object,
+/// sampled like `direct_diffuse` but without weighing, for shading that
+/// doesn't trace bounces, e.g., cel shading.
+pub fn diffuse_irradiance<T>(
+    rt: &T,
+    ray: &Ray,
+    obj: usize,
+    tri: &Triangle,
+    intersect: &Intersection<Barycentric>,
+    payload: &mut T::Payload,
+) -> Color
+    where T: PathTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    sample_lights(rt, ray, obj, tri, intersect, payload, 0.0)
+}
+
+/// Cosine weighted irradiance of one sample of each light, weighed against
+/// bounces of PDF `bsdf_pdf` if it's positive.
+fn sample_lights<T>(
+    rt: &T,
+    ray: &Ray,
+    obj: usize,
+    tri: &Triangle,
+    intersect: &Intersection<Barycentric>,
+    payload: &mut T::Payload,
+    bsdf_pdf: Real,
+) -> Color
+    where T: PathTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    let shading = rt.scene().objs[obj].shading_normal(intersect.prim, intersect.attr);
+    let (p, ng, n) = facing_surface(ray, tri, intersect, shading);
+    let o = offset_ray_origin(p, ng);
+    let mut rv = Color::default();
+    for light in rt.lights() {
+        let links = light.links();
+        if !links.illumination.includes(obj) { continue }
+        let sample = match light.illuminate(p, rng::random(), rng::random()) {
+            Some(x) => x,
+            None => continue,
+        };
+        let cos = sample.wi.dot(n);
+        if cos <= 0.0 || sample.wi.dot(ng) <= 0.0 { continue }
+        let shadow = Ray { o, v: sample.wi };
+        if rt.occluded_by(shadow, sample.dist, payload, |i| links.shadow.includes(i)) {
+            continue;
+        }
+        let pdf = light.pdf(p, sample.wi);
+        let w = if bsdf_pdf > 0.0 && pdf > 0.0 { power_heuristic(pdf, bsdf_pdf) } else { 1.0 };
+        let tr = rt.transmittance(&shadow, sample.dist);
+        rv = rv + sample.irradiance * narrow(cos * w * tr);
+    }
+    rv
+}
+
+/// Sample a bounce off a strand shaded by `hair` emitting `emit`, where `ray`
+/// hit `tri` of the `obj`-th object, the stand-in triangle of a hit on
+/// `Curves` running along the strand, see `Shape`. Lights are sampled once
+/// each for `Scatter::direct` like `direct_diffuse`, and bounces are sampled
+/// uniformly over the sphere since strands scatter light both ways.
+///
+/// `KajiyaKay::eval` is scaled by `1 / PI^2`, the integral of the sine of
+/// the angle to the strand over the sphere, so that strands reflect at most
+/// `KajiyaKay::diffuse` of the light diffusely.
+#[allow(clippy::too_many_arguments)]
+pub fn scatter_hair<T>(
+    rt: &T,
+    ray: &Ray,
+    obj: usize,
+    tri: &Triangle,
+    intersect: &Intersection<Barycentric>,
+    payload: &mut T::Payload,
+    hair: &KajiyaKay,
+    emit: Color,
+) -> Scatter<Ray>
+    where T: PathTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    const PI: Real = std::f64::consts::PI as Real;
+    const SPHERE_PDF: Real = 0.25 / PI;
+    let norm = narrow(1.0 / (PI * PI));
+    let bary = intersect.attr;
+    let p = tri.o.affine_add(bary.u * tri.x + bary.v * tri.y);
+    let tangent = tri.x.normalize();
+    let wo = -ray.v.normalize();
+    // Rays leave the ribbon on the side they head to.
+    let origin = |dir: Vector| {
+        offset_ray_origin(p, if dir.dot(tri.n) >= 0.0 { tri.n } else { -tri.n })
+    };
+    let mut direct = Color::default();
+    for light in rt.lights() {
+        let links = light.links();
+        if !links.illumination.includes(obj) { continue }
+        let sample = match light.illuminate(p, rng::random(), rng::random()) {
+            Some(x) => x,
+            None => continue,
+        };
+        let shadow = Ray { o: origin(sample.wi), v: sample.wi };
+        if rt.occluded_by(shadow, sample.dist, payload, |i| links.shadow.includes(i)) {
+            continue;
+        }
+        let pdf = light.pdf(p, sample.wi);
+        let w = if pdf > 0.0 { power_heuristic(pdf, SPHERE_PDF) } else { 1.0 };
+        let tr = rt.transmittance(&shadow, sample.dist);
+        let f = hair.eval(tangent, sample.wi, wo) * norm;
+        direct = direct + f * sample.irradiance * narrow(w * tr);
+    }
+    let dir = sphere(rng::random(), rng::random());
+    let weight = hair.eval(tangent, dir, wo) * norm * narrow(1.0 / SPHERE_PDF);
+    Scatter {
+        emit,
+        direct,
+        next: Some((Ray { o: origin(dir), v: dir }, weight)),
+        lobe: Lobe::Diffuse,
+        pdf: SPHERE_PDF,
+    }
+}
+
+/// Radiance of the area lights in `lights` that `ray` hits closer than
+/// parametric distance `t`, for `PathTracer::hit_lights`. Lights are seen by
+/// camera rays, i.e., with `from` of `None`, in full, and by bounces weighed
+/// against light sampling if they are linked to the object bounced off.
+/// Unlike surfaces, lights don't block rays.
+pub fn hit_area_lights(lights: &[Light], ray: &Ray, t: Real, from: Option<Bounce>) -> Color {
+    let len = ray.v.mag();
+    if len == 0.0 { return Color::default() }
+    let v = ray.v / len;
+    let mut rv = Color::default();
+    for light in lights {
+        let area = match light {
+            Light::Area(x) => x,
+            _ => continue,
+        };
+        match area.hit(ray.o, v) {
+            Some(dist) if dist < t * len => {},
+            _ => continue,
+        }
+        let pdf = area.pdf(ray.o, v);
+        if pdf <= 0.0 { continue }
+        let w = match from {
+            Some(Bounce { obj: Some(obj), .. }) if !area.links.illumination.includes(obj) => continue,
+            Some(Bounce { pdf: bsdf_pdf, .. }) if bsdf_pdf > 0.0 => power_heuristic(bsdf_pdf, pdf),
+            _ => 1.0,
+        };
+        rv = rv + area.radiance * narrow(w);
+    }
+    rv
+}
+
+/// Sample how a path continues where `ray` collides with `medium` at
+/// parametric distance `t`, for `PathTracer::collide`. Every light is sampled
+/// once for `Scatter::direct` with shadow rays attenuated by
+/// `PathTracer::transmittance`, and the path continues in a direction sampled
+/// from the phase function.
+pub fn scatter_medium<T>(
+    rt: &T,
+    medium: &HeterogeneousMedium,
+    ray: &Ray,
+    t: Real,
+    payload: &mut T::Payload,
+) -> Scatter<Ray>
+    where T: PathTracer<Ray = Ray>,
+{
+    let p = ray.o.affine_add(t * ray.v);
+    let v = ray.v.normalize();
+    let mut direct = Color::default();
+    for light in rt.lights() {
+        let sample = match light.illuminate(p, rng::random(), rng::random()) {
+            Some(x) => x,
+            None => continue,
+        };
+        let shadow = Ray { o: p, v: sample.wi };
+        let links = light.links();
+        if rt.occluded_by(shadow, sample.dist, payload, |i| links.shadow.includes(i)) {
+            continue;
+        }
+        let phase = henyey_greenstein(medium.g, v.dot(sample.wi));
+        let pdf = light.pdf(p, sample.wi);
+        let w = if pdf > 0.0 { power_heuristic(pdf, phase) } else { 1.0 };
+        let tr = rt.transmittance(&shadow, sample.dist);
+        direct = direct + sample.irradiance * narrow(phase * w * tr);
+    }
+    // The phase function is sampled exactly, so the path only carries the
+    // albedo.
+    let dir = medium.sample_phase(v, rng::random(), rng::random());
+    Scatter {
+        emit: Color::default(),
+        direct: medium.albedo * direct,
+        next: Some((Ray { o: p, v: dir }, medium.albedo)),
+        lobe: Lobe::Diffuse,
+        pdf: henyey_greenstein(medium.g, v.dot(dir)),
+    }
+}
+
+/// Radiance of a path split by basic light path expressions, where `C` is
+/// the camera, `D` and `S` are diffuse and specular bounces, and `L` is an
+/// emitter or the environment. Channels sum to the radiance of the path, so
+/// compositors can rebalance the lighting after the render.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LpeRadiance {
+    /// `C L`, light seen directly.
+    pub emission: Color,
+    /// `C D L`, light after a single diffuse bounce.
+    pub direct_diffuse: Color,
+    /// `C D .+ L`, light after a diffuse bounce and more.
+    pub indirect_diffuse: Color,
+    /// `C S .* L`, light after a specular bounce first.
+    pub specular: Color,
+}
+impl LpeRadiance {
+    pub fn total(&self) -> Color {
+        self.emission + self.direct_diffuse + self.indirect_diffuse + self.specular
+    }
+}
+
+/// Channel of light reaching the camera after `depth` bounces, the first of
+/// which off `first`.
+fn lpe_channel(radiance: &mut LpeRadiance, first: Option<Lobe>, depth: u32) -> &mut Color {
+    match (first, depth) {
+        (None, _) => &mut radiance.emission,
+        (Some(Lobe::Specular), _) => &mut radiance.specular,
+        (Some(Lobe::Diffuse), 1) => &mut radiance.direct_diffuse,
+        (Some(Lobe::Diffuse), _) => &mut radiance.indirect_diffuse,
+    }
+}
+
+/// A path traced by `PathTracer::trace_path_aov`, with statistics to tell
+/// why regions of an image are slow or noisy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PathSample {
+    pub radiance: LpeRadiance,
+    /// Number of times the path scattered.
+    pub bounces: u32,
+    /// Sum of `Intersection::t` of the surfaces hit, i.e., the length of the
+    /// path for unit length rays. The segment leaving the scene is not
+    /// counted.
+    pub length: Real,
+    /// Coverage of the pixel by the path: 1 if the camera ray hit a surface,
+    /// or the alpha of `miss` otherwise, e.g., 0 for transparent backgrounds.
+    pub alpha: f32,
+}
+
+/// Float images of the channels of `LpeRadiance` and the statistics of
+/// `PathSample`, see `PathTracer::draw_lpe`. Statistics are stored in all
+/// color channels; average them over passes like the radiance channels.
+pub struct LpeImages {
+    pub emission: Image,
+    pub direct_diffuse: Image,
+    pub indirect_diffuse: Image,
+    pub specular: Image,
+    pub bounces: Image,
+    pub path_length: Image,
+}
+impl LpeImages {
+    pub fn new(w: usize, h: usize) -> LpeImages {
+        LpeImages {
+            emission: Image::new(w, h),
+            direct_diffuse: Image::new(w, h),
+            indirect_diffuse: Image::new(w, h),
+            specular: Image::new(w, h),
+            bounces: Image::new(w, h),
+            path_length: Image::new(w, h),
+        }
+    }
+}
+
+/// Ray tracers that describe surfaces by sampling their BSDF rather than by
+/// tracing more rays from `closest_hit`. Paths are then extended in a loop
+/// with explicit state, so deep bounces cannot overflow the stack.
+pub trait PathTracer : RayTracer {
+    /// Sample how a path continues from a hit on the `obj`-th object of the
+    /// scene.
+    fn scatter(
+        &self,
+        ray: &Self::Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Self::RayAttr>,
+        payload: &mut Self::Payload,
+        mat: &Self::Material,
+    ) -> Scatter<Self::Ray>;
+    /// Radiance emitted towards `ray` from its hit, like `Scatter::emit` of
+    /// `scatter` but without sampling anything, e.g., to draw emitters in
+    /// other styles. Nothing is emitted by default.
+    fn emitted(
+        &self,
+        _ray: &Self::Ray,
+        _obj: usize,
+        _tri: &Triangle,
+        _intersect: &Intersection<Self::RayAttr>,
+        _mat: &Self::Material,
+    ) -> Color {
+        Color::default()
+    }
+    /// Maximum number of bounces of a path. Paths of payloads with a
+    /// `TraceContext` also end at its `max_depth`, so the context of camera
+    /// rays is expected to be created of this depth.
+    fn max_depth(&self) -> u32 { 8 }
+    /// Lights sampled directly by `scatter`, see `Scatter::direct`.
+    fn lights(&self) -> &[Light] { &[] }
+    /// Radiance of `lights()` that `ray` hits closer than parametric distance
+    /// `t`, infinite if it leaves the scene. `from` is the bounce before the
+    /// ray, `None` for camera rays. Lights cannot be hit by default; tracers
+    /// of `Ray`s can see area lights with `hit_area_lights`.
+    fn hit_lights(&self, _ray: &Self::Ray, _t: Real, _from: Option<Bounce>) -> Color {
+        Color::default()
+    }
+    /// Sample where `ray` collides with a participating medium before
+    /// parametric distance `t`, infinite if it leaves the scene, and how the
+    /// path continues from there, e.g., with
+    /// `HeterogeneousMedium::sample_distance` and `scatter_medium`. Rays
+    /// pass through by default.
+    fn collide(
+        &self,
+        _ray: &Self::Ray,
+        _t: Real,
+        _payload: &mut Self::Payload,
+    ) -> Option<(Real, Scatter<Self::Ray>)> {
+        None
+    }
+    /// Fraction of light passing through participating media along shadow
+    /// ray `ray` up to parametric distance `t`, e.g., estimated by
+    /// `HeterogeneousMedium::transmittance`. 1 by default.
+    fn transmittance(&self, _ray: &Self::Ray, _t: Real) -> Real { 1.0 }
+    /// Attenuate `radiance` reaching the camera along camera ray `ray` from
+    /// parametric distance `t`, infinite if the ray left the scene, e.g., by
+    /// fog evaluated in closed form. Nothing changes by default.
+    fn camera_fog(&self, _ray: &Self::Ray, _t: Real, _radiance: &mut LpeRadiance) {}
+
+    /// Trace a path from camera ray `ray`, accumulating the emission of every
+    /// vertex weighted by the throughput of the path so far. Paths that leave
+    /// the scene are terminated with the color returned by `miss`. The alpha
+    /// of the color is `PathSample::alpha`, by which the color is
+    /// premultiplied.
+    fn trace_path(
+        &self,
+        ray: Self::Ray,
+        payload: &mut Self::Payload,
+    ) -> Color {
+        premultiply_path(&self.trace_path_aov(ray, payload))
+    }
+    /// Same as `trace_path` for camera ray `ray` whose closest hit is already
+    /// found, e.g., for `closest_hit` of tracers drawn by `draw_wavefront`.
+    fn trace_path_hit(
+        &self,
+        ray: &Self::Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Self::RayAttr>,
+        payload: &mut Self::Payload,
+        mat: &Self::Material,
+    ) -> Color {
+        let first = (obj, tri, intersect, mat);
+        premultiply_path(&trace_path_from(self, ray.clone(), Some(first), payload))
+    }
+    /// Same as `trace_path` with the radiance split by light path expressions.
+    fn trace_path_lpe(
+        &self,
+        ray: Self::Ray,
+        payload: &mut Self::Payload,
+    ) -> LpeRadiance {
+        self.trace_path_aov(ray, payload).radiance
+    }
+    /// Same as `trace_path_lpe` along with the statistics of the path.
+    fn trace_path_aov(
+        &self,
+        ray: Self::Ray,
+        payload: &mut Self::Payload,
+    ) -> PathSample {
+        trace_path_from(self, ray, None, payload)
+    }
+
+    /// Wavefront drawing of whole paths for `draw_wavefront` of path tracers,
+    /// which otherwise batches primary rays only and traces the rest of each
+    /// path on its own. Every bounce of the paths of a batch runs in stages:
+    /// the rays of all paths are intersected, then shaded, which generates
+    /// the rays of the next bounce, and then terminated paths are retired
+    /// from the queue, so that later bounces only process live paths.
+    fn draw_wavefront_paths<FB>(&self, framebuf: &mut FB)
+        where Self: WavefrontRayTracer,
+              FB: Framebuffer,
+              Self::Ray: Send,
+              Self::Payload: Send,
+              Self::RayAttr: Send,
+              Self::Material: Sync,
+    {
+        use crate::par::*;
+        let w = framebuf.width();
+        let h = framebuf.height();
+        for batch in morton_order(w, h).chunks(WAVEFRONT_BATCH) {
+            let _span = trace::span_with("wavefront_batch", || format!("{} paths", batch.len()));
+            // Generate.
+            let (mut paths, weights) = batch.par_iter()
+                .enumerate()
+                .map(|(i, &(x, y))| {
+                    let (ray, payload, weight) = self.primary_sample(x, y, w, h);
+                    ((i, PathState::new(ray), payload), weight)
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>();
+            let mut samples = vec![None; batch.len()];
+            while !paths.is_empty() {
+                // Intersect.
+                let hits = paths.par_iter_mut()
+                    .map(|(_, path, payload)| self.closest(&path.ray, path.kind, payload))
+                    .collect::<Vec<_>>();
+                // Shade, generating the rays of the next bounce.
+                let live = paths.par_iter_mut()
+                    .zip(hits.into_par_iter())
+                    .map(|((_, path, payload), hit)| {
+                        let hit = hit.as_ref().map(|x| (x.obj, &x.tri, &x.intersect, x.mat));
+                        path.bounce(self, hit, payload)
+                    })
+                    .collect::<Vec<_>>();
+                // Compact.
+                let mut next = Vec::with_capacity(paths.len());
+                for ((i, path, payload), live) in paths.into_iter().zip(live) {
+                    if live {
+                        next.push((i, path, payload));
+                    } else {
+                        samples[i] = Some(path.finish(self));
+                    }
+                }
+                paths = next;
+            }
+            // Store.
+            for ((&(x, y), weight), sample) in batch.iter().zip(weights).zip(samples) {
+                let sample = sample.expect("every path is finished");
+                framebuf.store(x, y, premultiply_path(&sample) * weight);
+            }
+        }
+    }
+
+    /// Trace a path per pixel of `images` from the primary rays of
+    /// `WavefrontRayTracer`, storing each channel of the radiance and each
+    /// statistic of the path into its image.
+    fn draw_lpe(&self, images: &mut LpeImages)
+        where Self: WavefrontRayTracer,
+              Self::Ray: Send,
+              Self::Payload: Send,
+    {
+        use crate::par::*;
+        let w = images.emission.width() as u32;
+        let h = images.emission.height() as u32;
+        let samples = (0..w * h).into_par_iter()
+            .map(|i| {
+                let (ray, mut payload) = self.primary_ray(i % w, i / w, w, h);
+                self.trace_path_aov(ray, &mut payload)
+            })
+            .collect::<Vec<_>>();
+        let gray = |x: f32| Color(x, x, x, 1.0);
+        for (i, x) in samples.into_iter().enumerate() {
+            let (x0, y0) = (i % w as usize, i / w as usize);
+            images.emission.store_px(x0, y0, x.radiance.emission);
+            images.direct_diffuse.store_px(x0, y0, x.radiance.direct_diffuse);
+            images.indirect_diffuse.store_px(x0, y0, x.radiance.indirect_diffuse);
+            images.specular.store_px(x0, y0, x.radiance.specular);
+            images.bounces.store_px(x0, y0, gray(x.bounces as f32));
+            images.path_length.store_px(x0, y0, gray(narrow(x.length)));
+        }
+    }
+}
+
+/// Which surfaces `ClayRayTracer` overrides.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClayMode {
+    /// Every surface, so light only comes from the environment, i.e., `miss`.
+    #[default]
+    All,
+    /// Surfaces emitting light keep their materials and light the scene as
+    /// usual.
+    KeepEmissive,
+}
+
+/// Color of `sample` like `trace_path`.
+fn premultiply_path(sample: &PathSample) -> Color {
+    // Only the background seen by the camera is partially covered.
+    let c = sample.radiance.total() * sample.alpha;
+    Color(c.0, c.1, c.2, sample.alpha)
+}
+
+/// Hit of a ray on the `obj`-th object as passed to `PathTracer::scatter`.
+type HitRef<'a, T> = (
+    usize,
+    &'a Triangle,
+    &'a Intersection<<T as RayTracer>::RayAttr>,
+    &'a <T as RayTracer>::Material,
+);
+
+/// Trace a path from camera ray `ray` like `PathTracer::trace_path_aov`,
+/// starting from its closest hit `known` if it's already found.
+fn trace_path_from<T>(
+    rt: &T,
+    ray: T::Ray,
+    mut known: Option<HitRef<'_, T>>,
+    payload: &mut T::Payload,
+) -> PathSample
+    where T: PathTracer + ?Sized,
+{
+    let mut path = PathState::new(ray);
+    loop {
+        let record;
+        let hit = match known.take() {
+            Some(x) => Some(x),
+            None => {
+                record = rt.closest(&path.ray, path.kind, payload);
+                record.as_ref().map(|x| (x.obj, &x.tri, &x.intersect, x.mat))
+            },
+        };
+        if !path.bounce(rt, hit, payload) { break }
+    }
+    path.finish(rt)
+}
+
+/// A path being traced one bounce at a time, by `trace_path_from` or by
+/// `PathTracer::draw_wavefront_paths` for many paths at once.
+struct PathState<Ray> {
+    camera_ray: Ray,
+    camera_t: Real,
+    /// The ray to continue the path with.
+    ray: Ray,
+    kind: RayKind,
+    sample: PathSample,
+    throughput: Color,
+    /// Lobe of the first bounce.
+    first: Option<Lobe>,
+    from: Option<Bounce>,
+    depth: u32,
+}
+impl<Ray: Clone> PathState<Ray> {
+    fn new(ray: Ray) -> PathState<Ray> {
+        PathState {
+            camera_ray: ray.clone(),
+            camera_t: Real::INFINITY,
+            ray,
+            kind: RayKind::Camera,
+            sample: PathSample::default(),
+            throughput: Color(1.0, 1.0, 1.0, 1.0),
+            first: None,
+            from: None,
+            depth: 0,
+        }
+    }
+    /// Shade the closest hit `hit` of `ray`, `None` if it left the scene, and
+    /// continue the path. Returns whether the path goes on with `ray`.
+    fn bounce<T>(&mut self, rt: &T, hit: Option<HitRef<'_, T>>, payload: &mut T::Payload) -> bool
+        where T: PathTracer<Ray = Ray> + ?Sized,
+    {
+        let depth = self.depth;
+        let radiance = &mut self.sample.radiance;
+        let channel = lpe_channel(radiance, self.first, depth);
+        let t = hit.map_or(Real::INFINITY, |x| x.2.t);
+        // Media scatter the path before it reaches the surface.
+        let collision = rt.collide(&self.ray, t, payload);
+        let t = collision.as_ref().map_or(t, |x| x.0);
+        *channel = *channel + self.throughput * rt.hit_lights(&self.ray, t, self.from);
+        let (obj, scatter) = match (collision, hit) {
+            (Some((_, scatter)), _) => (None, scatter),
+            (None, Some((obj, tri, intersect, mat))) => {
+                (Some(obj), rt.scatter(&self.ray, obj, tri, intersect, payload, mat))
+            },
+            (None, None) => {
+                let bg = rt.miss(&self.ray, payload);
+                if depth == 0 { self.sample.alpha = bg.3 }
+                *channel = *channel + self.throughput * bg;
+                return false;
+            },
+        };
+        self.sample.length += t;
+        if depth == 0 {
+            self.camera_t = t;
+            self.sample.alpha = 1.0;
+        }
+        *channel = *channel + self.throughput * scatter.emit;
+        // Direct light scatters once more before reaching the camera.
+        let channel = lpe_channel(radiance, self.first.or(Some(scatter.lobe)), depth + 1);
+        *channel = *channel + self.throughput * scatter.direct;
+        let (next, weight) = match scatter.next {
+            Some(x) => x,
+            None => return false,
+        };
+        if let Some(ctx) = payload.context_mut() {
+            match ctx.child(weight) {
+                Some(x) => *ctx = x,
+                None => return false,
+            }
+        }
+        self.sample.bounces += 1;
+        self.throughput = self.throughput * weight;
+        self.ray = next;
+        self.kind = RayKind::Reflection;
+        self.first = self.first.or(Some(scatter.lobe));
+        self.from = Some(Bounce { obj, pdf: scatter.pdf });
+        self.depth += 1;
+        self.depth <= rt.max_depth()
+    }
+    /// The traced path, once `bounce` returned false.
+    fn finish<T>(mut self, rt: &T) -> PathSample
+        where T: PathTracer<Ray = Ray> + ?Sized,
+    {
+        rt.camera_fog(&self.camera_ray, self.camera_t, &mut self.sample.radiance);
+        self.sample
+    }
+}
+
+/// Path tracer rendering the scene of `inner` with its materials replaced by
+/// a single diffuse material, a "clay" render to check the lighting and the
+/// shapes apart from the materials. Cameras and the environment are those of
+/// `inner`.
+pub struct ClayRayTracer<T> {
+    pub inner: T,
+    pub albedo: Color,
+    pub mode: ClayMode,
+}
+impl<T> ClayRayTracer<T> {
+    /// Override with a mid gray.
+    pub fn new(inner: T, mode: ClayMode) -> ClayRayTracer<T> {
+        ClayRayTracer { inner, albedo: Color(0.5, 0.5, 0.5, 1.0), mode }
+    }
+}
+impl<T> RayTracer for ClayRayTracer<T>
+    where T: PathTracer + WavefrontRayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    type Material = T::Material;
+    type Payload = T::Payload;
+    type Ray = Ray;
+    type RayAttr = Barycentric;
+
+    fn ray_gen(&self, x: u32, y: u32, w: u32, h: u32) -> Color {
+        let (ray, mut payload, weight) = self.inner.primary_sample(x, y, w, h);
+        self.trace_path(ray, &mut payload) * weight
+    }
+    fn intersect(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        mat: &T::Material,
+    ) -> Option<Intersection<Barycentric>> {
+        self.inner.intersect(ray, tri, mat)
+    }
+    fn intersect_within(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        mat: &T::Material,
+        tmax: Real,
+    ) -> Option<Intersection<Barycentric>> {
+        self.inner.intersect_within(ray, tri, mat, tmax)
+    }
+    fn intersect_shape(
+        &self,
+        ray: &Ray,
+        obj: &Object<T::Material>,
+        tmax: Real,
+    ) -> Option<(Triangle, Intersection<Barycentric>)> {
+        self.inner.intersect_shape(ray, obj, tmax)
+    }
+    fn any_hit(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut T::Payload,
+        mat: &T::Material,
+    ) -> bool {
+        self.inner.any_hit(ray, tri, intersect, payload, mat)
+    }
+    fn miss(&self, ray: &Ray, payload: &mut T::Payload) -> Color {
+        self.inner.miss(ray, payload)
+    }
+    /// Continue the path from the hit, e.g., for `draw_wavefront`.
+    fn closest_hit(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut T::Payload,
+        mat: &T::Material,
+    ) -> Color {
+        self.trace_path_hit(ray, obj, tri, intersect, payload, mat)
+    }
+    fn accel(&self, ray: &Ray) -> Option<(&dyn Accel, Ray)> {
+        self.inner.accel(ray)
+    }
+    fn scene(&self) -> &Scene<T::Material> {
+        self.inner.scene()
+    }
+}
+impl<T> PathTracer for ClayRayTracer<T>
+    where T: PathTracer + WavefrontRayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    fn scatter(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut T::Payload,
+        mat: &T::Material,
+    ) -> Scatter<Ray> {
+        let emit = self.emitted(ray, obj, tri, intersect, mat);
+        if emit.0 > 0.0 || emit.1 > 0.0 || emit.2 > 0.0 {
+            return self.inner.scatter(ray, obj, tri, intersect, payload, mat);
+        }
+        let shading = self.scene().objs[obj].shading_normal(intersect.prim, intersect.attr);
+        let black = Color(0.0, 0.0, 0.0, 1.0);
+        let mut scatter = scatter_diffuse(ray, tri, intersect, shading, self.albedo, black);
+        scatter.direct = direct_diffuse(self, ray, obj, tri, intersect, payload, self.albedo);
+        scatter
+    }
+    fn emitted(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        mat: &T::Material,
+    ) -> Color {
+        match self.mode {
+            ClayMode::KeepEmissive => self.inner.emitted(ray, obj, tri, intersect, mat),
+            ClayMode::All => Color::default(),
+        }
+    }
+    fn max_depth(&self) -> u32 {
+        self.inner.max_depth()
+    }
+    fn lights(&self) -> &[Light] {
+        self.inner.lights()
+    }
+    fn hit_lights(&self, ray: &Ray, t: Real, from: Option<Bounce>) -> Color {
+        self.inner.hit_lights(ray, t, from)
+    }
+    fn collide(
+        &self,
+        ray: &Ray,
+        t: Real,
+        payload: &mut T::Payload,
+    ) -> Option<(Real, Scatter<Ray>)> {
+        self.inner.collide(ray, t, payload)
+    }
+    fn transmittance(&self, ray: &Ray, t: Real) -> Real {
+        self.inner.transmittance(ray, t)
+    }
+    fn camera_fog(&self, ray: &Ray, t: Real, radiance: &mut LpeRadiance) {
+        self.inner.camera_fog(ray, t, radiance)
+    }
+}
+impl<T> WavefrontRayTracer for ClayRayTracer<T>
+    where T: PathTracer + WavefrontRayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    fn primary_ray(&self, x: u32, y: u32, w: u32, h: u32) -> (Ray, T::Payload) {
+        self.inner.primary_ray(x, y, w, h)
+    }
+    fn primary_sample(&self, x: u32, y: u32, w: u32, h: u32) -> (Ray, T::Payload, Color) {
+        self.inner.primary_sample(x, y, w, h)
+    }
+    /// Whole paths are traced in wavefront order, see
+    /// `PathTracer::draw_wavefront_paths`.
+    fn draw_wavefront<FB>(&self, framebuf: &mut FB)
+        where FB: Framebuffer,
+              Self::Ray: Send,
+              Self::Payload: Send,
+              Self::RayAttr: Send,
+              Self::Material: Sync,
+    {
+        self.draw_wavefront_paths(framebuf)
+    }
+}
@@ -0,0 +1,222 @@
+//! Journals of finished tiles, streamed to disk while rendering so that long
+//! renders aren't lost to crashes, and can be inspected while they run.
+//! `TileJournal` is a framebuffer appending every tile to a file once it's
+//! finished, and `load_journal` reads the tiles written so far back into an
+//! image, e.g., to save it with `img::save_image`.
+//!
+//! A journal starts with the magic `LGRJ`, a format version and the size of
+//! the frame, followed by a record per tile: the number of pixels and then
+//! the coordinates and the color of each pixel. All numbers are 32-bit little
+//! endian, colors are floats. Later records overwrite earlier ones, and a
+//! record cut short by a crash is ignored. Renders of many passes store each
+//! pixel again and again, so the journal is compacted into a single record
+//! of the pixels so far once it grows past a few frames.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use crate::geom::Color;
+use crate::rt::Framebuffer;
+use crate::img::Image;
+
+const MAGIC: &[u8; 4] = b"LGRJ";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const PX_SIZE: usize = 24;
+/// Journals are compacted when they grow past this many full frames.
+const COMPACT_FRAMES: usize = 4;
+
+/// Error reading tile journals.
+#[derive(Debug)]
+pub enum JournalError {
+    Io(std::io::Error),
+    /// The file is not a tile journal or is of an unknown version.
+    Invalid(String),
+}
+impl std::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "failed to read journal: {}", e),
+            JournalError::Invalid(msg) => write!(f, "invalid journal: {}", msg),
+        }
+    }
+}
+impl std::error::Error for JournalError {}
+impl From<std::io::Error> for JournalError {
+    fn from(e: std::io::Error) -> JournalError {
+        JournalError::Io(e)
+    }
+}
+
+/// Framebuffer streaming finished tiles to a journal file, see the module
+/// documentation. Pixels are also kept in an image in memory.
+pub struct TileJournal {
+    img: Image,
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Bytes written to the file so far.
+    size: usize,
+    /// Whether each pixel has been written, in row-major order.
+    written: Vec<bool>,
+    /// Pixels stored since the last finished tile.
+    pending: Vec<(u32, u32, Color)>,
+    /// First error writing the file. Later tiles aren't written.
+    error: Option<std::io::Error>,
+}
+impl TileJournal {
+    /// Create a journal of a `w` by `h` frame at `path`, replacing any file
+    /// there.
+    pub fn create<P: AsRef<Path>>(path: P, w: u32, h: u32) -> std::io::Result<TileJournal> {
+        let path = path.as_ref().to_owned();
+        let file = create_file(&path, w, h, &[])?;
+        let journal = TileJournal {
+            img: Image::new(w as usize, h as usize),
+            path,
+            file,
+            size: HEADER_SIZE,
+            written: vec![false; (w * h) as usize],
+            pending: Vec::new(),
+            error: None,
+        };
+        Ok(journal)
+    }
+    /// Pixels stored so far.
+    pub fn image(&self) -> &Image {
+        &self.img
+    }
+    /// Write pixels stored since the last finished tile as a tile of their
+    /// own, e.g., after `RayTracer::draw_adaptive` which doesn't render in
+    /// tiles, and return the image. Fails if any tile failed to be written.
+    pub fn finish(mut self) -> std::io::Result<Image> {
+        self.write_pending();
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.img),
+        }
+    }
+    fn write_pending(&mut self) {
+        if self.pending.is_empty() || self.error.is_some() {
+            self.pending.clear();
+            return;
+        }
+        let w = self.img.width();
+        for &(x, y, _) in self.pending.iter() {
+            self.written[y as usize * w + x as usize] = true;
+        }
+        let buf = encode_record(&self.pending);
+        self.pending.clear();
+        // Flushed with every tile, so that the file is complete up to the
+        // last finished tile if the process dies.
+        let res = self.file.write_all(&buf).and_then(|_| self.file.flush());
+        self.size += buf.len();
+        let res = res.and_then(|_| {
+            if self.size > COMPACT_FRAMES * self.written.len() * PX_SIZE {
+                self.compact()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+    }
+    /// Replace the journal with a single record of all pixels written so far.
+    /// The new journal is written next to the old one and then renamed over
+    /// it, so that either of them is complete at any time.
+    fn compact(&mut self) -> std::io::Result<()> {
+        let w = self.img.width();
+        let pixels = self.written.iter()
+            .enumerate()
+            .filter(|(_, &x)| x)
+            .map(|(i, _)| {
+                let (x, y) = (i % w, i / w);
+                (x as u32, y as u32, self.img.load_px(x, y))
+            })
+            .collect::<Vec<_>>();
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let (w, h) = (self.width(), self.height());
+        self.file = create_file(&tmp, w, h, &pixels)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.size = HEADER_SIZE + 4 + pixels.len() * PX_SIZE;
+        Ok(())
+    }
+}
+impl Framebuffer for TileJournal {
+    fn width(&self) -> u32 { self.img.width() as u32 }
+    fn height(&self) -> u32 { self.img.height() as u32 }
+    fn store(&mut self, x: u32, y: u32, color: Color) {
+        self.img.store_px(x as usize, y as usize, color);
+        self.pending.push((x, y, color));
+    }
+    fn end_tile(&mut self, _pixels: &[(u32, u32)]) {
+        self.write_pending();
+    }
+}
+
+/// Create a journal file of a `w` by `h` frame with a record of `pixels` if
+/// any, to append more records to.
+fn create_file(path: &Path, w: u32, h: u32, pixels: &[(u32, u32, Color)]) -> std::io::Result<BufWriter<File>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    for x in [VERSION, w, h].iter() {
+        file.write_all(&x.to_le_bytes())?;
+    }
+    if !pixels.is_empty() {
+        file.write_all(&encode_record(pixels))?;
+    }
+    file.flush()?;
+    file.get_ref().sync_data()?;
+    Ok(file)
+}
+/// Encode a record of `pixels`.
+fn encode_record(pixels: &[(u32, u32, Color)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + pixels.len() * PX_SIZE);
+    buf.extend(&(pixels.len() as u32).to_le_bytes());
+    for &(x, y, c) in pixels {
+        buf.extend(&x.to_le_bytes());
+        buf.extend(&y.to_le_bytes());
+        for v in [c.0, c.1, c.2, c.3].iter() {
+            buf.extend(&v.to_le_bytes());
+        }
+    }
+    buf
+}
+
+/// Load the tiles written to the journal at `path`, e.g., by a render still
+/// in progress or one that crashed. Pixels of tiles not written yet are
+/// transparent black.
+pub fn load_journal<P: AsRef<Path>>(path: P) -> Result<Image, JournalError> {
+    let data = std::fs::read(path)?;
+    let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+        return Err(JournalError::Invalid("not a tile journal".to_owned()));
+    }
+    if u32_at(4) != VERSION {
+        return Err(JournalError::Invalid(format!("unknown version {}", u32_at(4))));
+    }
+    let (w, h) = (u32_at(8), u32_at(12));
+    let mut img = Image::new(w as usize, h as usize);
+    let mut i = HEADER_SIZE;
+    while i + 4 <= data.len() {
+        let n = u32_at(i) as usize;
+        let end = i + 4 + n * PX_SIZE;
+        if end > data.len() { break }
+        for px in data[i + 4..end].chunks_exact(PX_SIZE) {
+            let at = |j: usize| [px[j], px[j + 1], px[j + 2], px[j + 3]];
+            let (x, y) = (u32::from_le_bytes(at(0)), u32::from_le_bytes(at(4)));
+            if x >= w || y >= h {
+                return Err(JournalError::Invalid(format!("pixel ({}, {}) out of the frame", x, y)));
+            }
+            let c = Color(
+                f32::from_le_bytes(at(8)),
+                f32::from_le_bytes(at(12)),
+                f32::from_le_bytes(at(16)),
+                f32::from_le_bytes(at(20)),
+            );
+            img.store_px(x as usize, y as usize, c);
+        }
+        i = end;
+    }
+    Ok(img)
+}
@@ -0,0 +1,193 @@
+use crate::geom::{Real, Point, Ray, Triangle};
+use crate::scene::Scene;
+use crate::bvh::{Aabb, TriRef};
+use crate::accel::AccelStats;
+use crate::trace;
+
+/// Maximum number of triangles in a leaf.
+const MAX_LEAF_SIZE: usize = 8;
+/// Maximum depth of the tree, which bounds the traversal stack.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    /// Space split by plane `split` perpendicular to `axis`. The child below
+    /// the plane immediately follows this node.
+    Inner { axis: usize, split: Real, above: usize },
+    /// Triangles `idxs[start..start + ntri]`.
+    Leaf { start: usize, ntri: usize },
+}
+
+#[inline]
+fn axis_of(p: Point, axis: usize) -> Real {
+    match axis { 0 => p.0, 1 => p.1, _ => p.2 }
+}
+
+/// kd-tree over all the triangles of a scene. Space is split at the middle of
+/// the longest axis of each node, and triangles straddling a split plane are
+/// referenced by both children.
+#[derive(Debug, Clone)]
+pub struct KdTree {
+    bounds: Aabb,
+    nodes: Vec<Node>,
+    /// Indices into `prims` referenced by the leaves.
+    idxs: Vec<usize>,
+    prims: Vec<(TriRef, Triangle)>,
+}
+impl KdTree {
+    pub fn build<M>(scene: &Scene<M>) -> KdTree {
+        KdTree::with_tris(scene, &TriRef::all(scene))
+    }
+    /// Same as `build` but only over the triangles `tris`.
+    pub fn with_tris<M>(scene: &Scene<M>, tris: &[TriRef]) -> KdTree {
+        let _span = trace::span_with("kdtree_build", || format!("{} triangles", tris.len()));
+        let prims = tris.iter()
+            .map(|&r| (r, r.resolve(scene)))
+            .collect::<Vec<_>>();
+        let bounds = prims.iter()
+            .fold(Aabb::empty(), |seed, (_, tri)| seed.union(Aabb::of_tri(tri)));
+        let mut tree = KdTree { bounds, nodes: Vec::new(), idxs: Vec::new(), prims };
+        if !tree.prims.is_empty() {
+            let all = (0..tree.prims.len()).collect::<Vec<_>>();
+            tree.build_node(all, bounds, 0);
+        }
+        tree
+    }
+    fn build_node(&mut self, tris: Vec<usize>, bounds: Aabb, depth: usize) {
+        let inode = self.nodes.len();
+        let leaf = |tree: &mut KdTree, tris: Vec<usize>| {
+            let start = tree.idxs.len();
+            tree.nodes.push(Node::Leaf { start, ntri: tris.len() });
+            tree.idxs.extend(tris);
+        };
+        if tris.len() <= MAX_LEAF_SIZE || depth >= MAX_DEPTH {
+            return leaf(self, tris);
+        }
+        let d = bounds.max.rel_from(bounds.min);
+        let axis = if d.0 >= d.1 && d.0 >= d.2 { 0 } else if d.1 >= d.2 { 1 } else { 2 };
+        let split = 0.5 * (axis_of(bounds.min, axis) + axis_of(bounds.max, axis));
+        let mut below = Vec::new();
+        let mut above = Vec::new();
+        for &i in tris.iter() {
+            let b = Aabb::of_tri(&self.prims[i].1);
+            if axis_of(b.min, axis) <= split { below.push(i) }
+            if axis_of(b.max, axis) > split { above.push(i) }
+        }
+        if below.len() == tris.len() && above.len() == tris.len() {
+            // Splitting doesn't separate anything.
+            return leaf(self, tris);
+        }
+        let (mut bbelow, mut babove) = (bounds, bounds);
+        match axis {
+            0 => { bbelow.max.0 = split; babove.min.0 = split; },
+            1 => { bbelow.max.1 = split; babove.min.1 = split; },
+            _ => { bbelow.max.2 = split; babove.min.2 = split; },
+        }
+        self.nodes.push(Node::Inner { axis, split, above: 0 });
+        self.build_node(below, bbelow, depth + 1);
+        let iabove = self.nodes.len();
+        self.nodes[inode] = Node::Inner { axis, split, above: iabove };
+        self.build_node(above, babove, depth + 1);
+    }
+
+    pub fn stats(&self) -> AccelStats {
+        let root_area = self.bounds.surface_area();
+        let mut depth = 0;
+        let mut cost = 0.0;
+        let mut stack = vec![(0, self.bounds, 1)];
+        while let Some((inode, bounds, d)) = stack.pop() {
+            if inode >= self.nodes.len() { break }
+            depth = usize::max(depth, d);
+            let p = if root_area > 0.0 { bounds.surface_area() / root_area } else { 1.0 };
+            match self.nodes[inode] {
+                Node::Leaf { ntri, .. } => cost += p * ntri as Real,
+                Node::Inner { axis, split, above } => {
+                    cost += p;
+                    let (mut bbelow, mut babove) = (bounds, bounds);
+                    match axis {
+                        0 => { bbelow.max.0 = split; babove.min.0 = split; },
+                        1 => { bbelow.max.1 = split; babove.min.1 = split; },
+                        _ => { bbelow.max.2 = split; babove.min.2 = split; },
+                    }
+                    stack.push((inode + 1, bbelow, d + 1));
+                    stack.push((above, babove, d + 1));
+                },
+            }
+        }
+        AccelStats {
+            nnode: self.nodes.len(),
+            depth,
+            bytes: std::mem::size_of_val(self.nodes.as_slice()) +
+                std::mem::size_of_val(self.idxs.as_slice()) +
+                std::mem::size_of_val(self.prims.as_slice()),
+            cost,
+        }
+    }
+
+    /// Invoke `f` with the triangles in the leaves `ray` passes through, until
+    /// `f` returns false. Leaves are visited front to back, and a triangle
+    /// might be visited more than once.
+    pub fn traverse<F>(&self, ray: &Ray, mut f: F)
+        where F: FnMut(TriRef, &Triangle) -> bool
+    {
+        self.traverse_within(ray, Real::INFINITY, |r, tri, _| f(r, tri))
+    }
+    /// Same as `traverse` but only into leaves `ray` enters within parametric
+    /// distance `bound`, which `f` can lower, e.g., to the closest hit so far.
+    pub fn traverse_within<F>(&self, ray: &Ray, bound: Real, f: F)
+        where F: FnMut(TriRef, &Triangle, &mut Real) -> bool
+    {
+        self.traverse_counted(ray, bound, f, &mut 0)
+    }
+    /// Same as `traverse_within` but the nodes visited are counted into
+    /// `nnode`.
+    pub fn traverse_counted<F>(&self, ray: &Ray, bound: Real, mut f: F, nnode: &mut usize)
+        where F: FnMut(TriRef, &Triangle, &mut Real) -> bool
+    {
+        if self.nodes.is_empty() { return }
+        let mut bound = bound;
+        let (tmin, tmax) = match self.bounds.clip(ray) {
+            Some(x) => x,
+            None => return,
+        };
+        let mut stack = [(0, 0.0, 0.0); MAX_DEPTH + 1];
+        stack[0] = (0, tmin, tmax);
+        let mut nstack = 1;
+        while nstack > 0 {
+            nstack -= 1;
+            let (inode, tmin, tmax) = stack[nstack];
+            if tmin > bound { continue }
+            *nnode += 1;
+            match self.nodes[inode] {
+                Node::Leaf { start, ntri } => {
+                    for &i in self.idxs[start..start + ntri].iter() {
+                        let (r, tri) = &self.prims[i];
+                        if !f(*r, tri, &mut bound) { return }
+                    }
+                },
+                Node::Inner { axis, split, above } => {
+                    let o = axis_of(ray.o, axis);
+                    let v = match axis { 0 => ray.v.0, 1 => ray.v.1, _ => ray.v.2 };
+                    let tsplit = (split - o) / v;
+                    let below_first = o < split || (o == split && v <= 0.0);
+                    let (near, far) = if below_first {
+                        (inode + 1, above)
+                    } else {
+                        (above, inode + 1)
+                    };
+                    if tsplit > tmax || tsplit <= 0.0 {
+                        stack[nstack] = (near, tmin, tmax);
+                        nstack += 1;
+                    } else if tsplit < tmin {
+                        stack[nstack] = (far, tmin, tmax);
+                        nstack += 1;
+                    } else {
+                        stack[nstack] = (far, tsplit, tmax);
+                        stack[nstack + 1] = (near, tmin, tsplit);
+                        nstack += 2;
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Without the default feature `std`, only `geom` is built, under `no_std`,
+//! for projects that need the math and ray casting but not the renderer.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod geom;
+#[cfg(feature = "std")]
+pub mod rt;
+#[cfg(feature = "std")]
+pub mod scene;
+#[cfg(feature = "std")]
+pub mod model;
+#[cfg(feature = "std")]
+pub mod img;
+#[cfg(feature = "std")]
+pub mod texfile;
+#[cfg(feature = "std")]
+pub mod sampler;
+#[cfg(feature = "std")]
+pub mod camera;
+#[cfg(feature = "std")]
+pub mod post;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod integrator;
+#[cfg(feature = "std")]
+pub mod toon;
+#[cfg(feature = "std")]
+pub mod arena;
+#[cfg(feature = "std")]
+pub mod tiled;
+#[cfg(feature = "std")]
+pub mod sh;
+#[cfg(feature = "std")]
+pub mod light;
+#[cfg(feature = "std")]
+pub mod bvh;
+#[cfg(feature = "std")]
+pub mod kdtree;
+#[cfg(feature = "std")]
+pub mod qbvh;
+#[cfg(feature = "std")]
+pub mod accel;
+#[cfg(feature = "std")]
+pub mod units;
+#[cfg(feature = "std")]
+pub mod optics;
+#[cfg(feature = "std")]
+pub mod water;
+#[cfg(feature = "std")]
+pub mod curve;
+#[cfg(feature = "std")]
+pub mod points;
+#[cfg(feature = "std")]
+pub mod obj;
+#[cfg(feature = "std")]
+pub mod simplify;
+#[cfg(feature = "std")]
+pub mod tess;
+#[cfg(feature = "std")]
+pub mod spatial;
+#[cfg(feature = "std")]
+pub mod voxel;
+#[cfg(feature = "std")]
+pub mod medium;
+#[cfg(feature = "std")]
+pub mod sky;
+#[cfg(feature = "std")]
+pub mod noise;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod par;
+#[cfg(feature = "std")]
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod visibility;
+#[cfg(feature = "std")]
+pub mod bake;
+#[cfg(feature = "std")]
+pub mod aov;
+#[cfg(feature = "std")]
+pub mod journal;
+#[cfg(feature = "std")]
+pub mod desc;
+#[cfg(feature = "std")]
+pub mod preview;
+#[cfg(feature = "std")]
+pub mod furnace;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "numa")]
+pub mod numa;
+#[cfg(feature = "capi")]
+pub mod capi;
@@ -0,0 +1,767 @@
+use std::sync::Arc;
+use crate::geom::{Real, Point, Vector, Color, Transform, sphere, hemisphere, disk, narrow};
+use crate::img::Image;
+use crate::rt::HitKind;
+use crate::sampler::{Sampler2D, TexelDistribution, WrapMode, FilterMode};
+use crate::units::{intensity_from_candela, radiance_from_nits, radiance_from_lumens};
+
+/// A rectangle through which environment light enters the scene, e.g., a
+/// window of an interior.
+#[derive(Debug, Clone)]
+pub struct Portal {
+    /// A corner of the rectangle.
+    pub o: Point,
+    /// First edge from `o`.
+    pub x: Vector,
+    /// Second edge from `o`, perpendicular to `x`.
+    pub y: Vector,
+}
+impl Portal {
+    pub fn area(&self) -> Real {
+        self.x.cross(self.y).mag()
+    }
+    /// Unit normal vector.
+    pub fn normal(&self) -> Vector {
+        self.x.cross(self.y).normalize()
+    }
+    /// Distance from `p` to the portal in unit direction `v`, if the portal is
+    /// in that direction.
+    pub fn hit(&self, p: Point, v: Vector) -> Option<Real> {
+        let n = self.normal();
+        let cos = n.dot(v);
+        if cos == 0.0 { return None }
+        let t = self.o.rel_from(p).dot(n) / cos;
+        if t <= 0.0 { return None }
+        let q = p.affine_add(v * t).rel_from(self.o);
+        let u = q.dot(self.x) / self.x.dot(self.x);
+        let v = q.dot(self.y) / self.y.dot(self.y);
+        if (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+}
+
+/// Direction sampler of light coming from the environment. Without portals
+/// directions are drawn uniformly over the sphere. With portals, only the
+/// directions through the portals are drawn, so that points lit through small
+/// openings don't waste samples on walls.
+#[derive(Debug, Default, Clone)]
+pub struct EnvLight {
+    pub portals: Vec<Portal>,
+}
+impl EnvLight {
+    /// Sample a unit direction from `p` towards the environment. `a` and `b`
+    /// in [0..1) locate the sample and `c` in [0..1) selects a portal. The
+    /// direction is returned with its PDF in solid angle, or `None` if the
+    /// sample is degenerate.
+    pub fn sample(&self, p: Point, a: Real, b: Real, c: Real) -> Option<(Vector, Real)> {
+        const PI: Real = std::f64::consts::PI as Real;
+        if self.portals.is_empty() {
+            return Some((sphere(a, b), 0.25 / PI));
+        }
+        let i = ((c * self.portals.len() as Real) as usize).min(self.portals.len() - 1);
+        let portal = &self.portals[i];
+        let q = portal.o.affine_add(a * portal.x + b * portal.y);
+        let d = q.rel_from(p);
+        let dist = d.mag();
+        if dist == 0.0 { return None }
+        let v = d / dist;
+        let pdf = self.pdf(p, v);
+        if pdf > 0.0 { Some((v, pdf)) } else { None }
+    }
+    /// PDF in solid angle of sampling unit direction `v` from `p`.
+    pub fn pdf(&self, p: Point, v: Vector) -> Real {
+        const PI: Real = std::f64::consts::PI as Real;
+        if self.portals.is_empty() {
+            return 0.25 / PI;
+        }
+        let pdf = self.portals.iter()
+            .filter_map(|portal| {
+                let t = portal.hit(p, v)?;
+                let cos = portal.normal().dot(v).abs();
+                if cos == 0.0 { return None }
+                // Convert the uniform area density to solid angle.
+                Some(t * t / (portal.area() * cos))
+            })
+            .sum::<Real>();
+        pdf / self.portals.len() as Real
+    }
+}
+
+/// Unit vectors perpendicular to unit vector `w` and to each other.
+fn basis(w: Vector) -> (Vector, Vector) {
+    let up = if w.1.abs() < 0.9 { Vector(0.0, 1.0, 0.0) } else { Vector(0.0, 0.0, 1.0) };
+    let v = (up - w * w.dot(up)).normalize();
+    (v.cross(w), v)
+}
+
+fn smoothstep(a: Real, b: Real, x: Real) -> Real {
+    if b <= a { return if x < a { 0.0 } else { 1.0 } }
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// How light of a `PunctualLight` fades with distance. Physically, light
+/// falls off with the inverse square of the distance, i.e., an `exponent` of
+/// 2; smaller exponents carry light further and 0 doesn't fade at all.
+#[derive(Debug, Clone, Copy)]
+pub struct Falloff {
+    pub exponent: Real,
+    /// Distances over which light fades in from nothing to full strength, so
+    /// that surfaces too close to the light are left out.
+    pub near: Option<(Real, Real)>,
+    /// Distances over which light fades out from full strength to nothing,
+    /// bounding the reach of the light.
+    pub far: Option<(Real, Real)>,
+}
+impl Default for Falloff {
+    fn default() -> Falloff {
+        Falloff { exponent: 2.0, near: None, far: None }
+    }
+}
+impl Falloff {
+    /// Attenuation of light `dist` away from the light.
+    pub fn attenuation(&self, dist: Real) -> Real {
+        let mut rv = dist.powf(-self.exponent);
+        if let Some((a, b)) = self.near {
+            rv *= smoothstep(a, b, dist);
+        }
+        if let Some((a, b)) = self.far {
+            rv *= 1.0 - smoothstep(a, b, dist);
+        }
+        rv
+    }
+}
+
+/// Flaps cropping the beam of a spot light on each side, like those of stage
+/// lights. Each is the angle in radians from the axis of the spot to the
+/// flap, where `left` and `right` are about `Spot::up` and `top` and
+/// `bottom` are about the other axis. Light fades out over `softness`
+/// radians inside the flaps.
+#[derive(Debug, Clone, Copy)]
+pub struct BarnDoors {
+    pub left: Real,
+    pub right: Real,
+    pub top: Real,
+    pub bottom: Real,
+    pub softness: Real,
+}
+impl Default for BarnDoors {
+    /// Fully open.
+    fn default() -> BarnDoors {
+        const FRAC_PI_2: Real = std::f64::consts::FRAC_PI_2 as Real;
+        BarnDoors {
+            left: FRAC_PI_2,
+            right: FRAC_PI_2,
+            top: FRAC_PI_2,
+            bottom: FRAC_PI_2,
+            softness: 0.0,
+        }
+    }
+}
+impl BarnDoors {
+    /// Fraction of light let through towards `v` in the frame of the spot,
+    /// where +x is right, +y is up and +z is the axis.
+    fn transmittance(&self, v: Vector) -> Real {
+        let x = v.0.atan2(v.2);
+        let y = v.1.atan2(v.2);
+        let edge = |margin: Real| smoothstep(0.0, self.softness, margin);
+        edge(self.left + x) * edge(self.right - x) * edge(self.top - y) * edge(self.bottom + y)
+    }
+}
+
+/// Cone a spot light shines into.
+#[derive(Debug, Clone, Copy)]
+pub struct Spot {
+    /// Unit direction of the axis of the cone.
+    pub dir: Vector,
+    /// Unit direction perpendicular to `dir` that is up to `barn_doors`.
+    pub up: Vector,
+    /// Angle in radians between the axis and the edge of the cone.
+    pub angle: Real,
+    /// Angle in radians inside the edge over which light fades out.
+    pub penumbra: Real,
+    pub barn_doors: Option<BarnDoors>,
+}
+impl Spot {
+    /// Fraction of light the spot shines towards unit direction `v`.
+    fn transmittance(&self, v: Vector) -> Real {
+        let cos = v.dot(self.dir);
+        let angle = cos.clamp(-1.0, 1.0).acos();
+        let mut rv = 1.0 - smoothstep(self.angle - self.penumbra, self.angle, angle);
+        if let Some(doors) = &self.barn_doors {
+            if cos <= 0.0 { return 0.0 }
+            let right = self.up.cross(self.dir);
+            rv *= doors.transmittance(Vector(v.dot(right), v.dot(self.up), cos));
+        }
+        rv
+    }
+}
+
+/// Objects linked to a light, by their indices in `Scene::objs`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum LightLink {
+    #[default]
+    All,
+    Only(Vec<usize>),
+    Except(Vec<usize>),
+}
+impl LightLink {
+    pub fn includes(&self, obj: usize) -> bool {
+        match self {
+            LightLink::All => true,
+            LightLink::Only(x) => x.contains(&obj),
+            LightLink::Except(x) => !x.contains(&obj),
+        }
+    }
+}
+
+/// Objects a light interacts with, so that lights can be art directed per
+/// object.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LightLinks {
+    /// Objects lit by the light.
+    pub illumination: LightLink,
+    /// Objects casting shadows of the light.
+    pub shadow: LightLink,
+}
+
+/// Light arriving at a point from a `Light`.
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+    /// Unit direction towards the light.
+    pub wi: Vector,
+    /// Distance to the sampled point of the light, infinite for directional
+    /// lights.
+    pub dist: Real,
+    /// Irradiance on a surface facing the light.
+    pub irradiance: Color,
+}
+
+/// Light emitted from around a point, in every direction or into a `Spot`.
+/// Punctual lights cannot be hit by rays, so they only light surfaces
+/// sampling them directly.
+#[derive(Debug, Clone)]
+pub struct PunctualLight {
+    pub p: Point,
+    /// Radiant intensity, i.e., the irradiance at unit distance without
+    /// falloff shaping.
+    pub intensity: Color,
+    pub falloff: Falloff,
+    pub spot: Option<Spot>,
+    /// Radius of the ball the light is emitted from. Shadow rays are aimed
+    /// all over it so that shadows soften with the radius; 0 casts hard
+    /// shadows. Falloff and spot cones are measured from `p` regardless.
+    pub radius: Real,
+    pub links: LightLinks,
+}
+impl PunctualLight {
+    pub fn point(p: Point, intensity: Color) -> PunctualLight {
+        PunctualLight { p, intensity, falloff: Falloff::default(), spot: None, radius: 0.0, links: LightLinks::default() }
+    }
+    /// A spot light at `p` shining towards `dir` in a cone of `angle` radians
+    /// from the axis with hard edges.
+    pub fn spot(p: Point, dir: Vector, angle: Real, intensity: Color) -> PunctualLight {
+        let dir = dir.normalize();
+        let (_, up) = basis(dir);
+        let spot = Spot { dir, up, angle, penumbra: 0.0, barn_doors: None };
+        PunctualLight { spot: Some(spot), ..PunctualLight::point(p, intensity) }
+    }
+    pub fn with_falloff(self, falloff: Falloff) -> PunctualLight {
+        PunctualLight { falloff, ..self }
+    }
+    pub fn with_radius(self, radius: Real) -> PunctualLight {
+        PunctualLight { radius, ..self }
+    }
+    pub fn with_links(self, links: LightLinks) -> PunctualLight {
+        PunctualLight { links, ..self }
+    }
+    /// Same light of the tint of `intensity` with a luminous intensity of
+    /// `candela`.
+    pub fn with_candela(self, candela: f32) -> PunctualLight {
+        PunctualLight { intensity: intensity_from_candela(self.intensity, candela), ..self }
+    }
+    /// Same light of the tint of `intensity` emitting `lumens` in total, over
+    /// the whole sphere for point lights and over the cone for spot lights,
+    /// so that narrowing a spot light doesn't darken it. Penumbrae and barn
+    /// doors are not accounted for.
+    pub fn with_lumens(self, lumens: f32) -> PunctualLight {
+        const PI: Real = std::f64::consts::PI as Real;
+        let solid_angle = match &self.spot {
+            Some(spot) => 2.0 * PI * (1.0 - spot.angle.min(PI).cos()),
+            None => 4.0 * PI,
+        };
+        if solid_angle <= 0.0 { return self.with_candela(0.0) }
+        self.with_candela(lumens / narrow(solid_angle))
+    }
+    /// Light arriving at `x`, or `None` if no light reaches it. `a` and `b` in
+    /// [0..1) locate the sampled point of the light.
+    pub fn illuminate(&self, x: Point, a: Real, b: Real) -> Option<LightSample> {
+        let d = self.p.rel_from(x);
+        let dist = d.mag();
+        if dist <= self.radius { return None }
+        let wi = d / dist;
+        let mut w = self.falloff.attenuation(dist);
+        if let Some(spot) = &self.spot {
+            w *= spot.transmittance(-wi);
+        }
+        if !w.is_finite() || w <= 0.0 { return None }
+        let irradiance = self.intensity * narrow(w);
+        if self.radius == 0.0 {
+            return Some(LightSample { wi, dist, irradiance });
+        }
+        // The ball is seen as a disk facing `x`.
+        let (u, v) = basis(wi);
+        let (du, dv) = disk(a, b);
+        let d = d + (u * du + v * dv) * self.radius;
+        let dist = d.mag();
+        Some(LightSample { wi: d / dist, dist, irradiance })
+    }
+}
+
+/// Light from a faraway source like the sun, arriving at every point in the
+/// same direction.
+#[derive(Debug, Clone)]
+pub struct DirectionalLight {
+    /// Unit direction the light travels in.
+    pub dir: Vector,
+    /// Irradiance on surfaces facing the light.
+    pub irradiance: Color,
+    /// Angular diameter in radians of the source, e.g., about 0.0093 for the
+    /// sun. Shadows soften with it; 0 casts hard shadows.
+    pub angle: Real,
+    pub links: LightLinks,
+}
+impl DirectionalLight {
+    pub fn new(dir: Vector, irradiance: Color) -> DirectionalLight {
+        DirectionalLight {
+            dir: dir.normalize(),
+            irradiance,
+            angle: 0.0,
+            links: LightLinks::default(),
+        }
+    }
+    pub fn with_angle(self, angle: Real) -> DirectionalLight {
+        DirectionalLight { angle, ..self }
+    }
+    pub fn with_links(self, links: LightLinks) -> DirectionalLight {
+        DirectionalLight { links, ..self }
+    }
+    /// Light arriving at any point. `a` and `b` in [0..1) locate the sampled
+    /// direction within the source.
+    pub fn illuminate(&self, a: Real, b: Real) -> LightSample {
+        let w = -self.dir;
+        let cos_max = (0.5 * self.angle).cos();
+        let (u, v) = basis(w);
+        let wi = hemisphere(1.0 - a * (1.0 - cos_max), b).in_basis(u, v, w);
+        LightSample { wi, dist: Real::INFINITY, irradiance: self.irradiance }
+    }
+}
+
+/// Solid angles below which the spherical rectangle loses too much precision
+/// to its cancellations, and area lights are sampled by area instead.
+const MIN_SOLID_ANGLE: Real = 1e-3;
+
+/// A rectangle seen from a point, sampled uniformly in the solid angle it
+/// subtends.
+///
+/// See: Carlos Ureña, Marcos Fajardo and Alan King, An Area-Preserving
+/// Parametrization for Spherical Rectangles.
+struct SphericalRect {
+    o: Point,
+    x: Vector,
+    y: Vector,
+    z: Vector,
+    x0: Real,
+    x1: Real,
+    y0: Real,
+    y1: Real,
+    z0: Real,
+    b0: Real,
+    b1: Real,
+    k: Real,
+    /// Subtended solid angle.
+    s: Real,
+}
+impl SphericalRect {
+    /// Rectangle of perpendicular edges `ex` and `ey` from `corner`, seen
+    /// from `o`.
+    fn new(o: Point, corner: Point, ex: Vector, ey: Vector) -> SphericalRect {
+        const PI: Real = std::f64::consts::PI as Real;
+        let (exl, eyl) = (ex.mag(), ey.mag());
+        let (x, y) = (ex / exl, ey / eyl);
+        let mut z = x.cross(y);
+        let d = corner.rel_from(o);
+        let (x0, y0, mut z0) = (d.dot(x), d.dot(y), d.dot(z));
+        if z0 > 0.0 {
+            z0 = -z0;
+            z = -z;
+        }
+        let (x1, y1) = (x0 + exl, y0 + eyl);
+        let v00 = Vector(x0, y0, z0);
+        let v01 = Vector(x0, y1, z0);
+        let v10 = Vector(x1, y0, z0);
+        let v11 = Vector(x1, y1, z0);
+        let n0 = v00.cross(v10).normalize();
+        let n1 = v10.cross(v11).normalize();
+        let n2 = v11.cross(v01).normalize();
+        let n3 = v01.cross(v00).normalize();
+        let angle = |a: Vector, b: Vector| (-a.dot(b)).clamp(-1.0, 1.0).acos();
+        let g0 = angle(n0, n1);
+        let g1 = angle(n1, n2);
+        let g2 = angle(n2, n3);
+        let g3 = angle(n3, n0);
+        let k = 2.0 * PI - g2 - g3;
+        let s = g0 + g1 - k;
+        SphericalRect { o, x, y, z, x0, x1, y0, y1, z0, b0: n0.2, b1: n2.2, k, s }
+    }
+    fn is_valid(&self) -> bool {
+        self.s.is_finite() && self.s > MIN_SOLID_ANGLE
+    }
+    /// Point of the rectangle in a uniformly distributed direction from `o`.
+    /// `a` and `b` are in [0..1).
+    fn sample(&self, a: Real, b: Real) -> Point {
+        let au = a * self.s + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = ((fu * fu + self.b0 * self.b0).sqrt().recip() * fu.signum()).clamp(-1.0, 1.0);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).sqrt()).clamp(self.x0, self.x1);
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + b * (h1 - h0);
+        let hv2 = hv * hv;
+        let yv = if hv2 < 1.0 - 1e-6 { hv * d / (1.0 - hv2).sqrt() } else { self.y1 };
+        self.o.affine_add(self.x * xu + self.y * yv + self.z * self.z0)
+    }
+}
+
+/// Outline of an `AreaLight`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaShape {
+    Rect,
+    Disk,
+}
+
+/// Light emitted uniformly from a rectangle or a disk. Directions towards the
+/// light are sampled by the solid angle it subtends rather than by its area,
+/// so that surfaces close to the light aren't noisier than those far away.
+/// Disks are sampled by the rectangle around them, and directions through
+/// the corners are rejected. Area lights aren't part of the scene, but path
+/// tracers can see them with `integrator::hit_area_lights`, where `hit` and
+/// `pdf` weigh rays hitting them against light sampling.
+#[derive(Debug, Clone)]
+pub struct AreaLight {
+    /// Center.
+    pub p: Point,
+    /// Half of the first edge of a rectangle, or the first semi-axis of a
+    /// disk.
+    pub x: Vector,
+    /// Half of the second edge, or the second semi-axis, perpendicular to
+    /// `x`.
+    pub y: Vector,
+    pub shape: AreaShape,
+    /// Radiance emitted towards `x.cross(y)`.
+    pub radiance: Color,
+    /// Whether the light is also emitted from the back.
+    pub two_sided: bool,
+    pub links: LightLinks,
+}
+impl AreaLight {
+    /// A rectangle of half edges `x` and `y` centered at `p`. Any part of `y`
+    /// along `x` is removed so that the edges are perpendicular.
+    pub fn rect(p: Point, x: Vector, y: Vector, radiance: Color) -> AreaLight {
+        let xx = x.dot(x);
+        let y = if xx > 0.0 { y - x * (y.dot(x) / xx) } else { y };
+        AreaLight {
+            p,
+            x,
+            y,
+            shape: AreaShape::Rect,
+            radiance,
+            two_sided: false,
+            links: LightLinks::default(),
+        }
+    }
+    /// A disk of `radius` at `p` facing `dir`.
+    pub fn disk(p: Point, dir: Vector, radius: Real, radiance: Color) -> AreaLight {
+        let (x, y) = basis(dir.normalize());
+        AreaLight { shape: AreaShape::Disk, ..AreaLight::rect(p, x * radius, y * radius, radiance) }
+    }
+    pub fn with_two_sided(self, two_sided: bool) -> AreaLight {
+        AreaLight { two_sided, ..self }
+    }
+    pub fn with_links(self, links: LightLinks) -> AreaLight {
+        AreaLight { links, ..self }
+    }
+    /// Same light of the tint of `radiance` with a luminance of `nits`.
+    pub fn with_nits(self, nits: f32) -> AreaLight {
+        AreaLight { radiance: radiance_from_nits(self.radiance, nits), ..self }
+    }
+    /// Same light of the tint of `radiance` emitting `lumens` in total, split
+    /// between both sides if it's two-sided, so `two_sided` should be set
+    /// beforehand.
+    pub fn with_lumens(self, lumens: f32) -> AreaLight {
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        let area = narrow(self.area()) * sides;
+        if area <= 0.0 { return self.with_nits(0.0) }
+        AreaLight { radiance: radiance_from_lumens(self.radiance, lumens, area), ..self }
+    }
+    /// Unit normal vector of the front.
+    pub fn normal(&self) -> Vector {
+        self.x.cross(self.y).normalize()
+    }
+    pub fn area(&self) -> Real {
+        const PI: Real = std::f64::consts::PI as Real;
+        let rect = 4.0 * self.x.cross(self.y).mag();
+        match self.shape {
+            AreaShape::Rect => rect,
+            AreaShape::Disk => 0.25 * PI * rect,
+        }
+    }
+    /// Whether light is emitted towards `x`.
+    fn faces(&self, x: Point) -> bool {
+        let side = x.rel_from(self.p).dot(self.normal());
+        side > 0.0 || (side < 0.0 && self.two_sided)
+    }
+    /// Whether the point `p + x * u + y * v` is on the light.
+    fn contains(&self, u: Real, v: Real) -> bool {
+        match self.shape {
+            AreaShape::Rect => u.abs() <= 1.0 && v.abs() <= 1.0,
+            AreaShape::Disk => u * u + v * v <= 1.0,
+        }
+    }
+    /// Coordinates of `q` on the plane of the light along `x` and `y`.
+    fn coords(&self, q: Point) -> (Real, Real) {
+        let d = q.rel_from(self.p);
+        (d.dot(self.x) / self.x.dot(self.x), d.dot(self.y) / self.y.dot(self.y))
+    }
+    /// Distance from `x` to the light in unit direction `v`, if the light is
+    /// in that direction.
+    pub fn hit(&self, x: Point, v: Vector) -> Option<Real> {
+        let n = self.normal();
+        let cos = n.dot(v);
+        if cos == 0.0 { return None }
+        let t = self.p.rel_from(x).dot(n) / cos;
+        if t <= 0.0 { return None }
+        let (u, v) = self.coords(x.affine_add(v * t));
+        if self.contains(u, v) { Some(t) } else { None }
+    }
+    /// The rectangle, around disks, seen from `x`.
+    fn spherical_rect(&self, x: Point) -> SphericalRect {
+        SphericalRect::new(x, self.p.affine_add(-self.x - self.y), self.x * 2.0, self.y * 2.0)
+    }
+    /// Light arriving at `x`, or `None` if no light reaches it. `a` and `b` in
+    /// [0..1) locate the sampled point of the light. The irradiance is
+    /// estimated by the radiance over the PDF of the direction.
+    pub fn illuminate(&self, x: Point, a: Real, b: Real) -> Option<LightSample> {
+        if !self.faces(x) { return None }
+        let rect = self.spherical_rect(x);
+        let q = if rect.is_valid() {
+            rect.sample(a, b)
+        } else {
+            self.p.affine_add(self.x * (2.0 * a - 1.0) + self.y * (2.0 * b - 1.0))
+        };
+        let (u, v) = self.coords(q);
+        if !self.contains(u, v) { return None }
+        let d = q.rel_from(x);
+        let dist = d.mag();
+        if dist == 0.0 { return None }
+        let wi = d / dist;
+        let pdf = self.pdf_of(wi, dist, &rect);
+        if !pdf.is_finite() || pdf <= 0.0 { return None }
+        Some(LightSample { wi, dist, irradiance: self.radiance * narrow(pdf.recip()) })
+    }
+    /// PDF of the direction `v` towards a point of the light `dist` away.
+    fn pdf_of(&self, v: Vector, dist: Real, rect: &SphericalRect) -> Real {
+        if rect.is_valid() { return rect.s.recip() }
+        // Convert the uniform area density of the rectangle to solid angle.
+        let cos = self.normal().dot(v).abs();
+        if cos == 0.0 { return 0.0 }
+        dist * dist / (4.0 * self.x.cross(self.y).mag() * cos)
+    }
+    /// PDF in solid angle of `illuminate` sampling unit direction `v` from
+    /// `x`. Disks miss some samples, so the PDF integrates to less than 1
+    /// over their solid angle.
+    pub fn pdf(&self, x: Point, v: Vector) -> Real {
+        if !self.faces(x) { return 0.0 }
+        match self.hit(x, v) {
+            Some(t) => self.pdf_of(v, t, &self.spherical_rect(x)),
+            None => 0.0,
+        }
+    }
+}
+
+/// Lights sampled directly by path tracers, see `PathTracer::lights`.
+#[derive(Debug, Clone)]
+pub enum Light {
+    Punctual(PunctualLight),
+    Directional(DirectionalLight),
+    Area(AreaLight),
+}
+impl Light {
+    /// Light arriving at `x`, or `None` if no light reaches it. `a` and `b` in
+    /// [0..1) locate the sampled point of the light.
+    pub fn illuminate(&self, x: Point, a: Real, b: Real) -> Option<LightSample> {
+        match self {
+            Light::Punctual(light) => light.illuminate(x, a, b),
+            Light::Directional(light) => Some(light.illuminate(a, b)),
+            Light::Area(light) => light.illuminate(x, a, b),
+        }
+    }
+    /// PDF in solid angle of `illuminate` sampling unit direction `v` from
+    /// `x`, for multiple importance sampling. Lights which rays cannot hit
+    /// are never sampled by BSDFs, and their PDF is 0.
+    pub fn pdf(&self, x: Point, v: Vector) -> Real {
+        match self {
+            Light::Punctual(_) | Light::Directional(_) => 0.0,
+            Light::Area(light) => light.pdf(x, v),
+        }
+    }
+    pub fn links(&self) -> &LightLinks {
+        match self {
+            Light::Punctual(light) => &light.links,
+            Light::Directional(light) => &light.links,
+            Light::Area(light) => &light.links,
+        }
+    }
+    pub fn links_mut(&mut self) -> &mut LightLinks {
+        match self {
+            Light::Punctual(light) => &mut light.links,
+            Light::Directional(light) => &mut light.links,
+            Light::Area(light) => &mut light.links,
+        }
+    }
+}
+impl From<PunctualLight> for Light {
+    fn from(x: PunctualLight) -> Light {
+        Light::Punctual(x)
+    }
+}
+impl From<DirectionalLight> for Light {
+    fn from(x: DirectionalLight) -> Light {
+        Light::Directional(x)
+    }
+}
+impl From<AreaLight> for Light {
+    fn from(x: AreaLight) -> Light {
+        Light::Area(x)
+    }
+}
+
+/// Image of emitted radiance, e.g., of screens and neon signs, scaled by
+/// `intensity`. The image is mapped onto the xz-plane of object space over
+/// [-0.5, 0.5] squared like `make_pln`, with its top towards -z. Bright
+/// texels can be importance sampled, so that textures on planes are sampled
+/// directly as lights by `illuminate_plane`.
+#[derive(Clone)]
+pub struct EmissionTexture {
+    pub img: Arc<Image>,
+    pub intensity: f32,
+    dist: TexelDistribution,
+}
+impl EmissionTexture {
+    pub fn new(img: Arc<Image>, intensity: f32) -> EmissionTexture {
+        let dist = TexelDistribution::new(&*img);
+        EmissionTexture { img, intensity, dist }
+    }
+    /// Texture coordinates of point `p` in object space.
+    pub fn uv(p: Point) -> (f32, f32) {
+        (narrow(p.0 + 0.5), narrow(p.2 + 0.5))
+    }
+    /// Radiance emitted at texture coordinates `(u, v)`.
+    pub fn eval(&self, u: f32, v: f32) -> Color {
+        let samp = Sampler2D::new(WrapMode::Clamp, FilterMode::Linear);
+        samp.sample(&*self.img, u, v) * self.intensity
+    }
+    /// Same as `eval` but from the mip level of texels about `width` wide in
+    /// texture coordinates, see `Sampler2D::sample_footprint`.
+    pub fn eval_footprint(&self, u: f32, v: f32, width: f32) -> Color {
+        let samp = Sampler2D::new(WrapMode::Clamp, FilterMode::Linear);
+        samp.sample_footprint(&self.img, u, v, width) * self.intensity
+    }
+    /// Sample texture coordinates proportionally to the emitted luminance
+    /// from `a` and `b` in [0..1), with the PDF over the unit square, or
+    /// `None` if the texture is black.
+    pub fn sample(&self, a: Real, b: Real) -> Option<(f32, f32, f32)> {
+        if self.dist.is_empty() { return None }
+        let (u, v) = self.dist.sample(narrow(a), narrow(b));
+        Some((u, v, self.dist.pdf(u, v)))
+    }
+    /// PDF over the unit square of sampling texture coordinates `(u, v)`.
+    pub fn pdf(&self, u: f32, v: f32) -> f32 {
+        self.dist.pdf(u, v)
+    }
+    /// Light of the texture on a plane like `make_pln` placed in the world by
+    /// `world2obj` arriving at `x`, from a point sampled like `sample` from `a`
+    /// and `b` in [0..1).
+    pub fn illuminate_plane(&self, world2obj: Transform, x: Point, a: Real, b: Real) -> Option<EmissionSample> {
+        let (u, v, pdf) = self.sample(a, b)?;
+        let (o, eu, ev) = plane_frame(world2obj);
+        let d = o.affine_add(u as Real * eu + v as Real * ev).rel_from(x);
+        let dist = d.mag();
+        if dist == 0.0 { return None }
+        self.emission_sample(eu, ev, d / dist, dist, u, v, pdf)
+    }
+    /// Light of the texture on a plane like `illuminate_plane` seen from `x`
+    /// in unit direction `wi`, if the plane is in that direction.
+    pub fn hit_plane(&self, world2obj: Transform, x: Point, wi: Vector) -> Option<EmissionSample> {
+        let (o, eu, ev) = plane_frame(world2obj);
+        let n = eu.cross(ev);
+        let cos = wi.dot(n);
+        if cos == 0.0 { return None }
+        let dist = o.rel_from(x).dot(n) / cos;
+        if dist <= 0.0 { return None }
+        let r = x.affine_add(dist * wi).rel_from(o);
+        let nn = n.dot(n);
+        let (u, v) = (narrow(r.cross(ev).dot(n) / nn), narrow(eu.cross(r).dot(n) / nn));
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) { return None }
+        self.emission_sample(eu, ev, wi, dist, u, v, self.pdf(u, v))
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn emission_sample(
+        &self,
+        eu: Vector,
+        ev: Vector,
+        wi: Vector,
+        dist: Real,
+        u: f32,
+        v: f32,
+        pdf: f32,
+    ) -> Option<EmissionSample> {
+        let n = eu.cross(ev);
+        let area = n.mag();
+        let cos = wi.dot(n) / area;
+        if cos == 0.0 || area == 0.0 { return None }
+        // Convert the density over the unit square to solid angle.
+        let pdf = pdf as Real / area * dist * dist / cos.abs();
+        let kind = if cos < 0.0 { HitKind::Front } else { HitKind::Back };
+        Some(EmissionSample { wi, dist, radiance: self.eval(u, v), kind, pdf })
+    }
+}
+
+/// Corner of a plane like `make_pln` placed in the world by `world2obj` at
+/// texture coordinates `(0, 0)` and its edges along u and v, whose cross
+/// product is the normal of its front faces.
+fn plane_frame(world2obj: Transform) -> (Point, Vector, Vector) {
+    let o = world2obj * Point(-0.5, 0.0, -0.5);
+    let eu = (world2obj * Point(0.5, 0.0, -0.5)).rel_from(o);
+    let ev = (world2obj * Point(-0.5, 0.0, 0.5)).rel_from(o);
+    (o, eu, ev)
+}
+
+/// A point of an `EmissionTexture` seen from another point.
+#[derive(Debug, Clone, Copy)]
+pub struct EmissionSample {
+    /// Unit direction towards the point.
+    pub wi: Vector,
+    pub dist: Real,
+    /// Radiance emitted towards the other point, before `Sides::emit`.
+    pub radiance: Color,
+    /// The face of the plane seen.
+    pub kind: HitKind,
+    /// PDF in solid angle of sampling `wi` with `illuminate_plane`, 0 if the
+    /// point is black.
+    pub pdf: Real,
+}
@@ -1,23 +1,36 @@
-mod geom;
-mod rt;
-mod scene;
-mod model;
-mod img;
-mod sampler;
+use lighar::geom::*;
+use lighar::rt::*;
+use lighar::scene::*;
+use lighar::model::*;
+use lighar::img::*;
+use lighar::sampler::*;
+use lighar::filter::*;
+use lighar::integrator::*;
+use lighar::accel::*;
+use lighar::optics::*;
+use lighar::trace::{self, Level, StderrSubscriber};
+use lighar::rng;
 
-use geom::*;
-use rt::*;
-use scene::*;
-use model::*;
-use img::*;
-use sampler::*;
+/// Bounces traced from camera rays.
+const MAX_DEPTH: u32 = 5;
 
 #[derive(Default)]
+#[allow(dead_code)]
 struct PbrMaterial {
     albedo: Color,
     rough: f32,
     metal: f32,
     emit: Color,
+    /// Matte shadow catcher. The surface itself is invisible and only the
+    /// shadows and reflections it receives are rendered over the background,
+    /// so that the result can be composited onto a photographic backplate.
+    shadow_catcher: bool,
+    /// Smooth dielectric of this index of refraction, e.g., glass. Light is
+    /// only reflected or refracted at the surface.
+    ior: Option<Ior>,
+    /// Iridescent coating over the specular reflection.
+    thin_film: Option<ThinFilm>,
+    sides: Sides,
 }
 
 
@@ -48,16 +61,19 @@ impl Framebuffer for DemoFramebuffer {
     }
 }
 
-struct DebugPayload {
-    refl_ray: Ray,
-}
-
-
 struct DemoRayTracer {
     s: Scene<PbrMaterial>,
     ambient: Color,
+    #[allow(dead_code)]
     skybox: Vec<Image>,
+    #[allow(dead_code)]
     skybox_samp: CubeSampler,
+    /// Background image mapped in screen space. It's only seen by camera rays
+    /// and doesn't contribute to lighting.
+    backplate: Option<Image>,
+    /// Distribution of sub-pixel samples.
+    filter: PixelFilter,
+    accel: Box<dyn Accel>,
     counter: std::cell::RefCell<usize>,
 }
 impl DemoRayTracer {
@@ -66,14 +82,19 @@ impl DemoRayTracer {
         debug_assert!(skybox_samp.validate(&skybox),
             "sampled image failed to meet the sampler's requirement");
         let counter = std::cell::RefCell::new(0);
-        DemoRayTracer { s, ambient, skybox, skybox_samp, counter }
+        let filter = PixelFilter::default();
+        let accel = AccelKind::default().build(&s);
+        DemoRayTracer { s, ambient, skybox, skybox_samp, backplate: None, filter, accel, counter }
+    }
+    pub fn with_backplate(self, backplate: Image) -> DemoRayTracer {
+        DemoRayTracer { backplate: Some(backplate), ..self }
     }
 }
 unsafe impl Send for DemoRayTracer {}
 unsafe impl Sync for DemoRayTracer {}
 impl RayTracer for DemoRayTracer {
     type Material = PbrMaterial;
-    type Payload = i32; // Recursion count.
+    type Payload = TraceContext;
     type Ray = Ray;
     type RayAttr = Barycentric;
 
@@ -84,58 +105,46 @@ impl RayTracer for DemoRayTracer {
         w: u32,
         h: u32,
     ) -> Color {
-        let tic = std::time::Instant::now();
-
-        let id = x * h + y;
-        let w = w as f32 / 2.0;
-        let h = h as f32 / 2.0;
-        let x = (x as f32) / w - 1.0;
-        let y = (y as f32) / h - 1.0;
-
-        let n = 1;
-        let rn = (n as f32).recip();
-        let rn2 = rn * rn;
-        let rv: Color = (0..n).into_iter()
-            .fold(Color::default(), |seed, i| {
-                seed + (0..n).into_iter()
-                    .fold(Color::default(), |seed, j| {
-                        let ray = Ray {
-                            o: Point(
-                                x + i as f32 * rn / w,
-                                y + j as f32 * rn / h,
-                                0.0
-                            ),
-                            v: Vector(0.0, 0.0, 10.0),
-                        };
-                        let mut payload = Default::default();
-
-                        let cur = self.trace(ray, &mut payload);
-                        seed + cur
-                    })
-            });
-            
-        println!("traced ray for pixel #{} in {}s",
-            id,
-            tic.elapsed().as_millis() as f64 / 1000.0);
-        rv * ((n * n) as f32).recip()
+        let (ray, mut payload) = self.primary_ray(x, y, w, h);
+        self.trace_path(ray, &mut payload)
     }
     fn intersect(
         &self,
         ray: &Self::Ray,
         tri: &Triangle,
-        mat: &Self::Material,
+        _mat: &Self::Material,
     ) -> Option<Intersection<Self::RayAttr>> {
-        ray_cast_tri(&ray, tri)
+        ray_cast_tri_with(ray, tri, &self.s.precision)
     }
-    fn any_hit(
+    fn intersect_within(
         &self,
         ray: &Self::Ray,
         tri: &Triangle,
-        intersect: &Intersection<Self::RayAttr>,
-        payload: &mut Self::Payload,
-        mat: &Self::Material,
+        _mat: &Self::Material,
+        tmax: Real,
+    ) -> Option<Intersection<Self::RayAttr>> {
+        let precision = Precision { max_t: tmax.min(self.s.precision.max_t), ..self.s.precision };
+        ray_cast_tri_with(ray, tri, &precision)
+    }
+    fn intersect_shape(
+        &self,
+        ray: &Self::Ray,
+        obj: &Object<Self::Material>,
+        tmax: Real,
+    ) -> Option<(Triangle, Intersection<Self::RayAttr>)> {
+        let precision = Precision { max_t: tmax.min(self.s.precision.max_t), ..self.s.precision };
+        obj.ray_cast_shape(ray, &precision)
+    }
+    fn any_hit(
+        &self,
+        _ray: &Self::Ray,
+        _tri: &Triangle,
+        _intersect: &Intersection<Self::RayAttr>,
+        _payload: &mut Self::Payload,
+        _mat: &Self::Material,
     ) -> bool {
-        intersect.kind == HitKind::Front
+        // Back faces are shaded by `Sides`.
+        true
     }
     fn miss(
         &self,
@@ -145,66 +154,223 @@ impl RayTracer for DemoRayTracer {
         //let vec = Vector(ray.o.0, ray.o.1, ray.o.2 + 1.0).normalize();
         //self.skybox_samp.sample(&self.skybox, vec)
         *self.counter.borrow_mut() += 1;
-        self.ambient
+        match &self.backplate {
+            // Camera rays have not bounced yet. Their origins are on the image
+            // plane in [-1, 1].
+            Some(backplate) if payload.depth == 0 => {
+                let u = narrow((0.5 * (ray.o.0 + 1.0)).clamp(0.0, 1.0));
+                let v = narrow((0.5 * (ray.o.1 + 1.0)).clamp(0.0, 1.0));
+                let x = u * (backplate.width() - 1) as f32;
+                let y = v * (backplate.height() - 1) as f32;
+                backplate.load_px_premultiplied(x as usize, y as usize)
+            },
+            _ => self.ambient,
+        }
     }
+    /// Continue the path from the hit, e.g., for `draw_wavefront`.
     fn closest_hit(
         &self,
         ray: &Self::Ray,
+        obj: usize,
         tri: &Triangle,
         intersect: &Intersection<Self::RayAttr>,
         payload: &mut Self::Payload,
         mat: &Self::Material,
     ) -> Color {
-        // Number of extra rays to trace from this intersection.
+        self.trace_path_hit(ray, obj, tri, intersect, payload, mat)
+    }
+    fn scene(&self) -> &Scene<PbrMaterial> {
+        &self.s
+    }
+    fn accel(&self, ray: &Ray) -> Option<(&dyn Accel, Ray)> {
+        Some((&*self.accel, *ray))
+    }
+}
+impl PathTracer for DemoRayTracer {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        _obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut TraceContext,
+        mat: &PbrMaterial,
+    ) -> Scatter<Ray> {
+        // Number of shadow rays sampling the occlusion of shadow catchers.
         const NRAY: usize = 16;
         const F0: f32 = 0.04;
 
+        if let Some(x) = mat.sides.absorb(intersect.kind, mat.emit) {
+            return x;
+        }
+        let emit = mat.sides.emit(intersect.kind, mat.emit);
         let bary = intersect.attr;
         let p = tri.o.affine_add(bary.u * tri.x + bary.v * tri.y);
-        let refl = -reflect(ray.v, tri.n);
+        let n = tri.n;
+        if let Some(ior) = mat.ior {
+            // Dispersive media bend each wavelength differently, so only a
+            // single sampled wavelength continues the path. It's sampled once
+            // per path and reused at every later interface, or the spectrum
+            // would be weighed again at each of them.
+            let (wavelength, weight) = match payload.wavelength {
+                Some(x) => (x, Color(1.0, 1.0, 1.0, 1.0)),
+                None if ior.is_dispersive() => {
+                    let (a, b) = (rng::random::<Real>(), rng::random::<Real>());
+                    let (x, weight) = sample_wavelength(a, b);
+                    payload.wavelength = Some(x);
+                    (x, weight)
+                },
+                None => (550.0, Color(1.0, 1.0, 1.0, 1.0)),
+            };
+            let i = ray.v.normalize();
+            let (n, eta) = if i.dot(n) < 0.0 {
+                (n, ior.at(wavelength).recip())
+            } else {
+                (-n, ior.at(wavelength))
+            };
+            let f = fresnel_dielectric(-i.dot(n), eta);
+            let next = match refract(i, n, eta) {
+                Some(t) if rng::random::<Real>() >= f => {
+                    Ray { o: offset_ray_origin(p, -n), v: t }
+                },
+                _ => Ray { o: offset_ray_origin(p, n), v: reflect(-i, n) },
+            };
+            return Scatter {
+                emit,
+                direct: Color::default(),
+                next: Some((next, weight)),
+                lobe: Lobe::Specular,
+                pdf: 0.0,
+            };
+        }
+        let n = if intersect.kind == HitKind::Front { n } else { -n };
+        let u = tri.y.normalize();
+        let v = n.cross(u);
+        // Secondary rays leave from above the surface, on the side of their
+        // direction.
+        let o = offset_ray_origin(p, n);
+        let refl = -reflect(ray.v, n);
         let refl_ray = Ray {
-            o: p,
+            o: offset_ray_origin(p, if refl.dot(n) < 0.0 { -n } else { n }),
             v: refl.normalize(),
         };
 
-        if *payload < 5 {
-            *payload += 1;
-
-            // Lighting.
-            let specular = self.trace(refl_ray, payload);
-            let diffuse = {
-                let n = tri.n;
-                let u = tri.y.normalize();
-                let v = n.cross(u);
-                let mut temp = Color::default();
-                for _ in 0..NRAY {
-                    let dir = hemisphere(
-                        rand::random::<f32>(),
-                        rand::random::<f32>(),
-                    );
-                    let diffuse_ray = Ray { o: p, v: dir.normalize() };
-                    let mut payload2 = *payload;
-                    temp = temp + self.trace(diffuse_ray, &mut payload2);
-                }
-                temp * (NRAY as f32).recip()
+        if mat.shadow_catcher {
+            // Fraction of the hemisphere blocked by other objects.
+            let nocc = (0..NRAY)
+                .filter(|_| {
+                    let dir = hemisphere(rng::random::<Real>(), rng::random::<Real>());
+                    let shadow_ray = Ray { o, v: dir.in_basis(u, v, n) };
+                    self.occluded(shadow_ray, &mut payload.clone())
+                })
+                .count();
+            let shadow = nocc as f32 / NRAY as f32;
+            // Only reflections of other objects are kept, the environment
+            // reflected by the catcher is already part of the background.
+            // The demo framebuffer has no alpha, so the shadow darkens the
+            // background seen through the catcher instead of being left for
+            // compositing.
+            let next = if self.occluded(refl_ray, &mut payload.clone()) {
+                Some((refl_ray, Color(F0, F0, F0, F0)))
+            } else {
+                None
+            };
+            return Scatter {
+                emit: self.miss(ray, payload) * (1.0 - shadow),
+                direct: Color::default(),
+                next,
+                lobe: Lobe::Specular,
+                pdf: 0.0,
             };
+        }
 
-            mat.emit + mat.albedo * (diffuse + specular * F0)
-        } else {
+        if !payload.can_recurse() {
+            // Paths cut off at the maximum depth see the ambient light.
             *self.counter.borrow_mut() += 1;
-            mat.emit + self.ambient
+            return Scatter {
+                emit: emit + self.ambient,
+                direct: Color::default(),
+                next: None,
+                lobe: Lobe::Diffuse,
+                pdf: 0.0,
+            };
+        }
+        let fresnel = match mat.thin_film {
+            Some(film) => {
+                let cos_i = -ray.v.normalize().dot(n);
+                film.reflectance(cos_i, Ior::Constant(1.5))
+            },
+            None => Color(F0, F0, F0, F0),
+        };
+        // Either the specular or the diffuse lobe continues the path, picked
+        // by the mean reflectance and weighted by the inverse of its odds.
+        let q = ((fresnel.0 + fresnel.1 + fresnel.2) / 3.0).clamp(0.05, 0.95);
+        if rng::random::<f32>() < q {
+            return Scatter {
+                emit,
+                direct: Color::default(),
+                next: Some((refl_ray, mat.albedo * fresnel * q.recip())),
+                lobe: Lobe::Specular,
+                pdf: 0.0,
+            };
+        }
+        // Lambertian surface sampled uniformly over the hemisphere, the
+        // weight is `albedo / PI * cos / (1 / (2 * PI))`.
+        let cos = rng::random::<Real>();
+        let dir = hemisphere(cos, rng::random::<Real>());
+        let next = Ray { o, v: dir.in_basis(u, v, n) };
+        let weight = mat.albedo * (2.0 * narrow(cos) / (1.0 - q));
+        Scatter {
+            emit,
+            direct: Color::default(),
+            next: Some((next, weight)),
+            lobe: Lobe::Diffuse,
+            pdf: 0.0,
         }
     }
-    fn scene(&self) -> &Scene<PbrMaterial> {
-        &self.s
+    fn emitted(
+        &self,
+        _ray: &Ray,
+        _obj: usize,
+        _tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        mat: &PbrMaterial,
+    ) -> Color {
+        mat.sides.emit(intersect.kind, mat.emit)
+    }
+    fn max_depth(&self) -> u32 {
+        MAX_DEPTH
+    }
+}
+impl WavefrontRayTracer for DemoRayTracer {
+    fn primary_ray(
+        &self,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> (Ray, TraceContext) {
+        let (ox, oy) = (pixel_offset(x, y, 0), pixel_offset(x, y, 1));
+        let (dx, dy) = self.filter.sample(ox, oy);
+        let w = w as Real / 2.0;
+        let h = h as Real / 2.0;
+        let x = (x as Real + dx as Real) / w - 1.0;
+        let y = (y as Real + dy as Real) / h - 1.0;
+        let ray = Ray {
+            o: Point(x, y, 0.0),
+            v: Vector(0.0, 0.0, 10.0),
+        };
+        (ray, TraceContext::new(self.max_depth()))
     }
 }
 
 fn main() {
+    trace::set_subscriber(StderrSubscriber::default());
+    let scene_span = trace::span("scene_build");
     let cam_trans = Transform::eye()
         .scale(Vector(0.5, 0.5, 0.5))
-        .rotate((45.0 as f32).to_radians(), Vector(0.0, 1.0, 0.0))
-        .rotate((45.0 as f32).to_radians(), Vector(1.0, 0.0, 0.0))
+        .rotate(Real::to_radians(45.0), Vector(0.0, 1.0, 0.0))
+        .rotate(Real::to_radians(45.0), Vector(1.0, 0.0, 0.0))
         .translate(Vector(0.0, 0.0, 1.0));
     let cube = make_cube(
         PbrMaterial {
@@ -223,7 +389,7 @@ fn main() {
         },
         cam_trans * Transform::eye()
             .translate(Vector(0.75, 0.0, 0.0))
-            .rotate((15.0_f32).to_radians(), Vector(1.0, 1.0, 0.0).normalize())
+            .rotate(Real::to_radians(15.0), Vector(1.0, 1.0, 0.0).normalize())
             .translate(Vector(0.0, -1.0, 0.25)),
     );
     let cube3 = make_cube(
@@ -246,35 +412,21 @@ fn main() {
             .translate(Vector(0.0, 1.5, 0.0)),
     );
 
-    let scene = Scene {
-        objs: vec![cube, cube2, cube3, floor],
-    };
+    let scene = Scene::new(vec![cube, cube2, cube3, floor]);
+    drop(scene_span);
     let mut framebuf = DemoFramebuffer::new(256, 256);
     let ambient = [50, 50, 50].into();
-    let skybox = load_skybox();
-    let rt = DemoRayTracer::new(scene, ambient, skybox);
-    let tic = std::time::Instant::now();
-    rt.draw(&mut framebuf);
-    println!("traced {} rays in {}s",
-        rt.counter.borrow(),
-        tic.elapsed().as_millis() as f64 / 1000.0);
+    let skybox = load_cubemap("./skybox").expect("failed to load the skybox");
+    let mut rt = DemoRayTracer::new(scene, ambient, skybox);
+    if let Some(path) = std::env::args().nth(1) {
+        rt = rt.with_backplate(load_image(path).expect("failed to load the backplate"));
+    }
+    let stats = rt.s.stats()
+        .with_accel(&*rt.accel)
+        .with_textures(rt.skybox.iter().chain(rt.backplate.iter()));
+    trace::event(Level::Info, &stats.to_string());
+    rt.render(&mut framebuf, &RenderSettings::default())
+        .expect("the render is never cancelled");
+    trace::event(Level::Info, &format!("traced {} rays", rt.counter.borrow()));
     framebuf.save("1.bmp").unwrap();
 }
-
-fn load_img<P: AsRef<std::path::Path>>(p: P) -> Image {
-    image::io::Reader::open(p).unwrap()
-        .decode().unwrap()
-        .into()
-}
-fn load_skybox() -> Vec<Image> {
-    [
-        "./skybox/pos-x.png",
-        "./skybox/neg-x.png",
-        "./skybox/pos-y.png",
-        "./skybox/neg-y.png",
-        "./skybox/pos-z.png",
-        "./skybox/neg-z.png",
-    ].into_iter()
-        .map(|x| load_img(x))
-        .collect()
-}
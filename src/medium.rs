@@ -0,0 +1,567 @@
+use std::io::Read;
+use std::path::Path;
+use std::convert::TryFrom;
+use crate::geom::{Real, Point, Vector, Ray, Color, narrow};
+use crate::bvh::Aabb;
+
+/// Densities sampled on the corners of a regular grid filling `bounds`, e.g.,
+/// the density of smoke or a cloud. Densities between the samples are
+/// interpolated trilinearly and densities outside are zero.
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    pub bounds: Aabb,
+    dims: [usize; 3],
+    data: Vec<f32>,
+    /// The largest density, which bounds the density everywhere.
+    max: f32,
+}
+impl DensityGrid {
+    /// Create a grid of `dims` samples from `data` in x-major order, i.e., x
+    /// changes the fastest.
+    pub fn new(bounds: Aabb, dims: [usize; 3], data: Vec<f32>) -> DensityGrid {
+        assert_eq!(data.len(), dims[0] * dims[1] * dims[2],
+            "density grid size mismatches its dimensions");
+        assert!(dims.iter().all(|&x| x >= 2), "density grids need at least two samples per axis");
+        let max = data.iter().cloned().fold(0.0, f32::max);
+        DensityGrid { bounds, dims, data, max }
+    }
+    pub fn max_density(&self) -> f32 {
+        self.max
+    }
+    #[inline]
+    fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.data[(z * self.dims[1] + y) * self.dims[0] + x]
+    }
+    /// Density at `p`.
+    pub fn density(&self, p: Point) -> f32 {
+        let rel = p.rel_from(self.bounds.min);
+        let ext = self.bounds.max.rel_from(self.bounds.min);
+        let coord = |i: usize, rel: Real, ext: Real| {
+            let x = rel / ext * (self.dims[i] - 1) as Real;
+            if !(0.0..=(self.dims[i] - 1) as Real).contains(&x) { return None }
+            let x0 = (x.floor() as usize).min(self.dims[i] - 2);
+            Some((x0, crate::geom::narrow(x - x0 as Real)))
+        };
+        let (x, fx) = match coord(0, rel.0, ext.0) { Some(x) => x, None => return 0.0 };
+        let (y, fy) = match coord(1, rel.1, ext.1) { Some(x) => x, None => return 0.0 };
+        let (z, fz) = match coord(2, rel.2, ext.2) { Some(x) => x, None => return 0.0 };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let plane = |z: usize| {
+            lerp(
+                lerp(self.at(x, y, z), self.at(x + 1, y, z), fx),
+                lerp(self.at(x, y + 1, z), self.at(x + 1, y + 1, z), fx),
+                fy,
+            )
+        };
+        lerp(plane(z), plane(z + 1), fz)
+    }
+}
+
+/// Error loading volumes from files.
+#[derive(Debug)]
+pub enum VdbError {
+    Io(std::io::Error),
+    /// The file is malformed.
+    Parse(String),
+    /// The file uses features not supported, e.g., compression.
+    Unsupported(String),
+}
+impl std::fmt::Display for VdbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VdbError::Io(e) => write!(f, "failed to read volume: {}", e),
+            VdbError::Parse(msg) => write!(f, "malformed NanoVDB file: {}", msg),
+            VdbError::Unsupported(msg) => write!(f, "unsupported NanoVDB file: {}", msg),
+        }
+    }
+}
+impl std::error::Error for VdbError {}
+impl From<std::io::Error> for VdbError {
+    fn from(e: std::io::Error) -> VdbError {
+        VdbError::Io(e)
+    }
+}
+
+// Sizes and offsets in bytes of the parts of NanoVDB files, and of the nodes
+// of grids of floats.
+const NVDB_FILE_HEADER: usize = 16;
+const NVDB_FILE_META: usize = 176;
+const NVDB_GRID: usize = 672;
+const NVDB_GRID_MAT: usize = 384;
+const NVDB_GRID_VEC: usize = 528;
+const NVDB_GRID_CLASS: usize = 632;
+const NVDB_GRID_TYPE: usize = 636;
+const NVDB_ROOT: usize = 64;
+const NVDB_ROOT_TILE: usize = 32;
+const NVDB_UPPER_TABLE: usize = 8256;
+const NVDB_LOWER_TABLE: usize = 1088;
+const NVDB_LEAF_VALUES: usize = 96;
+const NVDB_GRID_TYPE_FLOAT: u32 = 1;
+const NVDB_GRID_CLASS_LEVEL_SET: u32 = 1;
+/// Largest number of samples of densified grids, 1 GiB of densities.
+const NVDB_MAX_SAMPLES: usize = 1 << 28;
+
+/// Load the first grid of an uncompressed NanoVDB file, which must be a fog
+/// volume of floats, into a dense grid covering its active voxels and a
+/// margin of one voxel of zero density. The index to world transform of the
+/// grid may scale and translate but not rotate, since density grids are
+/// axis aligned. Active tiles are filled in with their values and inactive
+/// voxels have zero density.
+///
+/// See: Ken Museth, NanoVDB: A GPU-Friendly and Portable VDB Data Structure
+/// For Real-Time Rendering And Simulation.
+pub fn load_nanovdb<P: AsRef<Path>>(path: P) -> Result<DensityGrid, VdbError> {
+    let mut buf = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut buf)?;
+    parse_nanovdb(&buf)
+}
+fn parse_nanovdb(buf: &[u8]) -> Result<DensityGrid, VdbError> {
+    let err = |msg: &str| VdbError::Parse(msg.to_owned());
+    fn bytes<const N: usize>(data: &[u8], i: usize) -> Result<[u8; N], VdbError> {
+        i.checked_add(N)
+            .and_then(|end| data.get(i..end))
+            .map(|x| {
+                let mut rv = [0; N];
+                rv.copy_from_slice(x);
+                rv
+            })
+            .ok_or_else(|| VdbError::Parse("unexpected end of file".to_owned()))
+    }
+    let u16_at = |data: &[u8], i: usize| bytes(data, i).map(u16::from_le_bytes);
+    let u32_at = |data: &[u8], i: usize| bytes(data, i).map(u32::from_le_bytes);
+    let u64_at = |data: &[u8], i: usize| bytes(data, i).map(u64::from_le_bytes);
+    let f32_at = |data: &[u8], i: usize| bytes(data, i).map(f32::from_le_bytes);
+    let f64_at = |data: &[u8], i: usize| bytes(data, i).map(f64::from_le_bytes);
+    let usize_at = |data: &[u8], i: usize| {
+        usize::try_from(u64_at(data, i)?).map_err(|_| err("offset overflows"))
+    };
+    let coord_at = |data: &[u8], i: usize| -> Result<[i32; 3], VdbError> {
+        let c = |j: usize| bytes(data, i + 4 * j).map(i32::from_le_bytes);
+        Ok([c(0)?, c(1)?, c(2)?])
+    };
+
+    // The file header and the metadata of the first grid precede the grid.
+    if !matches!(buf.get(0..8), Some(b"NanoVDB0") | Some(b"NanoVDB2")) {
+        return Err(err("missing magic number"));
+    }
+    if u16_at(buf, 12)? == 0 {
+        return Err(err("no grid"));
+    }
+    if u16_at(buf, 14)? != 0 || u16_at(buf, NVDB_FILE_HEADER + 168)? != 0 {
+        return Err(VdbError::Unsupported("compressed grid".to_owned()));
+    }
+    let grid_size = usize_at(buf, NVDB_FILE_HEADER)?;
+    let name_size = u32_at(buf, NVDB_FILE_HEADER + 136)? as usize;
+    let start = NVDB_FILE_HEADER + NVDB_FILE_META + name_size;
+    let grid = buf.get(start..start.checked_add(grid_size).ok_or_else(|| err("offset overflows"))?)
+        .ok_or_else(|| err("truncated grid"))?;
+    if grid.get(0..7) != Some(b"NanoVDB") {
+        return Err(err("missing grid magic number"));
+    }
+    if u32_at(grid, NVDB_GRID_TYPE)? != NVDB_GRID_TYPE_FLOAT {
+        return Err(VdbError::Unsupported("grid of values other than floats".to_owned()));
+    }
+    if u32_at(grid, NVDB_GRID_CLASS)? == NVDB_GRID_CLASS_LEVEL_SET {
+        return Err(VdbError::Unsupported("level set rather than fog volume".to_owned()));
+    }
+    let mut scale = [0.0; 3];
+    let mut translate = [0.0; 3];
+    for (i, (scale, translate)) in scale.iter_mut().zip(translate.iter_mut()).enumerate() {
+        for j in 0..3 {
+            let x = f64_at(grid, NVDB_GRID_MAT + 8 * (3 * i + j))?;
+            if i == j {
+                *scale = x;
+            } else if x != 0.0 {
+                return Err(VdbError::Unsupported("rotated grid".to_owned()));
+            }
+        }
+        *translate = f64_at(grid, NVDB_GRID_VEC + 8 * i)?;
+        if !(*scale > 0.0 && scale.is_finite() && translate.is_finite()) {
+            return Err(VdbError::Unsupported("flipped or degenerate grid".to_owned()));
+        }
+    }
+
+    // Nodes of each level are packed one after another, so they are visited
+    // by their offsets in the tree rather than from the root down.
+    let tree = NVDB_GRID;
+    let node_offset = |level: usize| -> Result<usize, VdbError> {
+        tree.checked_add(usize_at(grid, tree + 8 * level)?).ok_or_else(|| err("offset overflows"))
+    };
+    let node_count = |level: usize| u32_at(grid, tree + 32 + 4 * level).map(|x| x as usize);
+    let root = node_offset(3)?;
+    let bbox = [coord_at(grid, root)?, coord_at(grid, root + 12)?];
+    if (0..3).any(|i| bbox[0][i] > bbox[1][i]) {
+        return Err(err("empty grid"));
+    }
+    // The margin keeps at least two samples per axis and fades the densities
+    // out at the bounds.
+    let lo = [bbox[0][0] as i64 - 1, bbox[0][1] as i64 - 1, bbox[0][2] as i64 - 1];
+    let hi = [bbox[1][0] as i64 + 1, bbox[1][1] as i64 + 1, bbox[1][2] as i64 + 1];
+    let dims = [
+        (hi[0] - lo[0] + 1) as usize,
+        (hi[1] - lo[1] + 1) as usize,
+        (hi[2] - lo[2] + 1) as usize,
+    ];
+    let n = dims[0].checked_mul(dims[1]).and_then(|x| x.checked_mul(dims[2]))
+        .filter(|&x| x <= NVDB_MAX_SAMPLES)
+        .ok_or_else(|| VdbError::Unsupported("grid too large to densify".to_owned()))?;
+    let mut data = vec![0.0f32; n];
+    // Fill the cube of `size` voxels from `origin` with `value`, clipped to
+    // the active voxels.
+    let mut fill = |origin: [i64; 3], size: i64, value: f32| {
+        let value = value.max(0.0);
+        let range = |i: usize| {
+            origin[i].max(lo[i] + 1)..(origin[i] + size).min(hi[i])
+        };
+        for z in range(2) {
+            for y in range(1) {
+                let row = (((z - lo[2]) as usize * dims[1]) + (y - lo[1]) as usize) * dims[0];
+                for x in range(0) {
+                    data[row + (x - lo[0]) as usize] = value;
+                }
+            }
+        }
+    };
+    let is_set = |data: &[u8], mask: usize, n: usize| -> Result<bool, VdbError> {
+        Ok(u64_at(data, mask + 8 * (n / 64))? >> (n % 64) & 1 != 0)
+    };
+    let origin_of = |c: [i32; 3], mask: i32| {
+        [(c[0] & !mask) as i64, (c[1] & !mask) as i64, (c[2] & !mask) as i64]
+    };
+
+    // Active tiles of the root node, each of 4096^3 voxels.
+    let ntile = u32_at(grid, root + 24)? as usize;
+    for i in 0..ntile {
+        let tile = root + NVDB_ROOT + NVDB_ROOT_TILE * i;
+        if u64_at(grid, tile + 8)? != 0 || u32_at(grid, tile + 16)? == 0 { continue }
+        let key = u64_at(grid, tile)?;
+        let axis = |shift: u32| ((((key >> shift) & 0x1f_ffff) << 12) as u32 as i32) as i64;
+        fill([axis(42), axis(21), axis(0)], 4096, f32_at(grid, tile + 20)?);
+    }
+    // Active tiles of the upper and lower internal nodes, and active voxels
+    // of the leaves.
+    let levels: [(usize, i32, usize, usize); 2] = [
+        (2, 5, NVDB_UPPER_TABLE, 4096),
+        (1, 4, NVDB_LOWER_TABLE, 512),
+    ];
+    for &(level, log2dim, table, mask_size) in levels.iter() {
+        let child_log2 = if level == 2 { 7 } else { 3 };
+        let node_size = table + (8 << (3 * log2dim));
+        let first = node_offset(level)?;
+        for i in 0..node_count(level)? {
+            let node = first + node_size * i;
+            let origin = origin_of(coord_at(grid, node)?, (1 << (log2dim + child_log2)) - 1);
+            for j in 0..1usize << (3 * log2dim) {
+                if !is_set(grid, node + 32, j)? || is_set(grid, node + 32 + mask_size, j)? {
+                    continue;
+                }
+                let dim = 1 << log2dim;
+                let (x, y, z) = (j >> (2 * log2dim), (j >> log2dim) & (dim - 1), j & (dim - 1));
+                let tile = [
+                    origin[0] + ((x as i64) << child_log2),
+                    origin[1] + ((y as i64) << child_log2),
+                    origin[2] + ((z as i64) << child_log2),
+                ];
+                fill(tile, 1 << child_log2, f32_at(grid, node + table + 8 * j)?);
+            }
+        }
+    }
+    let first = node_offset(0)?;
+    for i in 0..node_count(0)? {
+        let leaf = first + (NVDB_LEAF_VALUES + 4 * 512) * i;
+        let origin = origin_of(coord_at(grid, leaf)?, 7);
+        for j in 0..512 {
+            if !is_set(grid, leaf + 16, j)? { continue }
+            let voxel = [
+                origin[0] + (j >> 6) as i64,
+                origin[1] + ((j >> 3) & 7) as i64,
+                origin[2] + (j & 7) as i64,
+            ];
+            fill(voxel, 1, f32_at(grid, leaf + NVDB_LEAF_VALUES + 4 * j)?);
+        }
+    }
+    let world = |c: [i64; 3]| {
+        Point(
+            (scale[0] * c[0] as f64 + translate[0]) as Real,
+            (scale[1] * c[1] as f64 + translate[1]) as Real,
+            (scale[2] * c[2] as f64 + translate[2]) as Real,
+        )
+    };
+    let bounds = Aabb { min: world(lo), max: world(hi) };
+    Ok(DensityGrid::new(bounds, dims, data))
+}
+
+/// Participating medium of varying density that absorbs and scatters light,
+/// e.g., smoke and clouds.
+#[derive(Debug, Clone)]
+pub struct HeterogeneousMedium {
+    pub density: DensityGrid,
+    /// Extinction coefficient at unit density, per unit length.
+    pub sigma_t: f32,
+    /// Fraction of extinction that is scattering rather than absorption.
+    pub albedo: Color,
+    /// Asymmetry of the Henyey-Greenstein phase function in (-1, 1). Positive
+    /// values scatter forwards.
+    pub g: Real,
+}
+impl HeterogeneousMedium {
+    /// Majorant of the extinction coefficient.
+    fn majorant(&self) -> Real {
+        (self.density.max_density() * self.sigma_t) as Real
+    }
+    /// Sample the distance along `ray` to the next collision with the medium
+    /// before `tmax`, by delta tracking. `rng` returns uniform random numbers
+    /// in [0..1). Returns `None` if the ray passes through, in which case the
+    /// ray carries the same radiance as in vacuum; otherwise the ray scatters
+    /// at the returned distance with weight `albedo`.
+    ///
+    /// See: E. R. Woodcock et al., Techniques Used in the GEM Code for Monte
+    /// Carlo Neutronics Calculations in Reactors and Other Systems of Complex
+    /// Geometry.
+    pub fn sample_distance<R>(&self, ray: &Ray, tmax: Real, rng: &mut R) -> Option<Real>
+        where R: FnMut() -> Real
+    {
+        let (t0, t1) = self.density.bounds.clip(ray)?;
+        let t1 = t1.min(tmax);
+        let majorant = self.majorant() * ray.v.mag();
+        if majorant <= 0.0 { return None }
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rng()).ln() / majorant;
+            if t >= t1 { return None }
+            let p = ray.o.affine_add(t * ray.v);
+            // Real collisions happen in proportion to the actual density; the
+            // rest are null collisions that change nothing.
+            let density = self.density.density(p) as Real;
+            if rng() * (self.density.max_density() as Real) < density {
+                return Some(t);
+            }
+        }
+    }
+    /// Estimate the transmittance along `ray` up to `tmax`, by ratio tracking.
+    ///
+    /// See: Jan Novák et al., Residual Ratio Tracking for Estimating
+    /// Attenuation in Participating Media.
+    pub fn transmittance<R>(&self, ray: &Ray, tmax: Real, rng: &mut R) -> Real
+        where R: FnMut() -> Real
+    {
+        let (t0, t1) = match self.density.bounds.clip(ray) {
+            Some(x) => x,
+            None => return 1.0,
+        };
+        let t1 = t1.min(tmax);
+        let majorant = self.majorant() * ray.v.mag();
+        if majorant <= 0.0 { return 1.0 }
+        let max = self.density.max_density() as Real;
+        let mut tr = 1.0;
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rng()).ln() / majorant;
+            if t >= t1 { return tr }
+            let p = ray.o.affine_add(t * ray.v);
+            tr *= 1.0 - self.density.density(p) as Real / max;
+        }
+    }
+    /// Sample the direction a ray of direction `v` scatters to, from `a` and
+    /// `b` in [0..1).
+    pub fn sample_phase(&self, v: Vector, a: Real, b: Real) -> Vector {
+        sample_henyey_greenstein(self.g, v, a, b)
+    }
+}
+
+/// Fog thinning out exponentially with height, e.g., for atmospheric depth
+/// cues in outdoor scenes. Unlike `HeterogeneousMedium` it only attenuates
+/// the light along camera rays and adds its own color, in closed form without
+/// tracing the medium.
+#[derive(Debug, Clone, Copy)]
+pub struct HeightFog {
+    /// Radiance scattered towards the camera by fog of unlimited thickness.
+    pub color: Color,
+    /// Extinction coefficient per unit length at `height`.
+    pub density: Real,
+    /// Rate at which the density falls off per unit height; the density
+    /// halves every `ln 2 / falloff` units above `height`. Uniform fog at 0.
+    pub falloff: Real,
+    /// Height where the density is `density`.
+    pub height: Real,
+    /// Unit vector pointing up.
+    pub up: Vector,
+}
+impl HeightFog {
+    /// Optical depth along `ray` up to parametric distance `t`, infinite if
+    /// `t` is.
+    pub fn optical_depth(&self, ray: &Ray, t: Real) -> Real {
+        let h0 = ray.o.rel_from(Point(0.0, 0.0, 0.0)).dot(self.up) - self.height;
+        let dh = ray.v.dot(self.up);
+        let k = self.falloff * dh;
+        // Integral of density * exp(-falloff * (h0 + s * dh)) over s in
+        // [0, t], times the length of `ray.v`.
+        let x = if t.is_infinite() {
+            if k <= 0.0 { return Real::INFINITY }
+            k.recip()
+        } else if (k * t).abs() < 1e-4 {
+            // Series of the closed form below, which divides by 0 as `k`
+            // does. It only holds while `k * t` is small, however small `k`
+            // is, e.g., for nearly level rays traced far.
+            t * (1.0 - 0.5 * k * t)
+        } else {
+            -(-k * t).exp_m1() / k
+        };
+        self.density * (-self.falloff * h0).exp() * x * ray.v.mag()
+    }
+    /// Fraction of light passing through the fog along `ray` up to
+    /// parametric distance `t`.
+    pub fn transmittance(&self, ray: &Ray, t: Real) -> Real {
+        (-self.optical_depth(ray, t)).exp()
+    }
+    /// Radiance `color` from parametric distance `t` along `ray` as seen
+    /// through the fog from the origin of `ray`.
+    pub fn apply(&self, color: Color, ray: &Ray, t: Real) -> Color {
+        let tr = narrow(self.transmittance(ray, t));
+        color * tr + self.color * (1.0 - tr)
+    }
+}
+
+/// Henyey-Greenstein phase function of asymmetry `g` at the cosine `cos` of the
+/// angle between the incoming and outgoing directions.
+pub fn henyey_greenstein(g: Real, cos: Real) -> Real {
+    use std::f64::consts::PI;
+    let denom = 1.0 + g * g - 2.0 * g * cos;
+    (1.0 - g * g) / (4.0 * PI as Real * denom * denom.sqrt())
+}
+/// Sample a direction scattered from direction `v` by the Henyey-Greenstein
+/// phase function of asymmetry `g`, from `a` and `b` in [0..1).
+pub fn sample_henyey_greenstein(g: Real, v: Vector, a: Real, b: Real) -> Vector {
+    use std::f64::consts::PI;
+    let cos = if g.abs() < 1e-3 {
+        1.0 - 2.0 * a
+    } else {
+        let x = (1.0 - g * g) / (1.0 + g - 2.0 * g * a);
+        (1.0 + g * g - x * x) / (2.0 * g)
+    };
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let (sin_phi, cos_phi) = (2.0 * PI as Real * b).sin_cos();
+    let z = v.normalize();
+    let up = if z.0.abs() < 0.9 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+    let x = up.cross(z).normalize();
+    let y = z.cross(x);
+    Vector(sin * cos_phi, sin * sin_phi, cos).in_basis(x, y, z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(buf: &mut [u8], i: usize, bytes: &[u8]) {
+        buf[i..i + bytes.len()].copy_from_slice(bytes);
+    }
+    fn put_coord(buf: &mut [u8], i: usize, c: [i32; 3]) {
+        for (j, x) in c.iter().enumerate() {
+            put(buf, i + 4 * j, &x.to_le_bytes());
+        }
+    }
+    /// A NanoVDB file of a fog volume of voxel size 0.5 translated by 1
+    /// along x, with one node per level: a leaf at the origin holding
+    /// `voxels`, and an active tile of value 1 in its lower node covering
+    /// [8, 16) x [0, 8) x [0, 8).
+    fn nvdb(voxels: &[([usize; 3], f32)], mat: [f64; 9]) -> Vec<u8> {
+        let leaf_size = NVDB_LEAF_VALUES + 4 * 512;
+        let lower_size = NVDB_LOWER_TABLE + 8 * 4096;
+        let upper_size = NVDB_UPPER_TABLE + 8 * 32768;
+        let root = NVDB_GRID + 64;
+        let upper = root + NVDB_ROOT;
+        let lower = upper + upper_size;
+        let leaf = lower + lower_size;
+        let mut grid = vec![0; leaf + leaf_size];
+        put(&mut grid, 0, b"NanoVDB0");
+        for (i, x) in mat.iter().enumerate() {
+            put(&mut grid, NVDB_GRID_MAT + 8 * i, &x.to_le_bytes());
+        }
+        put(&mut grid, NVDB_GRID_VEC, &1.0f64.to_le_bytes());
+        put(&mut grid, NVDB_GRID_CLASS, &2u32.to_le_bytes());
+        put(&mut grid, NVDB_GRID_TYPE, &NVDB_GRID_TYPE_FLOAT.to_le_bytes());
+        for (i, node) in [leaf, lower, upper, root].iter().enumerate() {
+            put(&mut grid, NVDB_GRID + 8 * i, &((node - NVDB_GRID) as u64).to_le_bytes());
+        }
+        for i in 0..3 {
+            put(&mut grid, NVDB_GRID + 32 + 4 * i, &1u32.to_le_bytes());
+        }
+        put_coord(&mut grid, root, [1, 0, 0]);
+        put_coord(&mut grid, root + 12, [15, 7, 7]);
+        put_coord(&mut grid, upper, [0, 0, 0]);
+        put_coord(&mut grid, lower, [0, 0, 0]);
+        let tile = 1 << 8;
+        grid[lower + 32 + tile / 8] |= 1 << (tile % 8);
+        put(&mut grid, lower + NVDB_LOWER_TABLE + 8 * tile, &1.0f32.to_le_bytes());
+        for &([x, y, z], value) in voxels.iter() {
+            let j = x << 6 | y << 3 | z;
+            grid[leaf + 16 + j / 8] |= 1 << (j % 8);
+            put(&mut grid, leaf + NVDB_LEAF_VALUES + 4 * j, &value.to_le_bytes());
+        }
+        put_coord(&mut grid, leaf, [1, 2, 3]);
+
+        let name = b"density\0";
+        let mut rv = vec![0; NVDB_FILE_HEADER + NVDB_FILE_META + name.len()];
+        put(&mut rv, 0, b"NanoVDB2");
+        put(&mut rv, 12, &1u16.to_le_bytes());
+        put(&mut rv, NVDB_FILE_HEADER, &(grid.len() as u64).to_le_bytes());
+        put(&mut rv, NVDB_FILE_HEADER + 8, &(grid.len() as u64).to_le_bytes());
+        put(&mut rv, NVDB_FILE_HEADER + 136, &(name.len() as u32).to_le_bytes());
+        put(&mut rv, NVDB_FILE_HEADER + NVDB_FILE_META, name);
+        rv.extend(grid);
+        rv
+    }
+    const SCALE: [f64; 9] = [0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5];
+
+    #[test]
+    fn nanovdb_densities() {
+        let grid = parse_nanovdb(&nvdb(&[([1, 2, 3], 2.0), ([2, 2, 3], 4.0)], SCALE)).unwrap();
+        assert_eq!(grid.max_density(), 4.0);
+        // One voxel of margin around the active voxels [1, 15] x [0, 7]^2.
+        let (min, max) = (grid.bounds.min, grid.bounds.max);
+        assert_eq!((min.0, min.1, min.2), (1.0, -0.5, -0.5));
+        assert_eq!((max.0, max.1, max.2), (9.0, 4.0, 4.0));
+        assert_eq!(grid.density(Point(1.5, 1.0, 1.5)), 2.0);
+        assert_eq!(grid.density(Point(2.0, 1.0, 1.5)), 4.0);
+        assert_eq!(grid.density(Point(1.75, 1.0, 1.5)), 3.0);
+        assert_eq!(grid.density(Point(6.0, 2.5, 2.5)), 1.0);
+        assert_eq!(grid.density(Point(3.0, 2.0, 2.0)), 0.0);
+        assert_eq!(grid.density(Point(8.75, 2.0, 2.0)), 0.5);
+    }
+    #[test]
+    fn nanovdb_malformed() {
+        let file = nvdb(&[([1, 2, 3], 2.0)], SCALE);
+        assert!(matches!(parse_nanovdb(&file[..file.len() - 1]), Err(VdbError::Parse(_))));
+        assert!(matches!(parse_nanovdb(&file[..100]), Err(VdbError::Parse(_))));
+        let mut compressed = file.clone();
+        put(&mut compressed, 14, &2u16.to_le_bytes());
+        assert!(matches!(parse_nanovdb(&compressed), Err(VdbError::Unsupported(_))));
+        let mut overflow = file.clone();
+        put(&mut overflow, NVDB_FILE_HEADER, &u64::MAX.to_le_bytes());
+        assert!(matches!(parse_nanovdb(&overflow), Err(VdbError::Parse(_))));
+        let mut rotated = SCALE;
+        rotated[1] = 0.1;
+        let rotated = nvdb(&[([1, 2, 3], 2.0)], rotated);
+        assert!(matches!(parse_nanovdb(&rotated), Err(VdbError::Unsupported(_))));
+    }
+    #[test]
+    fn height_fog_nearly_level_rays() {
+        let fog = HeightFog {
+            color: Color::default(),
+            density: 1.0,
+            falloff: 1.0,
+            height: 0.0,
+            up: Vector(0.0, 1.0, 0.0),
+        };
+        let depth = |dh: Real, t: Real| {
+            fog.optical_depth(&Ray { o: Point(0.0, 0.0, 0.0), v: Vector(1.0, dh, 0.0) }, t)
+        };
+        assert_eq!(depth(0.0, 2.0), 2.0);
+        assert_eq!(depth(0.0, Real::INFINITY), Real::INFINITY);
+        assert_eq!(depth(-1e-7, Real::INFINITY), Real::INFINITY);
+        // The density falls by `e` over the ray, however slowly it climbs.
+        let expected = (1.0 - (-1.0 as Real).exp()) * 1e7;
+        assert!((depth(1e-7, 1e7) / expected - 1.0).abs() < 1e-3);
+        assert!((depth(1e-7, Real::INFINITY) / 1e7 - 1.0).abs() < 1e-3);
+    }
+}
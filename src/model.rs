@@ -1,37 +1,45 @@
-use crate::geom::{Point, Transform};
-use crate::scene::Object;
+use crate::geom::{Real, Point, Transform};
+use crate::scene::{Object, Visibility};
 
 pub fn make_cube<M>(mat: M, world2obj: Transform) -> Object<M> {
     let obj2world = world2obj.inverse();
-    const p: f32 = 0.5;
-    const n: f32 = -0.5;
+    const P: Real = 0.5;
+    const N: Real = -0.5;
     let verts = vec![
-        Point(n,p,n),
-        Point(n,p,p),
-        Point(p,p,p),
-        Point(p,p,n),
-        Point(n,n,n),
-        Point(n,n,p),
-        Point(p,n,p),
-        Point(p,n,n),
+        Point(N,P,N),
+        Point(N,P,P),
+        Point(P,P,P),
+        Point(P,P,N),
+        Point(N,N,N),
+        Point(N,N,P),
+        Point(P,N,P),
+        Point(P,N,N),
     ];
-    const a: usize = 0;
-    const b: usize = 1;
-    const c: usize = 2;
-    const d: usize = 3;
-    const e: usize = 4;
-    const f: usize = 5;
-    const g: usize = 6;
-    const h: usize = 7;
+    const A: usize = 0;
+    const B: usize = 1;
+    const C: usize = 2;
+    const D: usize = 3;
+    const E: usize = 4;
+    const F: usize = 5;
+    const G: usize = 6;
+    const H: usize = 7;
     let idxs = vec![
-        (f, e, a), (f, a, b),
-        (g, f, b), (g, b, c),
-        (h, g, c), (h, c, d),
-        (e, h, d), (e, d, a),
-        (a, d, c), (a, c, b),
-        (e, f, g), (e, g, h),
+        (F, E, A), (F, A, B),
+        (G, F, B), (G, B, C),
+        (H, G, C), (H, C, D),
+        (E, H, D), (E, D, A),
+        (A, D, C), (A, C, B),
+        (E, F, G), (E, G, H),
     ];
-    Object { verts, idxs, mat, obj2world, world2obj }
+    let visibility = Visibility::default();
+    Object {
+        verts, idxs, mat, obj2world, world2obj, visibility,
+        cull_backfaces: false,
+        normals: None,
+        colors: None,
+        name: None,
+        shape: None,
+    }
 }
 
 pub fn make_pln<M>(mat: M, world2obj: Transform) -> Object<M> {
@@ -45,5 +53,152 @@ pub fn make_pln<M>(mat: M, world2obj: Transform) -> Object<M> {
     let idxs = vec![
         (0, 1, 2), (0, 2, 3),
     ];
-    Object { verts, idxs, mat, obj2world, world2obj }
+    let visibility = Visibility::default();
+    Object {
+        verts, idxs, mat, obj2world, world2obj, visibility,
+        cull_backfaces: false,
+        normals: None,
+        colors: None,
+        name: None,
+        shape: None,
+    }
+}
+
+/// A plane like `make_pln` subdivided into `nsubdiv` by `nsubdiv` quads, with
+/// every vertex lifted along the normal by `height(x, z)`, where `x` and `z`
+/// are in [-0.5, 0.5] in object space. `noise::fbm` gives natural looking
+/// terrain.
+pub fn make_displaced_pln<M, F>(
+    mat: M,
+    world2obj: Transform,
+    nsubdiv: usize,
+    height: F,
+) -> Object<M>
+    where F: Fn(Real, Real) -> Real
+{
+    let obj2world = world2obj.inverse();
+    let nsubdiv = nsubdiv.max(1);
+    let nvert = nsubdiv + 1;
+    let coord = |i: usize| i as Real / nsubdiv as Real - 0.5;
+    let mut verts = Vec::with_capacity(nvert * nvert);
+    for i in 0..nvert {
+        for j in 0..nvert {
+            let (x, z) = (coord(i), coord(j));
+            verts.push(Point(x, height(x, z), z));
+        }
+    }
+    let mut idxs = Vec::with_capacity(nsubdiv * nsubdiv * 2);
+    for i in 0..nsubdiv {
+        for j in 0..nsubdiv {
+            // Corners in the same order as those of `make_pln`.
+            let a = i * nvert + j;
+            let b = a + 1;
+            let c = b + nvert;
+            let d = a + nvert;
+            idxs.push((a, b, c));
+            idxs.push((a, c, d));
+        }
+    }
+    let visibility = Visibility::default();
+    Object {
+        verts, idxs, mat, obj2world, world2obj, visibility,
+        cull_backfaces: false,
+        normals: None,
+        colors: None,
+        name: None,
+        shape: None,
+    }
+}
+
+/// A UV sphere of diameter 1 like the unit cube of `make_cube`, with `nring`
+/// rings of quads from pole to pole along the y-axis, each split into
+/// `nseg` segments around it.
+pub fn make_sphere<M>(mat: M, world2obj: Transform, nring: usize, nseg: usize) -> Object<M> {
+    const PI: Real = std::f64::consts::PI as Real;
+    let obj2world = world2obj.inverse();
+    let nring = nring.max(2);
+    let nseg = nseg.max(3);
+    // Poles first, then the vertices of the `nring - 1` latitudes in between.
+    let mut verts = vec![Point(0.0, 0.5, 0.0), Point(0.0, -0.5, 0.0)];
+    for i in 1..nring {
+        let (sin_theta, cos_theta) = (i as Real / nring as Real * PI).sin_cos();
+        for j in 0..nseg {
+            let (sin_phi, cos_phi) = (j as Real / nseg as Real * 2.0 * PI).sin_cos();
+            verts.push(Point(
+                0.5 * sin_theta * cos_phi,
+                0.5 * cos_theta,
+                0.5 * sin_theta * sin_phi,
+            ));
+        }
+    }
+    let vert = |i: usize, j: usize| 2 + (i - 1) * nseg + j % nseg;
+    let mut idxs = Vec::with_capacity(2 * nseg * (nring - 1));
+    for j in 0..nseg {
+        idxs.push((0, vert(1, j + 1), vert(1, j)));
+        idxs.push((1, vert(nring - 1, j), vert(nring - 1, j + 1)));
+    }
+    for i in 1..nring - 1 {
+        for j in 0..nseg {
+            let (a, b) = (vert(i, j), vert(i, j + 1));
+            let (c, d) = (vert(i + 1, j + 1), vert(i + 1, j));
+            idxs.push((a, b, c));
+            idxs.push((a, c, d));
+        }
+    }
+    let visibility = Visibility::default();
+    Object {
+        verts, idxs, mat, obj2world, world2obj, visibility,
+        cull_backfaces: false,
+        normals: None,
+        colors: None,
+        name: None,
+        shape: None,
+    }
+}
+
+/// A torus around the y-axis fitting the unit cube of `make_cube`, i.e., of
+/// outer diameter 1, with a tube of radius `minor`. There are `nmajor`
+/// segments around the y-axis, each split into `nminor` segments around the
+/// tube.
+pub fn make_torus<M>(
+    mat: M,
+    world2obj: Transform,
+    minor: Real,
+    nmajor: usize,
+    nminor: usize,
+) -> Object<M> {
+    const PI: Real = std::f64::consts::PI as Real;
+    let obj2world = world2obj.inverse();
+    let minor = minor.clamp(0.0, 0.25);
+    let major = 0.5 - minor;
+    let nmajor = nmajor.max(3);
+    let nminor = nminor.max(3);
+    let mut verts = Vec::with_capacity(nmajor * nminor);
+    for i in 0..nmajor {
+        let (sin_phi, cos_phi) = (i as Real / nmajor as Real * 2.0 * PI).sin_cos();
+        for j in 0..nminor {
+            let (sin_theta, cos_theta) = (j as Real / nminor as Real * 2.0 * PI).sin_cos();
+            let r = major + minor * cos_theta;
+            verts.push(Point(r * cos_phi, minor * sin_theta, r * sin_phi));
+        }
+    }
+    let vert = |i: usize, j: usize| (i % nmajor) * nminor + j % nminor;
+    let mut idxs = Vec::with_capacity(2 * nmajor * nminor);
+    for i in 0..nmajor {
+        for j in 0..nminor {
+            let (a, b) = (vert(i, j), vert(i, j + 1));
+            let (c, d) = (vert(i + 1, j + 1), vert(i + 1, j));
+            idxs.push((a, b, c));
+            idxs.push((a, c, d));
+        }
+    }
+    let visibility = Visibility::default();
+    Object {
+        verts, idxs, mat, obj2world, world2obj, visibility,
+        cull_backfaces: false,
+        normals: None,
+        colors: None,
+        name: None,
+        shape: None,
+    }
 }
@@ -0,0 +1,116 @@
+use crate::geom::Real;
+
+/// Hash of integer lattice point `(x, y)` to a pseudo-random 32-bit integer.
+#[inline]
+pub(crate) fn hash2(x: i32, y: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^ (h >> 15)
+}
+
+/// 2D gradient noise in about [-1, 1], smoothly varying with features about
+/// one unit apart. It's zero at integer coordinates.
+///
+/// See: Ken Perlin, Improving Noise.
+pub fn perlin(x: Real, y: Real) -> Real {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (ix, iy) = (x0 as i32, y0 as i32);
+    let grad = |ix: i32, iy: i32, dx: Real, dy: Real| {
+        // One of eight evenly spread gradient directions.
+        match hash2(ix, iy) & 7 {
+            0 => dx + dy,
+            1 => dx - dy,
+            2 => -dx + dy,
+            3 => -dx - dy,
+            4 => dx,
+            5 => -dx,
+            6 => dy,
+            _ => -dy,
+        }
+    };
+    let fade = |t: Real| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let lerp = |a: Real, b: Real, t: Real| a + (b - a) * t;
+    let (u, v) = (fade(fx), fade(fy));
+    let n00 = grad(ix, iy, fx, fy);
+    let n10 = grad(ix + 1, iy, fx - 1.0, fy);
+    let n01 = grad(ix, iy + 1, fx, fy - 1.0);
+    let n11 = grad(ix + 1, iy + 1, fx - 1.0, fy - 1.0);
+    lerp(lerp(n00, n10, u), lerp(n01, n11, u), v)
+}
+
+/// Fractal Brownian motion, i.e., `octaves` layers of `perlin` noise, each of
+/// twice the frequency and half the amplitude of the previous one.
+pub fn fbm(x: Real, y: Real, octaves: u32) -> Real {
+    let mut rv = 0.0;
+    let mut freq = 1.0;
+    let mut amp = 0.5;
+    for _ in 0..octaves {
+        rv += amp * perlin(x * freq, y * freq);
+        freq *= 2.0;
+        amp *= 0.5;
+    }
+    rv
+}
+
+/// Hash of integer lattice point `(x, y, z)` to a pseudo-random 32-bit
+/// integer.
+#[inline]
+fn hash3(x: i32, y: i32, z: i32) -> u32 {
+    hash2(x, y) ^ hash2(z, 0x27d4_eb2d_u32 as i32).rotate_left(16)
+}
+
+/// 3D gradient noise like `perlin`, e.g., for volumes.
+pub fn perlin3(x: Real, y: Real, z: Real) -> Real {
+    let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+    let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+    let (ix, iy, iz) = (x0 as i32, y0 as i32, z0 as i32);
+    let grad = |ix: i32, iy: i32, iz: i32, dx: Real, dy: Real, dz: Real| {
+        // One of the twelve directions to the edges of a cube.
+        match hash3(ix, iy, iz) % 12 {
+            0 => dx + dy,
+            1 => -dx + dy,
+            2 => dx - dy,
+            3 => -dx - dy,
+            4 => dx + dz,
+            5 => -dx + dz,
+            6 => dx - dz,
+            7 => -dx - dz,
+            8 => dy + dz,
+            9 => -dy + dz,
+            10 => dy - dz,
+            _ => -dy - dz,
+        }
+    };
+    let fade = |t: Real| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let lerp = |a: Real, b: Real, t: Real| a + (b - a) * t;
+    let (u, v, w) = (fade(fx), fade(fy), fade(fz));
+    let corner = |i: i32, j: i32, k: i32| {
+        grad(ix + i, iy + j, iz + k, fx - i as Real, fy - j as Real, fz - k as Real)
+    };
+    let z0 = lerp(
+        lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+        lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+        v);
+    let z1 = lerp(
+        lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+        lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+        v);
+    lerp(z0, z1, w)
+}
+
+/// Fractal Brownian motion of `perlin3` noise like `fbm`.
+pub fn fbm3(x: Real, y: Real, z: Real, octaves: u32) -> Real {
+    let mut rv = 0.0;
+    let mut freq = 1.0;
+    let mut amp = 0.5;
+    for _ in 0..octaves {
+        rv += amp * perlin3(x * freq, y * freq, z * freq);
+        freq *= 2.0;
+        amp *= 0.5;
+    }
+    rv
+}
@@ -0,0 +1,213 @@
+//! Rendering on large multi-socket machines, where each socket is a NUMA
+//! node with memory of its own. Threads reading the scene from memory of
+//! another node are slowed down by the interconnect, which shows once the
+//! acceleration structure and textures outgrow the caches. `NumaRenderer`
+//! runs a thread pool per node, optionally pinning each thread to the CPUs
+//! of its node and giving each node a replica of the tracer in its own
+//! memory. Machines with a single node are rendered like with
+//! `RayTracer::draw`.
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::rt::{RayTracer, Framebuffer, morton_order};
+use crate::par::*;
+
+const TILE_SIZE: usize = 16;
+
+/// A NUMA node and the CPUs on it.
+#[derive(Debug, Clone)]
+pub struct NumaNode {
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+/// Parse CPU lists of the Linux sysfs like `0-3,8,10-11`.
+fn parse_cpulist(x: &str) -> Option<Vec<usize>> {
+    let mut rv = Vec::new();
+    for range in x.trim().split(',').filter(|x| !x.is_empty()) {
+        let mut ends = range.splitn(2, '-');
+        let a = ends.next()?.parse::<usize>().ok()?;
+        let b = match ends.next() {
+            Some(b) => b.parse::<usize>().ok()?,
+            None => a,
+        };
+        if b < a { return None }
+        rv.extend(a..=b);
+    }
+    Some(rv)
+}
+
+/// NUMA nodes of the machine with CPUs, from the Linux sysfs. Elsewhere, or
+/// if the topology can't be read, all CPUs are reported as a single node.
+pub fn numa_nodes() -> Vec<NumaNode> {
+    let mut nodes = std::fs::read_dir("/sys/devices/system/node")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let id = entry.file_name().to_str()?.strip_prefix("node")?.parse::<usize>().ok()?;
+            let cpulist = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            let cpus = parse_cpulist(&cpulist)?;
+            if cpus.is_empty() { return None }
+            Some(NumaNode { id, cpus })
+        })
+        .collect::<Vec<_>>();
+    if nodes.is_empty() {
+        let ncpu = std::thread::available_parallelism().map_or(1, |x| x.get());
+        nodes.push(NumaNode { id: 0, cpus: (0..ncpu).collect() });
+    }
+    nodes.sort_by_key(|x| x.id);
+    nodes
+}
+
+/// Pin the calling thread to `cpus`. Returns whether it succeeded; pinning
+/// is only supported on Linux.
+pub fn pin_current_thread(cpus: &[usize]) -> bool {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        libc::CPU_ZERO(&mut set);
+        let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+        for &cpu in cpus.iter().filter(|&&x| x < max) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = cpus;
+        false
+    }
+}
+
+/// How `NumaRenderer` places threads and data.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Placement {
+    /// Pin each thread to one CPU of its node, so that the OS doesn't migrate
+    /// it away from its caches. Threads are otherwise only kept on the CPUs
+    /// of their node.
+    pub pin: bool,
+    /// Build a replica of the tracer on each node, in memory local to it.
+    /// It trades memory for bandwidth, as the scene is held once per node.
+    pub replicate: bool,
+}
+
+/// Error setting up a `NumaRenderer`.
+#[derive(Debug)]
+pub enum NumaError {
+    /// The nodes to render on are unusable, e.g., a node without CPUs.
+    Nodes(String),
+    /// The render threads of a node failed to spawn.
+    ThreadPool(rayon::ThreadPoolBuildError),
+}
+impl std::fmt::Display for NumaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumaError::Nodes(msg) => write!(f, "invalid NUMA nodes: {}", msg),
+            NumaError::ThreadPool(e) => write!(f, "failed to spawn render threads: {}", e),
+        }
+    }
+}
+impl std::error::Error for NumaError {}
+impl From<rayon::ThreadPoolBuildError> for NumaError {
+    fn from(e: rayon::ThreadPoolBuildError) -> NumaError {
+        NumaError::ThreadPool(e)
+    }
+}
+
+/// Renderer with a thread pool per NUMA node, see the module documentation.
+pub struct NumaRenderer<T> {
+    /// Thread pool of each node with the tracer it renders with.
+    nodes: Vec<(rayon::ThreadPool, Arc<T>)>,
+}
+impl<T: RayTracer> NumaRenderer<T> {
+    /// Set up a renderer over `nodes`, e.g., from `numa_nodes`, building the
+    /// tracer with `make`. With `Placement::replicate`, `make` is called once
+    /// per node from a thread of that node, so that the memory it allocates
+    /// is local to the node by the first-touch policy of the OS. Fails if
+    /// there's no node, or a node has no CPUs.
+    pub fn new<F>(nodes: &[NumaNode], placement: Placement, make: F) -> Result<NumaRenderer<T>, NumaError>
+        where F: Fn() -> T + Sync,
+    {
+        if nodes.is_empty() {
+            return Err(NumaError::Nodes("no node to render on".to_owned()));
+        }
+        let mut shared = None;
+        let mut rv = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            if node.cpus.is_empty() {
+                return Err(NumaError::Nodes(format!("node {} has no CPUs", node.id)));
+            }
+            let cpus = node.cpus.clone();
+            let id = node.id;
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(cpus.len())
+                .thread_name(move |i| format!("lighar-numa-{}-{}", id, i))
+                .start_handler(move |i| {
+                    if placement.pin {
+                        pin_current_thread(&cpus[i % cpus.len()..][..1]);
+                    } else {
+                        pin_current_thread(&cpus);
+                    }
+                })
+                .build()?;
+            let rt = if placement.replicate {
+                Arc::new(pool.install(&make))
+            } else {
+                shared.get_or_insert_with(|| Arc::new(make())).clone()
+            };
+            rv.push((pool, rt));
+        }
+        Ok(NumaRenderer { nodes: rv })
+    }
+    /// The tracer of each node, the same one for all without replication.
+    pub fn tracers(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().map(|(_, rt)| &**rt)
+    }
+    /// Same as `RayTracer::draw` with the threads of every node. Nodes take
+    /// tiles from a shared queue so that faster nodes do more of the work.
+    pub fn draw<FB: Framebuffer>(&self, framebuf: &mut FB) {
+        let w = framebuf.width();
+        let h = framebuf.height();
+        let order = morton_order(w, h);
+        let tiles = order.chunks(TILE_SIZE * TILE_SIZE).collect::<Vec<_>>();
+        let next = AtomicUsize::new(0);
+        let framebuf = Mutex::new(framebuf);
+        let work = |rt: &T| while let Some(tile) = tiles.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let colors = tile.iter()
+                .map(|&(x, y)| rt.ray_gen(x, y, w, h))
+                .collect::<Vec<_>>();
+            let mut framebuf = framebuf.lock().unwrap();
+            for (&(x, y), color) in tile.iter().zip(colors) {
+                framebuf.store(x, y, color);
+            }
+            framebuf.end_tile(tile);
+        };
+        // Every thread of every node works off the queue until it's empty.
+        self.nodes.par_iter()
+            .for_each(|(pool, rt)| {
+                pool.install(|| {
+                    (0..pool.current_num_threads()).into_par_iter()
+                        .for_each(|_| work(rt));
+                });
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpulists() {
+        assert_eq!(parse_cpulist("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpulist("5"), Some(vec![5]));
+        assert_eq!(parse_cpulist("2-2"), Some(vec![2]));
+        // Nodes without CPUs have empty lists.
+        assert_eq!(parse_cpulist(""), Some(vec![]));
+        assert_eq!(parse_cpulist("\n"), Some(vec![]));
+        for x in ["a", "1-", "-1", "3-1", "1-2-3", "1,x", "-"] {
+            assert_eq!(parse_cpulist(x), None, "{}", x);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Wavefront obj mesh import. Polygons are triangulated as fans, and every
+//! triangle corner gets a shading normal: the one given by the file if any,
+//! or otherwise one generated from the faces around the vertex that share
+//! its smoothing group (`s N`). Faces out of any smoothing group (`s off` or
+//! `s 0`) are shaded flat, so hard-surface models keep their crisp edges
+//! while curved regions shade smoothly. Texture coordinates, groups and
+//! materials are ignored. Obj files don't record their unit of length, so
+//! meshes are taken to be in meters unless told otherwise with
+//! `ObjMesh::with_unit`. Likewise they are taken to be right-handed with the
+//! y-axis up and counter-clockwise faces, see `CoordinateSystem::OBJ`, and
+//! files of other tools are loaded with `load_obj_with`.
+use std::collections::HashMap;
+use std::path::Path;
+use crate::geom::{Point, Vector, Transform, CoordinateSystem};
+use crate::scene::{Object, Visibility};
+use crate::units::LengthUnit;
+
+/// Error loading obj files.
+#[derive(Debug)]
+pub enum ObjError {
+    Io(std::io::Error),
+    Parse(String),
+}
+impl std::fmt::Display for ObjError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjError::Io(e) => write!(f, "failed to read mesh: {}", e),
+            ObjError::Parse(msg) => write!(f, "malformed obj file: {}", msg),
+        }
+    }
+}
+impl std::error::Error for ObjError {}
+impl From<std::io::Error> for ObjError {
+    fn from(e: std::io::Error) -> ObjError {
+        ObjError::Io(e)
+    }
+}
+
+/// A triangle mesh loaded from an obj file.
+#[derive(Debug, Clone, Default)]
+pub struct ObjMesh {
+    pub verts: Vec<Point>,
+    /// Triangles in clockwise order like `geom::Triangle`, reordered from
+    /// the winding of the file so that front faces face outward.
+    pub idxs: Vec<(usize, usize, usize)>,
+    /// Unit shading normals of the corners of each triangle in `idxs`, in
+    /// the same order as its vertices.
+    pub normals: Vec<[Vector; 3]>,
+    /// Name of the first object (`o`) in the file, if any.
+    pub name: Option<String>,
+    /// Unit of length of `verts`.
+    pub unit: LengthUnit,
+}
+impl ObjMesh {
+    /// The mesh authored in `unit`, e.g., centimeters for assets of many
+    /// game engines and DCC tools.
+    pub fn with_unit(self, unit: LengthUnit) -> ObjMesh {
+        ObjMesh { unit, ..self }
+    }
+    /// Object of the mesh scaled into meters, then placed in the world by
+    /// `world2obj`, keeping the shading normals.
+    pub fn into_object<M>(self, mat: M, world2obj: Transform) -> Object<M> {
+        let obj2world = world2obj.inverse();
+        let visibility = Visibility::default();
+        let k = self.unit.meters();
+        Object {
+            verts: self.verts.into_iter().map(|p| Point(p.0 * k, p.1 * k, p.2 * k)).collect(),
+            idxs: self.idxs,
+            mat, obj2world, world2obj, visibility,
+            cull_backfaces: false,
+            normals: Some(self.normals),
+            colors: None,
+            name: self.name,
+            shape: None,
+        }
+    }
+}
+
+/// A triangle before normals are resolved: vertex indices, normal indices if
+/// the file gives them, and the smoothing group, 0 for none.
+struct Face {
+    verts: [usize; 3],
+    normals: Option<[usize; 3]>,
+    group: u32,
+}
+
+/// Load the obj file at `path`.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<ObjMesh, ObjError> {
+    load_obj_with(path, &CoordinateSystem::OBJ)
+}
+/// Load the obj file at `path` in coordinates `coords`, converted to scene
+/// coordinates.
+pub fn load_obj_with<P: AsRef<Path>>(path: P, coords: &CoordinateSystem) -> Result<ObjMesh, ObjError> {
+    let src = std::fs::read_to_string(path)?;
+    parse_obj_with(&src, coords)
+}
+/// Parse obj source `src`, see the module documentation.
+pub fn parse_obj(src: &str) -> Result<ObjMesh, ObjError> {
+    parse_obj_with(src, &CoordinateSystem::OBJ)
+}
+/// Parse obj source `src` in coordinates `coords` like `load_obj_with`.
+pub fn parse_obj_with(src: &str, coords: &CoordinateSystem) -> Result<ObjMesh, ObjError> {
+    let to_scene = coords.to_scene();
+    let mut mesh = ObjMesh::default();
+    let mut file_normals = Vec::new();
+    let mut faces = Vec::new();
+    let mut group = 0;
+    for (iline, line) in src.lines().enumerate() {
+        let err = |msg: String| ObjError::Parse(format!("line {}: {}", iline + 1, msg));
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let cmd = match tokens.next() {
+            Some(x) => x,
+            None => continue,
+        };
+        let args = tokens.collect::<Vec<_>>();
+        match cmd {
+            "v" | "vn" => {
+                if args.len() < 3 {
+                    return Err(err(format!("`{}` needs 3 coordinates", cmd)));
+                }
+                let mut xyz = [0.0; 3];
+                for (x, arg) in xyz.iter_mut().zip(args.iter()) {
+                    *x = arg.parse()
+                        .map_err(|_| err(format!("invalid number `{}`", arg)))?;
+                }
+                if cmd == "v" {
+                    mesh.verts.push(to_scene * Point(xyz[0], xyz[1], xyz[2]));
+                } else {
+                    file_normals.push(to_scene * Vector(xyz[0], xyz[1], xyz[2]));
+                }
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err("faces need at least 3 vertices".to_owned()));
+                }
+                let corners = args.iter()
+                    .map(|x| parse_corner(x, mesh.verts.len(), file_normals.len()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(err)?;
+                for i in 1..corners.len() - 1 {
+                    let tri = if coords.flips_winding() {
+                        [corners[0], corners[i + 1], corners[i]]
+                    } else {
+                        [corners[0], corners[i], corners[i + 1]]
+                    };
+                    let normals = match (tri[0].1, tri[1].1, tri[2].1) {
+                        (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                        _ => None,
+                    };
+                    let verts = [tri[0].0, tri[1].0, tri[2].0];
+                    faces.push(Face { verts, normals, group });
+                }
+            },
+            "s" => {
+                group = match args.first() {
+                    Some(&"off") => 0,
+                    Some(x) => x.parse()
+                        .map_err(|_| err(format!("invalid smoothing group `{}`", x)))?,
+                    None => return Err(err("`s` needs a smoothing group".to_owned())),
+                };
+            },
+            "o" if mesh.name.is_none() && !args.is_empty() => {
+                mesh.name = Some(args.join(" "));
+            },
+            _ => {},
+        }
+    }
+
+    // Area weighted sums of the face normals around each vertex, by
+    // smoothing group.
+    let verts = &mesh.verts;
+    let face_normal = |face: &Face| {
+        let [a, b, c] = face.verts;
+        let x = verts[b].rel_from(verts[a]);
+        let y = verts[c].rel_from(verts[a]);
+        y.cross(x)
+    };
+    let mut smooth = HashMap::<(usize, u32), Vector>::new();
+    for face in faces.iter().filter(|x| x.normals.is_none() && x.group != 0) {
+        let n = face_normal(face);
+        for &i in face.verts.iter() {
+            let sum = smooth.entry((i, face.group)).or_default();
+            *sum = *sum + n;
+        }
+    }
+    let unit = |n: Vector| if n.mag() > 0.0 { n.normalize() } else { n };
+    let mut idxs = Vec::with_capacity(faces.len());
+    let mut normals = Vec::with_capacity(faces.len());
+    for face in faces.iter() {
+        let [a, b, c] = face.verts;
+        idxs.push((a, b, c));
+        let ns = if let Some(ns) = face.normals {
+            [unit(file_normals[ns[0]]), unit(file_normals[ns[1]]), unit(file_normals[ns[2]])]
+        } else if face.group == 0 {
+            let n = unit(face_normal(face));
+            [n, n, n]
+        } else {
+            let n = |i: usize| unit(smooth[&(i, face.group)]);
+            [n(a), n(b), n(c)]
+        };
+        normals.push(ns);
+    }
+    Ok(ObjMesh { idxs, normals, ..mesh })
+}
+
+/// Parse a face corner `v`, `v/vt`, `v//vn` or `v/vt/vn` into zero-based
+/// vertex and normal indices. Negative indices count back from the last
+/// vertex or normal so far.
+fn parse_corner(x: &str, nvert: usize, nnormal: usize) -> Result<(usize, Option<usize>), String> {
+    let resolve = |idx: &str, n: usize| -> Result<usize, String> {
+        let i = idx.parse::<isize>()
+            .map_err(|_| format!("invalid index `{}`", idx))?;
+        let i = if i < 0 { n as isize + i } else { i - 1 };
+        if i < 0 || i as usize >= n {
+            return Err(format!("index `{}` out of range", idx));
+        }
+        Ok(i as usize)
+    };
+    let mut parts = x.split('/');
+    let v = resolve(parts.next().unwrap_or(""), nvert)?;
+    let vn = match parts.nth(1) {
+        Some(x) if !x.is_empty() => Some(resolve(x, nnormal)?),
+        _ => None,
+    };
+    Ok((v, vn))
+}
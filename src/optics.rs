@@ -0,0 +1,168 @@
+use crate::geom::{Real, Vector, Color};
+
+/// Index of refraction of a dielectric, possibly varying with wavelength.
+#[derive(Debug, Clone, Copy)]
+pub enum Ior {
+    Constant(Real),
+    /// Cauchy's equation `n = a + b / λ²`, with `λ` in micrometers.
+    Cauchy { a: Real, b: Real },
+    /// Sellmeier equation `n² = 1 + Σ bᵢλ² / (λ² - cᵢ)`, with `λ` in
+    /// micrometers.
+    Sellmeier { b: [Real; 3], c: [Real; 3] },
+}
+impl Ior {
+    /// Borosilicate crown glass, the usual optical glass.
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.039_612, 0.231_792_34, 1.010_469_4],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+    /// Dense flint glass, dispersing notably more than `BK7`.
+    pub const SF11: Ior = Ior::Sellmeier {
+        b: [1.737_596_9, 0.313_747_35, 1.898_781],
+        c: [0.013_188_707, 0.062_306_814, 155.236_3],
+    };
+    /// Diamond, known for its fire.
+    pub const DIAMOND: Ior = Ior::Sellmeier {
+        b: [0.3306, 4.3356, 0.0],
+        c: [0.030_625, 0.011_236, 0.0],
+    };
+
+    /// Index of refraction at `wavelength` in nanometers.
+    pub fn at(&self, wavelength: Real) -> Real {
+        let l = wavelength * 1e-3;
+        let l2 = l * l;
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3)
+                    .map(|i| b[i] * l2 / (l2 - c[i]))
+                    .sum::<Real>();
+                n2.sqrt()
+            },
+        }
+    }
+    /// Whether the index of refraction varies with wavelength, so that light
+    /// of different colors has to be traced separately.
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
+/// Wavelength ranges in nanometers represented by the red, green and blue
+/// channels.
+pub const RGB_BANDS: [(Real, Real); 3] = [
+    (580.0, 700.0),
+    (490.0, 580.0),
+    (400.0, 490.0),
+];
+
+/// Sample a wavelength in nanometers for a ray that is about to be dispersed.
+/// A color channel is picked by `a` in [0..1) and a wavelength within its band
+/// by `b` in [0..1). The returned weight keeps only the picked channel, divided
+/// by the probability of picking it.
+///
+/// Passing a constant `b` of 0.5 traces each channel at a single wavelength,
+/// which is cheaper to converge but splits white light into three distinct
+/// colors rather than a continuous spectrum.
+pub fn sample_wavelength(a: Real, b: Real) -> (Real, Color) {
+    let ichannel = ((a * 3.0) as usize).min(2);
+    let (lo, hi) = RGB_BANDS[ichannel];
+    let mut weight = [0.0; 3];
+    weight[ichannel] = 3.0;
+    (lo + (hi - lo) * b, Color(weight[0], weight[1], weight[2], 1.0))
+}
+
+/// Fresnel reflectance of unpolarized light at the interface of two
+/// dielectrics. `cos_i` is the cosine of the incident angle and `eta` is the
+/// index of refraction of the incident side over that of the other side.
+pub fn fresnel_dielectric(cos_i: Real, eta: Real) -> Real {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        // Total internal reflection.
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// Calculate the refracted direction of a ray travelling in direction `i`
+/// through a surface of normal `n` on the incident side. `eta` is the index of
+/// refraction of the incident side over that of the other side. Returns `None`
+/// on total internal reflection.
+///
+/// NOTE: `i` and `n` MUST be normalized.
+pub fn refract(i: Vector, n: Vector, eta: Real) -> Option<Vector> {
+    let cos_i = -n.dot(i);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 { return None }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(eta * i + (eta * cos_i - cos_t) * n)
+}
+
+/// A thin transparent layer coating a surface, e.g., a soap film or an oil
+/// slick. Light reflected by the top and the bottom of the layer interferes,
+/// so that the reflectance varies with wavelength and viewing angle.
+#[derive(Debug, Clone, Copy)]
+pub struct ThinFilm {
+    /// Thickness of the layer in nanometers.
+    pub thickness: Real,
+    /// Index of refraction of the layer.
+    pub ior: Real,
+}
+impl ThinFilm {
+    /// Number of wavelengths averaged within each color channel.
+    const NSAMPLE_PER_BAND: usize = 8;
+
+    /// Reflectance at wavelength `wavelength` in nanometers of a substrate of
+    /// index of refraction `base` under the film, lit from the air at incident
+    /// angle cosine `cos_i`.
+    ///
+    /// See: Laurent Belcour and Pascal Barla, A Practical Extension to
+    /// Microfacet Theory for the Modeling of Varying Iridescence.
+    pub fn reflectance_at(&self, cos_i: Real, base: Real, wavelength: Real) -> Real {
+        use std::f64::consts::PI;
+        let (n0, n1, n2) = (1.0, self.ior, base);
+        let cos0 = cos_i.clamp(0.0, 1.0);
+        let sin2_0 = 1.0 - cos0 * cos0;
+        let cos_of = |n: Real| (1.0 - sin2_0 / (n * n)).max(0.0).sqrt();
+        let (cos1, cos2) = (cos_of(n1), cos_of(n2));
+        // Phase difference between the two reflected waves.
+        let delta = 4.0 * PI as Real * n1 * self.thickness * cos1 / wavelength;
+        let airy = |r01: Real, r12: Real| {
+            let x = 2.0 * r01 * r12 * delta.cos();
+            let r = (r01 * r01 + r12 * r12 + x) / (1.0 + r01 * r01 * r12 * r12 + x);
+            r.clamp(0.0, 1.0)
+        };
+        let rs = airy(
+            (n0 * cos0 - n1 * cos1) / (n0 * cos0 + n1 * cos1),
+            (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2),
+        );
+        let rp = airy(
+            (n1 * cos0 - n0 * cos1) / (n1 * cos0 + n0 * cos1),
+            (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2),
+        );
+        0.5 * (rs + rp)
+    }
+    /// Reflectance of each color channel, averaged over the wavelengths in
+    /// `RGB_BANDS`. Alpha is the mean of the channels, so that the color can
+    /// stand in for a scalar Fresnel term.
+    pub fn reflectance(&self, cos_i: Real, base: Ior) -> Color {
+        let mut rgb = [0.0; 3];
+        for (x, (lo, hi)) in rgb.iter_mut().zip(RGB_BANDS.iter()) {
+            let sum = (0..Self::NSAMPLE_PER_BAND)
+                .map(|i| {
+                    let frac = (i as Real + 0.5) / Self::NSAMPLE_PER_BAND as Real;
+                    let wavelength = lo + (hi - lo) * frac;
+                    self.reflectance_at(cos_i, base.at(wavelength), wavelength)
+                })
+                .sum::<Real>();
+            *x = crate::geom::narrow(sum / Self::NSAMPLE_PER_BAND as Real);
+        }
+        let mean = (rgb[0] + rgb[1] + rgb[2]) / 3.0;
+        Color(rgb[0], rgb[1], rgb[2], mean)
+    }
+}
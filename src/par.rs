@@ -0,0 +1,76 @@
+//! Data parallelism. Work is spread over threads by rayon if the `parallel`
+//! feature is enabled, and runs sequentially on the calling thread otherwise,
+//! e.g., on `wasm32-unknown-unknown` where threads can't be spawned. Only the
+//! subset of rayon used by the crate is mirrored.
+
+#[cfg(feature = "parallel")]
+pub use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+pub use rayon::join;
+
+#[cfg(not(feature = "parallel"))]
+pub use self::seq::*;
+
+/// Fold chunks of `items` into partial results starting from `identity()`,
+/// and then combine the partial results with `reduce`.
+#[cfg(feature = "parallel")]
+pub fn fold_reduce<T, R, ID, F, G>(items: &[T], identity: ID, fold: F, reduce: G) -> R
+    where T: Sync,
+          R: Send,
+          ID: Fn() -> R + Sync + Send,
+          F: Fn(R, &T) -> R + Sync + Send,
+          G: Fn(R, R) -> R + Sync + Send,
+{
+    items.par_iter().fold(&identity, fold).reduce(&identity, reduce)
+}
+#[cfg(not(feature = "parallel"))]
+pub fn fold_reduce<T, R, ID, F, G>(items: &[T], identity: ID, fold: F, _reduce: G) -> R
+    where ID: Fn() -> R,
+          F: Fn(R, &T) -> R,
+          G: Fn(R, R) -> R,
+{
+    items.iter().fold(identity(), fold)
+}
+
+#[cfg(not(feature = "parallel"))]
+mod seq {
+    pub trait IntoParallelIterator {
+        type Iter: Iterator;
+        fn into_par_iter(self) -> Self::Iter;
+    }
+    impl<I: IntoIterator> IntoParallelIterator for I {
+        type Iter = I::IntoIter;
+        fn into_par_iter(self) -> I::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    pub trait ParallelSlice<T> {
+        fn par_iter(&self) -> std::slice::Iter<'_, T>;
+        fn par_chunks(&self, chunk_size: usize) -> std::slice::Chunks<'_, T>;
+    }
+    impl<T> ParallelSlice<T> for [T] {
+        fn par_iter(&self) -> std::slice::Iter<'_, T> {
+            self.iter()
+        }
+        fn par_chunks(&self, chunk_size: usize) -> std::slice::Chunks<'_, T> {
+            self.chunks(chunk_size)
+        }
+    }
+
+    pub trait ParallelSliceMut<T> {
+        fn par_iter_mut(&mut self) -> std::slice::IterMut<'_, T>;
+    }
+    impl<T> ParallelSliceMut<T> for [T] {
+        fn par_iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+            self.iter_mut()
+        }
+    }
+
+    pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+        where A: FnOnce() -> RA,
+              B: FnOnce() -> RB,
+    {
+        (a(), b())
+    }
+}
@@ -0,0 +1,453 @@
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use crate::geom::{Real, Point, Vector, Ray, Color, Sphere, Transform, Precision, ray_cast_sph};
+use crate::rt::{Intersection, HitKind};
+use crate::bvh::{Aabb, BoxBvh};
+use crate::scene::{Object, Shape, ShapeHit};
+use crate::units::LengthUnit;
+
+/// A point of a point cloud rendered as a small disk.
+#[derive(Debug, Clone)]
+pub struct Splat {
+    /// Center.
+    pub p: Point,
+    /// Unit normal vector. Points without normals have a zero normal and are
+    /// rendered as spheres instead, so that they look the same from every
+    /// direction.
+    pub n: Vector,
+    /// Radius.
+    pub r: Real,
+    pub color: Color,
+}
+
+/// Cast a ray to the splat and return the point of intersection if such point
+/// exists.
+#[inline]
+pub fn ray_cast_splat(ray: &Ray, splat: &Splat) -> Option<Intersection<Point>> {
+    if splat.n.dot(splat.n) == 0.0 {
+        let sph = Sphere { c: splat.p, r: splat.r };
+        return ray_cast_sph(ray, &sph)
+            .filter(|x| x.kind == HitKind::Front && x.t > 0.0);
+    }
+    let cos_theta = ray.v.dot(splat.n);
+    if cos_theta == 0.0 { return None }
+    let t = splat.p.rel_from(ray.o).dot(splat.n) / cos_theta;
+    if t <= 0.0 { return None }
+    let attr = ray.o.affine_add(t * ray.v);
+    let d = attr.rel_from(splat.p);
+    if d.dot(d) > splat.r * splat.r { return None }
+    let kind = if cos_theta < 0.0 { HitKind::Front } else { HitKind::Back };
+    Some(Intersection { attr, kind, t, prim: 0 })
+}
+
+/// Points of a scanned dataset. Splats can be cast to directly, and are
+/// traced with the scene as objects made by `into_object`.
+#[derive(Debug, Clone, Default)]
+pub struct PointCloud {
+    pub splats: Vec<Splat>,
+}
+impl PointCloud {
+    pub fn bounds(&self) -> Aabb {
+        self.splats.iter()
+            .fold(Aabb::empty(), |seed, x| {
+                let r = Vector(x.r, x.r, x.r);
+                seed.grow(x.p.affine_sub(r)).grow(x.p.affine_add(r))
+            })
+    }
+    /// The closest splat hit by `ray` and its index.
+    pub fn closest(&self, ray: &Ray) -> Option<(usize, Intersection<Point>)> {
+        let mut tmax = Real::INFINITY;
+        let mut closest = None;
+        for (i, splat) in self.splats.iter().enumerate() {
+            if let Some(x) = ray_cast_splat(ray, splat) {
+                if x.t < tmax {
+                    tmax = x.t;
+                    closest = Some((i, x));
+                }
+            }
+        }
+        closest
+    }
+    /// Object of the splats placed in the world by `world2obj`, traced
+    /// natively as a `Shape` and colored by splat, see `Object::colors`.
+    pub fn into_object<M>(self, mat: M, world2obj: Transform) -> Object<M> {
+        let colors = self.splats.iter().map(|x| x.color).collect();
+        Object::from_shape(Splats::new(self.splats), mat, world2obj).with_colors(colors)
+    }
+}
+
+/// Splats of a `PointCloud` culled by a `BoxBvh` over their bounding boxes
+/// before the exact test. Disks are hit on either face, and spheres from the
+/// outside only.
+struct Splats {
+    splats: Vec<Splat>,
+    bvh: BoxBvh,
+}
+impl Splats {
+    fn new(splats: Vec<Splat>) -> Splats {
+        let bounds = splats.iter()
+            .map(|x| {
+                let r = Vector(x.r, x.r, x.r);
+                Aabb { min: x.p.affine_sub(r), max: x.p.affine_add(r) }
+            })
+            .collect::<Vec<_>>();
+        Splats { splats, bvh: BoxBvh::new(&bounds) }
+    }
+}
+impl Shape for Splats {
+    fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+    fn ray_cast(&self, ray: &Ray, precision: &Precision) -> Option<ShapeHit> {
+        let mut closest = None;
+        self.bvh.traverse_within(ray, precision.max_t, |i, tmax| {
+            if let Some(x) = ray_cast_splat(ray, &self.splats[i]) {
+                if x.t < *tmax && x.t >= precision.ray_epsilon {
+                    *tmax = x.t;
+                    closest = Some((i, x));
+                }
+            }
+        });
+        let (i, x) = closest?;
+        let splat = &self.splats[i];
+        let n = if splat.n.dot(splat.n) > 0.0 {
+            splat.n
+        } else {
+            x.attr.rel_from(splat.p).normalize()
+        };
+        let a = if n.0.abs() < 0.9 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+        let tangent = (a - a.dot(n) * n).normalize();
+        Some(ShapeHit { t: x.t, kind: x.kind, prim: i, n, tangent })
+    }
+}
+
+/// Error loading point clouds from files.
+#[derive(Debug)]
+pub enum PlyError {
+    Io(std::io::Error),
+    /// The file is malformed.
+    Parse(String),
+    /// The file is valid but uses features not supported.
+    Unsupported(String),
+}
+impl std::fmt::Display for PlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlyError::Io(e) => write!(f, "failed to read point cloud: {}", e),
+            PlyError::Parse(msg) => write!(f, "malformed ply file: {}", msg),
+            PlyError::Unsupported(msg) => write!(f, "unsupported ply file: {}", msg),
+        }
+    }
+}
+impl std::error::Error for PlyError {}
+impl From<std::io::Error> for PlyError {
+    fn from(e: std::io::Error) -> PlyError {
+        PlyError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+#[derive(Debug, Clone, Copy)]
+enum PlyType {
+    I8, U8, I16, U16, I32, U32, F32, F64,
+}
+impl PlyType {
+    fn parse(x: &str) -> Result<PlyType, PlyError> {
+        let ty = match x {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return Err(PlyError::Parse(format!("unknown type `{}`", x))),
+        };
+        Ok(ty)
+    }
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+    /// Scale to normalize integer colors to [0, 1].
+    fn color_scale(self) -> f64 {
+        match self {
+            PlyType::U8 => 255.0,
+            PlyType::U16 => 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+#[derive(Debug)]
+struct PlyProperty {
+    name: String,
+    ty: PlyType,
+    /// Type of the element count if the property is a list.
+    count_ty: Option<PlyType>,
+}
+#[derive(Debug)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    props: Vec<PlyProperty>,
+}
+
+/// Reads scalar values from the body of a ply file.
+struct PlyReader<R> {
+    inner: R,
+    format: PlyFormat,
+    /// Tokens left on the current line of an ascii file, reversed.
+    tokens: Vec<String>,
+}
+impl<R: BufRead> PlyReader<R> {
+    fn read(&mut self, ty: PlyType) -> Result<f64, PlyError> {
+        if self.format == PlyFormat::Ascii {
+            while self.tokens.is_empty() {
+                let mut line = String::new();
+                if self.inner.read_line(&mut line)? == 0 {
+                    return Err(PlyError::Parse("unexpected end of file".to_owned()));
+                }
+                self.tokens = line.split_whitespace().rev().map(str::to_owned).collect();
+            }
+            let token = self.tokens.pop().unwrap();
+            return token.parse::<f64>()
+                .map_err(|_| PlyError::Parse(format!("invalid number `{}`", token)));
+        }
+        let mut buf = [0u8; 8];
+        let buf = &mut buf[..ty.size()];
+        self.inner.read_exact(buf)?;
+        if self.format == PlyFormat::BigEndian { buf.reverse() }
+        let x = match ty {
+            PlyType::I8 => buf[0] as i8 as f64,
+            PlyType::U8 => buf[0] as f64,
+            PlyType::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            PlyType::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            PlyType::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            PlyType::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            PlyType::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            PlyType::F64 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(buf);
+                f64::from_le_bytes(bytes)
+            },
+        };
+        Ok(x)
+    }
+}
+
+/// Load the vertices of a ply file as a point cloud. Positions `x`, `y` and
+/// `z` are required; normals `nx`, `ny` and `nz`, colors `red`, `green` and
+/// `blue`, and a per-point `radius` are used if present. Points without a
+/// radius get radius `radius`. Other elements like faces are ignored.
+/// Positions and radii are in `unit`, since ply files don't record it, and
+/// are scaled into meters.
+pub fn load_ply<P: AsRef<Path>>(path: P, radius: Real, unit: LengthUnit) -> Result<PointCloud, PlyError> {
+    let file = std::fs::File::open(path)?;
+    let mut cloud = read_ply(BufReader::new(file), radius)?;
+    let k = unit.meters();
+    for splat in cloud.splats.iter_mut() {
+        splat.p = Point(splat.p.0 * k, splat.p.1 * k, splat.p.2 * k);
+        splat.r *= k;
+    }
+    Ok(cloud)
+}
+fn read_ply<R: BufRead>(mut inner: R, radius: Real) -> Result<PointCloud, PlyError> {
+    let read_line = |inner: &mut R| -> Result<String, PlyError> {
+        let mut line = String::new();
+        if inner.read_line(&mut line)? == 0 {
+            return Err(PlyError::Parse("unexpected end of header".to_owned()));
+        }
+        Ok(line.trim().to_owned())
+    };
+    if read_line(&mut inner)? != "ply" {
+        return Err(PlyError::Parse("missing magic number".to_owned()));
+    }
+    let mut format = None;
+    let mut elems: Vec<PlyElement> = Vec::new();
+    loop {
+        let line = read_line(&mut inner)?;
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["comment", ..] | ["obj_info", ..] | [] => {},
+            ["format", fmt, _version] => {
+                format = Some(match *fmt {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::LittleEndian,
+                    "binary_big_endian" => PlyFormat::BigEndian,
+                    _ => return Err(PlyError::Unsupported(format!("format `{}`", fmt))),
+                });
+            },
+            ["element", name, count] => {
+                let count = count.parse()
+                    .map_err(|_| PlyError::Parse(format!("invalid count `{}`", count)))?;
+                elems.push(PlyElement { name: (*name).to_owned(), count, props: Vec::new() });
+            },
+            ["property", "list", count_ty, ty, name] => {
+                let elem = elems.last_mut()
+                    .ok_or_else(|| PlyError::Parse("property before element".to_owned()))?;
+                elem.props.push(PlyProperty {
+                    name: (*name).to_owned(),
+                    ty: PlyType::parse(ty)?,
+                    count_ty: Some(PlyType::parse(count_ty)?),
+                });
+            },
+            ["property", ty, name] => {
+                let elem = elems.last_mut()
+                    .ok_or_else(|| PlyError::Parse("property before element".to_owned()))?;
+                elem.props.push(PlyProperty {
+                    name: (*name).to_owned(),
+                    ty: PlyType::parse(ty)?,
+                    count_ty: None,
+                });
+            },
+            _ => return Err(PlyError::Parse(format!("unexpected header line `{}`", line))),
+        }
+    }
+    let format = format.ok_or_else(|| PlyError::Parse("missing format".to_owned()))?;
+    let mut reader = PlyReader { inner, format, tokens: Vec::new() };
+
+    let mut cloud = PointCloud::default();
+    for elem in elems.iter() {
+        let is_vertex = elem.name == "vertex";
+        if is_vertex {
+            for name in ["x", "y", "z"].iter() {
+                if !elem.props.iter().any(|x| x.name == *name) {
+                    return Err(PlyError::Parse(format!("vertices lack `{}`", name)));
+                }
+            }
+        }
+        let mut vals = vec![0.0; elem.props.len()];
+        for _ in 0..elem.count {
+            for (val, prop) in vals.iter_mut().zip(elem.props.iter()) {
+                match prop.count_ty {
+                    Some(count_ty) => {
+                        let n = reader.read(count_ty)? as usize;
+                        for _ in 0..n { reader.read(prop.ty)?; }
+                    },
+                    None => *val = reader.read(prop.ty)?,
+                }
+            }
+            if !is_vertex { continue }
+            let get = |name: &str| {
+                elem.props.iter()
+                    .position(|x| x.name == name)
+                    .map(|i| (vals[i], elem.props[i].ty))
+            };
+            let real = |name: &str| get(name).map(|(x, _)| x as Real);
+            let channel = |name: &str| {
+                get(name).map(|(x, ty)| (x / ty.color_scale()) as f32).unwrap_or(1.0)
+            };
+            let p = Point(real("x").unwrap(), real("y").unwrap(), real("z").unwrap());
+            let n = match (real("nx"), real("ny"), real("nz")) {
+                (Some(x), Some(y), Some(z)) if x != 0.0 || y != 0.0 || z != 0.0 => {
+                    Vector(x, y, z).normalize()
+                },
+                _ => Vector(0.0, 0.0, 0.0),
+            };
+            let r = real("radius").unwrap_or(radius);
+            let color = Color(channel("red"), channel("green"), channel("blue"), 1.0);
+            cloud.splats.push(Splat { p, n, r, color });
+        }
+        // Nothing after the vertices matters.
+        if is_vertex { break }
+    }
+    Ok(cloud)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Scene;
+
+    #[test]
+    fn ray_cast_disk() {
+        let splat = Splat {
+            p: Point(0.0, 0.0, 0.0),
+            n: Vector(0.0, 1.0, 0.0),
+            r: 0.5,
+            color: Color::default(),
+        };
+        let down = Vector(0.0, -1.0, 0.0);
+        let hit = ray_cast_splat(&Ray { o: Point(0.3, 2.0, 0.3), v: down }, &splat).unwrap();
+        assert_eq!(hit.kind, HitKind::Front);
+        assert!((hit.t - 2.0).abs() < 1e-6);
+        assert!((hit.attr.0 - 0.3).abs() < 1e-6 && hit.attr.1.abs() < 1e-6);
+        let up = Ray { o: Point(0.0, -2.0, 0.0), v: Vector(0.0, 4.0, 0.0) };
+        let hit = ray_cast_splat(&up, &splat).unwrap();
+        assert_eq!(hit.kind, HitKind::Back);
+        assert!((hit.t - 0.5).abs() < 1e-6);
+        // Outside the radius, parallel to the disk and behind the ray.
+        assert!(ray_cast_splat(&Ray { o: Point(0.4, 2.0, 0.4), v: down }, &splat).is_none());
+        let along = Ray { o: Point(-2.0, 0.0, 0.0), v: Vector(1.0, 0.0, 0.0) };
+        assert!(ray_cast_splat(&along, &splat).is_none());
+        assert!(ray_cast_splat(&Ray { o: Point(0.0, -2.0, 0.0), v: down }, &splat).is_none());
+    }
+
+    #[test]
+    fn ray_cast_sphere_splat() {
+        let splat = Splat {
+            p: Point(1.0, 0.0, 0.0),
+            n: Vector(0.0, 0.0, 0.0),
+            r: 0.5,
+            color: Color::default(),
+        };
+        let ray = Ray { o: Point(1.0, 0.0, -3.0), v: Vector(0.0, 0.0, 1.0) };
+        let hit = ray_cast_splat(&ray, &splat).unwrap();
+        assert_eq!(hit.kind, HitKind::Front);
+        assert!((hit.t - 2.5).abs() < 1e-6);
+        // Rays leaving the sphere don't hit it.
+        let inside = Ray { o: Point(1.0, 0.0, 0.0), v: Vector(0.0, 0.0, 1.0) };
+        assert!(ray_cast_splat(&inside, &splat).is_none());
+        let miss = Ray { o: Point(1.0, 0.6, -3.0), v: Vector(0.0, 0.0, 1.0) };
+        assert!(ray_cast_splat(&miss, &splat).is_none());
+    }
+
+    #[test]
+    fn points_in_scene() {
+        let red = Color(1.0, 0.0, 0.0, 1.0);
+        let green = Color(0.0, 1.0, 0.0, 1.0);
+        let cloud = PointCloud {
+            splats: vec![
+                Splat { p: Point(0.0, 0.0, 0.0), n: Vector(0.0, 1.0, 0.0), r: 0.5, color: red },
+                Splat { p: Point(2.0, 0.0, 0.0), n: Vector(0.0, 0.0, 0.0), r: 0.5, color: green },
+            ],
+        };
+        let scene = Scene::new(vec![cloud.into_object((), Transform::eye())]);
+        let down = Vector(0.0, -1.0, 0.0);
+        let hit = scene.raycast(&Ray { o: Point(0.1, 5.0, 0.1), v: down }).unwrap();
+        assert!(hit.front);
+        assert!(hit.p.1.abs() < 1e-4);
+        assert!(hit.n.dot(Vector(0.0, 1.0, 0.0)) > 0.999);
+        assert_eq!(scene.objs[0].color(hit.tri).unwrap().0, 1.0);
+        let hit = scene.raycast(&Ray { o: Point(0.1, -5.0, 0.1), v: -down }).unwrap();
+        assert!(!hit.front);
+        // Spheres face outward from every side.
+        let sides = [
+            (down, Vector(0.1, 0.0, 0.05)),
+            (Vector(1.0, 0.0, 0.0), Vector(0.0, 0.1, -0.05)),
+            (Vector(0.0, 0.0, -1.0), Vector(-0.1, 0.05, 0.0)),
+        ];
+        for &(v, offset) in sides.iter() {
+            let ray = Ray { o: Point(2.0, 0.0, 0.0).affine_add(offset).affine_sub(5.0 * v), v };
+            let hit = scene.raycast(&ray).unwrap();
+            assert!(hit.front);
+            let t = 5.0 - (0.25 - offset.dot(offset)).sqrt();
+            assert!((hit.t - t).abs() < 1e-4);
+            assert!(hit.n.dot(hit.p.rel_from(Point(2.0, 0.0, 0.0)) * 2.0) > 0.999);
+            assert_eq!(scene.objs[0].color(hit.tri).unwrap().1, 1.0);
+        }
+        let miss = Ray { o: Point(0.0, 5.0, 0.6), v: down };
+        assert!(scene.raycast(&miss).is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::geom::Color;
+use crate::img::Image;
+use crate::sampler::{Sampler2D, WrapMode, FilterMode};
+
+/// Color adjustments applied to the linear float image before it's clamped
+/// into the framebuffer.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorGrading {
+    /// Exposure compensation in EV. Each step doubles the brightness.
+    pub exposure: f32,
+    /// Color temperature of the scene illuminant in Kelvin. The image is
+    /// balanced so that this illuminant appears white. 6500 is neutral.
+    pub temperature: f32,
+    /// Green-magenta shift in [-1, 1]. Positive values are more magenta.
+    pub tint: f32,
+    /// Saturation multiplier. 0 is grayscale and 1 is neutral.
+    pub saturation: f32,
+    /// Contrast around middle gray. 1 is neutral.
+    pub contrast: f32,
+}
+impl Default for ColorGrading {
+    fn default() -> ColorGrading {
+        ColorGrading {
+            exposure: 0.0,
+            temperature: 6500.0,
+            tint: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+        }
+    }
+}
+impl ColorGrading {
+    /// Per-channel gains of white balance and exposure.
+    fn gains(&self) -> Color {
+        let ref_white = blackbody(6500.0);
+        let white = blackbody(self.temperature);
+        let exposure = self.exposure.exp2();
+        Color(
+            exposure * ref_white.0 / white.0,
+            exposure * ref_white.1 / white.1 * (1.0 - 0.5 * self.tint),
+            exposure * ref_white.2 / white.2,
+            1.0,
+        )
+    }
+    pub fn apply(&self, c: Color) -> Color {
+        self.apply_gains(c, self.gains())
+    }
+    fn apply_gains(&self, c: Color, gains: Color) -> Color {
+        const MID_GRAY: f32 = 0.18;
+        let c = c * gains;
+        let l = luminance(c);
+        let sat = |x: f32| l + (x - l) * self.saturation;
+        let con = |x: f32| {
+            if x > 0.0 {
+                MID_GRAY * (x / MID_GRAY).powf(self.contrast)
+            } else {
+                x
+            }
+        };
+        Color(con(sat(c.0)), con(sat(c.1)), con(sat(c.2)), c.3)
+    }
+    /// Apply grading to every pixel of `img`.
+    pub fn apply_img(&self, img: &mut Image) {
+        let gains = self.gains();
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                let c = self.apply_gains(img.load_px(x, y), gains);
+                img.store_px(x, y, c);
+            }
+        }
+    }
+}
+
+/// Relative luminance of a linear Rec.709 color.
+#[inline]
+pub fn luminance(c: Color) -> f32 {
+    0.2126 * c.0 + 0.7152 * c.1 + 0.0722 * c.2
+}
+
+/// Approximate color of a blackbody radiator at `kelvin`, normalized so that
+/// the largest channel is 1.
+///
+/// See: https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html
+fn blackbody(kelvin: f32) -> Color {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let (r, g, b) = if t <= 66.0 {
+        let g = 99.470_8 * t.ln() - 161.119_57;
+        let b = if t <= 19.0 { 0.0 } else { 138.517_73 * (t - 10.0).ln() - 305.044_8 };
+        (255.0, g, b)
+    } else {
+        let r = 329.698_73 * (t - 60.0).powf(-0.133_204_76);
+        let g = 288.122_16 * (t - 60.0).powf(-0.075_514_846);
+        (r, g, 255.0)
+    };
+    // Keep channels positive so that they can be divided by.
+    let f = |x: f32| (x / 255.0).clamp(1e-3, 1.0);
+    Color(f(r), f(g), f(b), 1.0)
+}
+
+/// Glare around bright pixels. The bright part of the image is repeatedly
+/// halved in resolution and blurred, and the blurred levels are summed back
+/// onto the image, so that the glare has a sharp core and a wide tail.
+#[derive(Debug, Clone, Copy)]
+pub struct Bloom {
+    /// Only the luminance above this threshold contributes to the glare.
+    pub threshold: f32,
+    /// Strength of the glare added onto the image.
+    pub intensity: f32,
+    /// Standard deviation of the Gaussian kernel in pixels of each level.
+    /// Levels aren't blurred if it's not positive, so the glare is only
+    /// spread by downsampling.
+    pub sigma: f32,
+    /// Number of pyramid levels.
+    pub levels: usize,
+}
+impl Default for Bloom {
+    fn default() -> Bloom {
+        Bloom {
+            threshold: 1.0,
+            intensity: 0.1,
+            sigma: 1.5,
+            levels: 5,
+        }
+    }
+}
+impl Bloom {
+    pub fn apply_img(&self, img: &mut Image) {
+        let w = img.width();
+        let h = img.height();
+        let mut level = Image::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let c = img.load_px(x, y);
+                let l = luminance(c);
+                if l > self.threshold {
+                    let c = c * ((l - self.threshold) / l);
+                    level.store_px(x, y, Color(c.0, c.1, c.2, 0.0));
+                }
+            }
+        }
+        let samp = Sampler2D::new(WrapMode::Clamp, FilterMode::Linear);
+        let mut glare = Image::new(w, h);
+        let mut nlevel = 0;
+        for _ in 0..self.levels {
+            if level.width() < 2 || level.height() < 2 { break }
+            level = downsample(&level);
+            // The kernel of a non-positive sigma divides by zero.
+            if self.sigma > 0.0 {
+                level = blur(&level, self.sigma);
+            }
+            for y in 0..h {
+                for x in 0..w {
+                    let u = (x as f32 + 0.5) / w as f32;
+                    let v = (y as f32 + 0.5) / h as f32;
+                    let c = glare.load_px(x, y) + samp.sample(&level, u, v);
+                    glare.store_px(x, y, c);
+                }
+            }
+            nlevel += 1;
+        }
+        if nlevel == 0 { return }
+        let k = self.intensity / nlevel as f32;
+        for y in 0..h {
+            for x in 0..w {
+                let c = img.load_px(x, y) + glare.load_px(x, y) * k;
+                img.store_px(x, y, c);
+            }
+        }
+    }
+}
+
+/// Outlines of technical illustrations, detected on the depth and normal
+/// AOVs of `aov::GeometryAovs` and drawn over the image: silhouettes against
+/// the background, steps in depth where a surface passes in front of another,
+/// and creases where the surface turns sharply. Lines lie on the side of the
+/// nearer surface so that they hug the objects in front.
+#[derive(Debug, Clone, Copy)]
+pub struct Outline {
+    /// Width of the lines in pixels.
+    pub width: f32,
+    /// Color of the lines, blended over the image by its alpha.
+    pub color: Color,
+    /// Lines are drawn where the depth jumps by more than this fraction of
+    /// the depth between neighboring pixels, beyond what the slope of the
+    /// surface accounts for.
+    pub depth_ratio: f32,
+    /// Lines are drawn where the normals of neighboring pixels are more than
+    /// this many radians apart.
+    pub crease_angle: f32,
+}
+impl Default for Outline {
+    fn default() -> Outline {
+        Outline {
+            width: 1.5,
+            color: Color(0.0, 0.0, 0.0, 1.0),
+            depth_ratio: 0.05,
+            crease_angle: 45.0_f32.to_radians(),
+        }
+    }
+}
+impl Outline {
+    /// Whether pixel `(x, y)` lies on a line, given the `depth` and normal
+    /// `n` AOVs.
+    fn is_edge(&self, depth: &Image, n: &Image, x: usize, y: usize) -> bool {
+        let (w, h) = (depth.width(), depth.height());
+        let d = depth.load_px(x, y);
+        if d.3 <= 0.0 { return false }
+        let px = |dx: isize, dy: isize| {
+            let (x, y) = (x as isize + dx, y as isize + dy);
+            if x < 0 || y < 0 || x >= w as isize || y >= h as isize { return None }
+            Some((x as usize, y as usize))
+        };
+        let nc = n.load_px(x, y);
+        let cos = self.crease_angle.cos();
+        for &(dx, dy) in [(1, 0), (0, 1), (-1, 0), (0, -1)].iter() {
+            let (nx, ny) = match px(dx, dy) {
+                Some(x) => x,
+                None => continue,
+            };
+            let dn = depth.load_px(nx, ny);
+            if dn.3 <= 0.0 { return true }
+            // Each crease is drawn once, on the pixels before it.
+            let nn = n.load_px(nx, ny);
+            let dot = nc.0 * nn.0 + nc.1 * nn.1 + nc.2 * nn.2;
+            if dx + dy > 0 && dot < cos { return true }
+            // The second difference of depth cancels the slope of surfaces,
+            // and is positive on the nearer side of steps in depth.
+            let back = px(-dx, -dy).map(|(x, y)| depth.load_px(x, y)).filter(|x| x.3 > 0.0);
+            let back = match back {
+                Some(x) => x.0,
+                None => continue,
+            };
+            if dn.0 + back - 2.0 * d.0 > self.depth_ratio * d.0 { return true }
+        }
+        false
+    }
+    /// Draw the outlines of the depth and normal AOVs `depth` and `n` over
+    /// `img` of the same size.
+    pub fn apply_img(&self, img: &mut Image, depth: &Image, n: &Image) {
+        let w = img.width();
+        let h = img.height();
+        let mut edges = vec![false; w * h];
+        for y in 0..h {
+            for x in 0..w {
+                edges[y * w + x] = self.is_edge(depth, n, x, y);
+            }
+        }
+        // Lines are edge pixels dilated by a disk of the line width, with a
+        // pixel of antialiased falloff.
+        let r = 0.5 * self.width.max(0.0);
+        let reach = (r + 0.5).ceil() as isize;
+        for y in 0..h {
+            for x in 0..w {
+                let mut cover = 0.0_f32;
+                for dy in -reach..=reach {
+                    for dx in -reach..=reach {
+                        let (sx, sy) = (x as isize + dx, y as isize + dy);
+                        if sx < 0 || sy < 0 || sx >= w as isize || sy >= h as isize { continue }
+                        if !edges[sy as usize * w + sx as usize] { continue }
+                        let dist = ((dx * dx + dy * dy) as f32).sqrt();
+                        cover = cover.max((r + 0.5 - dist).clamp(0.0, 1.0));
+                    }
+                }
+                if cover <= 0.0 { continue }
+                let a = cover * self.color.3;
+                let c = img.load_px(x, y);
+                // Images are premultiplied by alpha, lines are straight.
+                let rgb = c * (1.0 - a) + self.color * a;
+                img.store_px(x, y, Color(rgb.0, rgb.1, rgb.2, a + c.3 * (1.0 - a)));
+            }
+        }
+    }
+}
+
+/// Halve the resolution of `img` by averaging 2x2 blocks.
+fn downsample(img: &Image) -> Image {
+    let w = img.width() / 2;
+    let h = img.height() / 2;
+    let mut out = Image::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            let c = img.load_px(2 * x, 2 * y) +
+                img.load_px(2 * x + 1, 2 * y) +
+                img.load_px(2 * x, 2 * y + 1) +
+                img.load_px(2 * x + 1, 2 * y + 1);
+            out.store_px(x, y, c * 0.25);
+        }
+    }
+    out
+}
+/// Separable Gaussian blur with edge pixels extended outwards. `sigma` must be
+/// positive.
+fn blur(img: &Image, sigma: f32) -> Image {
+    let w = img.width();
+    let h = img.height();
+    let radius = (3.0 * sigma).ceil() as isize;
+    let kernel = (-radius..=radius)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let norm = kernel.iter().sum::<f32>().recip();
+    let conv = |src: &Image, dx: isize, dy: isize| {
+        let mut dst = Image::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut c = Color::default();
+                for (i, k) in (-radius..=radius).zip(kernel.iter()) {
+                    let sx = (x as isize + i * dx).clamp(0, w as isize - 1);
+                    let sy = (y as isize + i * dy).clamp(0, h as isize - 1);
+                    c = c + src.load_px(sx as usize, sy as usize) * *k;
+                }
+                dst.store_px(x, y, c * norm);
+            }
+        }
+        dst
+    };
+    conv(&conv(img, 1, 0), 0, 1)
+}
+
+/// A distinct color for each object index for visualizing object ID passes.
+/// Pixels seeing no object are transparent black.
+pub fn id_color(id: Option<usize>) -> Color {
+    match id {
+        Some(id) => {
+            let h = crate::sampler::pixel_hash(id as u32, 0, 0);
+            let c = [(h & 0xff) as u8, ((h >> 8) & 0xff) as u8, ((h >> 16) & 0xff) as u8];
+            c.into()
+        },
+        None => Color::default(),
+    }
+}
+
+/// False color of `t` in [0..1] for visualizing scalar passes like sample
+/// counts, from dark blue through cyan, green and yellow to red. `t` out of
+/// the range is clamped.
+pub fn false_color(t: f32) -> Color {
+    const STOPS: [Color; 5] = [
+        Color(0.0, 0.0, 0.5, 1.0),
+        Color(0.0, 0.8, 1.0, 1.0),
+        Color(0.2, 0.9, 0.2, 1.0),
+        Color(1.0, 0.9, 0.0, 1.0),
+        Color(0.9, 0.0, 0.0, 1.0),
+    ];
+    let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) } * (STOPS.len() - 1) as f32;
+    let i = (t as usize).min(STOPS.len() - 2);
+    let f = t - i as f32;
+    STOPS[i] * (1.0 - f) + STOPS[i + 1] * f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_without_blur() {
+        let mut img = Image::new(8, 8);
+        img.store_px(3, 3, Color(4.0, 4.0, 4.0, 1.0));
+        for sigma in [0.0, -1.0, f32::NAN] {
+            let mut img = img.crop(0, 0, 8, 8);
+            Bloom { sigma, ..Bloom::default() }.apply_img(&mut img);
+            let c = img.load_px(2, 2);
+            assert!(c.0.is_finite() && c.0 > 0.0, "sigma {} gave {}", sigma, c.0);
+        }
+    }
+}
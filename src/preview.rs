@@ -0,0 +1,86 @@
+//! Thumbnails of materials for material libraries and quick iteration: a
+//! sphere of the material on a ground plane, lit by a built-in sky.
+use std::sync::Arc;
+use crate::geom::*;
+use crate::scene::Scene;
+use crate::model::{make_sphere, make_pln};
+use crate::img::Image;
+use crate::camera::Camera;
+use crate::sky::Sky;
+use crate::desc::{DiffuseMaterial, DiffuseRayTracer};
+use crate::rt::RayTracer;
+use crate::par::*;
+
+/// Equirectangular sky lighting previews: the default `Sky`, a blue gradient
+/// brightening towards the horizon and a soft sun high up front, so that the
+/// sphere is shaded and casts a shadow. Below the horizon is dark gray.
+pub fn default_environment() -> Image {
+    Sky::default().to_equirect(256, 128)
+}
+
+/// Settings of material previews.
+#[derive(Debug, Clone, Copy)]
+pub struct MaterialPreview {
+    /// Width and height of the square thumbnails.
+    pub size: u32,
+    /// Samples per pixel.
+    pub spp: u32,
+    /// Material of the ground plane.
+    pub ground: DiffuseMaterial,
+}
+impl Default for MaterialPreview {
+    fn default() -> MaterialPreview {
+        MaterialPreview {
+            size: 128,
+            spp: 64,
+            ground: DiffuseMaterial { albedo: Color(0.5, 0.5, 0.5, 1.0), ..Default::default() },
+        }
+    }
+}
+impl MaterialPreview {
+    /// Render a thumbnail of `mat` seen from the front.
+    pub fn render(&self, mat: DiffuseMaterial) -> Image {
+        self.render_from(mat, 0.0)
+    }
+    /// Render `nframe` thumbnails of `mat` from a camera orbiting the sphere
+    /// once, e.g., to show off anisotropic materials or textures.
+    pub fn turntable(&self, mat: DiffuseMaterial, nframe: u32) -> Vec<Image> {
+        const PI: Real = std::f64::consts::PI as Real;
+        (0..nframe)
+            .map(|i| self.render_from(mat, i as Real / nframe as Real * 2.0 * PI))
+            .collect()
+    }
+    fn render_from(&self, mat: DiffuseMaterial, angle: Real) -> Image {
+        let sphere = make_sphere(mat, Transform::eye(), 24, 48);
+        let ground = make_pln(self.ground, Transform::eye()
+            .scale(Vector(20.0, 1.0, 20.0))
+            .translate(Vector(0.0, -0.5, 0.0)));
+        let cam2world = Transform::eye()
+            .translate(Vector(0.0, 0.0, -2.0))
+            .rotate(angle, Vector(0.0, 1.0, 0.0));
+        let cam = Camera::new(cam2world, 40.0_f64.to_radians() as Real, 1.0);
+        let scene = Scene::new(vec![sphere, ground]);
+        let mut rt = DiffuseRayTracer::new(scene, cam, Color::default());
+        rt.environment = Some((Arc::new(default_environment()), 1.0));
+        let n = self.size;
+        let rn = (self.spp.max(1) as f32).recip();
+        let colors = (0..n * n).into_par_iter()
+            .map(|i| {
+                let sum = (0..self.spp.max(1))
+                    .map(|_| rt.ray_gen(i % n, i / n, n, n))
+                    .fold(Color::default(), |a, b| a + b);
+                sum * rn
+            })
+            .collect::<Vec<_>>();
+        let mut img = Image::new(n as usize, n as usize);
+        for (i, c) in colors.into_iter().enumerate() {
+            img.store_px(i % n as usize, i / n as usize, Color(c.0, c.1, c.2, 1.0));
+        }
+        img
+    }
+}
+
+/// Render a thumbnail of `mat` with the default `MaterialPreview`.
+pub fn render_material_preview(mat: DiffuseMaterial) -> Image {
+    MaterialPreview::default().render(mat)
+}
@@ -0,0 +1,263 @@
+use crate::geom::{Real, Point, Ray, Triangle};
+use crate::scene::Scene;
+use crate::bvh::{self, Aabb, Bvh, TriRef};
+use crate::accel::AccelStats;
+use crate::trace;
+
+/// Maximum number of children of a node.
+const WIDTH: usize = 4;
+/// Each collapsed level adds up to `WIDTH - 1` entries to the traversal stack.
+const MAX_STACK: usize = bvh::MAX_DEPTH * (WIDTH - 1) + 1;
+
+#[derive(Debug, Clone, Copy)]
+enum Child {
+    Empty,
+    Node(u32),
+    Leaf { start: u32, ntri: u32 },
+}
+
+/// A node with the bounds of its children quantized to 8 bits per axis,
+/// relative to the bounds of the node itself.
+#[derive(Debug, Clone, Copy)]
+struct QNode {
+    origin: [f32; 3],
+    /// Size of a quantization step along each axis.
+    scale: [f32; 3],
+    qmin: [[u8; 3]; WIDTH],
+    qmax: [[u8; 3]; WIDTH],
+    children: [Child; WIDTH],
+}
+impl QNode {
+    /// Union of the dequantized bounds of the children.
+    fn bounds(&self) -> Aabb {
+        (0..WIDTH)
+            .filter(|&i| !matches!(self.children[i], Child::Empty))
+            .fold(Aabb::empty(), |seed, i| seed.union(dequantize(self, i)))
+    }
+}
+
+/// BVH with `WIDTH`-wide nodes of quantized child bounds, collapsed from a
+/// binary `Bvh`. Nodes are several times smaller than those of the binary
+/// tree, which cuts memory traffic during traversal in large scenes. The
+/// quantized bounds are conservative, so no hit is lost; rays only visit a few
+/// more triangles.
+#[derive(Debug, Clone)]
+pub struct QuantizedBvh {
+    nodes: Vec<QNode>,
+    prims: Vec<(TriRef, Triangle)>,
+}
+impl QuantizedBvh {
+    pub fn build<M: Sync>(scene: &Scene<M>) -> QuantizedBvh {
+        QuantizedBvh::from_bvh(Bvh::build(scene))
+    }
+    pub fn from_bvh(bvh: Bvh) -> QuantizedBvh {
+        let _span = trace::span_with("qbvh_collapse", || format!("{} nodes", bvh.nodes.len()));
+        let mut rv = QuantizedBvh { nodes: Vec::new(), prims: Vec::new() };
+        if let Some(root) = bvh.nodes.first() {
+            if root.ntri > 0 {
+                // Wrap a lone leaf so that the root is always a node.
+                rv.nodes.push(quantize(root.bounds, &[(root.bounds, leaf_of(root))]));
+            } else {
+                rv.collapse(&bvh, 0);
+            }
+        }
+        rv.prims = bvh.prims;
+        rv
+    }
+    /// Collapse binary inner node `inode` and its descendants into wide nodes.
+    /// Returns the index of the wide node.
+    fn collapse(&mut self, bvh: &Bvh, inode: usize) -> u32 {
+        let node = &bvh.nodes[inode];
+        // Open the inner child of the largest surface area until the node is
+        // full.
+        let mut children = vec![node.start, node.start + 1];
+        while children.len() < WIDTH {
+            let best = children.iter()
+                .enumerate()
+                .filter(|(_, &i)| bvh.nodes[i].ntri == 0)
+                .max_by(|(_, &a), (_, &b)| {
+                    let a = bvh.nodes[a].bounds.surface_area();
+                    let b = bvh.nodes[b].bounds.surface_area();
+                    a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .map(|(i, _)| i);
+            match best {
+                Some(i) => {
+                    let opened = children.swap_remove(i);
+                    children.push(bvh.nodes[opened].start);
+                    children.push(bvh.nodes[opened].start + 1);
+                },
+                None => break,
+            }
+        }
+        let iqnode = self.nodes.len();
+        self.nodes.push(quantize(node.bounds, &[]));
+        let children = children.into_iter()
+            .map(|i| {
+                let child = &bvh.nodes[i];
+                if child.ntri > 0 {
+                    (child.bounds, leaf_of(child))
+                } else {
+                    (child.bounds, Child::Node(self.collapse(bvh, i)))
+                }
+            })
+            .collect::<Vec<_>>();
+        self.nodes[iqnode] = quantize(node.bounds, &children);
+        iqnode as u32
+    }
+
+    pub fn stats(&self) -> AccelStats {
+        let mut depth = 0;
+        let mut cost = 0.0;
+        if let Some(root) = self.nodes.first() {
+            let root_area = root.bounds().surface_area();
+            cost = 1.0;
+            let mut stack = vec![(0, 1)];
+            while let Some((inode, d)) = stack.pop() {
+                depth = usize::max(depth, d);
+                let node = &self.nodes[inode];
+                for (i, child) in node.children.iter().enumerate() {
+                    let area = dequantize(node, i).surface_area();
+                    let p = if root_area > 0.0 { area / root_area } else { 1.0 };
+                    match *child {
+                        Child::Empty => {},
+                        Child::Node(x) => {
+                            cost += p;
+                            stack.push((x as usize, d + 1));
+                        },
+                        Child::Leaf { ntri, .. } => cost += p * ntri as Real,
+                    }
+                }
+            }
+        }
+        AccelStats {
+            nnode: self.nodes.len(),
+            depth,
+            bytes: std::mem::size_of_val(self.nodes.as_slice()) +
+                std::mem::size_of_val(self.prims.as_slice()),
+            cost,
+        }
+    }
+
+    /// Invoke `f` with the triangles in the leaves `ray` passes through, in no
+    /// particular order, until `f` returns false.
+    pub fn traverse<F>(&self, ray: &Ray, mut f: F)
+        where F: FnMut(TriRef, &Triangle) -> bool
+    {
+        self.traverse_within(ray, Real::INFINITY, |r, tri, _| f(r, tri))
+    }
+    /// Same as `traverse` but only into nodes `ray` enters within parametric
+    /// distance `tmax`, which `f` can lower, e.g., to the closest hit so far.
+    pub fn traverse_within<F>(&self, ray: &Ray, tmax: Real, f: F)
+        where F: FnMut(TriRef, &Triangle, &mut Real) -> bool
+    {
+        self.traverse_counted(ray, tmax, f, &mut 0)
+    }
+    /// Same as `traverse_within` but the nodes visited are counted into
+    /// `nnode`.
+    pub fn traverse_counted<F>(&self, ray: &Ray, tmax: Real, mut f: F, nnode: &mut usize)
+        where F: FnMut(TriRef, &Triangle, &mut Real) -> bool
+    {
+        if self.nodes.is_empty() { return }
+        let mut tmax = tmax;
+        let mut stack = [(0, 0.0); MAX_STACK];
+        let mut nstack = 1;
+        while nstack > 0 {
+            nstack -= 1;
+            let (inode, tenter) = stack[nstack];
+            if tenter > tmax { continue }
+            let node = &self.nodes[inode as usize];
+            *nnode += 1;
+            for (i, child) in node.children.iter().enumerate() {
+                let bounds = match child {
+                    Child::Empty => continue,
+                    _ => dequantize(node, i),
+                };
+                let tenter = match bounds.clip(ray) {
+                    Some((t0, _)) if t0 <= tmax => t0,
+                    _ => continue,
+                };
+                match *child {
+                    Child::Node(inode) => {
+                        stack[nstack] = (inode, tenter);
+                        nstack += 1;
+                    },
+                    Child::Leaf { start, ntri } => {
+                        let (start, ntri) = (start as usize, ntri as usize);
+                        for (r, tri) in self.prims[start..start + ntri].iter() {
+                            if !f(*r, tri, &mut tmax) { return }
+                        }
+                    },
+                    Child::Empty => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+fn leaf_of(node: &bvh::Node) -> Child {
+    Child::Leaf { start: node.start as u32, ntri: node.ntri as u32 }
+}
+
+fn quantize(bounds: Aabb, children: &[(Aabb, Child)]) -> QNode {
+    let to_arr = |p: Point| [p.0, p.1, p.2];
+    let origin = to_arr(bounds.min);
+    let max = to_arr(bounds.max);
+    let mut rv = QNode {
+        origin: [0.0; 3],
+        scale: [0.0; 3],
+        qmin: [[0; 3]; WIDTH],
+        qmax: [[0; 3]; WIDTH],
+        children: [Child::Empty; WIDTH],
+    };
+    for axis in 0..3 {
+        // Stored in single precision, rounded so that the dequantized bounds
+        // still contain the exact ones.
+        let o = next_down(origin[axis]);
+        let scale = next_up((max[axis] - origin[axis]) / 255.0);
+        rv.origin[axis] = o;
+        rv.scale[axis] = scale;
+        for (i, (cbounds, _)) in children.iter().enumerate() {
+            let (lo, hi) = (to_arr(cbounds.min)[axis], to_arr(cbounds.max)[axis]);
+            let (qlo, qhi) = if scale > 0.0 {
+                let s = scale as Real;
+                let o = o as Real;
+                (((lo - o) / s).floor(), ((hi - o) / s).ceil())
+            } else {
+                (0.0, 0.0)
+            };
+            rv.qmin[i][axis] = qlo.clamp(0.0, 255.0) as u8;
+            rv.qmax[i][axis] = qhi.clamp(0.0, 255.0) as u8;
+        }
+    }
+    for (i, (_, child)) in children.iter().enumerate() {
+        rv.children[i] = *child;
+    }
+    rv
+}
+fn dequantize(node: &QNode, i: usize) -> Aabb {
+    let f = |axis: usize, q: u8| {
+        node.origin[axis] as Real + q as Real * node.scale[axis] as Real
+    };
+    Aabb {
+        min: Point(f(0, node.qmin[i][0]), f(1, node.qmin[i][1]), f(2, node.qmin[i][2])),
+        max: Point(f(0, node.qmax[i][0]), f(1, node.qmax[i][1]), f(2, node.qmax[i][2])),
+    }
+}
+/// The largest `f32` no greater than `x`.
+fn next_down(x: Real) -> f32 {
+    let y = crate::geom::narrow(x);
+    if (y as Real) <= x { y } else { f32::from_bits(step_bits(y, false)) }
+}
+/// The smallest `f32` no less than `x`.
+fn next_up(x: Real) -> f32 {
+    let y = crate::geom::narrow(x);
+    if (y as Real) >= x { y } else { f32::from_bits(step_bits(y, true)) }
+}
+fn step_bits(y: f32, up: bool) -> u32 {
+    let bits = y.to_bits();
+    if y == 0.0 {
+        return if up { 1 } else { 0x8000_0001 };
+    }
+    if (y > 0.0) == up { bits + 1 } else { bits - 1 }
+}
@@ -0,0 +1,56 @@
+//! Random numbers drawn while rendering. Each thread draws from a generator of
+//! its own, seeded from the OS on first use. Targets without an entropy
+//! source, like `wasm32-unknown-unknown` where `rand::random` panics, start
+//! from fixed seeds instead, and `seed` makes renders on the current thread
+//! repeatable anywhere.
+use std::cell::RefCell;
+use rand::{Rng, SeedableRng};
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::StdRng;
+
+thread_local! {
+    static RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Reseed the generator of the current thread, e.g., for repeatable renders
+/// on targets without threads. Threads of a pool are left as they are.
+pub fn seed(seed: u64) {
+    RNG.with(|x| *x.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
+}
+
+/// Random value like `rand::random` from the generator of the current
+/// thread.
+pub fn random<T>() -> T
+    where Standard: Distribution<T>
+{
+    RNG.with(|x| x.borrow_mut().get_or_insert_with(new_rng).gen())
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn new_rng() -> StdRng {
+    StdRng::from_entropy()
+}
+/// Threads are numbered so that they don't draw the same numbers.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn new_rng() -> StdRng {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NTHREAD: AtomicU64 = AtomicU64::new(0);
+    StdRng::seed_from_u64(0x6c69_6768_6172 ^ NTHREAD.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_draws_repeat() {
+        seed(7);
+        let a = (0..16).map(|_| random::<u32>()).collect::<Vec<_>>();
+        seed(7);
+        let b = (0..16).map(|_| random::<u32>()).collect::<Vec<_>>();
+        assert_eq!(a, b);
+        seed(8);
+        let c = (0..16).map(|_| random::<u32>()).collect::<Vec<_>>();
+        assert_ne!(a, c);
+    }
+}
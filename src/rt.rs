@@ -1,30 +1,364 @@
-use std::ops::Mul;
-use crate::geom::{Transform, Triangle, Color};
-use crate::scene::Scene;
+use crate::geom::{Real, Ray, Triangle, Color, faces_away};
+use crate::scene::{Scene, Object, RayKind};
+use crate::img::Image;
+use crate::post::{ColorGrading, Bloom, luminance, false_color};
+use crate::arena::with_verts;
+use crate::accel::{Accel, AccelKind};
+use crate::trace;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub use crate::geom::{HitKind, Intersection};
 
 pub trait Framebuffer : Send + Sync {
     fn width(&self) -> u32;
     fn height(&self) -> u32;
     fn store(&mut self, x: u32, y: u32, color: Color);
+    /// Called once all `pixels` of a tile have been stored by
+    /// `RayTracer::draw_region`, e.g., to stream finished tiles to disk.
+    /// Does nothing by default.
+    fn end_tile(&mut self, _pixels: &[(u32, u32)]) {}
+}
+
+/// Configurations of a render.
+pub struct RenderSettings {
+    /// Color grading applied to the float image.
+    pub grading: ColorGrading,
+    /// Glare added around bright pixels before color grading.
+    pub bloom: Option<Bloom>,
+    /// Aborts the render when cancelled.
+    pub cancel: CancelToken,
+    /// Only pixels in the region are rendered; the rest of the framebuffer is
+    /// left untouched. The whole frame if `None`.
+    pub region: Option<Region>,
+    /// Samples per pixel, each drawn in a pass over the frame.
+    pub spp: u32,
+    /// Stop once the render has taken this long, keeping the samples drawn so
+    /// far, e.g., for previews at a fixed latency.
+    pub max_time: Option<Duration>,
+    /// Stop once this many rays have been traced, counting camera, shadow and
+    /// spawned rays alike, e.g., to benchmark at a fixed cost. Tiles already
+    /// started are finished, so a few more rays might be traced.
+    pub max_rays: Option<u64>,
+    /// Acceleration structure to build tracers with instead of the one the
+    /// scene asks for, see `SceneDesc::into_tracer_with`.
+    pub accel: Option<AccelKind>,
+}
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            grading: ColorGrading::default(),
+            bloom: None,
+            cancel: CancelToken::default(),
+            region: None,
+            spp: 1,
+            max_time: None,
+            max_rays: None,
+            accel: None,
+        }
+    }
+}
+impl RenderSettings {
+    /// Render only pixels `x0..x1` of rows `y0..y1`, e.g., to iterate on a
+    /// small part of a large frame.
+    pub fn region(self, x0: u32, y0: u32, x1: u32, y1: u32) -> RenderSettings {
+        RenderSettings { region: Some(Region { x0, y0, x1, y1 }), ..self }
+    }
+}
+
+/// Rectangle of pixels `x0..x1` of rows `y0..y1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+impl Region {
+    /// The whole of a `w` by `h` frame.
+    pub fn full(w: u32, h: u32) -> Region {
+        Region { x0: 0, y0: 0, x1: w, y1: h }
+    }
+    /// The part of the region within a `w` by `h` frame.
+    pub fn clamp(self, w: u32, h: u32) -> Region {
+        let x1 = self.x1.min(w);
+        let y1 = self.y1.min(h);
+        Region { x0: self.x0.min(x1), y0: self.y0.min(y1), x1, y1 }
+    }
+}
+
+/// Handle to abort a render in progress, e.g., from a UI thread. Clones share
+/// the same state. Renders check it before each tile, so they stop within
+/// about the time of tracing a tile.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A render was aborted by its `CancelToken`. Part of the framebuffer might
+/// have been drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "render cancelled")
+    }
+}
+impl std::error::Error for Cancelled {}
+
+/// Summary of a render by `RayTracer::render`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderReport {
+    /// Passes drawn over the whole region. Pixels of the pass cut short by a
+    /// budget have one more sample than this.
+    pub spp: u32,
+    /// Rays traced.
+    pub rays: u64,
+    /// Whether the render stopped early for `RenderSettings::max_time` or
+    /// `RenderSettings::max_rays`.
+    pub budget_exhausted: bool,
+}
+
+thread_local! {
+    /// Rays traced on this thread, by `RayTracer::closest` and
+    /// `RayTracer::occluded_by`.
+    static NRAY: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+fn count_ray() {
+    NRAY.with(|x| x.set(x.get() + 1));
+}
+fn nray() -> u64 {
+    NRAY.with(|x| x.get())
+}
+
+/// Whether `ray` passes through `tri` of a surface letting through the
+/// fraction `transparency` of light, chosen at random with that probability,
+/// for `RayTracer::any_hit`. Instead of gathering and sorting every hit on
+/// stacked transparent surfaces, e.g., of foliage, each ray stops at one of
+/// them, which converges to the same image over many samples. The choice
+/// hashes the ray and the triangle, so a triangle tested again by the same
+/// ray, e.g., in several leaves of a kd-tree, is passed through alike.
+pub fn stochastic_pass(ray: &Ray, tri: &Triangle, transparency: f32) -> bool {
+    if transparency <= 0.0 { return false }
+    if transparency >= 1.0 { return true }
+    let xs = [
+        ray.o.0, ray.o.1, ray.o.2, ray.v.0, ray.v.1, ray.v.2,
+        tri.o.0, tri.o.1, tri.o.2, tri.x.0, tri.x.1, tri.x.2,
+    ];
+    let h = xs.iter().fold(0u32, |h, &x| {
+        crate::sampler::pixel_hash(crate::geom::narrow(x).to_bits(), h, 0x7472)
+    });
+    ((h >> 8) as f32 / (1 << 24) as f32) < transparency
+}
+
+/// Framebuffer summing the samples of each pixel.
+struct Accumulator {
+    w: u32,
+    h: u32,
+    sums: Vec<(Color, u32)>,
+}
+impl Framebuffer for Accumulator {
+    fn width(&self) -> u32 { self.w }
+    fn height(&self) -> u32 { self.h }
+    fn store(&mut self, x: u32, y: u32, color: Color) {
+        let (sum, n) = &mut self.sums[(y * self.w + x) as usize];
+        *sum = *sum + color;
+        *n += 1;
+    }
+}
+
+/// Draw the tiles of `region` with `rt` until `stop` returns true, which is
+/// checked before each tile. Returns whether all tiles were drawn. Rays
+/// traced are added to `rays`.
+fn draw_tiles<T, FB, F>(rt: &T, framebuf: &mut FB, region: Region, stop: F, rays: &AtomicU64) -> bool
+    where T: RayTracer + ?Sized,
+          FB: Framebuffer,
+          F: Fn() -> bool + Sync,
+{
+    use crate::par::*;
+    let w = framebuf.width();
+    let h = framebuf.height();
+    let region = region.clamp(w, h);
+    let framebuf = std::sync::Mutex::new(framebuf);
+    let stopped = AtomicBool::new(false);
+
+    // Each chunk of the Morton order is a square tile, traced by one thread
+    // so that adjacent rays share cached nodes and triangles. Tiles are
+    // aligned to the corner of the region.
+    let order = morton_order(region.x1 - region.x0, region.y1 - region.y0)
+        .into_iter()
+        .map(|(x, y)| (region.x0 + x, region.y0 + y))
+        .collect::<Vec<_>>();
+    order.par_chunks(TILE_SIZE * TILE_SIZE)
+        .for_each(|tile| {
+            if stopped.load(Ordering::Relaxed) || stop() {
+                stopped.store(true, Ordering::Relaxed);
+                return;
+            }
+            let _span = trace::span_with("tile", || {
+                let (x, y) = tile[0];
+                format!("{}, {}", x as usize / TILE_SIZE, y as usize / TILE_SIZE)
+            });
+            let nray0 = nray();
+            let colors = tile.iter()
+                .map(|&(x, y)| rt.ray_gen(x, y, w, h))
+                .collect::<Vec<_>>();
+            rays.fetch_add(nray() - nray0, Ordering::Relaxed);
+            let mut framebuf = framebuf.lock().unwrap();
+            for (&(x, y), color) in tile.iter().zip(colors) {
+                framebuf.store(x, y, color);
+            }
+            framebuf.end_tile(tile);
+        });
+    !stopped.into_inner()
+}
+
+/// Settings of `RayTracer::draw_adaptive`.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveSampling {
+    /// Samples every pixel takes before its noise is estimated. Noise can't
+    /// be estimated from a single sample, so pixels take at least 2 samples
+    /// even if this is lower.
+    pub min_spp: u32,
+    /// Samples a pixel takes at most, raised to `min_spp` if lower.
+    pub max_spp: u32,
+    /// A pixel stops sampling once the standard error of its mean luminance
+    /// falls below this fraction of the mean.
+    pub threshold: f32,
+}
+impl Default for AdaptiveSampling {
+    fn default() -> AdaptiveSampling {
+        AdaptiveSampling { min_spp: 16, max_spp: 1024, threshold: 0.02 }
+    }
+}
+
+/// Per-pixel statistics of an adaptive render, in row-major order, to tell
+/// where samples went and why, e.g., to tune `AdaptiveSampling::threshold`.
+#[derive(Debug, Clone)]
+pub struct SampleStats {
+    pub w: u32,
+    pub h: u32,
+    /// Number of samples taken by each pixel.
+    pub counts: Vec<u32>,
+    /// Sample variance of the luminance of each pixel.
+    pub variances: Vec<f32>,
+}
+impl SampleStats {
+    /// Samples per pixel taken on average, rounded to the nearest integer,
+    /// e.g., to be recorded as `RenderMetadata::spp`.
+    pub fn mean_spp(&self) -> u32 {
+        if self.counts.is_empty() { return 0 }
+        let sum = self.counts.iter().map(|&x| x as u64).sum::<u64>();
+        let n = self.counts.len() as u64;
+        ((sum + n / 2) / n) as u32
+    }
+    /// False color heatmap of `counts`, from `min_spp` in blue to `max_spp`
+    /// in red.
+    pub fn count_heatmap(&self, settings: &AdaptiveSampling) -> Image {
+        let lo = settings.min_spp as f32;
+        let range = (settings.max_spp as f32 - lo).max(1.0);
+        self.heatmap(|i| (self.counts[i] as f32 - lo) / range)
+    }
+    /// False color heatmap of `variances` on a log scale, from 0 in blue to
+    /// the largest variance in the frame in red.
+    pub fn variance_heatmap(&self) -> Image {
+        let max = self.variances.iter().cloned().fold(0.0, f32::max);
+        if max <= 0.0 { return self.heatmap(|_| 0.0) }
+        self.heatmap(|i| self.variances[i].ln_1p() / max.ln_1p())
+    }
+    fn heatmap<F: Fn(usize) -> f32>(&self, f: F) -> Image {
+        let (w, h) = (self.w as usize, self.h as usize);
+        let mut img = Image::new(w, h);
+        for i in 0..w * h {
+            img.store_px(i % w, i / w, false_color(f(i)));
+        }
+        img
+    }
+}
+
+/// Depth and throughput of a ray among rays traced from a camera ray. Ray
+/// tracers using it as their payload spawn rays by `trace_child`, and paths
+/// of `PathTracer`s advance it by each bounce, which bounds the depth, so
+/// that `closest_hit` and `scatter` don't need to.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceContext {
+    /// Number of rays from the camera ray, which is of depth 0.
+    pub depth: u32,
+    /// Depth beyond which no ray is traced.
+    pub max_depth: u32,
+    /// Product of the weights of the rays from the camera ray, i.e., the
+    /// fraction of the radiance of this ray reaching the camera.
+    pub throughput: Color,
+    /// Wavelength in nanometers the path was narrowed to at its first
+    /// dispersive interface, if any, see `optics::sample_wavelength`. Rays
+    /// spawned from the path carry it, so that every later interface bends
+    /// light of the same wavelength.
+    pub wavelength: Option<Real>,
+}
+impl Default for TraceContext {
+    fn default() -> TraceContext {
+        TraceContext::new(8)
+    }
+}
+impl TraceContext {
+    /// Context of a camera ray.
+    pub fn new(max_depth: u32) -> TraceContext {
+        TraceContext {
+            depth: 0,
+            max_depth,
+            throughput: Color(1.0, 1.0, 1.0, 1.0),
+            wavelength: None,
+        }
+    }
+    /// Whether rays of this context can spawn more rays.
+    pub fn can_recurse(&self) -> bool {
+        self.depth < self.max_depth
+    }
+    /// Context of a ray spawned with `weight`, or `None` beyond `max_depth`
+    /// or if none of the radiance of the ray would reach the camera.
+    pub fn child(&self, weight: Color) -> Option<TraceContext> {
+        if !self.can_recurse() { return None }
+        let throughput = self.throughput * weight;
+        if throughput.0 <= 0.0 && throughput.1 <= 0.0 && throughput.2 <= 0.0 { return None }
+        Some(TraceContext { depth: self.depth + 1, throughput, ..*self })
+    }
 }
 
-#[derive(PartialEq, Eq)]
-pub enum HitKind {
-    Front, Back
+/// Payload of rays, which may carry the `TraceContext` bounding how deep
+/// rays are traced.
+pub trait TracePayload {
+    fn context(&self) -> Option<&TraceContext> { None }
+    fn context_mut(&mut self) -> Option<&mut TraceContext> { None }
+}
+impl TracePayload for () {}
+impl TracePayload for TraceContext {
+    fn context(&self) -> Option<&TraceContext> { Some(self) }
+    fn context_mut(&mut self) -> Option<&mut TraceContext> { Some(self) }
 }
-pub struct Intersection<RayAttr> {
-    /// Data used to describe a hit, say, the position of intersection.
-    pub attr: RayAttr,
-    /// Front face or back face.
-    pub kind: HitKind,
-    /// Distance from ray origin to triangle.
-    pub t: f32,
+
+/// The closest accepted hit of a ray, ready to be shaded by `closest_hit`.
+pub struct HitRecord<'a, Material, RayAttr> {
+    /// Index of the object hit in the scene.
+    pub obj: usize,
+    pub tri: Triangle,
+    pub mat: &'a Material,
+    pub intersect: Intersection<RayAttr>,
 }
 
 pub trait RayTracer : Sync + Send {
     type Material;
     /// User specified data for computation.
-    type Payload;
+    type Payload: TracePayload;
     /// Ray data.
     type Ray: Clone;
     /// Data that describes how a ray intersected with a primitive.
@@ -45,6 +379,31 @@ pub trait RayTracer : Sync + Send {
         tri: &Triangle,
         mat: &Self::Material,
     ) -> Option<Intersection<Self::RayAttr>>;
+    /// Same as `intersect` but only hits closer than `tmax` are of interest.
+    /// Override it to reject farther triangles before the intersection is
+    /// fully computed, e.g., by passing `tmax` to `ray_cast_tri_with` as
+    /// `Precision::max_t`.
+    fn intersect_within(
+        &self,
+        ray: &Self::Ray,
+        tri: &Triangle,
+        mat: &Self::Material,
+        tmax: Real,
+    ) -> Option<Intersection<Self::RayAttr>> {
+        self.intersect(ray, tri, mat).filter(|x| x.t < tmax)
+    }
+    /// Intersect `ray` with the `Shape` of `obj` closer than `tmax`, along
+    /// with the triangle standing in for the surface hit, see `Shape`. Shapes
+    /// cannot be hit by default; tracers of `Ray`s with barycentric
+    /// attributes can see them with `Object::ray_cast_shape`.
+    fn intersect_shape(
+        &self,
+        _ray: &Self::Ray,
+        _obj: &Object<Self::Material>,
+        _tmax: Real,
+    ) -> Option<(Triangle, Intersection<Self::RayAttr>)> {
+        None
+    }
     /// The ray hit any object. Returns whether the hit is accepted.
     fn any_hit(
         &self,
@@ -60,74 +419,789 @@ pub trait RayTracer : Sync + Send {
         ray: &Self::Ray,
         payload: &mut Self::Payload
     ) -> Color;
-    /// The ray hit the nearest object.
+    /// The ray hit the nearest object, the `obj`-th of the scene.
     fn closest_hit(
         &self,
         ray: &Self::Ray,
+        obj: usize,
         tri: &Triangle,
         intersect: &Intersection<Self::RayAttr>,
         payload: &mut Self::Payload,
         mat: &Self::Material,
     ) -> Color;
 
-    /// Trace ray in the scene.
+    /// Acceleration structure to look up the triangles `ray` might hit in,
+    /// along with `ray` as a geometric ray to traverse it with. By default no
+    /// structure is used and every triangle in the scene is tested. Hit
+    /// distances of `intersect` are taken as parametric distances along the
+    /// geometric ray, so that the traversal stops short of them.
+    fn accel(&self, _ray: &Self::Ray) -> Option<(&dyn Accel, Ray)> {
+        None
+    }
+
+    /// Trace a ray spawned from a surface in the scene. Same as `trace_as`
+    /// with `RayKind::Reflection`.
     fn trace(
         &self,
         ray: Self::Ray,
         payload: &mut Self::Payload,
     ) -> Color {
-        let mut closest: Option<(
-            Triangle,
-            &Self::Material,
-            Intersection<Self::RayAttr>,
-        )> = None;
-        for obj in self.scene().objs.iter() {
-            let verts = obj.verts.iter()
-                .map(|&x| obj.world2obj * x)
-                .collect::<Vec<_>>();
-            for (x, y, z) in obj.idxs.iter() {
-                let tri = Triangle::new(
-                    verts[*x],
-                    verts[*y],
-                    verts[*z],
-                );
-                if let Some(x) = self.intersect(&ray, &tri, &obj.mat) {
-                    if self.any_hit(&ray, &tri, &x, payload, &obj.mat) {
-                        let tmax = closest.as_ref()
-                            .map(|(_, _, intersect)| intersect.t)
-                            .unwrap_or(std::f32::INFINITY);
-                        if x.t < tmax {
-                            closest = Some((tri, &obj.mat, x));
+        self.trace_as(ray, RayKind::Reflection, payload)
+    }
+    /// Trace `ray` spawned by a ray of context `ctx` like `trace`, where the
+    /// radiance of `ray` contributes to that of the spawning ray by `weight`.
+    /// `None` if `ray` would be deeper than `TraceContext::max_depth`, in
+    /// which case nothing is traced.
+    fn trace_child(
+        &self,
+        ray: Self::Ray,
+        ctx: &TraceContext,
+        weight: Color,
+    ) -> Option<Color>
+        where Self: RayTracer<Payload = TraceContext>,
+    {
+        let mut child = ctx.child(weight)?;
+        Some(self.trace(ray, &mut child))
+    }
+    /// Trace ray in the scene, only seeing objects visible to `kind`. Rays
+    /// deeper than the `max_depth` of their `TraceContext` are black.
+    fn trace_as(
+        &self,
+        ray: Self::Ray,
+        kind: RayKind,
+        payload: &mut Self::Payload,
+    ) -> Color {
+        if payload.context().is_some_and(|x| x.depth > x.max_depth) {
+            return Color::default();
+        }
+        if let Some(hit) = self.closest(&ray, kind, payload) {
+            self.closest_hit(&ray, hit.obj, &hit.tri, &hit.intersect, payload, hit.mat)
+        } else {
+            self.miss(&ray, payload)
+        }
+    }
+
+    /// Find the closest hit accepted by `any_hit` among objects visible to
+    /// `kind`, without shading it. The search is bounded by the closest hit
+    /// so far, so farther triangles are rejected early and `any_hit` is only
+    /// invoked for hits closer than any accepted one.
+    fn closest(
+        &self,
+        ray: &Self::Ray,
+        kind: RayKind,
+        payload: &mut Self::Payload,
+    ) -> Option<HitRecord<'_, Self::Material, Self::RayAttr>> {
+        count_ray();
+        let mut closest: Option<HitRecord<'_, Self::Material, Self::RayAttr>> = None;
+        let objs = &self.scene().objs;
+        if let Some((accel, geom_ray)) = self.accel(ray) {
+            accel.traverse_within(&geom_ray, Real::INFINITY, &mut |r, tri, tmax| {
+                let obj = &objs[r.obj];
+                if !obj.visibility.visible_to(kind) { return true }
+                if obj.cull_backfaces && faces_away(&geom_ray, tri) { return true }
+                if let Some(x) = self.intersect_within(ray, tri, &obj.mat, *tmax) {
+                    if x.t < *tmax && self.any_hit(ray, tri, &x, payload, &obj.mat) {
+                        *tmax = x.t;
+                        closest = Some(HitRecord {
+                            obj: r.obj,
+                            tri: tri.clone(),
+                            mat: &obj.mat,
+                            intersect: Intersection { prim: r.tri, ..x },
+                        });
+                    }
+                }
+                true
+            });
+        } else {
+            with_verts(|verts| {
+                let mut tmax = Real::INFINITY;
+                for (i, obj) in objs.iter().enumerate() {
+                    if !obj.visibility.visible_to(kind) { continue }
+                    verts.clear();
+                    verts.extend(obj.verts.iter().map(|&x| obj.world2obj * x));
+                    for (prim, (x, y, z)) in obj.idxs.iter().enumerate() {
+                        let tri = Triangle::new(
+                            verts[*x],
+                            verts[*y],
+                            verts[*z],
+                        );
+                        if let Some(x) = self.intersect_within(ray, &tri, &obj.mat, tmax) {
+                            // No geometric ray to cull with before intersection.
+                            if obj.cull_backfaces && x.kind == HitKind::Back { continue }
+                            if x.t < tmax && self.any_hit(ray, &tri, &x, payload, &obj.mat) {
+                                tmax = x.t;
+                                let intersect = Intersection { prim, ..x };
+                                closest = Some(HitRecord { obj: i, tri, mat: &obj.mat, intersect });
+                            }
                         }
                     }
                 }
+            });
+        }
+        // Shapes aren't in acceleration structures, each is bounded on its
+        // own instead.
+        let mut tmax = closest.as_ref().map_or(Real::INFINITY, |x| x.intersect.t);
+        for (i, obj) in objs.iter().enumerate() {
+            if obj.shape.is_none() || !obj.visibility.visible_to(kind) { continue }
+            if let Some((tri, x)) = self.intersect_shape(ray, obj, tmax) {
+                if obj.cull_backfaces && x.kind == HitKind::Back { continue }
+                if x.t < tmax && self.any_hit(ray, &tri, &x, payload, &obj.mat) {
+                    tmax = x.t;
+                    closest = Some(HitRecord { obj: i, tri, mat: &obj.mat, intersect: x });
+                }
             }
         }
-        if let Some((tri, mat, intersect)) = closest {
-            self.closest_hit(&ray, &tri, &intersect, payload, mat)
+        closest
+    }
+
+    /// Test whether `ray` is blocked by any object casting shadows. Like a ray
+    /// traced with terminate-on-first-hit and skip-closest-hit flags, only
+    /// `intersect` and `any_hit` are invoked; neither `closest_hit` nor `miss`
+    /// is called.
+    fn occluded(
+        &self,
+        ray: Self::Ray,
+        payload: &mut Self::Payload,
+    ) -> bool {
+        self.occluded_within(ray, Real::INFINITY, payload)
+    }
+    /// Same as `occluded` but only blocked by hits closer than `tmax`, e.g.,
+    /// to test whether two points see each other.
+    fn occluded_within(
+        &self,
+        ray: Self::Ray,
+        tmax: Real,
+        payload: &mut Self::Payload,
+    ) -> bool {
+        self.occluded_by(ray, tmax, payload, |_| true)
+    }
+    /// Same as `occluded_within` but only blocked by objects whose indices in
+    /// the scene pass `casts`, e.g., for shadow linking.
+    fn occluded_by<F>(
+        &self,
+        ray: Self::Ray,
+        tmax: Real,
+        payload: &mut Self::Payload,
+        casts: F,
+    ) -> bool
+        where F: Fn(usize) -> bool,
+    {
+        count_ray();
+        let objs = &self.scene().objs;
+        let hit = if let Some((accel, geom_ray)) = self.accel(&ray) {
+            let mut hit = false;
+            accel.traverse_within(&geom_ray, tmax, &mut |r, tri, _| {
+                let obj = &objs[r.obj];
+                if !obj.visibility.shadow || !casts(r.obj) { return true }
+                if obj.cull_backfaces && faces_away(&geom_ray, tri) { return true }
+                if let Some(x) = self.intersect_within(&ray, tri, &obj.mat, tmax) {
+                    hit = x.t < tmax && self.any_hit(&ray, tri, &x, payload, &obj.mat);
+                }
+                !hit
+            });
+            hit
         } else {
-            self.miss(&ray, payload)
-        }
+            with_verts(|verts| {
+                for (i, obj) in objs.iter().enumerate() {
+                    if !obj.visibility.shadow || !casts(i) { continue }
+                    verts.clear();
+                    verts.extend(obj.verts.iter().map(|&x| obj.world2obj * x));
+                    for (x, y, z) in obj.idxs.iter() {
+                        let tri = Triangle::new(
+                            verts[*x],
+                            verts[*y],
+                            verts[*z],
+                        );
+                        if let Some(x) = self.intersect_within(&ray, &tri, &obj.mat, tmax) {
+                            if obj.cull_backfaces && x.kind == HitKind::Back { continue }
+                            if x.t < tmax && self.any_hit(&ray, &tri, &x, payload, &obj.mat) {
+                                return true;
+                            }
+                        }
+                    }
+                }
+                false
+            })
+        };
+        if hit { return true }
+        objs.iter().enumerate().any(|(i, obj)| {
+            if obj.shape.is_none() || !obj.visibility.shadow || !casts(i) { return false }
+            match self.intersect_shape(&ray, obj, tmax) {
+                Some((_, x)) if obj.cull_backfaces && x.kind == HitKind::Back => false,
+                Some((tri, x)) => x.t < tmax && self.any_hit(&ray, &tri, &x, payload, &obj.mat),
+                None => false,
+            }
+        })
     }
 
     fn draw<FB>(&self, framebuf: &mut FB)
         where FB: Framebuffer
     {
-        use rayon::prelude::*;
+        // Never cancelled.
+        let _ = self.draw_cancellable(framebuf, &CancelToken::new());
+    }
+    /// Same as `draw` but stops early once `cancel` is cancelled. Tiles not
+    /// started yet are left as they are in `framebuf`.
+    fn draw_cancellable<FB>(
+        &self,
+        framebuf: &mut FB,
+        cancel: &CancelToken,
+    ) -> Result<(), Cancelled>
+        where FB: Framebuffer
+    {
+        let region = Region::full(framebuf.width(), framebuf.height());
+        self.draw_region(framebuf, region, cancel)
+    }
+    /// Same as `draw_cancellable` but only draws pixels in `region`.
+    fn draw_region<FB>(
+        &self,
+        framebuf: &mut FB,
+        region: Region,
+        cancel: &CancelToken,
+    ) -> Result<(), Cancelled>
+        where FB: Framebuffer
+    {
+        draw_tiles(self, framebuf, region, || cancel.is_cancelled(), &AtomicU64::new(0));
+        if cancel.is_cancelled() { Err(Cancelled) } else { Ok(()) }
+    }
+
+    /// Draw with as many samples per pixel as needed for the noise of each
+    /// pixel to fall below `settings.threshold`, spending more samples where
+    /// the image is noisy. Each pixel is the mean of its samples. Returns
+    /// where the samples went.
+    fn draw_adaptive<FB>(&self, framebuf: &mut FB, settings: &AdaptiveSampling) -> SampleStats
+        where FB: Framebuffer
+    {
+        use crate::par::*;
         let w = framebuf.width();
         let h = framebuf.height();
-        let framebuf = std::sync::Arc::new(std::sync::Mutex::new(framebuf));
-
-        (0..w).into_par_iter()
-            .for_each(|x| {
-                (0..h).into_par_iter()
-                    .for_each(|y| {
-                        framebuf.lock().unwrap()
-                            .store(x, y, self.ray_gen(x, y, w, h));
-                    });
-            });
+        // See `AdaptiveSampling::min_spp`.
+        let min_spp = settings.min_spp.max(2);
+        let max_spp = settings.max_spp.max(min_spp);
+        let pixels = (0..w * h).into_par_iter()
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                // Running mean and sum of squared deviations of the luminance.
+                // See: B. P. Welford, Note on a Method for Calculating
+                // Corrected Sums of Squares and Products.
+                let (mut n, mut mean, mut m2) = (0u32, 0.0f32, 0.0f32);
+                let mut sum = Color::default();
+                while n < max_spp {
+                    let c = self.ray_gen(x, y, w, h);
+                    sum = sum + c;
+                    n += 1;
+                    let l = luminance(c);
+                    let d = l - mean;
+                    mean += d / n as f32;
+                    m2 += d * (l - mean);
+                    if n >= min_spp {
+                        let var = m2 / (n - 1) as f32;
+                        if (var / n as f32).sqrt() <= settings.threshold * mean.abs() {
+                            break;
+                        }
+                    }
+                }
+                (sum * (n as f32).recip(), n, m2 / (n - 1) as f32)
+            })
+            .collect::<Vec<_>>();
+        let mut counts = Vec::with_capacity(pixels.len());
+        let mut variances = Vec::with_capacity(pixels.len());
+        for (i, (color, n, var)) in pixels.into_iter().enumerate() {
+            framebuf.store(i as u32 % w, i as u32 / w, color);
+            counts.push(n);
+            variances.push(var);
+        }
+        SampleStats { w, h, counts, variances }
+    }
+
+    /// Draw `settings.spp` samples per pixel into an intermediate float
+    /// image, post-process it as configured by `settings`, and then store the
+    /// result into `framebuf`. `framebuf` is left untouched if the render is
+    /// cancelled. If a budget of `settings` runs out, the mean of the samples
+    /// drawn so far is stored instead; pixels without any are black.
+    fn render<FB>(
+        &self,
+        framebuf: &mut FB,
+        settings: &RenderSettings,
+    ) -> Result<RenderReport, Cancelled>
+        where FB: Framebuffer
+    {
+        let _span = trace::span("render");
+        let w = framebuf.width();
+        let h = framebuf.height();
+        let region = settings.region.unwrap_or_else(|| Region::full(w, h)).clamp(w, h);
+        // The clock is only read with a time budget, as it's unavailable on
+        // some targets.
+        let deadline = settings.max_time.map(|x| Instant::now() + x);
+        let rays = AtomicU64::new(0);
+        let exhausted = || {
+            deadline.is_some_and(|x| Instant::now() >= x) ||
+                settings.max_rays.is_some_and(|x| rays.load(Ordering::Relaxed) >= x)
+        };
+        let mut accum = Accumulator { w, h, sums: vec![(Color::default(), 0); (w * h) as usize] };
+        let mut report = RenderReport { spp: 0, rays: 0, budget_exhausted: false };
+        while report.spp < settings.spp {
+            let stop = || settings.cancel.is_cancelled() || exhausted();
+            if !draw_tiles(self, &mut accum, region, stop, &rays) { break }
+            report.spp += 1;
+        }
+        if settings.cancel.is_cancelled() { return Err(Cancelled) }
+        report.rays = rays.into_inner();
+        report.budget_exhausted = report.spp < settings.spp;
+        // Pixels out of the region stay black, so bloom doesn't spread from
+        // beyond the region.
+        let mut hdr = Image::new(w as usize, h as usize);
+        for (i, &(sum, n)) in accum.sums.iter().enumerate() {
+            if n > 0 {
+                hdr.store_px(i % w as usize, i / w as usize, sum * (n as f32).recip());
+            }
+        }
+        let _post = trace::span("post_process");
+        if let Some(bloom) = &settings.bloom {
+            bloom.apply_img(&mut hdr);
+        }
+        settings.grading.apply_img(&mut hdr);
+        for y in region.y0..region.y1 {
+            for x in region.x0..region.x1 {
+                framebuf.store(x, y, hdr.load_px(x as usize, y as usize));
+            }
+        }
+        if report.budget_exhausted {
+            trace::event(trace::Level::Info, &format!(
+                "render budget exhausted after {} passes and {} rays", report.spp, report.rays));
+        }
+        Ok(report)
     }
 
     /// The scene the tracer is bound to.
     fn scene(&self) -> &Scene<Self::Material>;
 }
+
+/// Closest hit of a primary ray, kept by `HitCache`.
+pub struct CachedHit<RayAttr> {
+    /// Index of the object hit in the scene.
+    pub obj: usize,
+    pub tri: Triangle,
+    pub intersect: Intersection<RayAttr>,
+}
+/// Primary rays and their closest hits of every pixel of a frame, kept across
+/// progressive passes so that passes after the first don't traverse the scene
+/// for primary rays again. It's only valid as long as the camera and the
+/// geometry are static; `clear` it after either changes.
+pub struct HitCache<Ray, RayAttr> {
+    w: u32,
+    h: u32,
+    /// In the order pixels are traced.
+    entries: Vec<(Ray, Option<CachedHit<RayAttr>>)>,
+    /// Index into `entries` of each pixel, row by row.
+    index: Vec<usize>,
+    /// Weight of the primary sample of each entry, see
+    /// `WavefrontRayTracer::primary_sample`.
+    weights: Vec<Color>,
+    /// Whether shading each entry traced more rays, e.g., shadow rays or
+    /// bounces, through which it may see any object. Empty until shaded.
+    traced: Vec<bool>,
+}
+impl<Ray, RayAttr> HitCache<Ray, RayAttr> {
+    pub fn new() -> HitCache<Ray, RayAttr> {
+        HitCache {
+            w: 0,
+            h: 0,
+            entries: Vec::new(),
+            index: Vec::new(),
+            weights: Vec::new(),
+            traced: Vec::new(),
+        }
+    }
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.weights.clear();
+        self.index.clear();
+        self.traced.clear();
+    }
+    /// Whether the cache holds the primary hits of a `w` by `h` frame.
+    pub fn is_filled_for(&self, w: u32, h: u32) -> bool {
+        self.w == w && self.h == h && self.entries.len() == (w * h) as usize
+    }
+    /// Primary ray of pixel `(x, y)` and its closest hit, if the pixel is in
+    /// the cached frame.
+    pub fn get(&self, x: u32, y: u32) -> Option<(&Ray, Option<&CachedHit<RayAttr>>)> {
+        if x >= self.w || y >= self.h { return None }
+        let (ray, hit) = self.entries.get(*self.index.get((y * self.w + x) as usize)?)?;
+        Some((ray, hit.as_ref()))
+    }
+}
+impl<Ray, RayAttr> Default for HitCache<Ray, RayAttr> {
+    fn default() -> HitCache<Ray, RayAttr> {
+        HitCache::new()
+    }
+}
+
+/// Objects edited since the last render, recorded by whoever edits the scene
+/// and consumed by `WavefrontRayTracer::draw_wavefront_incremental`.
+#[derive(Debug, Clone, Default)]
+pub struct SceneChanges {
+    /// Objects whose materials changed.
+    materials: Vec<usize>,
+    /// Objects that moved or deformed.
+    geometry: Vec<usize>,
+}
+impl SceneChanges {
+    pub fn new() -> SceneChanges {
+        SceneChanges::default()
+    }
+    /// Record that the material of the `iobj`-th object changed.
+    pub fn material_changed(&mut self, iobj: usize) {
+        if !self.materials.contains(&iobj) {
+            self.materials.push(iobj);
+        }
+    }
+    /// Record that the `iobj`-th object moved or deformed.
+    pub fn geometry_changed(&mut self, iobj: usize) {
+        if !self.geometry.contains(&iobj) {
+            self.geometry.push(iobj);
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty() && self.geometry.is_empty()
+    }
+    /// Whether any object moved or deformed.
+    pub fn has_geometry(&self) -> bool {
+        !self.geometry.is_empty()
+    }
+    /// Whether the `iobj`-th object changed in any way.
+    pub fn touches(&self, iobj: usize) -> bool {
+        self.materials.contains(&iobj) || self.geometry.contains(&iobj)
+    }
+    pub fn clear(&mut self) {
+        self.materials.clear();
+        self.geometry.clear();
+    }
+}
+
+/// Side length of the square tiles pixels are traced in. Must be a power of
+/// two for tiles to be contiguous in the Morton order.
+pub(crate) const TILE_SIZE: usize = 16;
+
+/// Interleave the bits of `x` and `y`, `x` in the even bits.
+fn morton2(x: u32, y: u32) -> u64 {
+    let spread = |x: u32| {
+        let mut x = x as u64;
+        x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+        x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+        x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+        x = (x | (x << 1)) & 0x5555_5555_5555_5555;
+        x
+    };
+    spread(x) | (spread(y) << 1)
+}
+/// Coordinates of all pixels of a `w` by `h` image in Morton order, so that
+/// pixels close in the order are also close on screen, and every aligned
+/// power-of-two square is contiguous.
+pub fn morton_order(w: u32, h: u32) -> Vec<(u32, u32)> {
+    let mut rv = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .collect::<Vec<_>>();
+    rv.sort_unstable_by_key(|&(x, y)| morton2(x, y));
+    rv
+}
+
+/// Number of rays processed together in each stage of wavefront tracing.
+pub(crate) const WAVEFRONT_BATCH: usize = 4096;
+
+/// Ray tracers that can also be driven in wavefront order. Instead of tracing
+/// each pixel from generation to shading before moving to the next, primary
+/// rays are processed in large batches, one stage at a time: all rays of a
+/// batch are generated, then intersected, then shaded, before the results are
+/// stored. Each stage runs the same code over many rays, which is friendlier
+/// to caches and leaves room for vectorized stages in the future.
+pub trait WavefrontRayTracer : RayTracer {
+    /// Generate the primary ray of pixel `(x, y)` with its initial payload.
+    fn primary_ray(
+        &self,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> (Self::Ray, Self::Payload);
+    /// Same as `primary_ray` along with the weight of the radiance the ray
+    /// carries, e.g., for vignetting, by which the color of the pixel sample
+    /// is multiplied. The alpha of the weight is 1. White by default.
+    fn primary_sample(
+        &self,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> (Self::Ray, Self::Payload, Color) {
+        let (ray, payload) = self.primary_ray(x, y, w, h);
+        (ray, payload, Color(1.0, 1.0, 1.0, 1.0))
+    }
+
+    /// Index of the object seen by pixel `(x, y)` of a `w` by `h` frame.
+    fn pick(&self, x: u32, y: u32, w: u32, h: u32) -> Option<usize> {
+        let (ray, mut payload) = self.primary_ray(x, y, w, h);
+        self.closest(&ray, RayKind::Camera, &mut payload)
+            .map(|hit| hit.obj)
+    }
+    /// Object ID pass of a `w` by `h` frame in row-major order, i.e., the
+    /// index of the object seen by each pixel.
+    fn object_ids(&self, w: u32, h: u32) -> Vec<Option<usize>>
+        where Self::Ray: Send,
+              Self::Payload: Send,
+    {
+        use crate::par::*;
+        (0..w * h).into_par_iter()
+            .map(|i| self.pick(i % w, i / w, w, h))
+            .collect()
+    }
+
+    /// Draw a frame in wavefront order. Only primary rays are batched; any
+    /// rays traced by `closest_hit` are traced one pixel at a time, so path
+    /// tracers override this with `PathTracer::draw_wavefront_paths`.
+    fn draw_wavefront<FB>(&self, framebuf: &mut FB)
+        where FB: Framebuffer,
+              Self::Ray: Send,
+              Self::Payload: Send,
+              Self::RayAttr: Send,
+              Self::Material: Sync,
+    {
+        use crate::par::*;
+        let w = framebuf.width();
+        let h = framebuf.height();
+        for batch in morton_order(w, h).chunks(WAVEFRONT_BATCH) {
+            let _span = trace::span_with("wavefront_batch", || format!("{} rays", batch.len()));
+            // Generate.
+            let (mut rays, weights) = batch.par_iter()
+                .map(|&(x, y)| {
+                    let (ray, payload, weight) = self.primary_sample(x, y, w, h);
+                    ((ray, payload), weight)
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>();
+            // Intersect.
+            let hits = rays.par_iter_mut()
+                .map(|(ray, payload)| self.closest(ray, RayKind::Camera, payload))
+                .collect::<Vec<_>>();
+            // Shade.
+            let colors = rays.into_par_iter()
+                .zip(hits.into_par_iter())
+                .map(|((ray, mut payload), hit)| {
+                    if let Some(hit) = hit {
+                        covered(self.closest_hit(&ray, hit.obj, &hit.tri, &hit.intersect, &mut payload, hit.mat))
+                    } else {
+                        background(self.miss(&ray, &mut payload))
+                    }
+                })
+                .collect::<Vec<_>>();
+            // Store.
+            for ((&(x, y), weight), color) in batch.iter().zip(weights).zip(colors) {
+                framebuf.store(x, y, color * weight);
+            }
+        }
+    }
+    /// A progressive pass of `draw_wavefront`. Primary rays and their hits
+    /// are taken from `cache` if it was filled by a previous pass of the same
+    /// size, and are traced and stored into it otherwise. Passes reusing the
+    /// cache only shade, so the sub-pixel positions of the first pass are kept
+    /// and `any_hit` is not invoked for primary rays again.
+    fn draw_wavefront_cached<FB>(
+        &self,
+        framebuf: &mut FB,
+        cache: &mut HitCache<Self::Ray, Self::RayAttr>,
+    )
+        where FB: Framebuffer,
+              Self::Ray: Send + Sync,
+              Self::Payload: Send,
+              Self::RayAttr: Send + Sync,
+              Self::Material: Sync,
+    {
+        let w = framebuf.width();
+        let h = framebuf.height();
+        self.fill_hit_cache(cache, w, h);
+        shade_cached(self, framebuf, &morton_order(w, h), cache, |_| true);
+    }
+    /// Trace the primary rays of a `w` by `h` frame into `cache` for
+    /// `draw_wavefront_cached`, unless it already holds them.
+    fn fill_hit_cache(&self, cache: &mut HitCache<Self::Ray, Self::RayAttr>, w: u32, h: u32)
+        where Self::Ray: Send + Sync,
+              Self::Payload: Send,
+              Self::RayAttr: Send + Sync,
+              Self::Material: Sync,
+    {
+        use crate::par::*;
+        if cache.is_filled_for(w, h) { return }
+        let order = morton_order(w, h);
+        cache.w = w;
+        cache.h = h;
+        let (entries, weights) = order.par_iter()
+            .map(|&(x, y)| {
+                let (ray, mut payload, weight) = self.primary_sample(x, y, w, h);
+                let hit = self.closest(&ray, RayKind::Camera, &mut payload)
+                    .map(|hit| CachedHit {
+                        obj: hit.obj,
+                        tri: hit.tri,
+                        intersect: hit.intersect,
+                    });
+                ((ray, hit), weight)
+            })
+            .unzip();
+        cache.entries = entries;
+        cache.weights = weights;
+        cache.traced.clear();
+        cache.index = vec![0; order.len()];
+        for (i, &(x, y)) in order.iter().enumerate() {
+            cache.index[(y * w + x) as usize] = i;
+        }
+    }
+    /// Update a frame drawn by `draw_wavefront_cached` after the objects in
+    /// `changes` were edited, re-shading only the tiles that see them, e.g.,
+    /// for interactive material tweaks. Primary rays of the cache are traced
+    /// again only if any object moved, so the acceleration structure must
+    /// already be updated, cheaply with `Bvh::update` which refits instead of
+    /// rebuilding. Changes are followed through primary visibility, and
+    /// tiles whose shading traced more rays, e.g., for shadows and indirect
+    /// light, are drawn again on any change, since those rays may see any
+    /// object; for path tracers that's usually the whole frame. Frames not
+    /// in the cache are drawn in full. Returns the number of tiles drawn.
+    fn draw_wavefront_incremental<FB>(
+        &self,
+        framebuf: &mut FB,
+        cache: &mut HitCache<Self::Ray, Self::RayAttr>,
+        changes: &SceneChanges,
+    ) -> usize
+        where FB: Framebuffer,
+              Self::Ray: Send + Sync,
+              Self::Payload: Send,
+              Self::RayAttr: Send + Sync,
+              Self::Material: Sync,
+    {
+        use crate::par::*;
+        let w = framebuf.width();
+        let h = framebuf.height();
+        let ntile_x = (w as usize).div_ceil(TILE_SIZE);
+        let ntile_y = (h as usize).div_ceil(TILE_SIZE);
+        if !cache.is_filled_for(w, h) {
+            self.draw_wavefront_cached(framebuf, cache);
+            return ntile_x * ntile_y;
+        }
+        let order = morton_order(w, h);
+        let tile_of = |(x, y): (u32, u32)| {
+            (y as usize / TILE_SIZE) * ntile_x + x as usize / TILE_SIZE
+        };
+        let mut dirty = vec![false; ntile_x * ntile_y];
+        if !changes.geometry.is_empty() {
+            let hits = order.par_iter()
+                .zip(cache.entries.par_iter())
+                .map(|(&(x, y), (ray, _))| {
+                    let (_, mut payload) = self.primary_ray(x, y, w, h);
+                    self.closest(ray, RayKind::Camera, &mut payload)
+                        .map(|hit| CachedHit {
+                            obj: hit.obj,
+                            tri: hit.tri,
+                            intersect: hit.intersect,
+                        })
+                })
+                .collect::<Vec<_>>();
+            let moved = |hit: &Option<CachedHit<Self::RayAttr>>| {
+                hit.as_ref().is_some_and(|x| changes.geometry.contains(&x.obj))
+            };
+            // Hits only change on static objects if a moved object covers or
+            // uncovers them, so it's enough to look for moved objects.
+            for (i, hit) in hits.into_iter().enumerate() {
+                if moved(&cache.entries[i].1) || moved(&hit) {
+                    dirty[tile_of(order[i])] = true;
+                }
+                cache.entries[i].1 = hit;
+            }
+        }
+        for (&px, (_, hit)) in order.iter().zip(cache.entries.iter()) {
+            if hit.as_ref().is_some_and(|x| changes.materials.contains(&x.obj)) {
+                dirty[tile_of(px)] = true;
+            }
+        }
+        // Cached frames that weren't shaded yet don't know which pixels
+        // traced more rays.
+        let traced = |i: usize| cache.traced.get(i).copied().unwrap_or(true);
+        if !changes.is_empty() {
+            for (i, &px) in order.iter().enumerate() {
+                if traced(i) {
+                    dirty[tile_of(px)] = true;
+                }
+            }
+        }
+        shade_cached(self, framebuf, &order, cache, |px| dirty[tile_of(px)]);
+        dirty.into_iter().filter(|&x| x).count()
+    }
+}
+
+/// Color of a pixel whose camera ray hit a surface of color `c`, covering
+/// the pixel.
+fn covered(c: Color) -> Color {
+    Color(c.0, c.1, c.2, 1.0)
+}
+/// Color of a pixel seeing the background of color `c` returned by `miss`,
+/// premultiplied by its alpha so that transparent backgrounds composite.
+fn background(c: Color) -> Color {
+    c.premultiply()
+}
+
+/// Shade the primary hits in `cache` of the pixels in `order` for which `filter`
+/// returns true and store them into `framebuf`. Whether each pixel shaded
+/// traced more rays is recorded in `cache`.
+fn shade_cached<T, FB, F>(
+    rt: &T,
+    framebuf: &mut FB,
+    order: &[(u32, u32)],
+    cache: &mut HitCache<T::Ray, T::RayAttr>,
+    filter: F,
+)
+    where T: WavefrontRayTracer + ?Sized,
+          FB: Framebuffer,
+          T::Ray: Send + Sync,
+          T::Payload: Send,
+          T::RayAttr: Send + Sync,
+          T::Material: Sync,
+          F: Fn((u32, u32)) -> bool + Sync,
+{
+    use crate::par::*;
+    let w = framebuf.width();
+    let h = framebuf.height();
+    let objs = &rt.scene().objs;
+    let mut traced = std::mem::take(&mut cache.traced);
+    // Pixels never shaded are assumed to trace more rays.
+    traced.resize(cache.entries.len(), true);
+    for (((batch, entries), weights), traced) in order.chunks(WAVEFRONT_BATCH)
+        .zip(cache.entries.chunks(WAVEFRONT_BATCH))
+        .zip(cache.weights.chunks(WAVEFRONT_BATCH))
+        .zip(traced.chunks_mut(WAVEFRONT_BATCH))
+    {
+        // Shade.
+        let colors = batch.par_iter()
+            .zip(entries.par_iter())
+            .map(|(&(x, y), (ray, hit))| {
+                if !filter((x, y)) { return None }
+                let (_, mut payload) = rt.primary_ray(x, y, w, h);
+                // Rays are counted per thread, and each pixel is shaded on
+                // one thread.
+                let nray0 = nray();
+                let color = if let Some(hit) = hit {
+                    let mat = &objs[hit.obj].mat;
+                    covered(rt.closest_hit(ray, hit.obj, &hit.tri, &hit.intersect, &mut payload, mat))
+                } else {
+                    background(rt.miss(ray, &mut payload))
+                };
+                Some((color, nray() != nray0))
+            })
+            .collect::<Vec<_>>();
+        // Store.
+        let pixels = batch.iter().zip(weights).zip(traced.iter_mut());
+        for (((&(x, y), &weight), traced), color) in pixels.zip(colors) {
+            if let Some((color, pixel_traced)) = color {
+                framebuf.store(x, y, color * weight);
+                *traced = pixel_traced;
+            }
+        }
+    }
+    cache.traced = traced;
+}
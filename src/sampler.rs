@@ -1,48 +1,589 @@
-use crate::img::Image;
-use crate::geom::{Color, Vector};
+use crate::img::{PixelSource, Image};
+use crate::geom::{Real, Color, Vector, narrow};
+use crate::post::luminance;
 
+/// How texture coordinates outside of [0, 1] are mapped back into the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    /// Tile the image.
+    Repeat,
+    /// Extend the edge texels.
+    Clamp,
+    /// Tile the image, flipping every other tile.
+    Mirror,
+    /// Read the border color.
+    Border,
+}
+/// How texels are combined into a sample.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    /// Take the texel covering the texture coordinates.
+    #[default]
+    Nearest,
+    /// Interpolate between the four nearest texels.
+    Linear,
+}
+
+/// Sampler of 2D images at texture coordinates `(u, v)`, where `(0, 0)` is the
+/// top-left corner of the first texel and `(1, 1)` is the bottom-right corner
+/// of the last texel. Samples are premultiplied by alpha, so that filtering
+/// doesn't bleed the colors of transparent texels into opaque ones.
+#[derive(Debug, Clone, Copy)]
+pub struct Sampler2D {
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub filter: FilterMode,
+    /// Color of texels outside of the image with `WrapMode::Border`.
+    pub border: Color,
+}
+impl Default for Sampler2D {
+    fn default() -> Sampler2D {
+        Sampler2D {
+            wrap_u: WrapMode::Repeat,
+            wrap_v: WrapMode::Repeat,
+            filter: FilterMode::Linear,
+            border: Color::default(),
+        }
+    }
+}
+impl Sampler2D {
+    pub fn new(wrap: WrapMode, filter: FilterMode) -> Sampler2D {
+        Sampler2D { wrap_u: wrap, wrap_v: wrap, filter, ..Default::default() }
+    }
+
+    /// Map texel index `i` into `[0, n)`, or `None` if it's a border texel.
+    /// `n` must be positive.
+    #[inline]
+    fn wrap(i: isize, n: usize, mode: WrapMode) -> Option<usize> {
+        let n = n as isize;
+        let i = match mode {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n { i } else { 2 * n - 1 - i }
+            },
+            WrapMode::Border => {
+                if i < 0 || i >= n { return None }
+                i
+            },
+        };
+        Some(i as usize)
+    }
+    #[inline]
+    fn texel<I: PixelSource>(&self, img: &I, x: isize, y: isize) -> Color {
+        let x = Self::wrap(x, img.width(), self.wrap_u);
+        let y = Self::wrap(y, img.height(), self.wrap_v);
+        match (x, y) {
+            (Some(x), Some(y)) => img.load_px_premultiplied(x, y),
+            _ => self.border,
+        }
+    }
+    /// Sample `img` at `(u, v)`. Empty images are transparent black whatever
+    /// the wrap modes, as there's no texel to wrap into.
+    pub fn sample<I: PixelSource>(&self, img: &I, u: f32, v: f32) -> Color {
+        if img.width() == 0 || img.height() == 0 { return Color::default() }
+        let x = u * img.width() as f32;
+        let y = v * img.height() as f32;
+        match self.filter {
+            FilterMode::Nearest => {
+                self.texel(img, x.floor() as isize, y.floor() as isize)
+            },
+            FilterMode::Linear => {
+                // Texel centers are at half-integer coordinates.
+                let x = x - 0.5;
+                let y = y - 0.5;
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let fx = x - x0;
+                let fy = y - y0;
+                let x0 = x0 as isize;
+                let y0 = y0 as isize;
+                let top = self.texel(img, x0, y0) * (1.0 - fx) +
+                    self.texel(img, x0 + 1, y0) * fx;
+                let bottom = self.texel(img, x0, y0 + 1) * (1.0 - fx) +
+                    self.texel(img, x0 + 1, y0 + 1) * fx;
+                top * (1.0 - fy) + bottom * fy
+            },
+        }
+    }
+    /// Same as `sample` but from the mip level of `img` whose texels are
+    /// about `width` wide in texture coordinates, e.g., the footprint of a
+    /// ray, interpolating between the two nearest levels. Images without
+    /// mips are sampled at full resolution, see `Image::with_mips`.
+    pub fn sample_footprint(&self, img: &Image, u: f32, v: f32, width: f32) -> Color {
+        let nlevel = img.nlevel();
+        let texels = width * img.width().max(img.height()) as f32;
+        if nlevel == 1 || texels.is_nan() || texels <= 1.0 { return self.sample(img, u, v) }
+        let lod = texels.log2().min((nlevel - 1) as f32);
+        let i = lod as usize;
+        let f = lod - i as f32;
+        let lo = self.sample(img.level(i), u, v);
+        if i + 1 == nlevel || f == 0.0 { return lo }
+        lo * (1.0 - f) + self.sample(img.level(i + 1), u, v) * f
+    }
+}
+
+/// Distribution of texture coordinates proportional to the luminance of the
+/// texels of an image, piecewise constant over each texel, to importance
+/// sample textures.
+#[derive(Debug, Clone)]
+pub struct TexelDistribution {
+    w: usize,
+    h: usize,
+    /// CDF over rows, `h + 1` entries.
+    rows: Vec<f32>,
+    /// CDF within each row, `w + 1` entries per row.
+    cols: Vec<f32>,
+}
+impl TexelDistribution {
+    pub fn new<I: PixelSource>(img: &I) -> TexelDistribution {
+        let (w, h) = (img.width(), img.height());
+        let mut rows = vec![0.0];
+        let mut cols = Vec::with_capacity((w + 1) * h);
+        for y in 0..h {
+            let mut acc = 0.0;
+            cols.push(0.0);
+            for x in 0..w {
+                acc += luminance(img.load_px_premultiplied(x, y)).max(0.0);
+                cols.push(acc);
+            }
+            rows.push(rows[y] + acc);
+            let row = &mut cols[y * (w + 1)..];
+            if acc > 0.0 {
+                for x in row[..=w].iter_mut() { *x /= acc }
+            }
+        }
+        let total = rows[h];
+        if total > 0.0 {
+            for x in rows.iter_mut() { *x /= total }
+        }
+        TexelDistribution { w, h, rows, cols }
+    }
+    /// Whether every texel is black, in which case nothing can be sampled.
+    pub fn is_empty(&self) -> bool {
+        self.rows[self.h] <= 0.0
+    }
+    /// Map `a` and `b` in [0..1) to texture coordinates `(u, v)`, see
+    /// `Sampler2D`.
+    pub fn sample(&self, a: f32, b: f32) -> (f32, f32) {
+        let (y, fy) = sample_cdf(&self.rows, b);
+        let (x, fx) = sample_cdf(&self.cols[y * (self.w + 1)..(y + 1) * (self.w + 1)], a);
+        ((x as f32 + fx) / self.w as f32, (y as f32 + fy) / self.h as f32)
+    }
+    /// PDF over the unit square of sampling texture coordinates `(u, v)`.
+    pub fn pdf(&self, u: f32, v: f32) -> f32 {
+        if self.is_empty() || !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+            return 0.0;
+        }
+        let x = ((u * self.w as f32) as usize).min(self.w - 1);
+        let y = ((v * self.h as f32) as usize).min(self.h - 1);
+        let row = &self.cols[y * (self.w + 1)..];
+        (self.rows[y + 1] - self.rows[y]) * (row[x + 1] - row[x]) * (self.w * self.h) as f32
+    }
+}
+/// Bin of normalized CDF `cdf` that `a` falls in, with the fraction of `a`
+/// into the bin.
+fn sample_cdf(cdf: &[f32], a: f32) -> (usize, f32) {
+    let n = cdf.len() - 1;
+    let i = cdf.partition_point(|&x| x <= a).clamp(1, n) - 1;
+    let (lo, hi) = (cdf[i], cdf[i + 1]);
+    let frac = if hi > lo { ((a - lo) / (hi - lo)).clamp(0.0, 1.0) } else { 0.5 };
+    (i, frac)
+}
+
+/// Sampler of a set of images by a direction.
 pub trait Sampler {
     /// Validate if `imgs` can be sampled with this sampler.
-    fn validate(&self, img: &[Image]) -> bool;
-    /// Sample a color from `imgs`.
+    fn validate<I: PixelSource>(&self, imgs: &[I]) -> bool;
+    /// Sample a color premultiplied by alpha from `imgs`.
     ///
     /// NOTE: `v` must be normalized.
-    fn sample(&self, imgs: &[Image], v: Vector) -> Color;
+    fn sample<I: PixelSource>(&self, imgs: &[I], v: Vector) -> Color;
 }
 
+/// Sampler of cube maps given as six faces in the order of +X, -X, +Y, -Y, +Z
+/// and -Z.
 #[derive(Default)]
-pub struct CubeSampler();
-impl Sampler for CubeSampler {
-    fn validate(&self, imgs: &[Image]) -> bool {
-        imgs.len() == 6
+pub struct CubeSampler {
+    pub filter: FilterMode,
+}
+impl CubeSampler {
+    /// Direction through `(u, v)` in [-1, 1] on face `face`, in the same
+    /// orientation as used for sampling. The direction is not normalized.
+    pub fn face2dir(face: usize, u: f32, v: f32) -> Vector {
+        let (u, v) = (u as Real, v as Real);
+        match face {
+            0 => Vector(1.0, v, -u),
+            1 => Vector(-1.0, v, u),
+            2 => Vector(u, 1.0, -v),
+            3 => Vector(u, -1.0, v),
+            4 => Vector(u, v, 1.0),
+            5 => Vector(-u, v, -1.0),
+            _ => panic!("cube map only has 6 faces"),
+        }
     }
-    fn sample(&self, imgs: &[Image], v: Vector) -> Color {
-        let Vector(x, y, z) = v;
-        let dir = [x, y, z];
+}
+impl CubeSampler {
+    /// Face `dir` points at and the texture coordinates on it in [0, 1].
+    fn face_uv(dir: Vector) -> (usize, Real, Real) {
+        let Vector(x, y, z) = dir;
         let absdir = [x.abs(), y.abs(), z.abs()];
-        let i = (0..3).into_iter()
+        let i = (0..3)
             .max_by(|a, b| {
                 absdir[*a].partial_cmp(&absdir[*b])
                     .unwrap_or(std::cmp::Ordering::Equal)
             }).unwrap();
-        let (u, v, img) = match (i, dir[i] > 0.0) {
+        let (u, v, face) = match (i, [x, y, z][i] > 0.0) {
             // Positive x.
-            (0, true) => (-z, y, &imgs[0]),
+            (0, true) => (-z, y, 0),
             // Negative x.
-            (0, false) => (z, y, &imgs[1]),
+            (0, false) => (z, y, 1),
             // Positive y.
-            (1, true) => (x, -z, &imgs[2]),
+            (1, true) => (x, -z, 2),
             // Negative y.
-            (1, false) => (x, z, &imgs[3]),
+            (1, false) => (x, z, 3),
             // Positive z.
-            (2, true) => (x, y, &imgs[4]),
+            (2, true) => (x, y, 4),
             // Negative z.
-            (2, false) => (-x, y, &imgs[5]),
+            (2, false) => (-x, y, 5),
             (_, _) => unreachable!(),
         };
         let max = absdir[i];
-        let u = (0.5 * (u / max + 1.0)).max(0.0).min(1.0) * (img.width() - 1) as f32;
-        let v = (0.5 * (v / max + 1.0)).max(0.0).min(1.0) * (img.height() - 1) as f32;
-        img.load_px(u as usize, v as usize)
+        (face, 0.5 * (u / max + 1.0), 0.5 * (v / max + 1.0))
+    }
+    /// Texel `(x, y)` of face `face`. Texels off the face are fetched from the
+    /// adjacent faces instead, so that filtering is continuous across edges.
+    fn texel<I: PixelSource>(imgs: &[I], face: usize, x: isize, y: isize) -> Color {
+        let img = &imgs[face];
+        let (w, h) = (img.width() as isize, img.height() as isize);
+        if (0..w).contains(&x) && (0..h).contains(&y) {
+            return img.load_px_premultiplied(x as usize, y as usize);
+        }
+        // Direction through the texel center on the extended face plane.
+        let u = (x as f32 + 0.5) / w as f32 * 2.0 - 1.0;
+        let v = (y as f32 + 0.5) / h as f32 * 2.0 - 1.0;
+        let (face, u, v) = CubeSampler::face_uv(CubeSampler::face2dir(face, u, v));
+        let img = &imgs[face];
+        let x = ((narrow(u) * img.width() as f32) as usize).min(img.width() - 1);
+        let y = ((narrow(v) * img.height() as f32) as usize).min(img.height() - 1);
+        img.load_px_premultiplied(x, y)
+    }
+}
+impl Sampler for CubeSampler {
+    fn validate<I: PixelSource>(&self, imgs: &[I]) -> bool {
+        imgs.len() == 6
+    }
+    fn sample<I: PixelSource>(&self, imgs: &[I], v: Vector) -> Color {
+        let (face, u, v) = CubeSampler::face_uv(v);
+        let img = &imgs[face];
+        let x = narrow(u) * img.width() as f32;
+        let y = narrow(v) * img.height() as f32;
+        match self.filter {
+            FilterMode::Nearest => {
+                CubeSampler::texel(imgs, face, x.floor() as isize, y.floor() as isize)
+            },
+            FilterMode::Linear => {
+                // Taps across the face edges are taken from the adjacent
+                // faces, otherwise the edges show up as seams.
+                let x = x - 0.5;
+                let y = y - 0.5;
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let fx = x - x0;
+                let fy = y - y0;
+                let x0 = x0 as isize;
+                let y0 = y0 as isize;
+                let texel = |x, y| CubeSampler::texel(imgs, face, x, y);
+                let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+                let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+                top * (1.0 - fy) + bottom * fy
+            },
+        }
+    }
+}
+
+/// Sampler of equirectangular (latitude-longitude) environment maps. The
+/// image spans longitudes from -PI to PI around the y-axis left to right,
+/// starting from -z, and latitudes from +y to -y top to bottom.
+#[derive(Default)]
+pub struct EquirectSampler {
+    pub filter: FilterMode,
+}
+impl EquirectSampler {
+    /// Texture coordinates of direction `v`.
+    pub fn dir2uv(v: Vector) -> (f32, f32) {
+        const PI: Real = std::f64::consts::PI as Real;
+        let Vector(x, y, z) = v;
+        let u = 0.5 + x.atan2(-z) / (2.0 * PI);
+        let v = y.clamp(-1.0, 1.0).acos() / PI;
+        (narrow(u), narrow(v))
+    }
+    /// Direction of texture coordinates `(u, v)`.
+    pub fn uv2dir(u: f32, v: f32) -> Vector {
+        const PI: Real = std::f64::consts::PI as Real;
+        let (u, v) = (u as Real, v as Real);
+        let phi = (u - 0.5) * 2.0 * PI;
+        let (sin_theta, cos_theta) = (v * PI).sin_cos();
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        Vector(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi)
+    }
+}
+impl Sampler for EquirectSampler {
+    fn validate<I: PixelSource>(&self, imgs: &[I]) -> bool {
+        imgs.len() == 1
+    }
+    fn sample<I: PixelSource>(&self, imgs: &[I], v: Vector) -> Color {
+        let (u, v) = Self::dir2uv(v);
+        let samp = Sampler2D {
+            wrap_u: WrapMode::Repeat,
+            wrap_v: WrapMode::Clamp,
+            filter: self.filter,
+            border: Color::default(),
+        };
+        samp.sample(&imgs[0], u, v)
+    }
+}
+
+/// Van der Corput radical inverse of `i` in `base`, in [0..1).
+#[inline]
+pub fn radical_inverse(mut i: u32, base: u32) -> f32 {
+    let inv_base = (base as f32).recip();
+    let mut inv = inv_base;
+    let mut rv = 0.0;
+    while i > 0 {
+        rv += (i % base) as f32 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    rv.min(1.0 - f32::EPSILON)
+}
+/// The `i`-th point of the 2D Halton sequence in bases 2 and 3.
+#[inline]
+pub fn halton2(i: u32) -> (f32, f32) {
+    (radical_inverse(i, 2), radical_inverse(i, 3))
+}
+
+/// Hash pixel coordinates and a seed into a well-mixed 32-bit integer.
+#[inline]
+pub fn pixel_hash(x: u32, y: u32, seed: u32) -> u32 {
+    // PCG-style mixing of the three values.
+    let mut h = x.wrapping_mul(0x8da6_b343) ^
+        y.wrapping_mul(0xd816_3841) ^
+        seed.wrapping_mul(0xcb1a_b31f);
+    h = (h ^ (h >> 16)).wrapping_mul(0x7feb_352d);
+    h = (h ^ (h >> 15)).wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+/// Interleaved gradient noise at pixel `(x, y)` in [0..1). Neighboring pixels
+/// get very different values, so the noise has mostly high frequencies like
+/// blue noise.
+///
+/// See: Jorge Jimenez, Next Generation Post Processing in Call of Duty:
+/// Advanced Warfare.
+#[inline]
+pub fn interleaved_gradient_noise(x: u32, y: u32) -> f32 {
+    let f = 0.067_110_56 * x as f32 + 0.005_837_15 * y as f32;
+    (52.982_918 * f.fract()).fract()
+}
+/// Per-pixel offset for dimension `dim` of a sample sequence, used to rotate
+/// the sequence with `cranley_patterson`. The first two dimensions use
+/// interleaved gradient noise to keep the error blue; higher dimensions fall
+/// back to white noise.
+#[inline]
+pub fn pixel_offset(x: u32, y: u32, dim: u32) -> f32 {
+    match dim {
+        0 => interleaved_gradient_noise(x, y),
+        1 => interleaved_gradient_noise(x + 5, y + 7),
+        _ => (pixel_hash(x, y, dim) >> 8) as f32 / (1 << 24) as f32,
+    }
+}
+/// Width and height of the mask of `blue_noise`.
+pub const BLUE_NOISE_SIZE: u32 = 64;
+
+/// Tileable blue noise mask of `BLUE_NOISE_SIZE` squared texels in row-major
+/// order. Each value in [0..1) appears once, and pixels close to each other
+/// on the torus get very different values, so thresholding the mask at any
+/// level gives evenly spread points. The mask is generated deterministically
+/// on first use, which takes a fraction of a second.
+///
+/// See: Robert Ulichney, The void-and-cluster method for dither array
+/// generation.
+pub fn blue_noise_mask() -> &'static [f32] {
+    static MASK: std::sync::OnceLock<Vec<f32>> = std::sync::OnceLock::new();
+    MASK.get_or_init(void_and_cluster)
+}
+fn void_and_cluster() -> Vec<f32> {
+    const SIGMA: f32 = 1.5;
+    let n = BLUE_NOISE_SIZE as usize;
+    let npx = n * n;
+    // Gaussian energy contributed by a set pixel at each toroidal offset.
+    let kernel = (0..npx)
+        .map(|i| {
+            let d = |x: usize| x.min(n - x) as f32;
+            let (dx, dy) = (d(i % n), d(i / n));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect::<Vec<_>>();
+    let update = |energy: &mut [f32], i: usize, sign: f32| {
+        let (x, y) = (i % n, i / n);
+        for (j, e) in energy.iter_mut().enumerate() {
+            let dx = (j % n + n - x) % n;
+            let dy = (j / n + n - y) % n;
+            *e += sign * kernel[dy * n + dx];
+        }
+    };
+    // Pixel of the highest or lowest energy among those set or not.
+    let extreme = |energy: &[f32], set: &[bool], want: bool, highest: bool| {
+        let mut best = None;
+        for (i, &e) in energy.iter().enumerate() {
+            if set[i] != want { continue }
+            let better = match best {
+                None => true,
+                Some((_, b)) => if highest { e > b } else { e < b },
+            };
+            if better { best = Some((i, e)) }
+        }
+        best.map(|x| x.0).unwrap_or(0)
+    };
+
+    // Initial pattern of a tenth of the pixels chosen by hashing, relaxed by
+    // moving the pixel in the tightest cluster to the largest void until it
+    // stays. Ties in energy can make pixels cycle, so give up after as many
+    // moves as there are pixels; the pattern is relaxed enough by then.
+    let mut set = vec![false; npx];
+    let mut energy = vec![0.0; npx];
+    let mut nset = 0;
+    for (i, x) in set.iter_mut().enumerate() {
+        let h = pixel_hash(i as u32 % BLUE_NOISE_SIZE, i as u32 / BLUE_NOISE_SIZE, 0x626e);
+        if h.is_multiple_of(10) {
+            *x = true;
+            update(&mut energy, i, 1.0);
+            nset += 1;
+        }
+    }
+    for _ in 0..npx {
+        let cluster = extreme(&energy, &set, true, true);
+        set[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = extreme(&energy, &set, false, false);
+        set[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster { break }
+    }
+
+    let mut rank = vec![0; npx];
+    // Pixels of the initial pattern rank by removing the tightest clusters
+    // first, and the rest by filling the largest voids first.
+    {
+        let mut set = set.clone();
+        let mut energy = energy.clone();
+        for r in (0..nset).rev() {
+            let cluster = extreme(&energy, &set, true, true);
+            set[cluster] = false;
+            update(&mut energy, cluster, -1.0);
+            rank[cluster] = r;
+        }
+    }
+    for r in nset..npx {
+        let void = extreme(&energy, &set, false, false);
+        set[void] = true;
+        update(&mut energy, void, 1.0);
+        rank[void] = r;
+    }
+    rank.into_iter()
+        .map(|r| (r as f32 + 0.5) / npx as f32)
+        .collect()
+}
+/// Blue noise at pixel `(x, y)` in [0..1), tiling `blue_noise_mask` over the
+/// screen, e.g., to dither quantization or to threshold stochastic alpha.
+/// Unlike `interleaved_gradient_noise` it has no visible structure.
+#[inline]
+pub fn blue_noise(x: u32, y: u32) -> f32 {
+    let x = x % BLUE_NOISE_SIZE;
+    let y = y % BLUE_NOISE_SIZE;
+    blue_noise_mask()[(y * BLUE_NOISE_SIZE + x) as usize]
+}
+/// Blue noise for dimension `dim` of a sample sequence at pixel `(x, y)`,
+/// to decorrelate samples across pixels with `cranley_patterson`. Dimensions
+/// read the mask at different toroidal shifts, so that each of them is blue
+/// but they aren't equal.
+#[inline]
+pub fn blue_noise_offset(x: u32, y: u32, dim: u32) -> f32 {
+    let h = pixel_hash(dim, dim, 0x626e);
+    blue_noise(x.wrapping_add(h & 0xffff), y.wrapping_add(h >> 16))
+}
+/// Cranley-Patterson rotation of sample `u` by `offset`, both in [0..1). The
+/// rotated samples keep the stratification of the original sequence while
+/// decorrelating it across pixels.
+#[inline]
+pub fn cranley_patterson(u: f32, offset: f32) -> f32 {
+    let u = u + offset;
+    if u >= 1.0 { u - 1.0 } else { u }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(size: usize, c: Color) -> Image {
+        let mut img = Image::new(size, size);
+        for y in 0..size {
+            for x in 0..size {
+                img.store_px(x, y, c);
+            }
+        }
+        img
+    }
+
+    #[test]
+    fn empty_images_are_transparent() {
+        let border = Color(1.0, 1.0, 1.0, 1.0);
+        for wrap in [WrapMode::Repeat, WrapMode::Clamp, WrapMode::Mirror, WrapMode::Border] {
+            for filter in [FilterMode::Nearest, FilterMode::Linear] {
+                let samp = Sampler2D { border, ..Sampler2D::new(wrap, filter) };
+                for (w, h) in [(0, 0), (0, 4), (4, 0)] {
+                    let c = samp.sample(&Image::new(w, h), 0.3, 1.7);
+                    assert_eq!((c.0, c.1, c.2, c.3), (0.0, 0.0, 0.0, 0.0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blue_noise_ranks_are_unique() {
+        let n = (BLUE_NOISE_SIZE * BLUE_NOISE_SIZE) as usize;
+        let mask = blue_noise_mask();
+        assert_eq!(mask.len(), n);
+        let mut seen = vec![false; n];
+        for &x in mask {
+            let r = (x * n as f32) as usize;
+            assert!(r < n && !seen[r]);
+            seen[r] = true;
+        }
+    }
+
+    #[test]
+    fn footprint_picks_mip_levels() {
+        let red = Color(1.0, 0.0, 0.0, 1.0);
+        let green = Color(0.0, 1.0, 0.0, 1.0);
+        let blue = Color(0.0, 0.0, 1.0, 1.0);
+        let img = solid(4, red).with_mips(vec![solid(2, green), solid(1, blue)]);
+        let samp = Sampler2D::default();
+        let at = |width: f32| {
+            let c = samp.sample_footprint(&img, 0.3, 0.6, width);
+            (c.0, c.1, c.2)
+        };
+        // Footprints of up to a texel take the full resolution.
+        assert_eq!(at(0.0), (1.0, 0.0, 0.0));
+        assert_eq!(at(0.25), (1.0, 0.0, 0.0));
+        assert_eq!(at(0.5), (0.0, 1.0, 0.0));
+        assert_eq!(at(1.0), (0.0, 0.0, 1.0));
+        assert_eq!(at(16.0), (0.0, 0.0, 1.0));
+        let (r, g, b) = at(0.5 * 2.0_f32.sqrt());
+        assert!(r == 0.0 && (g - 0.5).abs() < 1e-5 && (b - 0.5).abs() < 1e-5);
+        // Images without mips are sampled at full resolution.
+        let c = samp.sample_footprint(&solid(4, red), 0.3, 0.6, 1.0);
+        assert_eq!((c.0, c.1, c.2), (1.0, 0.0, 0.0));
     }
 }
@@ -1,4 +1,83 @@
-use crate::geom::{Point, Transform};
+use std::sync::Arc;
+use crate::geom::{
+    Real, Point, Vector, Ray, Triangle, Barycentric, Transform, Color, Precision, ray_cast_tri_with,
+    faces_away,
+};
+use crate::rt::{HitKind, Intersection};
+use crate::accel::{Accel, AccelStats};
+use crate::img::Image;
+use crate::arena::with_verts;
+use crate::bvh::Aabb;
+
+/// Purpose of a traced ray.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RayKind {
+    /// Rays from the camera.
+    Camera,
+    /// Rays testing whether a point is occluded.
+    Shadow,
+    /// Other rays spawned from surfaces, e.g., reflections and refractions.
+    Reflection,
+}
+
+/// Which kinds of rays an object is visible to. Rays don't see objects
+/// invisible to them at all, as if the objects didn't exist.
+#[derive(Debug, Clone, Copy)]
+pub struct Visibility {
+    pub camera: bool,
+    pub shadow: bool,
+    pub reflection: bool,
+}
+impl Default for Visibility {
+    fn default() -> Visibility {
+        Visibility {
+            camera: true,
+            shadow: true,
+            reflection: true,
+        }
+    }
+}
+impl Visibility {
+    #[inline]
+    pub fn visible_to(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Reflection => self.reflection,
+        }
+    }
+}
+
+/// Geometry other than triangles, intersected natively rather than meshed,
+/// e.g., curves, point splats and voxels, which would take far too many
+/// triangles to mesh. Shapes are in object space, like `Object::verts`.
+///
+/// Hits are shaded as if they were on a triangle standing in for the surface:
+/// its origin `o` is the point hit, `n` is the unit normal of the front face,
+/// `x` and `y` are unit tangents with `x` along the primitive, e.g., along
+/// strands for hair shading, and the barycentric coordinates are zero.
+pub trait Shape : Send + Sync {
+    /// Bounds in object space.
+    fn bounds(&self) -> Aabb;
+    /// The closest hit of `ray` in object space within the tolerances of
+    /// `precision`, whose `max_t` can be lowered to the closest hit so far.
+    fn ray_cast(&self, ray: &Ray, precision: &Precision) -> Option<ShapeHit>;
+}
+
+/// A ray hitting a `Shape`, in object space.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeHit {
+    /// Parametric distance from the ray origin, see `Intersection::t`.
+    pub t: Real,
+    pub kind: HitKind,
+    /// Index of the primitive hit in the shape, e.g., of the curve, which
+    /// `Object::colors` of shaped objects are indexed by.
+    pub prim: usize,
+    /// Unit normal of the front face.
+    pub n: Vector,
+    /// Unit tangent perpendicular to `n`, see `Shape`.
+    pub tangent: Vector,
+}
 
 pub struct Object<Material> {
     pub verts: Vec<Point>,
@@ -6,8 +85,272 @@ pub struct Object<Material> {
     pub mat: Material,
     pub obj2world: Transform,
     pub world2obj: Transform,
+    pub visibility: Visibility,
+    /// Never hit the back faces of the triangles, e.g., of opaque closed
+    /// meshes whose back faces can't be seen anyway. Back hits are rejected
+    /// before intersection in acceleration structure traversal and
+    /// `Scene::raycast`, and after intersection by tracers without an
+    /// acceleration structure.
+    pub cull_backfaces: bool,
+    /// Unit shading normals of the corners of each triangle in `idxs`, in
+    /// object space, e.g., smoothed normals of imported meshes. Surfaces are
+    /// shaded with their geometric normals without them.
+    pub normals: Option<Vec<[Vector; 3]>>,
+    /// Base colors of the triangles in `idxs`, e.g., of the points of scans,
+    /// multiplying the albedo of materials that take them.
+    pub colors: Option<Vec<Color>>,
+    /// Optional human readable name. Objects are otherwise identified by
+    /// their indices in `Scene::objs`.
+    pub name: Option<String>,
+    /// Geometry intersected natively in addition to the triangles, usually
+    /// instead of any, see `RayTracer::intersect_shape`.
+    pub shape: Option<Arc<dyn Shape>>,
+}
+impl<Material> Object<Material> {
+    /// Object of `shape` alone placed in the world by `world2obj`.
+    pub fn from_shape<S: Shape + 'static>(shape: S, mat: Material, world2obj: Transform) -> Object<Material> {
+        Object {
+            verts: Vec::new(),
+            idxs: Vec::new(),
+            mat,
+            obj2world: world2obj.inverse(),
+            world2obj,
+            visibility: Visibility::default(),
+            cull_backfaces: false,
+            normals: None,
+            colors: None,
+            name: None,
+            shape: Some(Arc::new(shape)),
+        }
+    }
+    pub fn with_colors(self, colors: Vec<Color>) -> Object<Material> {
+        Object { colors: Some(colors), ..self }
+    }
+    pub fn with_name(self, name: &str) -> Object<Material> {
+        Object { name: Some(name.to_owned()), ..self }
+    }
+    pub fn with_cull_backfaces(self, cull_backfaces: bool) -> Object<Material> {
+        Object { cull_backfaces, ..self }
+    }
+    /// Unit shading normal of triangle `tri` at `bary`, in world space,
+    /// interpolated from the corner normals if the object has them.
+    pub fn shading_normal(&self, tri: usize, bary: Barycentric) -> Option<Vector> {
+        let ns = self.normals.as_ref()?.get(tri)?;
+        let n = (1.0 - bary.u - bary.v) * ns[0] + bary.u * ns[1] + bary.v * ns[2];
+        // Normals transform by the inverse transpose of the transform applied
+        // to the vertices, i.e., the transpose of `obj2world`.
+        let (x, y, z) = self.obj2world.to_cols();
+        let n = Vector(x.dot(n), y.dot(n), z.dot(n));
+        if n.mag() > 0.0 { Some(n.normalize()) } else { None }
+    }
+    /// Base color of triangle `tri` if the object has colors.
+    pub fn color(&self, tri: usize) -> Option<Color> {
+        self.colors.as_ref()?.get(tri).copied()
+    }
+    /// The closest hit of world space `ray` on `shape` within the tolerances
+    /// of `precision`, with the triangle standing in for the surface in world
+    /// space, see `Shape`.
+    pub fn ray_cast_shape(
+        &self,
+        ray: &Ray,
+        precision: &Precision,
+    ) -> Option<(Triangle, Intersection<Barycentric>)> {
+        let shape = self.shape.as_ref()?;
+        // Directions aren't normalized so that distances stay parametric.
+        let local = Ray { o: self.obj2world * ray.o, v: self.obj2world * ray.v };
+        shape.bounds().clip(&local).filter(|&(t0, _)| t0 <= precision.max_t)?;
+        let hit = shape.ray_cast(&local, precision)?;
+        // See `shading_normal`.
+        let (x, y, z) = self.obj2world.to_cols();
+        let n = Vector(x.dot(hit.n), y.dot(hit.n), z.dot(hit.n)).normalize();
+        let tangent = self.world2obj * hit.tangent;
+        let tangent = (tangent - tangent.dot(n) * n).normalize();
+        let tri = Triangle {
+            o: ray.o.affine_add(hit.t * ray.v),
+            x: tangent,
+            y: tangent.cross(n),
+            n,
+        };
+        let attr = Barycentric { u: 0.0, v: 0.0 };
+        Some((tri, Intersection { attr, kind: hit.kind, t: hit.t, prim: hit.prim }))
+    }
+}
+
+/// A ray hitting a triangle of a scene, see `Scene::raycast`.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    /// Index of the object hit in `Scene::objs`.
+    pub obj: usize,
+    /// Index of the triangle hit in `Object::idxs`.
+    pub tri: usize,
+    /// Parametric distance from the ray origin, see `Intersection::t`.
+    pub t: Real,
+    /// Point hit, in world space.
+    pub p: Point,
+    /// Unit geometric normal of the triangle, in world space.
+    pub n: Vector,
+    pub bary: Barycentric,
+    /// Whether the front face or the back face is hit.
+    pub front: bool,
+}
+impl Hit {
+    fn new(obj: usize, tri: usize, world_tri: &Triangle, x: Intersection<Barycentric>) -> Hit {
+        let bary = x.attr;
+        let p = world_tri.o.affine_add(bary.u * world_tri.x + bary.v * world_tri.y);
+        Hit { obj, tri, t: x.t, p, n: world_tri.n, bary, front: x.kind == HitKind::Front }
+    }
 }
 
 pub struct Scene<Material> {
     pub objs: Vec<Object<Material>>,
+    /// Tolerances of intersection, for intersectors to pass to
+    /// `ray_cast_tri_with`.
+    pub precision: Precision,
+}
+impl<Material> Scene<Material> {
+    pub fn new(objs: Vec<Object<Material>>) -> Scene<Material> {
+        Scene { objs, precision: Precision::default() }
+    }
+    pub fn with_precision(self, precision: Precision) -> Scene<Material> {
+        Scene { precision, ..self }
+    }
+    /// Index of the first object named `name`.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.objs.iter()
+            .position(|x| x.name.as_deref() == Some(name))
+    }
+    /// The closest triangle or shape hit by `ray` from either side unless
+    /// culled, in any object regardless of its visibility. No `RayTracer` is needed, so
+    /// scenes can serve picking and collision queries. Every triangle is
+    /// tested, so for many queries on large scenes, build an acceleration
+    /// structure and use `Accel::closest` instead.
+    pub fn raycast(&self, ray: &Ray) -> Option<Hit> {
+        let mut closest: Option<Hit> = None;
+        self.for_each_hit(ray, |hit| {
+            if closest.as_ref().is_none_or(|x| hit.t < x.t) {
+                closest = Some(hit);
+            }
+        });
+        closest
+    }
+    /// All triangles hit by `ray` like `raycast`, from the closest to the
+    /// farthest. Shapes only report their closest hits.
+    pub fn raycast_all(&self, ray: &Ray) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        self.for_each_hit(ray, |hit| hits.push(hit));
+        hits.sort_by(|a, b| a.t.total_cmp(&b.t));
+        hits
+    }
+    fn for_each_hit<F: FnMut(Hit)>(&self, ray: &Ray, mut f: F) {
+        with_verts(|verts| {
+            for (iobj, obj) in self.objs.iter().enumerate() {
+                verts.clear();
+                verts.extend(obj.verts.iter().map(|&x| obj.world2obj * x));
+                for (itri, (x, y, z)) in obj.idxs.iter().enumerate() {
+                    let tri = Triangle::new(verts[*x], verts[*y], verts[*z]);
+                    if obj.cull_backfaces && faces_away(ray, &tri) { continue }
+                    if let Some(x) = ray_cast_tri_with(ray, &tri, &self.precision) {
+                        f(Hit::new(iobj, itri, &tri, x));
+                    }
+                }
+                if let Some((tri, x)) = obj.ray_cast_shape(ray, &self.precision) {
+                    if obj.cull_backfaces && x.kind == HitKind::Back { continue }
+                    f(Hit::new(iobj, x.prim, &tri, x));
+                }
+            }
+        });
+    }
+    /// World space bounds of the vertices and shapes of all objects
+    /// regardless of their visibility, empty if there are none.
+    pub fn bounds(&self) -> Aabb {
+        self.objs.iter()
+            .flat_map(|obj| {
+                let corners = obj.shape.iter()
+                    .map(|x| x.bounds())
+                    .filter(|x| x.min.0 <= x.max.0)
+                    .flat_map(|Aabb { min, max }| (0..8).map(move |i| {
+                        let pick = |bit: usize, lo: Real, hi: Real| if i & bit == 0 { lo } else { hi };
+                        Point(pick(1, min.0, max.0), pick(2, min.1, max.1), pick(4, min.2, max.2))
+                    }));
+                obj.verts.iter().copied().chain(corners).map(move |x| obj.world2obj * x)
+            })
+            .fold(Aabb::empty(), |seed, p| seed.grow(p))
+    }
+    /// Scale the whole scene about the origin by `k`, e.g.,
+    /// `LengthUnit::Centimeters.to(LengthUnit::Meters)` for a scene built
+    /// of assets in centimeters, together with the tolerances of
+    /// `precision`. Lights and cameras are placed by tracers and are left
+    /// to be scaled alike, and acceleration structures must be rebuilt.
+    pub fn rescale(&mut self, k: Real) {
+        let scale = Transform::eye().scale(Vector(k, k, k));
+        let unscale = Transform::eye().scale(Vector(1.0 / k, 1.0 / k, 1.0 / k));
+        for obj in self.objs.iter_mut() {
+            obj.world2obj = scale * obj.world2obj;
+            obj.obj2world = obj.obj2world * unscale;
+        }
+        let precision = &mut self.precision;
+        precision.ray_epsilon *= k;
+        precision.min_tri_area *= k * k;
+        precision.max_t *= k;
+    }
+    /// Size of the scene geometry. Acceleration structures and textures are
+    /// not owned by the scene; add them with `SceneStats::with_accel` and
+    /// `SceneStats::with_textures`.
+    pub fn stats(&self) -> SceneStats {
+        let nvert = self.objs.iter().map(|x| x.verts.len()).sum::<usize>();
+        let ntri = self.objs.iter().map(|x| x.idxs.len()).sum::<usize>();
+        let geometry_bytes = self.objs.iter()
+            .map(|x| {
+                std::mem::size_of_val(x.verts.as_slice()) +
+                    std::mem::size_of_val(x.idxs.as_slice())
+            })
+            .sum();
+        SceneStats {
+            nobj: self.objs.len(),
+            ntri,
+            nvert,
+            geometry_bytes,
+            accel: None,
+            texture_bytes: 0,
+        }
+    }
+}
+
+/// What is about to be rendered, see `Scene::stats`. Displayed as a short
+/// human readable report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SceneStats {
+    pub nobj: usize,
+    pub ntri: usize,
+    pub nvert: usize,
+    /// Memory taken by vertex and index buffers.
+    pub geometry_bytes: usize,
+    pub accel: Option<AccelStats>,
+    /// Memory taken by the pixels of textures.
+    pub texture_bytes: usize,
+}
+impl SceneStats {
+    pub fn with_accel(self, accel: &dyn Accel) -> SceneStats {
+        SceneStats { accel: Some(accel.stats()), ..self }
+    }
+    pub fn with_textures<'a, I>(self, imgs: I) -> SceneStats
+        where I: IntoIterator<Item = &'a Image>
+    {
+        let texture_bytes = imgs.into_iter().map(Image::byte_size).sum::<usize>();
+        SceneStats { texture_bytes: self.texture_bytes + texture_bytes, ..self }
+    }
+}
+impl std::fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mib = |x: usize| x as f64 / (1024.0 * 1024.0);
+        writeln!(f, "objects: {}", self.nobj)?;
+        writeln!(f, "triangles: {} ({} vertices, {:.2} MiB)",
+            self.ntri, self.nvert, mib(self.geometry_bytes))?;
+        if let Some(accel) = &self.accel {
+            writeln!(f, "acceleration: {} nodes, depth {}, {:.2} MiB",
+                accel.nnode, accel.depth, mib(accel.bytes))?;
+            writeln!(f, "estimated cost per ray: {:.1}", accel.cost)?;
+        }
+        write!(f, "textures: {:.2} MiB", mib(self.texture_bytes))
+    }
 }
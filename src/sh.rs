@@ -0,0 +1,104 @@
+use crate::geom::{Color, Vector, narrow};
+use crate::img::PixelSource;
+use crate::sampler::{CubeSampler, EquirectSampler};
+
+/// Radiance over the sphere of directions projected onto real spherical
+/// harmonics of bands 0 to 2. Nine coefficients capture low-frequency
+/// lighting well enough for the diffuse term, which acts as a low-pass filter.
+///
+/// See: Ravi Ramamoorthi and Pat Hanrahan, An Efficient Representation for
+/// Irradiance Environment Maps.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShL2 {
+    pub coeffs: [Color; 9],
+}
+impl ShL2 {
+    /// SH basis functions evaluated at unit direction `v`.
+    pub fn basis(v: Vector) -> [f32; 9] {
+        let Vector(x, y, z) = v;
+        let (x, y, z) = (narrow(x), narrow(y), narrow(z));
+        [
+            0.282_095,
+            0.488_603 * y,
+            0.488_603 * z,
+            0.488_603 * x,
+            1.092_548 * x * y,
+            1.092_548 * y * z,
+            0.315_392 * (3.0 * z * z - 1.0),
+            1.092_548 * x * z,
+            0.546_274 * (x * x - y * y),
+        ]
+    }
+    /// Accumulate radiance `c` arriving from unit direction `v` over solid
+    /// angle `d_omega`.
+    pub fn add(&mut self, v: Vector, c: Color, d_omega: f32) {
+        for (coeff, y) in self.coeffs.iter_mut().zip(ShL2::basis(v).iter()) {
+            *coeff = *coeff + c * (y * d_omega);
+        }
+    }
+
+    /// Project a cube map with faces in the order used by `CubeSampler`.
+    pub fn from_cubemap<I: PixelSource>(faces: &[I]) -> ShL2 {
+        let mut sh = ShL2::default();
+        for (i, face) in faces.iter().enumerate() {
+            let w = face.width();
+            let h = face.height();
+            let du = 2.0 / w as f32;
+            let dv = 2.0 / h as f32;
+            for y in 0..h {
+                for x in 0..w {
+                    let u = (x as f32 + 0.5) * du - 1.0;
+                    let v = (y as f32 + 0.5) * dv - 1.0;
+                    let dir = CubeSampler::face2dir(i, u, v);
+                    // Texels further from the face center cover less solid
+                    // angle.
+                    let r2 = dir.dot(dir);
+                    let d_omega = du * dv / narrow(r2 * r2.sqrt());
+                    sh.add(dir.normalize(), face.load_px(x, y), d_omega);
+                }
+            }
+        }
+        sh
+    }
+    /// Project an equirectangular environment map in the layout used by
+    /// `EquirectSampler`.
+    pub fn from_equirect<I: PixelSource>(img: &I) -> ShL2 {
+        use std::f32::consts::PI;
+        let w = img.width();
+        let h = img.height();
+        let dphi = 2.0 * PI / w as f32;
+        let dtheta = PI / h as f32;
+        let mut sh = ShL2::default();
+        for y in 0..h {
+            let v = (y as f32 + 0.5) / h as f32;
+            // Rows near the poles cover less solid angle.
+            let d_omega = (v * PI).sin() * dphi * dtheta;
+            for x in 0..w {
+                let u = (x as f32 + 0.5) / w as f32;
+                let dir = EquirectSampler::uv2dir(u, v);
+                sh.add(dir, img.load_px(x, y), d_omega);
+            }
+        }
+        sh
+    }
+
+    /// Reconstructed radiance arriving from unit direction `v`.
+    pub fn eval(&self, v: Vector) -> Color {
+        self.coeffs.iter()
+            .zip(ShL2::basis(v).iter())
+            .fold(Color::default(), |seed, (c, y)| seed + *c * *y)
+    }
+    /// Irradiance on a surface of unit normal `n`, i.e., radiance convolved
+    /// with the clamped cosine lobe. The outgoing radiance of a Lambertian
+    /// surface is `albedo * irradiance / PI`.
+    pub fn irradiance(&self, n: Vector) -> Color {
+        use std::f32::consts::PI;
+        // Convolution of each band with the clamped cosine.
+        const BAND: [usize; 9] = [0, 1, 1, 1, 2, 2, 2, 2, 2];
+        let a = [PI, 2.0 * PI / 3.0, PI / 4.0];
+        self.coeffs.iter()
+            .zip(ShL2::basis(n).iter())
+            .zip(BAND.iter())
+            .fold(Color::default(), |seed, ((c, y), l)| seed + *c * (a[*l] * y))
+    }
+}
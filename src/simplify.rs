@@ -0,0 +1,481 @@
+//! Mesh simplification by quadric error metrics, so that heavy meshes like
+//! scans can be reduced to trace at interactive rates, and chains of levels
+//! of detail picked by the size of objects on screen.
+//!
+//! Edges are collapsed one by one, cheapest first, where the cost of moving a
+//! vertex is the sum of its squared distances to the planes of the triangles
+//! around the collapsed vertices. Mesh borders are kept in place by planes
+//! perpendicular to them, and collapses flipping triangles or pinching the
+//! surface are rejected.
+//!
+//! See: Michael Garland and Paul S. Heckbert, Surface Simplification Using
+//! Quadric Error Metrics.
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use crate::geom::{Real, Point, Vector};
+use crate::scene::Object;
+use crate::camera::Camera;
+
+/// Weight of the planes keeping mesh borders in place relative to the
+/// planes of the triangles.
+const BORDER_WEIGHT: Real = 1000.0;
+
+/// Symmetric 4x4 matrix `Q` measuring the weighted sum of squared distances
+/// of points `p` to a set of planes as `[p, 1] Q [p, 1]^T`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric {
+    q: [Real; 10],
+    /// Total weight of the planes of triangles, i.e., their area.
+    area: Real,
+}
+impl Quadric {
+    /// Quadric of the plane through `p` with unit normal `n`, weighted by `w`.
+    fn plane(p: Point, n: Vector, w: Real) -> Quadric {
+        let d = -(n.0 * p.0 + n.1 * p.1 + n.2 * p.2);
+        let Vector(a, b, c) = n;
+        let q = [
+            a * a * w, a * b * w, a * c * w, a * d * w,
+            b * b * w, b * c * w, b * d * w,
+            c * c * w, c * d * w,
+            d * d * w,
+        ];
+        Quadric { q, area: w }
+    }
+    fn add(self, rhs: Quadric) -> Quadric {
+        let mut rv = self;
+        for (x, y) in rv.q.iter_mut().zip(rhs.q.iter()) {
+            *x += *y;
+        }
+        rv.area += rhs.area;
+        rv
+    }
+    fn error(&self, p: Point) -> Real {
+        let q = &self.q;
+        let Point(x, y, z) = p;
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9]
+    }
+    /// Point of the least error, or `None` if there isn't a single one, e.g.,
+    /// if all the planes are parallel.
+    fn minimizer(&self) -> Option<Point> {
+        let q = &self.q;
+        let (a, b, c, d, e, f) = (q[0], q[1], q[2], q[4], q[5], q[7]);
+        let det = a * (d * f - e * e) - b * (b * f - e * c) + c * (b * e - d * c);
+        let scale = a + d + f;
+        if det.abs() <= 1e-6 * scale * scale * scale { return None }
+        // Solve the symmetric system by Cramer's rule.
+        let (r0, r1, r2) = (-q[3], -q[6], -q[8]);
+        let x = r0 * (d * f - e * e) - b * (r1 * f - e * r2) + c * (r1 * e - d * r2);
+        let y = a * (r1 * f - r2 * e) - r0 * (b * f - e * c) + c * (b * r2 - r1 * c);
+        let z = a * (d * r2 - e * r1) - b * (b * r2 - r1 * c) + r0 * (b * e - d * c);
+        Some(Point(x / det, y / det, z / det))
+    }
+}
+
+/// Candidate edge collapse in the queue. Collapses are stale once either
+/// vertex has changed since, as tracked by the vertex versions.
+struct Collapse {
+    cost: Real,
+    /// Mean squared distance to the planes of the triangles, weighted by
+    /// area.
+    mean_error: Real,
+    p: Point,
+    u: usize,
+    v: usize,
+    versions: (u32, u32),
+}
+impl PartialEq for Collapse {
+    fn eq(&self, rhs: &Collapse) -> bool { self.cmp(rhs) == Ordering::Equal }
+}
+impl Eq for Collapse {}
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, rhs: &Collapse) -> Option<Ordering> { Some(self.cmp(rhs)) }
+}
+impl Ord for Collapse {
+    // Reversed, so that the binary heap pops the cheapest collapse first.
+    fn cmp(&self, rhs: &Collapse) -> Ordering { rhs.cost.total_cmp(&self.cost) }
+}
+
+struct Decimator {
+    verts: Vec<Point>,
+    quadrics: Vec<Quadric>,
+    versions: Vec<u32>,
+    /// Triangles around each vertex, including removed ones.
+    vert_tris: Vec<Vec<usize>>,
+    tris: Vec<[usize; 3]>,
+    alive: Vec<bool>,
+    nalive: usize,
+    queue: BinaryHeap<Collapse>,
+}
+impl Decimator {
+    fn new(verts: &[Point], idxs: &[(usize, usize, usize)]) -> Decimator {
+        let tris = idxs.iter()
+            .map(|&(a, b, c)| [a, b, c])
+            .filter(|&[a, b, c]| a != b && b != c && c != a)
+            .collect::<Vec<_>>();
+        let mut vert_tris = vec![Vec::new(); verts.len()];
+        let mut quadrics = vec![Quadric::default(); verts.len()];
+        // Number of triangles on each edge, to find the borders.
+        let mut edges = HashMap::<(usize, usize), usize>::new();
+        for (i, tri) in tris.iter().enumerate() {
+            let n = normal(verts, tri);
+            let area = 0.5 * n.mag();
+            for (k, &a) in tri.iter().enumerate() {
+                vert_tris[a].push(i);
+                if area > 0.0 {
+                    quadrics[a] = quadrics[a].add(Quadric::plane(verts[a], n / n.mag(), area));
+                }
+                let b = tri[(k + 1) % 3];
+                *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        for tri in tris.iter() {
+            let n = normal(verts, tri);
+            for k in 0..3 {
+                let (a, b) = (tri[k], tri[(k + 1) % 3]);
+                if edges[&(a.min(b), a.max(b))] != 1 { continue }
+                let e = verts[b].rel_from(verts[a]);
+                let m = e.cross(n);
+                if m.mag() == 0.0 { continue }
+                let q = Quadric::plane(verts[a], m / m.mag(), BORDER_WEIGHT * e.dot(e));
+                let q = Quadric { area: 0.0, ..q };
+                quadrics[a] = quadrics[a].add(q);
+                quadrics[b] = quadrics[b].add(q);
+            }
+        }
+        let nalive = tris.len();
+        let mut rv = Decimator {
+            verts: verts.to_owned(),
+            quadrics,
+            versions: vec![0; verts.len()],
+            vert_tris,
+            alive: vec![true; tris.len()],
+            tris,
+            nalive,
+            queue: BinaryHeap::new(),
+        };
+        for &(a, b) in edges.keys() {
+            rv.push(a, b);
+        }
+        rv
+    }
+
+    fn push(&mut self, u: usize, v: usize) {
+        let q = self.quadrics[u].add(self.quadrics[v]);
+        let (pu, pv) = (self.verts[u], self.verts[v]);
+        let mid = Point((pu.0 + pv.0) * 0.5, (pu.1 + pv.1) * 0.5, (pu.2 + pv.2) * 0.5);
+        let p = q.minimizer()
+            .into_iter()
+            .chain([pu, pv, mid].iter().copied())
+            .map(|p| (q.error(p), p))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap()
+            .1;
+        let cost = q.error(p).max(0.0);
+        let mean_error = if q.area > 0.0 { cost / q.area } else { cost };
+        let versions = (self.versions[u], self.versions[v]);
+        self.queue.push(Collapse { cost, mean_error, p, u, v, versions });
+    }
+
+    fn neighbors(&self, u: usize) -> Vec<usize> {
+        let mut rv = self.vert_tris[u].iter()
+            .filter(|&&i| self.alive[i])
+            .flat_map(|&i| self.tris[i].iter().copied())
+            .filter(|&x| x != u)
+            .collect::<Vec<_>>();
+        rv.sort_unstable();
+        rv.dedup();
+        rv
+    }
+
+    /// Whether collapsing edge `(u, v)` into `p` keeps the surface manifold
+    /// and doesn't flip any triangle over.
+    fn can_collapse(&self, u: usize, v: usize, p: Point) -> bool {
+        // The vertices connected to both ends must be exactly the ones of the
+        // triangles on the edge.
+        let nu = self.neighbors(u);
+        let nshared = self.neighbors(v).iter()
+            .filter(|x| nu.binary_search(x).is_ok())
+            .count();
+        let nedge = self.vert_tris[u].iter()
+            .filter(|&&i| self.alive[i] && self.tris[i].contains(&v))
+            .count();
+        if nshared != nedge { return false }
+        for &x in [u, v].iter() {
+            for &i in self.vert_tris[x].iter() {
+                let tri = self.tris[i];
+                if !self.alive[i] || (tri.contains(&u) && tri.contains(&v)) { continue }
+                let before = normal(&self.verts, &tri);
+                let after = {
+                    let mut verts = [self.verts[tri[0]], self.verts[tri[1]], self.verts[tri[2]]];
+                    for (k, &y) in tri.iter().enumerate() {
+                        if y == x { verts[k] = p }
+                    }
+                    normal(&verts, &[0, 1, 2])
+                };
+                if after.dot(before) <= 0.0 { return false }
+            }
+        }
+        true
+    }
+
+    fn collapse(&mut self, u: usize, v: usize, p: Point) {
+        self.verts[u] = p;
+        self.quadrics[u] = self.quadrics[u].add(self.quadrics[v]);
+        let moved = std::mem::take(&mut self.vert_tris[v]);
+        for i in moved {
+            if !self.alive[i] { continue }
+            if self.tris[i].contains(&u) {
+                self.alive[i] = false;
+                self.nalive -= 1;
+            } else {
+                for x in self.tris[i].iter_mut() {
+                    if *x == v { *x = u }
+                }
+                self.vert_tris[u].push(i);
+            }
+        }
+        let alive = &self.alive;
+        self.vert_tris[u].retain(|&i| alive[i]);
+        // Only the edges around `u` changed cost.
+        self.versions[u] += 1;
+        self.versions[v] += 1;
+        for w in self.neighbors(u) {
+            self.push(u, w);
+        }
+    }
+
+    /// Collapse edges until there are `ntri` triangles left, skipping the
+    /// ones whose error is beyond `max_error`.
+    fn run(&mut self, ntri: usize, max_error: Real) {
+        while self.nalive > ntri {
+            let c = match self.queue.pop() {
+                Some(x) => x,
+                None => break,
+            };
+            if c.versions != (self.versions[c.u], self.versions[c.v]) { continue }
+            if c.mean_error > max_error * max_error { continue }
+            if self.can_collapse(c.u, c.v, c.p) {
+                self.collapse(c.u, c.v, c.p);
+            }
+        }
+    }
+
+    /// Remaining vertices and triangles, without unused vertices.
+    fn finish(self) -> (Vec<Point>, Vec<(usize, usize, usize)>) {
+        let mut remap = vec![usize::MAX; self.verts.len()];
+        let mut verts = Vec::new();
+        let mut idxs = Vec::with_capacity(self.nalive);
+        for (tri, _) in self.tris.iter().zip(self.alive.iter()).filter(|(_, &x)| x) {
+            let mut map = |i: usize| {
+                if remap[i] == usize::MAX {
+                    remap[i] = verts.len();
+                    verts.push(self.verts[i]);
+                }
+                remap[i]
+            };
+            idxs.push((map(tri[0]), map(tri[1]), map(tri[2])));
+        }
+        (verts, idxs)
+    }
+}
+
+/// Normal of `tri` scaled by twice its area, in the clockwise order of
+/// `geom::Triangle`.
+fn normal(verts: &[Point], tri: &[usize; 3]) -> Vector {
+    let x = verts[tri[1]].rel_from(verts[tri[0]]);
+    let y = verts[tri[2]].rel_from(verts[tri[0]]);
+    y.cross(x)
+}
+
+/// Simplify the mesh of `verts` and `idxs` down to about `ntri` triangles, or
+/// as close as it can get without tearing it, see the module documentation.
+/// Degenerate and unused vertices are dropped.
+pub fn decimate(
+    verts: &[Point],
+    idxs: &[(usize, usize, usize)],
+    ntri: usize,
+) -> (Vec<Point>, Vec<(usize, usize, usize)>) {
+    decimate_impl(verts, idxs, ntri, Real::INFINITY)
+}
+/// Simplify the mesh of `verts` and `idxs` as far as it goes while staying
+/// within about `max_error` of the original surface, measured as the root
+/// mean square distance to the planes of the original triangles around each
+/// vertex, weighted by area. Flat regions are reduced to few large triangles
+/// while curved ones keep as many as it takes.
+pub fn decimate_within(
+    verts: &[Point],
+    idxs: &[(usize, usize, usize)],
+    max_error: Real,
+) -> (Vec<Point>, Vec<(usize, usize, usize)>) {
+    decimate_impl(verts, idxs, 0, max_error)
+}
+fn decimate_impl(
+    verts: &[Point],
+    idxs: &[(usize, usize, usize)],
+    ntri: usize,
+    max_error: Real,
+) -> (Vec<Point>, Vec<(usize, usize, usize)>) {
+    // Quadrics are evaluated in a unit box around the mesh so that precision
+    // doesn't depend on where it is and how big it is.
+    let (lo, hi) = verts.iter().fold(
+        (Point(Real::MAX, Real::MAX, Real::MAX), Point(Real::MIN, Real::MIN, Real::MIN)),
+        |(lo, hi), p| {
+            (Point(lo.0.min(p.0), lo.1.min(p.1), lo.2.min(p.2)),
+             Point(hi.0.max(p.0), hi.1.max(p.1), hi.2.max(p.2)))
+        });
+    let extent = hi.rel_from(lo);
+    let scale = extent.0.max(extent.1).max(extent.2);
+    let scale = if scale > 0.0 { scale } else { 1.0 };
+    let unit = verts.iter()
+        .map(|p| Point((p.0 - lo.0) / scale, (p.1 - lo.1) / scale, (p.2 - lo.2) / scale))
+        .collect::<Vec<_>>();
+    let mut decimator = Decimator::new(&unit, idxs);
+    decimator.run(ntri, max_error / scale);
+    let (verts, idxs) = decimator.finish();
+    let verts = verts.into_iter()
+        .map(|p| Point(p.0 * scale + lo.0, p.1 * scale + lo.1, p.2 * scale + lo.2))
+        .collect();
+    (verts, idxs)
+}
+
+/// `obj` simplified down to about `ntri` triangles with `decimate`. Shading
+/// normals and colors of the triangles are dropped.
+pub fn decimate_object<M: Clone>(obj: &Object<M>, ntri: usize) -> Object<M> {
+    let (verts, idxs) = decimate(&obj.verts, &obj.idxs, ntri);
+    Object {
+        verts,
+        idxs,
+        mat: obj.mat.clone(),
+        obj2world: obj.obj2world,
+        world2obj: obj.world2obj,
+        visibility: obj.visibility,
+        cull_backfaces: obj.cull_backfaces,
+        normals: None,
+        colors: None,
+        name: obj.name.clone(),
+        shape: obj.shape.clone(),
+    }
+}
+
+/// Levels of detail of an object, each with about half the triangles of the
+/// previous one, from the object itself at level 0.
+pub struct Lod<M> {
+    levels: Vec<Object<M>>,
+    /// Bounding sphere of the object in world space.
+    center: Point,
+    radius: Real,
+    /// Pixels on screen a triangle should cover at least, 4 by default.
+    pub px_per_tri: Real,
+}
+impl<M: Clone> Lod<M> {
+    /// Levels of detail of `obj`, down to `nlevel` levels or until the mesh
+    /// can't be simplified further.
+    pub fn new(obj: Object<M>, nlevel: usize) -> Lod<M> {
+        let world = obj.verts.iter()
+            .map(|&p| obj.world2obj * p)
+            .collect::<Vec<_>>();
+        let n = world.len().max(1) as Real;
+        let (sx, sy, sz) = world.iter()
+            .fold((0.0, 0.0, 0.0), |(x, y, z), p| (x + p.0, y + p.1, z + p.2));
+        let center = Point(sx / n, sy / n, sz / n);
+        let radius = world.iter()
+            .map(|&p| p.rel_from(center).mag())
+            .fold(0.0, Real::max);
+        let mut levels = vec![obj];
+        while levels.len() < nlevel {
+            let last = levels.last().unwrap();
+            let ntri = last.idxs.len();
+            let next = decimate_object(last, ntri / 2);
+            if next.idxs.is_empty() || next.idxs.len() * 10 > ntri * 9 { break }
+            levels.push(next);
+        }
+        Lod { levels, center, radius, px_per_tri: 4.0 }
+    }
+}
+impl<M> Lod<M> {
+    pub fn levels(&self) -> &[Object<M>] {
+        &self.levels
+    }
+    /// The coarsest level still having a triangle every `px_per_tri` pixels
+    /// the object covers in a frame `h` pixels tall seen through `cam`. The
+    /// screen size is estimated from the bounding sphere of the object and
+    /// the vertical field of view, also of panoramas.
+    pub fn select(&self, cam: &Camera, h: u32) -> &Object<M> {
+        let eye = cam.cam2world * Point(0.0, 0.0, 0.0);
+        let dist = self.center.rel_from(eye).mag();
+        if dist <= self.radius { return &self.levels[0] }
+        let tan = (self.radius / dist).asin().tan();
+        let r_px = tan / (cam.fov * 0.5).tan() * h as Real * 0.5;
+        let ntri = std::f64::consts::PI as Real * r_px * r_px / self.px_per_tri;
+        self.levels.iter()
+            .rev()
+            .find(|x| x.idxs.len() as Real >= ntri)
+            .unwrap_or(&self.levels[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Transform;
+    use crate::model::{make_displaced_pln, make_sphere};
+
+    fn as_arrays(idxs: &[(usize, usize, usize)]) -> Vec<[usize; 3]> {
+        idxs.iter().map(|&(a, b, c)| [a, b, c]).collect()
+    }
+
+    #[test]
+    fn plane_reaches_target_without_flipping() {
+        let pln = make_displaced_pln((), Transform::eye(), 16, |_, _| 0.0);
+        let up = normal(&pln.verts, &as_arrays(&pln.idxs)[0]);
+        let (verts, idxs) = decimate(&pln.verts, &pln.idxs, 64);
+        assert!(!idxs.is_empty() && idxs.len() <= 64, "{} triangles", idxs.len());
+        for tri in as_arrays(&idxs) {
+            let n = normal(&verts, &tri);
+            assert!(n.dot(up) > 0.0);
+        }
+        // Borders are kept in place.
+        for p in verts.iter() {
+            assert!(p.1.abs() < 1e-5 && p.0.abs() <= 0.5 + 1e-5 && p.2.abs() <= 0.5 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn closed_meshes_stay_closed() {
+        let sph = make_sphere((), Transform::eye(), 16, 32);
+        let (verts, idxs) = decimate(&sph.verts, &sph.idxs, 100);
+        assert!(idxs.len() <= 100 && idxs.len() * 2 < sph.idxs.len());
+        // Every edge is shared by exactly two triangles walking it in
+        // opposite directions.
+        let mut edges = HashMap::new();
+        for [a, b, c] in as_arrays(&idxs) {
+            assert!(a < verts.len() && b < verts.len() && c < verts.len());
+            for e in [(a, b), (b, c), (c, a)] {
+                *edges.entry(e).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &n) in edges.iter() {
+            assert_eq!((n, edges.get(&(b, a))), (1, Some(&1)));
+        }
+    }
+
+    #[test]
+    fn lod_coarsens_with_distance() {
+        let lod = Lod::new(make_sphere((), Transform::eye(), 32, 64), 6);
+        assert!(lod.levels().len() > 3);
+        let ntri = |dist: Real| {
+            let cam2world = Transform::eye().translate(Vector(0.0, 0.0, -dist));
+            let cam = Camera::new(cam2world, 45.0_f64.to_radians() as Real, 1.0);
+            lod.select(&cam, 512).idxs.len()
+        };
+        let ntris = [0.1, 1.0, 4.0, 16.0, 64.0, 256.0].iter()
+            .map(|&x| ntri(x))
+            .collect::<Vec<_>>();
+        assert!(ntris.windows(2).all(|x| x[0] >= x[1]), "{:?}", ntris);
+        assert_eq!(ntris[0], lod.levels()[0].idxs.len());
+        assert_eq!(ntris[5], lod.levels().last().unwrap().idxs.len());
+    }
+}
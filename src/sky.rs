@@ -0,0 +1,179 @@
+//! Procedural skies, so that outdoor scenes look right without external
+//! assets: a gradient from the horizon to the zenith with a sun, and an
+//! optional layer of clouds ray marched through noise. Skies are seen from
+//! the origin with the y-axis up, and are baked into equirectangular
+//! environment maps with `Sky::to_equirect`.
+use crate::geom::{Real, Point, Vector, Color, narrow};
+use crate::img::Image;
+use crate::sampler::EquirectSampler;
+use crate::medium::henyey_greenstein;
+use crate::noise::fbm3;
+use crate::par::*;
+
+/// A layer of clouds between two altitudes, lit by single scattering of
+/// sunlight plus an ambient term standing for the light of the rest of the
+/// sky.
+#[derive(Debug, Clone, Copy)]
+pub struct CloudLayer {
+    /// Altitude of the bottom of the layer above the viewer.
+    pub bottom: Real,
+    /// Altitude of the top of the layer above the viewer.
+    pub top: Real,
+    /// Fraction of the sky covered by clouds in [0, 1].
+    pub coverage: f32,
+    /// Extinction coefficient in the thickest parts of the clouds, per unit
+    /// length.
+    pub density: f32,
+    /// Size of the cloud features.
+    pub scale: Real,
+    /// Offset of the clouds, e.g., moved along with the wind over the frames
+    /// of an animation.
+    pub offset: Vector,
+    /// Asymmetry of the Henyey-Greenstein phase function of the clouds. Water
+    /// droplets scatter mostly forward, so that clouds are lined with light
+    /// when seen towards the sun.
+    pub g: Real,
+    /// Number of steps marched along view rays through the layer.
+    pub nstep: u32,
+    /// Number of steps marched towards the sun from each step along view
+    /// rays.
+    pub nstep_sun: u32,
+    /// Distance along view rays beyond which clouds fade out into the
+    /// horizon.
+    pub max_dist: Real,
+}
+impl Default for CloudLayer {
+    fn default() -> CloudLayer {
+        CloudLayer {
+            bottom: 1500.0,
+            top: 3000.0,
+            coverage: 0.5,
+            density: 0.02,
+            scale: 3000.0,
+            offset: Vector(0.0, 0.0, 0.0),
+            g: 0.6,
+            nstep: 32,
+            nstep_sun: 6,
+            max_dist: 40000.0,
+        }
+    }
+}
+impl CloudLayer {
+    /// Extinction coefficient at point `p`, thinning out towards the bottom
+    /// and the top of the layer so that clouds have rounded tops.
+    pub fn extinction(&self, p: Point) -> f32 {
+        let h = (p.1 - self.bottom) / (self.top - self.bottom);
+        if !(0.0..=1.0).contains(&h) { return 0.0 }
+        let profile = narrow(4.0 * h * (1.0 - h));
+        let q = Vector(p.0, p.1, p.2) / self.scale + self.offset / self.scale;
+        // Noise is mostly within [-0.5, 0.5], so that coverages of 0 and 1
+        // clear the sky and fill it respectively.
+        let x = narrow(fbm3(q.0, q.1, q.2, 5)) + self.coverage - 0.5;
+        (x * 4.0 * profile - (1.0 - profile)).clamp(0.0, 1.0) * self.density
+    }
+    /// Optical depth from `p` along unit direction `dir` out of the layer.
+    fn optical_depth(&self, p: Point, dir: Vector) -> f32 {
+        if dir.1 <= 0.0 { return f32::INFINITY }
+        let dist = ((self.top - p.1) / dir.1).min(self.top - self.bottom);
+        let dt = dist / self.nstep_sun.max(1) as Real;
+        (0..self.nstep_sun.max(1))
+            .map(|i| self.extinction(p.affine_add(dir * ((i as Real + 0.5) * dt))))
+            .sum::<f32>() * narrow(dt)
+    }
+}
+
+/// A procedural sky, see the module documentation. The default one is the
+/// sky of material previews.
+#[derive(Debug, Clone, Copy)]
+pub struct Sky {
+    /// Unit direction to the sun.
+    pub sun: Vector,
+    /// Radiance of the sun.
+    pub sun_color: Color,
+    /// Cosine of the angular radius of the sun.
+    pub sun_cos: Real,
+    pub zenith: Color,
+    pub horizon: Color,
+    /// Radiance below the horizon.
+    pub ground: Color,
+    pub clouds: Option<CloudLayer>,
+}
+impl Default for Sky {
+    fn default() -> Sky {
+        Sky {
+            sun: Vector(-1.0, 2.0, -1.0).normalize(),
+            // A wide sun converges much faster than a realistic one.
+            sun_color: Color(8.0, 7.6, 6.8, 1.0),
+            sun_cos: 0.9,
+            zenith: Color(0.3, 0.5, 0.9, 1.0),
+            horizon: Color(1.0, 1.0, 1.0, 1.0),
+            ground: Color(0.1, 0.1, 0.1, 1.0),
+            clouds: None,
+        }
+    }
+}
+impl Sky {
+    /// Radiance of the sky without clouds in unit direction `dir`.
+    fn background(&self, dir: Vector) -> Color {
+        if dir.1 < 0.0 { return self.ground }
+        let a = narrow(dir.1);
+        let sky = self.horizon * (1.0 - a) + self.zenith * a;
+        if dir.dot(self.sun) > self.sun_cos { sky + self.sun_color } else { sky }
+    }
+    /// Radiance of the sky in unit direction `dir`.
+    pub fn radiance(&self, dir: Vector) -> Color {
+        let bg = self.background(dir);
+        match self.clouds {
+            Some(clouds) if dir.1 > 0.0 => self.march_clouds(&clouds, dir, bg),
+            _ => bg,
+        }
+    }
+    /// Radiance of `bg` seen through `clouds` in unit direction `dir`.
+    fn march_clouds(&self, clouds: &CloudLayer, dir: Vector, bg: Color) -> Color {
+        const PI: Real = std::f64::consts::PI as Real;
+        let t0 = clouds.bottom / dir.1;
+        if t0 >= clouds.max_dist { return bg }
+        let t1 = (clouds.top / dir.1).min(clouds.max_dist);
+        let dt = (t1 - t0) / clouds.nstep.max(1) as Real;
+        // Sunlight over the solid angle of the sun, and the rest of the sky
+        // scattered evenly.
+        let sun = self.sun_color * narrow(2.0 * PI * (1.0 - self.sun_cos));
+        let phase = narrow(henyey_greenstein(clouds.g, dir.dot(self.sun)));
+        let ambient = (self.zenith + self.horizon) * 0.5;
+        let (mut tr, mut scattered) = (1.0_f32, Color::default());
+        for i in 0..clouds.nstep.max(1) {
+            let p = Point(0.0, 0.0, 0.0).affine_add(dir * (t0 + (i as Real + 0.5) * dt));
+            let sigma = clouds.extinction(p);
+            if sigma <= 0.0 { continue }
+            let tr_sun = (-clouds.optical_depth(p, self.sun)).exp();
+            let step = (-sigma * narrow(dt)).exp();
+            // Light scattered within the step towards the viewer, integrated
+            // analytically over the step.
+            let lit = sun * (phase * tr_sun) + ambient;
+            scattered = scattered + lit * (tr * (1.0 - step));
+            tr *= step;
+            if tr < 1e-3 { break }
+        }
+        // Faraway clouds fade into the haze of the horizon.
+        let fade = 1.0 - narrow(t0 / clouds.max_dist);
+        let clouded = bg * tr + scattered;
+        let rv = clouded * fade + bg * (1.0 - fade);
+        Color(rv.0, rv.1, rv.2, 1.0)
+    }
+
+    /// Equirectangular environment map of `w` by `h` pixels of the sky.
+    pub fn to_equirect(&self, w: usize, h: usize) -> Image {
+        let colors = (0..w * h).into_par_iter()
+            .map(|i| {
+                let u = ((i % w) as f32 + 0.5) / w as f32;
+                let v = ((i / w) as f32 + 0.5) / h as f32;
+                self.radiance(EquirectSampler::uv2dir(u, v))
+            })
+            .collect::<Vec<_>>();
+        let mut img = Image::new(w, h);
+        for (i, c) in colors.into_iter().enumerate() {
+            img.store_px(i % w, i / w, Color(c.0, c.1, c.2, 1.0));
+        }
+        img
+    }
+}
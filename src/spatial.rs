@@ -0,0 +1,392 @@
+//! Spatial containers of points carrying payloads, e.g., photons, irradiance
+//! cache records, or points of a point cloud, for radius and k-nearest
+//! queries around a point. `HashGrid` takes points one at a time, while
+//! `PointKdTree` is built at once and adapts to the density of the points.
+use std::collections::HashMap;
+use crate::geom::{Real, Point};
+
+/// Points with payloads bucketed in a uniform grid of cubic cells, of which
+/// only the occupied ones are stored in a hash map. Queries are fastest when
+/// the cell size is about the query radius; much smaller cells make queries
+/// visit many cells and much larger ones make them test many points.
+#[derive(Debug, Clone)]
+pub struct HashGrid<T> {
+    cell_size: Real,
+    items: Vec<(Point, T)>,
+    /// Indices into `items` of the points in each occupied cell.
+    cells: HashMap<[i32; 3], Vec<usize>>,
+    /// Lowest and highest coordinates of occupied cells.
+    bounds: Option<([i32; 3], [i32; 3])>,
+}
+impl<T> HashGrid<T> {
+    pub fn new(cell_size: Real) -> HashGrid<T> {
+        assert!(cell_size > 0.0, "cell size must be positive");
+        HashGrid { cell_size, items: Vec::new(), cells: HashMap::new(), bounds: None }
+    }
+    pub fn cell_size(&self) -> Real {
+        self.cell_size
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    /// Remove all points, keeping the allocations.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.cells.clear();
+        self.bounds = None;
+    }
+    fn cell_of(&self, p: Point) -> [i32; 3] {
+        let rcp = self.cell_size.recip();
+        [
+            (p.0 * rcp).floor() as i32,
+            (p.1 * rcp).floor() as i32,
+            (p.2 * rcp).floor() as i32,
+        ]
+    }
+    /// Insert `payload` at `p` and return its index, by which it's referred
+    /// to in query results.
+    pub fn insert(&mut self, p: Point, payload: T) -> usize {
+        let i = self.items.len();
+        let cell = self.cell_of(p);
+        self.items.push((p, payload));
+        self.cells.entry(cell).or_default().push(i);
+        self.bounds = Some(match self.bounds {
+            Some((lo, hi)) => (
+                [lo[0].min(cell[0]), lo[1].min(cell[1]), lo[2].min(cell[2])],
+                [hi[0].max(cell[0]), hi[1].max(cell[1]), hi[2].max(cell[2])],
+            ),
+            None => (cell, cell),
+        });
+        i
+    }
+    /// The point at index `i` and its payload.
+    pub fn get(&self, i: usize) -> Option<(Point, &T)> {
+        self.items.get(i).map(|(p, x)| (*p, x))
+    }
+    /// Mutable access to the payload at index `i`.
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut T> {
+        self.items.get_mut(i).map(|(_, x)| x)
+    }
+    /// All points and their payloads in order of insertion.
+    pub fn iter(&self) -> impl Iterator<Item = (Point, &T)> {
+        self.items.iter().map(|(p, x)| (*p, x))
+    }
+
+    /// Call `f` with the index, the point, the payload and the squared
+    /// distance to `p` of every point within distance `r` of `p`, in no
+    /// particular order.
+    pub fn for_each_within<F>(&self, p: Point, r: Real, mut f: F)
+        where F: FnMut(usize, Point, &T, Real)
+    {
+        let (lo, hi) = match self.bounds {
+            Some(x) => x,
+            None => return,
+        };
+        let rcp = self.cell_size.recip();
+        let range = |x: Real, lo: i32, hi: i32| {
+            let a = ((x - r) * rcp).floor().max(lo as Real) as i32;
+            let b = ((x + r) * rcp).floor().min(hi as Real) as i32;
+            a..=b
+        };
+        let r2 = r * r;
+        for z in range(p.2, lo[2], hi[2]) {
+            for y in range(p.1, lo[1], hi[1]) {
+                for x in range(p.0, lo[0], hi[0]) {
+                    let idxs = match self.cells.get(&[x, y, z]) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    for &i in idxs {
+                        let (q, payload) = &self.items[i];
+                        let v = q.rel_from(p);
+                        let d2 = v.dot(v);
+                        if d2 <= r2 {
+                            f(i, *q, payload, d2);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// Indices of the points within distance `r` of `p` with their distances,
+    /// in no particular order.
+    pub fn within(&self, p: Point, r: Real) -> Vec<(usize, Real)> {
+        let mut rv = Vec::new();
+        self.for_each_within(p, r, |i, _, _, d2| rv.push((i, d2.sqrt())));
+        rv
+    }
+    /// Indices of the `k` points closest to `p` within distance `max_dist`
+    /// with their distances, closest first. Cells are visited in growing
+    /// shells around the cell of `p` until no unvisited point can be closer
+    /// than the `k`-th closest so far, so `max_dist` can be infinite.
+    pub fn nearest(&self, p: Point, k: usize, max_dist: Real) -> Vec<(usize, Real)> {
+        let mut rv: Vec<(usize, Real)> = Vec::with_capacity(k + 1);
+        let (lo, hi) = match self.bounds {
+            Some(x) if k > 0 => x,
+            _ => return rv,
+        };
+        // Cell coordinates of far points can be far apart, so shells are
+        // measured in `i64`.
+        let c = self.cell_of(p);
+        let (c, lo, hi) = (c.map(i64::from), lo.map(i64::from), hi.map(i64::from));
+        let max_d2 = max_dist * max_dist;
+        // Shells before the first one are out of the occupied cells, and so
+        // are those beyond the last one.
+        let first = (0..3)
+            .map(|i| (lo[i] - c[i]).max(c[i] - hi[i]).max(0))
+            .max()
+            .unwrap_or(0);
+        let last = (0..3)
+            .map(|i| (c[i] - lo[i]).abs().max((hi[i] - c[i]).abs()))
+            .max()
+            .unwrap_or(0);
+        for shell in first..=last {
+            // Points out of the cells visited so far are at least this far
+            // from `p`, which lies in the center cell.
+            let bound = (shell as Real - 1.0).max(0.0) * self.cell_size;
+            if bound > max_dist { break }
+            if rv.len() == k && rv[k - 1].1 <= bound * bound { break }
+            // Cells on the surface of the shell among the occupied ones.
+            let range = |i: usize| (c[i] - shell).max(lo[i])..=(c[i] + shell).min(hi[i]);
+            for z in range(2) {
+                for y in range(1) {
+                    let xs = if (y - c[1]).abs() == shell || (z - c[2]).abs() == shell {
+                        range(0).collect::<Vec<_>>()
+                    } else {
+                        [c[0] - shell, c[0] + shell].iter()
+                            .copied()
+                            .filter(|x| range(0).contains(x))
+                            .collect::<Vec<_>>()
+                    };
+                    for x in xs {
+                        // Occupied cells are within the range of `i32`.
+                        let idxs = match self.cells.get(&[x as i32, y as i32, z as i32]) {
+                            Some(x) => x,
+                            None => continue,
+                        };
+                        for &i in idxs {
+                            let v = self.items[i].0.rel_from(p);
+                            let d2 = v.dot(v);
+                            if d2 > max_d2 { continue }
+                            if rv.len() == k && d2 >= rv[k - 1].1 { continue }
+                            let at = rv.iter().position(|x| x.1 > d2).unwrap_or(rv.len());
+                            rv.insert(at, (i, d2));
+                            rv.truncate(k);
+                        }
+                    }
+                }
+            }
+        }
+        for x in rv.iter_mut() {
+            x.1 = x.1.sqrt();
+        }
+        rv
+    }
+}
+impl<T> std::iter::Extend<(Point, T)> for HashGrid<T> {
+    fn extend<I: IntoIterator<Item = (Point, T)>>(&mut self, iter: I) {
+        for (p, x) in iter {
+            self.insert(p, x);
+        }
+    }
+}
+
+fn coord(p: Point, axis: u8) -> Real {
+    match axis {
+        0 => p.0,
+        1 => p.1,
+        _ => p.2,
+    }
+}
+
+/// Balanced kd-tree of points with payloads, built once from all points.
+/// Unlike `HashGrid`, it needs no cell size and copes with points clustered
+/// at very different densities, at the cost of being immutable. The tree is
+/// implicit: each node is the median of its range of points, split along the
+/// axis of the largest extent of the range.
+#[derive(Debug, Clone)]
+pub struct PointKdTree<T> {
+    /// Points and payloads with their indices in the input, in tree order.
+    items: Vec<(Point, T, usize)>,
+    /// Split axis of the node at each position of `items`.
+    axes: Vec<u8>,
+    /// Position in `items` of each point by index in the input.
+    pos: Vec<usize>,
+}
+impl<T> PointKdTree<T> {
+    /// Build a tree of `points`, referred to in query results by their index
+    /// in `points`.
+    pub fn build<I: IntoIterator<Item = (Point, T)>>(points: I) -> PointKdTree<T> {
+        let mut items = points.into_iter()
+            .enumerate()
+            .map(|(i, (p, x))| (p, x, i))
+            .collect::<Vec<_>>();
+        let mut axes = vec![0; items.len()];
+        Self::build_range(&mut items, &mut axes);
+        let mut pos = vec![0; items.len()];
+        for (i, item) in items.iter().enumerate() {
+            pos[item.2] = i;
+        }
+        PointKdTree { items, axes, pos }
+    }
+    fn build_range(items: &mut [(Point, T, usize)], axes: &mut [u8]) {
+        if items.is_empty() { return }
+        let (mut lo, mut hi) = ([Real::INFINITY; 3], [Real::NEG_INFINITY; 3]);
+        for (p, _, _) in items.iter() {
+            for axis in 0..3 {
+                lo[axis] = lo[axis].min(coord(*p, axis as u8));
+                hi[axis] = hi[axis].max(coord(*p, axis as u8));
+            }
+        }
+        let axis = (0..3)
+            .max_by(|&a, &b| (hi[a] - lo[a]).total_cmp(&(hi[b] - lo[b])))
+            .unwrap_or(0) as u8;
+        let mid = items.len() / 2;
+        // A total order, so that points of NaN coordinates can't break the
+        // selection.
+        items.select_nth_unstable_by(mid, |a, b| coord(a.0, axis).total_cmp(&coord(b.0, axis)));
+        axes[mid] = axis;
+        let (left, right) = items.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::build_range(left, left_axes);
+        Self::build_range(&mut right[1..], &mut right_axes[1..]);
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    /// The point at index `i` and its payload.
+    pub fn get(&self, i: usize) -> Option<(Point, &T)> {
+        let (p, x, _) = self.items.get(*self.pos.get(i)?)?;
+        Some((*p, x))
+    }
+
+    /// Call `f` with the index, the point, the payload and the squared
+    /// distance to `p` of every point within distance `r` of `p`, in no
+    /// particular order.
+    pub fn for_each_within<F>(&self, p: Point, r: Real, mut f: F)
+        where F: FnMut(usize, Point, &T, Real)
+    {
+        self.within_range(0, self.items.len(), p, r * r, &mut f);
+    }
+    fn within_range<F>(&self, a: usize, b: usize, p: Point, r2: Real, f: &mut F)
+        where F: FnMut(usize, Point, &T, Real)
+    {
+        if a >= b { return }
+        let mid = a + (b - a) / 2;
+        let (q, x, i) = &self.items[mid];
+        let v = q.rel_from(p);
+        let d2 = v.dot(v);
+        if d2 <= r2 {
+            f(*i, *q, x, d2);
+        }
+        let diff = coord(p, self.axes[mid]) - coord(*q, self.axes[mid]);
+        if diff <= 0.0 || diff * diff <= r2 {
+            self.within_range(a, mid, p, r2, f);
+        }
+        if diff >= 0.0 || diff * diff <= r2 {
+            self.within_range(mid + 1, b, p, r2, f);
+        }
+    }
+    /// Indices of the points within distance `r` of `p` with their distances,
+    /// in no particular order.
+    pub fn within(&self, p: Point, r: Real) -> Vec<(usize, Real)> {
+        let mut rv = Vec::new();
+        self.for_each_within(p, r, |i, _, _, d2| rv.push((i, d2.sqrt())));
+        rv
+    }
+    /// Indices of the `k` points closest to `p` within distance `max_dist`
+    /// with their distances, closest first.
+    pub fn nearest(&self, p: Point, k: usize, max_dist: Real) -> Vec<(usize, Real)> {
+        let mut rv = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.nearest_range(0, self.items.len(), p, k, max_dist * max_dist, &mut rv);
+        }
+        for x in rv.iter_mut() {
+            x.1 = x.1.sqrt();
+        }
+        rv
+    }
+    /// Gather the closest points of the range into `rv` of squared distances.
+    fn nearest_range(
+        &self,
+        a: usize,
+        b: usize,
+        p: Point,
+        k: usize,
+        max_d2: Real,
+        rv: &mut Vec<(usize, Real)>,
+    ) {
+        if a >= b { return }
+        let mid = a + (b - a) / 2;
+        let (q, _, i) = &self.items[mid];
+        let v = q.rel_from(p);
+        let d2 = v.dot(v);
+        if d2 <= max_d2 && (rv.len() < k || d2 < rv[k - 1].1) {
+            let at = rv.iter().position(|x| x.1 > d2).unwrap_or(rv.len());
+            rv.insert(at, (*i, d2));
+            rv.truncate(k);
+        }
+        let diff = coord(p, self.axes[mid]) - coord(*q, self.axes[mid]);
+        let (near, far) = if diff <= 0.0 {
+            ((a, mid), (mid + 1, b))
+        } else {
+            ((mid + 1, b), (a, mid))
+        };
+        self.nearest_range(near.0, near.1, p, k, max_d2, rv);
+        // Points across the split are at least `|diff|` away.
+        let bound = if rv.len() < k { max_d2 } else { rv[k - 1].1.min(max_d2) };
+        if diff * diff <= bound {
+            self.nearest_range(far.0, far.1, p, k, max_d2, rv);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_grid_nearest_far_away() {
+        let mut grid = HashGrid::new(1.0);
+        grid.insert(Point(0.5, 0.5, 0.5), ());
+        grid.insert(Point(2.5, 0.5, 0.5), ());
+        let nearest = grid.nearest(Point(-1e6, 0.5, 0.5), 1, Real::INFINITY);
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].0, 0);
+        let nearest = grid.nearest(Point(1e6, 0.5, 0.5), 1, Real::INFINITY);
+        assert_eq!(nearest[0].0, 1);
+        // Cells of points beyond the range of `i32` are clamped to it, and
+        // only the shells reaching the occupied cells are visited.
+        let nearest = grid.nearest(Point(1e12, -1e12, 0.5), 2, Real::INFINITY);
+        assert_eq!(nearest.len(), 2);
+    }
+    #[test]
+    fn hash_grid_nearest_stops_at_max_dist() {
+        let mut grid = HashGrid::new(1.0);
+        grid.insert(Point(0.5, 0.5, 0.5), ());
+        grid.insert(Point(5.5, 0.5, 0.5), ());
+        let nearest = grid.nearest(Point(0.5, 0.5, 0.5), 2, 3.0);
+        assert_eq!(nearest, vec![(0, 0.0)]);
+        assert!(grid.nearest(Point(20.5, 0.5, 0.5), 1, 3.0).is_empty());
+        let nearest = grid.nearest(Point(3.5, 0.5, 0.5), 2, Real::INFINITY);
+        assert_eq!(nearest, vec![(1, 2.0), (0, 3.0)]);
+    }
+    #[test]
+    fn kd_tree_with_nan_points() {
+        let points = (0..16)
+            .map(|i| Point(i as Real, 0.0, 0.0))
+            .chain(std::iter::once(Point(Real::NAN, 0.0, 0.0)))
+            .map(|p| (p, ()));
+        let tree = PointKdTree::build(points);
+        assert_eq!(tree.len(), 17);
+        let nearest = tree.nearest(Point(3.2, 0.0, 0.0), 1, Real::INFINITY);
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].0, 3);
+    }
+}
@@ -0,0 +1,234 @@
+//! Statistical checks that sampling routines draw directions by the PDFs they
+//! claim. Sampled directions are binned over the sphere and compared to the
+//! counts expected from the PDF with Pearson's chi-square test, which catches
+//! distribution bugs that merely look noisy in renders.
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::geom::{Real, Vector};
+
+/// Bins expected to get fewer samples are pooled, below which the chi-square
+/// distribution is a poor approximation.
+const MIN_EXPECTED: Real = 5.0;
+/// Subdivisions of each bin along both axes to integrate PDFs over it, fine
+/// enough for the edges of lights crossing bins.
+const NSUBDIV: usize = 32;
+
+/// Outcome of `chi_square_sphere`.
+#[derive(Debug, Clone, Copy)]
+pub struct ChiSquare {
+    pub statistic: Real,
+    /// Degrees of freedom.
+    pub dof: usize,
+}
+impl ChiSquare {
+    /// Standard normal score of the statistic, by the Wilson-Hilferty
+    /// approximation. Scores beyond 4 or so mean that the samples don't
+    /// follow the PDF.
+    ///
+    /// See: Edwin B. Wilson and Margaret M. Hilferty, The Distribution of
+    /// Chi-Square.
+    pub fn z_score(&self) -> Real {
+        let k = self.dof.max(1) as Real;
+        let s = 2.0 / (9.0 * k);
+        ((self.statistic / k).cbrt() - (1.0 - s)) / s.sqrt()
+    }
+}
+
+/// Integral of `f` over the solid angle of the bin `(i, j)` of `ntheta` by
+/// `nphi` bins of equal solid angle, see `sphere_bin`.
+fn integrate_bin<F: Fn(Vector) -> Real>(f: &F, i: usize, j: usize, ntheta: usize, nphi: usize) -> Real {
+    const PI: Real = std::f64::consts::PI as Real;
+    let dz = 2.0 / (ntheta * NSUBDIV) as Real;
+    let dphi = 2.0 * PI / (nphi * NSUBDIV) as Real;
+    let mut rv = 0.0;
+    for k in 0..NSUBDIV {
+        let z = -1.0 + ((i * NSUBDIV + k) as Real + 0.5) * dz;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        for l in 0..NSUBDIV {
+            let phi = -PI + ((j * NSUBDIV + l) as Real + 0.5) * dphi;
+            let (sin, cos) = phi.sin_cos();
+            rv += f(Vector(r * cos, r * sin, z));
+        }
+    }
+    // The area element of the sphere is `dz * dphi`.
+    rv * dz * dphi
+}
+/// Bin of unit direction `v` among `ntheta` by `nphi` bins of equal solid
+/// angle, by the z component and the azimuth about the z-axis.
+fn sphere_bin(v: Vector, ntheta: usize, nphi: usize) -> (usize, usize) {
+    const PI: Real = std::f64::consts::PI as Real;
+    let i = ((v.2 + 1.0) * 0.5 * ntheta as Real) as usize;
+    let j = ((v.1.atan2(v.0) + PI) / (2.0 * PI) * nphi as Real) as usize;
+    (i.min(ntheta - 1), j.min(nphi - 1))
+}
+
+/// Integral of `f` over the sphere, e.g., to check that a PDF in solid angle
+/// integrates to 1.
+pub fn integrate_sphere<F: Fn(Vector) -> Real>(f: F, ntheta: usize, nphi: usize) -> Real {
+    (0..ntheta)
+        .flat_map(|i| (0..nphi).map(move |j| (i, j)))
+        .map(|(i, j)| integrate_bin(&f, i, j, ntheta, nphi))
+        .sum()
+}
+
+/// Chi-square test of `n` unit directions drawn by `sample` against `pdf` in
+/// solid angle, over `ntheta` by `nphi` bins of equal solid angle. `sample`
+/// maps three numbers in [0..1) to a direction, or `None` for degenerate
+/// samples, which count towards no bin. Numbers are drawn from a fixed seed
+/// so that results are reproducible.
+pub fn chi_square_sphere<S, P>(
+    mut sample: S,
+    pdf: P,
+    n: usize,
+    ntheta: usize,
+    nphi: usize,
+) -> ChiSquare
+    where S: FnMut(Real, Real, Real) -> Option<Vector>,
+          P: Fn(Vector) -> Real,
+{
+    let mut rng = StdRng::seed_from_u64(0x6c69_6768);
+    let mut observed = vec![0.0; ntheta * nphi];
+    for _ in 0..n {
+        let (a, b, c) = (rng.gen::<Real>(), rng.gen::<Real>(), rng.gen::<Real>());
+        if let Some(v) = sample(a, b, c) {
+            let (i, j) = sphere_bin(v, ntheta, nphi);
+            observed[i * nphi + j] += 1.0;
+        }
+    }
+    let mut bins = (0..ntheta * nphi)
+        .map(|k| {
+            let expected = n as Real * integrate_bin(&pdf, k / nphi, k % nphi, ntheta, nphi);
+            (observed[k], expected)
+        })
+        .collect::<Vec<_>>();
+    bins.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap_or(std::cmp::Ordering::Equal));
+    let mut statistic = 0.0;
+    let mut dof = 0;
+    let (mut pool_observed, mut pool_expected) = (0.0, 0.0);
+    for (observed, expected) in bins {
+        if expected < MIN_EXPECTED {
+            pool_observed += observed;
+            pool_expected += expected;
+            continue;
+        }
+        let d = observed - expected;
+        statistic += d * d / expected;
+        dof += 1;
+    }
+    if pool_expected > 0.0 {
+        let d = pool_observed - pool_expected;
+        statistic += d * d / pool_expected.max(MIN_EXPECTED);
+        dof += 1;
+    } else if pool_observed > 0.0 {
+        // Samples where the PDF is zero.
+        statistic = Real::INFINITY;
+    }
+    ChiSquare { statistic, dof: dof.max(2) - 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{Point, Color, hemisphere, sphere};
+    use crate::light::{EnvLight, Portal, DirectionalLight, AreaLight};
+    use crate::medium::{henyey_greenstein, sample_henyey_greenstein};
+
+    const PI: Real = std::f64::consts::PI as Real;
+    const N: usize = 200_000;
+
+    fn assert_follows<S, P>(sample: S, pdf: P)
+        where S: FnMut(Real, Real, Real) -> Option<Vector>,
+              P: Fn(Vector) -> Real + Copy,
+    {
+        let integral = integrate_sphere(pdf, 32, 64);
+        assert!((integral - 1.0).abs() < 0.01, "PDF integrates to {}", integral);
+        let x = chi_square_sphere(sample, pdf, N, 16, 32);
+        assert!(x.z_score() < 4.0, "{:?}, z = {}", x, x.z_score());
+    }
+
+    #[test]
+    fn sphere_is_uniform() {
+        assert_follows(|a, b, _| Some(sphere(a, b)), |_| 0.25 / PI);
+    }
+
+    #[test]
+    fn hemisphere_is_uniform() {
+        assert_follows(
+            |a, b, _| Some(hemisphere(a, b)),
+            |v| if v.2 > 0.0 { 0.5 / PI } else { 0.0 },
+        );
+    }
+
+    #[test]
+    fn henyey_greenstein_matches_phase() {
+        for &g in [-0.5, 0.0, 0.7].iter() {
+            let v = Vector(0.0, 0.0, 1.0);
+            assert_follows(
+                |a, b, _| Some(sample_henyey_greenstein(g, v, a, b)),
+                |w| henyey_greenstein(g, v.dot(w)),
+            );
+        }
+    }
+
+    #[test]
+    fn directional_light_cone_is_uniform() {
+        // The edge of the cone is on a bin boundary, otherwise the PDF
+        // jumps within a bin and integrating it takes many more subdivisions.
+        let cos_max: Real = 0.75;
+        let light = DirectionalLight::new(Vector(0.0, 0.0, -1.0), Color::default())
+            .with_angle(2.0 * cos_max.acos());
+        assert_follows(
+            |a, b, _| Some(light.illuminate(a, b).wi),
+            |v| if v.2 > cos_max { 0.5 / (PI * (1.0 - cos_max)) } else { 0.0 },
+        );
+    }
+
+    #[test]
+    fn env_light_portals_match_pdf() {
+        let light = EnvLight {
+            portals: vec![
+                Portal { o: Point(-1.0, -1.0, 1.0), x: Vector(2.0, 0.0, 0.0), y: Vector(0.0, 1.0, 0.0) },
+                Portal { o: Point(1.0, -1.0, -1.0), x: Vector(0.0, 0.0, 1.0), y: Vector(0.0, 2.0, 0.0) },
+            ],
+        };
+        let p = Point(0.0, 0.0, 0.0);
+        assert_follows(|a, b, c| Some(light.sample(p, a, b, c)?.0), |v| light.pdf(p, v));
+    }
+
+    #[test]
+    fn rect_light_matches_pdf() {
+        let light = AreaLight::rect(
+            Point(0.3, 0.2, 0.5),
+            Vector(0.6, 0.0, 0.0),
+            Vector(0.0, 0.4, 0.1),
+            Color::default(),
+        ).with_two_sided(true);
+        let p = Point(0.0, 0.0, 0.0);
+        assert_follows(|a, b, _| Some(light.illuminate(p, a, b)?.wi), |v| light.pdf(p, v));
+    }
+
+    #[test]
+    fn disk_light_matches_pdf() {
+        // Samples through the corners of the rectangle around the disk are
+        // rejected, so the PDF integrates to less than 1.
+        let light = AreaLight::disk(Point(0.2, -0.3, 0.6), Vector(0.3, -0.2, -1.0), 0.8, Color::default());
+        let p = Point(0.0, 0.0, 0.0);
+        let x = chi_square_sphere(
+            |a, b, _| Some(light.illuminate(p, a, b)?.wi),
+            |v| light.pdf(p, v),
+            N, 16, 32,
+        );
+        assert!(x.z_score() < 4.0, "{:?}, z = {}", x, x.z_score());
+    }
+
+    #[test]
+    fn detects_wrong_pdf() {
+        // Cosine distributed samples claimed to be uniform.
+        let x = chi_square_sphere(
+            |a, b, _| Some(hemisphere(a.sqrt(), b)),
+            |v| if v.2 > 0.0 { 0.5 / PI } else { 0.0 },
+            N, 16, 32,
+        );
+        assert!(x.z_score() > 4.0, "{:?}, z = {}", x, x.z_score());
+    }
+}
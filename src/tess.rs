@@ -0,0 +1,224 @@
+//! Tessellation of analytic surfaces, e.g., for the triangle-only fast path,
+//! with as many triangles as it takes to stay within a tolerance of the
+//! surface rather than at a fixed resolution. Tolerances are in world space,
+//! and `view_tolerance` gives one of a fraction of a pixel on screen, so that
+//! objects far from the camera get few triangles.
+use crate::geom::{Real, Point, Vector, Transform};
+use crate::scene::{Object, Visibility};
+use crate::camera::Camera;
+use crate::model::{make_sphere, make_torus};
+use crate::simplify::decimate_within;
+
+/// Most segments around a circle, so that tiny tolerances don't run out of
+/// memory.
+const MAX_SEGMENTS: usize = 4096;
+/// Most grid cells along each axis sampling signed distance fields.
+const MAX_SDF_RES: usize = 256;
+
+/// Number of segments around a circle of radius `radius` for the chords to
+/// stay within `tolerance` of it.
+pub fn segments(radius: Real, tolerance: Real) -> usize {
+    const PI: Real = std::f64::consts::PI as Real;
+    if tolerance <= 0.0 { return MAX_SEGMENTS }
+    if tolerance >= radius { return 3 }
+    // The sagitta of a chord spanning angle `2 * a` is `r * (1 - cos(a))`.
+    let n = (PI / (1.0 - tolerance / radius).acos()).ceil();
+    (n as usize).clamp(3, MAX_SEGMENTS)
+}
+
+/// Largest scale `world2obj` applies along any axis.
+fn max_scale(world2obj: &Transform) -> Real {
+    let (x, y, z) = world2obj.to_cols();
+    x.mag().max(y.mag()).max(z.mag())
+}
+
+/// World space tolerance spanning `px` pixels in a frame `h` pixels tall seen
+/// through `cam`, at the part of an object in the unit cube placed by
+/// `world2obj` closest to the camera.
+pub fn view_tolerance(cam: &Camera, h: u32, world2obj: Transform, px: Real) -> Real {
+    let eye = cam.cam2world * Point(0.0, 0.0, 0.0);
+    let center = world2obj * Point(0.0, 0.0, 0.0);
+    let radius = max_scale(&world2obj) * (0.75 as Real).sqrt();
+    // Objects around the camera are seen up close; keep a tiny distance so
+    // that the tolerance doesn't vanish.
+    let dist = (center.rel_from(eye).mag() - radius).max(radius * 1e-3);
+    px * 2.0 * dist * (cam.fov * 0.5).tan() / h as Real
+}
+
+/// A sphere like `model::make_sphere` within `tolerance` of the actual
+/// sphere.
+pub fn tess_sphere<M>(mat: M, world2obj: Transform, tolerance: Real) -> Object<M> {
+    // Chords along and across the rings add up in the middle of the quads.
+    let nseg = segments(0.5 * max_scale(&world2obj), 0.5 * tolerance);
+    make_sphere(mat, world2obj, nseg.div_ceil(2), nseg)
+}
+
+/// A torus like `model::make_torus` within `tolerance` of the actual torus.
+/// Segments around the y-axis are decided by the outer rim where they are
+/// the longest, and ones around the tube by its radius.
+pub fn tess_torus<M>(mat: M, world2obj: Transform, minor: Real, tolerance: Real) -> Object<M> {
+    let scale = max_scale(&world2obj);
+    let minor = minor.clamp(0.0, 0.25);
+    let nmajor = segments(0.5 * scale, 0.5 * tolerance);
+    let nminor = segments(minor * scale, 0.5 * tolerance);
+    make_torus(mat, world2obj, minor, nmajor, nminor)
+}
+
+/// The surface where signed distance field `sdf` is zero, within `tolerance`
+/// of it. `sdf` is negative inside and is evaluated in object space, where
+/// the surface is expected to fit the unit cube of `model::make_cube`; it's
+/// cut off at the faces of the cube otherwise.
+///
+/// The field is sampled on a grid fine enough for features of curvature
+/// radius down to 1/32 of the cube to be within tolerance, and polygonized by
+/// surface nets with vertices projected onto the surface. The mesh is then
+/// decimated within tolerance, so that flat regions end up with a few large
+/// triangles while highly curved ones keep many small ones. Sharp edges and
+/// corners are rounded off by up to about a grid cell.
+pub fn tess_sdf<M, F>(mat: M, world2obj: Transform, sdf: F, tolerance: Real) -> Object<M>
+    where F: Fn(Point) -> Real
+{
+    let obj2world = world2obj.inverse();
+    let tolerance = tolerance / max_scale(&world2obj);
+    // The sagitta of a chord of length `h` on a circle of radius `r` is about
+    // `h^2 / (8 * r)`, within tolerance if `h = sqrt(tolerance) / 2` and
+    // `r >= 1 / 32`.
+    let cell = 0.5 * tolerance.max(0.0).sqrt();
+    let res = ((1.0 / cell).ceil() as usize).clamp(4, MAX_SDF_RES);
+    let (verts, idxs) = surface_nets(&sdf, res);
+    // Half of the tolerance is left to the chords between the vertices.
+    let (verts, idxs) = decimate_within(&verts, &idxs, 0.5 * tolerance);
+    let visibility = Visibility::default();
+    Object {
+        verts, idxs, mat, obj2world, world2obj, visibility,
+        cull_backfaces: false,
+        normals: None,
+        colors: None,
+        name: None,
+        shape: None,
+    }
+}
+
+/// Polygonize `sdf` in the unit cube on a grid of `res` cells along each
+/// axis.
+fn surface_nets<F>(sdf: &F, res: usize) -> (Vec<Point>, Vec<(usize, usize, usize)>)
+    where F: Fn(Point) -> Real
+{
+    let cell = 1.0 / res as Real;
+    // Samples are one cell beyond the cube on each side, where the field is
+    // taken to be outside, so that the surface is closed.
+    let n = res + 3;
+    let coord = |i: usize| (i as Real - 1.0) * cell - 0.5;
+    let at = |i: usize, j: usize, k: usize| (i * n + j) * n + k;
+    let mut samples = vec![0.0; n * n * n];
+    for i in 0..n {
+        for j in 0..n {
+            for k in 0..n {
+                let border = [i, j, k].iter().any(|&x| x == 0 || x == n - 1);
+                samples[at(i, j, k)] = if border {
+                    cell
+                } else {
+                    sdf(Point(coord(i), coord(j), coord(k)))
+                };
+            }
+        }
+    }
+
+    // A vertex in each cell the surface passes through, at the mean of the
+    // crossings on its edges, then projected onto the surface.
+    const CORNERS: [(usize, usize, usize); 8] = [
+        (0, 0, 0), (1, 0, 0), (0, 1, 0), (1, 1, 0),
+        (0, 0, 1), (1, 0, 1), (0, 1, 1), (1, 1, 1),
+    ];
+    const EDGES: [(usize, usize); 12] = [
+        (0, 1), (2, 3), (4, 5), (6, 7),
+        (0, 2), (1, 3), (4, 6), (5, 7),
+        (0, 4), (1, 5), (2, 6), (3, 7),
+    ];
+    let m = n - 1;
+    let cell_at = |i: usize, j: usize, k: usize| (i * m + j) * m + k;
+    let mut cell_verts = vec![usize::MAX; m * m * m];
+    let mut verts = Vec::new();
+    for i in 0..m {
+        for j in 0..m {
+            for k in 0..m {
+                let f = CORNERS.map(|(x, y, z)| samples[at(i + x, j + y, k + z)]);
+                let (mut sum, mut count) = (Vector::default(), 0);
+                for &(a, b) in EDGES.iter() {
+                    if (f[a] < 0.0) == (f[b] < 0.0) { continue }
+                    let t = f[a] / (f[a] - f[b]);
+                    let (pa, pb) = (CORNERS[a], CORNERS[b]);
+                    let lerp = |x: usize, y: usize| x as Real + (y as Real - x as Real) * t;
+                    sum = sum + Vector(lerp(pa.0, pb.0), lerp(pa.1, pb.1), lerp(pa.2, pb.2));
+                    count += 1;
+                }
+                if count == 0 { continue }
+                let local = sum / count as Real;
+                let p = Point(
+                    coord(i) + local.0 * cell,
+                    coord(j) + local.1 * cell,
+                    coord(k) + local.2 * cell,
+                );
+                cell_verts[cell_at(i, j, k)] = verts.len();
+                verts.push(project(sdf, p, cell));
+            }
+        }
+    }
+
+    // A quad around each grid edge the surface crosses, joining the vertices
+    // of the 4 cells sharing the edge.
+    let mut idxs = Vec::new();
+    for i in 1..m {
+        for j in 1..m {
+            for k in 1..m {
+                let f0 = samples[at(i, j, k)];
+                let ends = [
+                    samples[at(i + 1, j, k)],
+                    samples[at(i, j + 1, k)],
+                    samples[at(i, j, k + 1)],
+                ];
+                for (axis, &f1) in ends.iter().enumerate() {
+                    if (f0 < 0.0) == (f1 < 0.0) { continue }
+                    // Cells around the edge, counter-clockwise seen from the
+                    // positive end of the axis.
+                    let quad = match axis {
+                        0 => [(i, j - 1, k - 1), (i, j, k - 1), (i, j, k), (i, j - 1, k)],
+                        1 => [(i - 1, j, k - 1), (i - 1, j, k), (i, j, k), (i, j, k - 1)],
+                        _ => [(i - 1, j - 1, k), (i, j - 1, k), (i, j, k), (i - 1, j, k)],
+                    };
+                    let [a, b, c, d] = quad.map(|(x, y, z)| cell_verts[cell_at(x, y, z)]);
+                    // Triangles here are clockwise seen from the outside.
+                    if f0 < 0.0 {
+                        idxs.push((a, b, c));
+                        idxs.push((a, c, d));
+                    } else {
+                        idxs.push((a, c, b));
+                        idxs.push((a, d, c));
+                    }
+                }
+            }
+        }
+    }
+    (verts, idxs)
+}
+
+/// Move `p` onto the zero set of `sdf` by a few Newton steps, by no further
+/// than `cell`.
+fn project<F>(sdf: &F, p: Point, cell: Real) -> Point
+    where F: Fn(Point) -> Real
+{
+    let eps = cell * 1e-2;
+    let mut q = p;
+    for _ in 0..4 {
+        let f = sdf(q);
+        let grad = Vector(
+            sdf(Point(q.0 + eps, q.1, q.2)) - sdf(Point(q.0 - eps, q.1, q.2)),
+            sdf(Point(q.0, q.1 + eps, q.2)) - sdf(Point(q.0, q.1 - eps, q.2)),
+            sdf(Point(q.0, q.1, q.2 + eps)) - sdf(Point(q.0, q.1, q.2 - eps)),
+        ) / (2.0 * eps);
+        let g2 = grad.dot(grad);
+        if g2 == 0.0 || !f.is_finite() { break }
+        q = q.affine_sub(grad * (f / g2));
+    }
+    if q.rel_from(p).mag() > cell { p } else { q }
+}
@@ -0,0 +1,985 @@
+//! GPU texture containers, DDS and KTX2, as shipped with game assets. Pixels
+//! are decoded into `Image`s of the closest storage format, block compressed
+//! ones included (BC1 to BC5 and BC7), and the mip levels stored in the file
+//! are kept. Only the first image of arrays and cube maps is loaded, and
+//! supercompressed KTX2 files are not supported.
+use std::convert::TryFrom;
+use std::path::Path;
+use crate::geom::Color;
+use crate::img::{Image, Format, Alpha, ColorSpace, LoadError, f16_to_f32};
+
+/// Layout of the pixels of a texture in a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PixelFormat {
+    /// 8-bit unsigned normalized channels, `nchannel` of them in RGBA order
+    /// or in BGRA order if `bgr`.
+    Unorm8 { nchannel: usize, bgr: bool },
+    /// Uncompressed pixels of `bits` bits, with channels picked out by masks
+    /// in RGBA order, as in legacy DDS files.
+    Masked { bits: u32, masks: [u32; 4] },
+    Rgba16f,
+    Rgba32f,
+    /// BC1 with 1-bit alpha.
+    Bc1,
+    Bc2,
+    Bc3,
+    Bc4 { signed: bool },
+    Bc5 { signed: bool },
+    Bc7,
+}
+impl PixelFormat {
+    /// Bytes per block of 4 by 4 pixels for block compressed formats.
+    fn block_size(self) -> Option<usize> {
+        match self {
+            PixelFormat::Bc1 | PixelFormat::Bc4 { .. } => Some(8),
+            PixelFormat::Bc2 | PixelFormat::Bc3 | PixelFormat::Bc5 { .. } | PixelFormat::Bc7 => Some(16),
+            _ => None,
+        }
+    }
+    /// Bytes per pixel for uncompressed formats.
+    fn pixel_size(self) -> usize {
+        match self {
+            PixelFormat::Unorm8 { nchannel, .. } => nchannel,
+            PixelFormat::Masked { bits, .. } => bits as usize / 8,
+            PixelFormat::Rgba16f => 8,
+            PixelFormat::Rgba32f => 16,
+            _ => 0,
+        }
+    }
+    /// Bytes taken by a `w` by `h` level, or `None` if it overflows.
+    fn level_size(self, w: usize, h: usize) -> Option<usize> {
+        match self.block_size() {
+            Some(n) => w.div_ceil(4).checked_mul(h.div_ceil(4))?.checked_mul(n),
+            None => w.checked_mul(h)?.checked_mul(self.pixel_size()),
+        }
+    }
+    /// Storage format holding decoded pixels without loss.
+    fn storage(self) -> Format {
+        match self {
+            PixelFormat::Rgba16f => Format::Rgba16f,
+            PixelFormat::Rgba32f | PixelFormat::Bc4 { signed: true } | PixelFormat::Bc5 { signed: true } => {
+                Format::Rgba32f
+            },
+            _ => Format::Rgba8,
+        }
+    }
+}
+
+/// How to decode the levels of a texture.
+struct Layout {
+    fmt: PixelFormat,
+    space: ColorSpace,
+    alpha: Alpha,
+}
+
+fn malformed(msg: &str) -> LoadError {
+    LoadError::Container(msg.to_owned())
+}
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, LoadError> {
+    data.get(offset..offset + 4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .ok_or_else(|| malformed("truncated header"))
+}
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, LoadError> {
+    let lo = read_u32(data, offset)? as u64;
+    let hi = read_u32(data, offset + 4)? as u64;
+    Ok(lo | (hi << 32))
+}
+/// Number of levels of a full mip chain of a `w` by `h` texture, which
+/// bounds the levels of a file.
+fn max_nlevel(w: usize, h: usize) -> usize {
+    (usize::BITS - w.max(h).leading_zeros()) as usize
+}
+/// The `lw` by `lh` level of `fmt` at byte `offset` of `data`.
+fn level_bytes(
+    data: &[u8],
+    offset: usize,
+    fmt: PixelFormat,
+    lw: usize,
+    lh: usize,
+) -> Result<&[u8], LoadError> {
+    let size = fmt.level_size(lw, lh)
+        .ok_or_else(|| malformed("level size overflows"))?;
+    let end = offset.checked_add(size)
+        .ok_or_else(|| malformed("level size overflows"))?;
+    data.get(offset..end).ok_or_else(|| malformed("truncated pixel data"))
+}
+
+/// Load the mip levels of the DDS or KTX2 texture at `path`, the full
+/// resolution level first.
+pub fn load_texture_levels<P: AsRef<Path>>(path: P) -> Result<Vec<Image>, LoadError> {
+    let data = std::fs::read(path).map_err(image::ImageError::from)?;
+    if data.starts_with(DDS_MAGIC) {
+        decode_dds(&data)
+    } else if data.starts_with(KTX2_MAGIC) {
+        decode_ktx2(&data)
+    } else {
+        Err(LoadError::Unsupported("texture container other than DDS and KTX2".to_owned()))
+    }
+}
+
+const DDS_MAGIC: &[u8] = b"DDS ";
+/// Size of the magic and the legacy header.
+const DDS_HEADER_SIZE: usize = 128;
+const DDS_DX10_HEADER_SIZE: usize = 20;
+const DDSD_MIPMAPCOUNT: u32 = 0x2_0000;
+const DDSCAPS2_VOLUME: u32 = 0x20_0000;
+const DDPF_ALPHAPIXELS: u32 = 0x1;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDPF_LUMINANCE: u32 = 0x2_0000;
+/// Alpha mode of the DX10 header of premultiplied alpha.
+const DDS_ALPHA_MODE_PREMULTIPLIED: u32 = 2;
+
+/// Decode the mip levels of DDS file `data`, see `load_texture_levels`.
+/// Legacy DDS files of 8-bit colors are taken as sRGB like other 8-bit
+/// images.
+pub fn decode_dds(data: &[u8]) -> Result<Vec<Image>, LoadError> {
+    if !data.starts_with(DDS_MAGIC) || read_u32(data, 4)? != 124 {
+        return Err(malformed("not a DDS file"));
+    }
+    let flags = read_u32(data, 8)?;
+    let h = read_u32(data, 12)? as usize;
+    let w = read_u32(data, 16)? as usize;
+    let nlevel = if flags & DDSD_MIPMAPCOUNT != 0 { read_u32(data, 28)?.max(1) as usize } else { 1 };
+    if w == 0 || h == 0 {
+        return Err(malformed("empty texture"));
+    }
+    if nlevel > max_nlevel(w, h) {
+        return Err(malformed("more levels than a full mip chain"));
+    }
+    if read_u32(data, 112)? & DDSCAPS2_VOLUME != 0 {
+        return Err(LoadError::Unsupported("DDS volume textures".to_owned()));
+    }
+    let pf_flags = read_u32(data, 80)?;
+    let fourcc = data.get(84..88).ok_or_else(|| malformed("truncated header"))?;
+    let (fmt, space, alpha, offset) = if pf_flags & DDPF_FOURCC != 0 && fourcc == b"DX10" {
+        let dxgi = read_u32(data, DDS_HEADER_SIZE)?;
+        let alpha = match read_u32(data, DDS_HEADER_SIZE + 16)? & 0x7 {
+            DDS_ALPHA_MODE_PREMULTIPLIED => Alpha::Premultiplied,
+            _ => Alpha::Straight,
+        };
+        let (fmt, space) = dxgi_format(dxgi)?;
+        (fmt, space, alpha, DDS_HEADER_SIZE + DDS_DX10_HEADER_SIZE)
+    } else if pf_flags & DDPF_FOURCC != 0 {
+        let (fmt, alpha) = match fourcc {
+            b"DXT1" => (PixelFormat::Bc1, Alpha::Straight),
+            b"DXT2" => (PixelFormat::Bc2, Alpha::Premultiplied),
+            b"DXT3" => (PixelFormat::Bc2, Alpha::Straight),
+            b"DXT4" => (PixelFormat::Bc3, Alpha::Premultiplied),
+            b"DXT5" => (PixelFormat::Bc3, Alpha::Straight),
+            b"ATI1" | b"BC4U" => (PixelFormat::Bc4 { signed: false }, Alpha::Straight),
+            b"BC4S" => (PixelFormat::Bc4 { signed: true }, Alpha::Straight),
+            b"ATI2" | b"BC5U" => (PixelFormat::Bc5 { signed: false }, Alpha::Straight),
+            b"BC5S" => (PixelFormat::Bc5 { signed: true }, Alpha::Straight),
+            // `D3DFORMAT` codes of float formats.
+            [113, 0, 0, 0] => (PixelFormat::Rgba16f, Alpha::Premultiplied),
+            [116, 0, 0, 0] => (PixelFormat::Rgba32f, Alpha::Premultiplied),
+            _ => {
+                let name = String::from_utf8_lossy(fourcc);
+                return Err(LoadError::Unsupported(format!("DDS format `{}`", name)));
+            },
+        };
+        let space = match fmt {
+            PixelFormat::Bc1 | PixelFormat::Bc2 | PixelFormat::Bc3 => ColorSpace::Srgb,
+            _ => ColorSpace::Linear,
+        };
+        (fmt, space, alpha, DDS_HEADER_SIZE)
+    } else if pf_flags & (DDPF_RGB | DDPF_LUMINANCE) != 0 {
+        let bits = read_u32(data, 88)?;
+        if !matches!(bits, 8 | 16 | 24 | 32) {
+            return Err(LoadError::Unsupported(format!("DDS pixels of {} bits", bits)));
+        }
+        let mut masks = [0; 4];
+        for (i, mask) in masks.iter_mut().enumerate() {
+            *mask = read_u32(data, 92 + 4 * i)?;
+        }
+        if pf_flags & DDPF_ALPHAPIXELS == 0 { masks[3] = 0 }
+        if pf_flags & DDPF_LUMINANCE != 0 {
+            // Luminance is replicated to all color channels.
+            masks[1] = masks[0];
+            masks[2] = masks[0];
+        }
+        (PixelFormat::Masked { bits, masks }, ColorSpace::Srgb, Alpha::Straight, DDS_HEADER_SIZE)
+    } else {
+        return Err(LoadError::Unsupported("DDS pixel format".to_owned()));
+    };
+    let layout = Layout { fmt, space, alpha };
+    // Levels of the first image are stored first, largest to smallest.
+    let mut offset = offset;
+    let mut levels = Vec::with_capacity(nlevel);
+    for level in 0..nlevel {
+        let (lw, lh) = ((w >> level).max(1), (h >> level).max(1));
+        let bytes = level_bytes(data, offset, fmt, lw, lh)?;
+        levels.push(decode_level(&layout, bytes, lw, lh));
+        offset += bytes.len();
+    }
+    Ok(levels)
+}
+/// Pixel format and color space of `DXGI_FORMAT` `dxgi`.
+fn dxgi_format(dxgi: u32) -> Result<(PixelFormat, ColorSpace), LoadError> {
+    use ColorSpace::{Linear, Srgb};
+    let rgba8 = PixelFormat::Unorm8 { nchannel: 4, bgr: false };
+    let bgra8 = PixelFormat::Unorm8 { nchannel: 4, bgr: true };
+    let rv = match dxgi {
+        2 => (PixelFormat::Rgba32f, Linear),
+        10 => (PixelFormat::Rgba16f, Linear),
+        28 => (rgba8, Linear),
+        29 => (rgba8, Srgb),
+        71 => (PixelFormat::Bc1, Linear),
+        72 => (PixelFormat::Bc1, Srgb),
+        74 => (PixelFormat::Bc2, Linear),
+        75 => (PixelFormat::Bc2, Srgb),
+        77 => (PixelFormat::Bc3, Linear),
+        78 => (PixelFormat::Bc3, Srgb),
+        80 => (PixelFormat::Bc4 { signed: false }, Linear),
+        81 => (PixelFormat::Bc4 { signed: true }, Linear),
+        83 => (PixelFormat::Bc5 { signed: false }, Linear),
+        84 => (PixelFormat::Bc5 { signed: true }, Linear),
+        87 => (bgra8, Linear),
+        91 => (bgra8, Srgb),
+        98 => (PixelFormat::Bc7, Linear),
+        99 => (PixelFormat::Bc7, Srgb),
+        _ => return Err(LoadError::Unsupported(format!("DXGI format {}", dxgi))),
+    };
+    Ok(rv)
+}
+
+const KTX2_MAGIC: &[u8] = b"\xabKTX 20\xbb\r\n\x1a\n";
+/// Offset of the level index, of an entry of offset, size and uncompressed
+/// size per level.
+const KTX2_LEVEL_INDEX: usize = 80;
+const KTX2_LEVEL_ENTRY_SIZE: usize = 24;
+/// Flag of the data format descriptor of premultiplied alpha.
+const KHR_DF_FLAG_ALPHA_PREMULTIPLIED: u8 = 1;
+
+/// Decode the mip levels of KTX2 file `data`, see `load_texture_levels`.
+pub fn decode_ktx2(data: &[u8]) -> Result<Vec<Image>, LoadError> {
+    if !data.starts_with(KTX2_MAGIC) {
+        return Err(malformed("not a KTX2 file"));
+    }
+    let vk_format = read_u32(data, 12)?;
+    let w = read_u32(data, 20)? as usize;
+    let h = (read_u32(data, 24)? as usize).max(1);
+    if read_u32(data, 28)? > 1 {
+        return Err(LoadError::Unsupported("KTX2 volume textures".to_owned()));
+    }
+    let nlevel = read_u32(data, 40)?.max(1) as usize;
+    if w == 0 {
+        return Err(malformed("empty texture"));
+    }
+    if nlevel > max_nlevel(w, h) {
+        return Err(malformed("more levels than a full mip chain"));
+    }
+    if KTX2_LEVEL_INDEX + KTX2_LEVEL_ENTRY_SIZE * nlevel > data.len() {
+        return Err(malformed("truncated level index"));
+    }
+    if read_u32(data, 44)? != 0 {
+        return Err(LoadError::Unsupported("supercompressed KTX2 textures".to_owned()));
+    }
+    if vk_format == 0 {
+        return Err(LoadError::Unsupported("KTX2 textures without a Vulkan format".to_owned()));
+    }
+    let (fmt, space) = vk_format_of(vk_format)?;
+    // The flags of the basic descriptor block follow the total size of the
+    // descriptor, the block header and the color model, primaries and
+    // transfer function.
+    let dfd = read_u32(data, 48)? as usize;
+    let alpha = match data.get(dfd + 4 + 8 + 3) {
+        Some(&x) if x & KHR_DF_FLAG_ALPHA_PREMULTIPLIED != 0 => Alpha::Premultiplied,
+        _ => Alpha::Straight,
+    };
+    let alpha = match fmt {
+        PixelFormat::Rgba16f | PixelFormat::Rgba32f => Alpha::Premultiplied,
+        _ => alpha,
+    };
+    let layout = Layout { fmt, space, alpha };
+    let mut levels = Vec::with_capacity(nlevel);
+    for level in 0..nlevel {
+        let entry = KTX2_LEVEL_INDEX + KTX2_LEVEL_ENTRY_SIZE * level;
+        let offset = usize::try_from(read_u64(data, entry)?)
+            .map_err(|_| malformed("level offset overflows"))?;
+        let (lw, lh) = ((w >> level).max(1), (h >> level).max(1));
+        // Layers and faces follow the first image in each level.
+        let bytes = level_bytes(data, offset, fmt, lw, lh)?;
+        levels.push(decode_level(&layout, bytes, lw, lh));
+    }
+    Ok(levels)
+}
+/// Pixel format and color space of `VkFormat` `vk_format`.
+fn vk_format_of(vk_format: u32) -> Result<(PixelFormat, ColorSpace), LoadError> {
+    use ColorSpace::{Linear, Srgb};
+    let unorm8 = |nchannel: usize, bgr: bool| PixelFormat::Unorm8 { nchannel, bgr };
+    let rv = match vk_format {
+        9 => (unorm8(1, false), Linear),
+        15 => (unorm8(1, false), Srgb),
+        16 => (unorm8(2, false), Linear),
+        22 => (unorm8(2, false), Srgb),
+        23 => (unorm8(3, false), Linear),
+        29 => (unorm8(3, false), Srgb),
+        30 => (unorm8(3, true), Linear),
+        36 => (unorm8(3, true), Srgb),
+        37 => (unorm8(4, false), Linear),
+        43 => (unorm8(4, false), Srgb),
+        44 => (unorm8(4, true), Linear),
+        50 => (unorm8(4, true), Srgb),
+        97 => (PixelFormat::Rgba16f, Linear),
+        109 => (PixelFormat::Rgba32f, Linear),
+        131 | 133 => (PixelFormat::Bc1, Linear),
+        132 | 134 => (PixelFormat::Bc1, Srgb),
+        135 => (PixelFormat::Bc2, Linear),
+        136 => (PixelFormat::Bc2, Srgb),
+        137 => (PixelFormat::Bc3, Linear),
+        138 => (PixelFormat::Bc3, Srgb),
+        139 => (PixelFormat::Bc4 { signed: false }, Linear),
+        140 => (PixelFormat::Bc4 { signed: true }, Linear),
+        141 => (PixelFormat::Bc5 { signed: false }, Linear),
+        142 => (PixelFormat::Bc5 { signed: true }, Linear),
+        145 => (PixelFormat::Bc7, Linear),
+        146 => (PixelFormat::Bc7, Srgb),
+        _ => return Err(LoadError::Unsupported(format!("Vulkan format {}", vk_format))),
+    };
+    Ok(rv)
+}
+
+/// Decode the pixels `bytes` of a `w` by `h` level.
+fn decode_level(layout: &Layout, bytes: &[u8], w: usize, h: usize) -> Image {
+    // Pixels are stored as they are encoded and tagged afterwards.
+    let mut img = Image::with_format(w, h, layout.fmt.storage());
+    if let Some(block_size) = layout.fmt.block_size() {
+        let nblock_x = w.div_ceil(4);
+        for (i, block) in bytes.chunks_exact(block_size).enumerate() {
+            let (bx, by) = (i % nblock_x * 4, i / nblock_x * 4);
+            let texels = decode_block(layout.fmt, block);
+            for (j, c) in texels.iter().enumerate() {
+                let (x, y) = (bx + j % 4, by + j / 4);
+                if x < w && y < h { img.store_px(x, y, *c) }
+            }
+        }
+    } else {
+        let n = layout.fmt.pixel_size();
+        for (i, px) in bytes.chunks_exact(n).enumerate() {
+            img.store_px(i % w, i / w, decode_pixel(layout.fmt, px));
+        }
+    }
+    img.with_alpha(layout.alpha).with_color_space(layout.space)
+}
+/// Decode uncompressed pixel `px`.
+fn decode_pixel(fmt: PixelFormat, px: &[u8]) -> Color {
+    let unorm = |x: u8| x as f32 / 255.0;
+    match fmt {
+        PixelFormat::Unorm8 { bgr, .. } => {
+            let mut c = [0.0, 0.0, 0.0, 1.0];
+            for (i, x) in px.iter().enumerate() {
+                c[i] = unorm(*x);
+            }
+            if bgr { c.swap(0, 2) }
+            Color(c[0], c[1], c[2], c[3])
+        },
+        PixelFormat::Masked { masks, .. } => {
+            let mut bits = [0; 4];
+            bits[..px.len()].copy_from_slice(px);
+            let x = u32::from_le_bytes(bits);
+            let channel = |mask: u32, default: f32| {
+                if mask == 0 { return default }
+                ((x & mask) >> mask.trailing_zeros()) as f32 / (mask >> mask.trailing_zeros()) as f32
+            };
+            Color(channel(masks[0], 0.0), channel(masks[1], 0.0), channel(masks[2], 0.0), channel(masks[3], 1.0))
+        },
+        PixelFormat::Rgba16f => {
+            let c = |i: usize| f16_to_f32(u16::from_le_bytes([px[2 * i], px[2 * i + 1]]));
+            Color(c(0), c(1), c(2), c(3))
+        },
+        PixelFormat::Rgba32f => {
+            let c = |i: usize| f32::from_le_bytes([px[4 * i], px[4 * i + 1], px[4 * i + 2], px[4 * i + 3]]);
+            Color(c(0), c(1), c(2), c(3))
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Decode block compressed `block` into its 4 by 4 pixels in row-major
+/// order.
+fn decode_block(fmt: PixelFormat, block: &[u8]) -> [Color; 16] {
+    let mut rv = [Color::default(); 16];
+    match fmt {
+        PixelFormat::Bc1 => {
+            let rgba = decode_bc1(block, true);
+            for (c, x) in rv.iter_mut().zip(rgba.iter()) { *c = (*x).into() }
+        },
+        PixelFormat::Bc2 => {
+            let mut rgba = decode_bc1(&block[8..], false);
+            for (i, x) in rgba.iter_mut().enumerate() {
+                x[3] = ((block[i / 2] >> (4 * (i % 2))) & 0xf) * 17;
+            }
+            for (c, x) in rv.iter_mut().zip(rgba.iter()) { *c = (*x).into() }
+        },
+        PixelFormat::Bc3 => {
+            let mut rgba = decode_bc1(&block[8..], false);
+            let alpha = decode_bc4(&block[..8], false);
+            for (x, a) in rgba.iter_mut().zip(alpha.iter()) {
+                x[3] = (a * 255.0 + 0.5) as u8;
+            }
+            for (c, x) in rv.iter_mut().zip(rgba.iter()) { *c = (*x).into() }
+        },
+        PixelFormat::Bc4 { signed } => {
+            let r = decode_bc4(block, signed);
+            for (c, r) in rv.iter_mut().zip(r.iter()) { *c = Color(*r, *r, *r, 1.0) }
+        },
+        PixelFormat::Bc5 { signed } => {
+            let r = decode_bc4(&block[..8], signed);
+            let g = decode_bc4(&block[8..], signed);
+            for (i, c) in rv.iter_mut().enumerate() { *c = Color(r[i], g[i], 0.0, 1.0) }
+        },
+        PixelFormat::Bc7 => {
+            let rgba = decode_bc7(block);
+            for (c, x) in rv.iter_mut().zip(rgba.iter()) { *c = (*x).into() }
+        },
+        _ => unreachable!(),
+    }
+    rv
+}
+
+/// Decode the color block of BC1 to BC3. With `punch_through`, blocks
+/// ordering their endpoints ascending have three colors and transparent
+/// black, as in BC1.
+fn decode_bc1(block: &[u8], punch_through: bool) -> [[u8; 4]; 16] {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let rgb565 = |c: u16| {
+        let (r, g, b) = ((c >> 11) as u32 & 0x1f, (c >> 5) as u32 & 0x3f, c as u32 & 0x1f);
+        [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+    };
+    let (e0, e1) = (rgb565(c0), rgb565(c1));
+    let mix = |a: u32, b: u32, d: u32| {
+        [(a * e0[0] + b * e1[0]) / d, (a * e0[1] + b * e1[1]) / d, (a * e0[2] + b * e1[2]) / d]
+    };
+    let opaque = |c: [u32; 3]| [c[0] as u8, c[1] as u8, c[2] as u8, 255];
+    let palette = if c0 > c1 || !punch_through {
+        [opaque(e0), opaque(e1), opaque(mix(2, 1, 3)), opaque(mix(1, 2, 3))]
+    } else {
+        [opaque(e0), opaque(e1), opaque(mix(1, 1, 2)), [0, 0, 0, 0]]
+    };
+    let idxs = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    let mut rv = [[0; 4]; 16];
+    for (i, x) in rv.iter_mut().enumerate() {
+        *x = palette[(idxs >> (2 * i)) as usize & 3];
+    }
+    rv
+}
+/// Decode a BC4 block, which is also the alpha block of BC3, into values in
+/// [0, 1], or in [-1, 1] if `signed`.
+fn decode_bc4(block: &[u8], signed: bool) -> [f32; 16] {
+    let (e0, e1) = if signed {
+        // -128 and -127 both stand for -1.
+        let snorm = |x: u8| (x as i8).max(-127) as f32 / 127.0;
+        (snorm(block[0]), snorm(block[1]))
+    } else {
+        (block[0] as f32 / 255.0, block[1] as f32 / 255.0)
+    };
+    let (lo, hi) = if signed { (-1.0, 1.0) } else { (0.0, 1.0) };
+    let mut palette = [e0, e1, 0.0, 0.0, 0.0, 0.0, lo, hi];
+    // Endpoints are compared as stored, which orders signed ones the same.
+    let descending = if signed { (block[0] as i8) > (block[1] as i8) } else { block[0] > block[1] };
+    if descending {
+        for (i, x) in palette[2..].iter_mut().enumerate() {
+            let t = (i + 1) as f32 / 7.0;
+            *x = e0 * (1.0 - t) + e1 * t;
+        }
+    } else {
+        for (i, x) in palette[2..6].iter_mut().enumerate() {
+            let t = (i + 1) as f32 / 5.0;
+            *x = e0 * (1.0 - t) + e1 * t;
+        }
+    }
+    let mut bits = [0; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let idxs = u64::from_le_bytes(bits);
+    let mut rv = [0.0; 16];
+    for (i, x) in rv.iter_mut().enumerate() {
+        *x = palette[(idxs >> (3 * i)) as usize & 7];
+    }
+    rv
+}
+
+/// Parameters of the BC7 modes.
+struct Bc7Mode {
+    nsubset: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    /// Whether each endpoint has a p-bit, the lowest bit of all its channels.
+    endpoint_pbits: bool,
+    /// Whether each subset has a p-bit shared by both endpoints.
+    shared_pbits: bool,
+    index_bits: u32,
+    /// Bits of the second set of indices of the modes indexing alpha
+    /// separately, 0 for the others.
+    index2_bits: u32,
+}
+const BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode {
+        nsubset: 3, partition_bits: 4, rotation_bits: 0, index_selection_bits: 0,
+        color_bits: 4, alpha_bits: 0, endpoint_pbits: true, shared_pbits: false, index_bits: 3,
+        index2_bits: 0,
+    },
+    Bc7Mode {
+        nsubset: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0,
+        color_bits: 6, alpha_bits: 0, endpoint_pbits: false, shared_pbits: true, index_bits: 3,
+        index2_bits: 0,
+    },
+    Bc7Mode {
+        nsubset: 3, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0,
+        color_bits: 5, alpha_bits: 0, endpoint_pbits: false, shared_pbits: false, index_bits: 2,
+        index2_bits: 0,
+    },
+    Bc7Mode {
+        nsubset: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0,
+        color_bits: 7, alpha_bits: 0, endpoint_pbits: true, shared_pbits: false, index_bits: 2,
+        index2_bits: 0,
+    },
+    Bc7Mode {
+        nsubset: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 1,
+        color_bits: 5, alpha_bits: 6, endpoint_pbits: false, shared_pbits: false, index_bits: 2,
+        index2_bits: 3,
+    },
+    Bc7Mode {
+        nsubset: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 0,
+        color_bits: 7, alpha_bits: 8, endpoint_pbits: false, shared_pbits: false, index_bits: 2,
+        index2_bits: 2,
+    },
+    Bc7Mode {
+        nsubset: 1, partition_bits: 0, rotation_bits: 0, index_selection_bits: 0,
+        color_bits: 7, alpha_bits: 7, endpoint_pbits: true, shared_pbits: false, index_bits: 4,
+        index2_bits: 0,
+    },
+    Bc7Mode {
+        nsubset: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0,
+        color_bits: 5, alpha_bits: 5, endpoint_pbits: true, shared_pbits: false, index_bits: 2,
+        index2_bits: 0,
+    },
+];
+/// Subsets of the pixels of the 2-subset partitions, a bit per pixel.
+const BC7_PARTITIONS2: [u16; 64] = [
+    0xcccc, 0x8888, 0xeeee, 0xecc8, 0xc880, 0xfeec, 0xfec8, 0xec80,
+    0xc800, 0xffec, 0xfe80, 0xe800, 0xffe8, 0xff00, 0xfff0, 0xf000,
+    0xf710, 0x008e, 0x7100, 0x08ce, 0x008c, 0x7310, 0x3100, 0x8cce,
+    0x088c, 0x3110, 0x6666, 0x366c, 0x17e8, 0x0ff0, 0x718e, 0x399c,
+    0xaaaa, 0xf0f0, 0x5a5a, 0x33cc, 0x3c3c, 0x55aa, 0x9696, 0xa55a,
+    0x73ce, 0x13c8, 0x324c, 0x3bdc, 0x6996, 0xc33c, 0x9966, 0x0660,
+    0x0272, 0x04e4, 0x4e40, 0x2720, 0xc936, 0x936c, 0x39c6, 0x639c,
+    0x9336, 0x9cc6, 0x817e, 0xe718, 0xccf0, 0x0fcc, 0x7744, 0xee22,
+];
+/// Subsets of the pixels of the 3-subset partitions.
+const BC7_PARTITIONS3: [[u8; 16]; 64] = [
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 1, 2, 2, 2, 2],
+    [0, 0, 0, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 2, 0, 0, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2],
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2],
+    [0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+    [0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0, 2, 2, 2, 0],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2],
+    [0, 1, 1, 1, 0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0],
+    [0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2, 0, 2, 2, 2],
+    [0, 0, 0, 1, 0, 0, 0, 1, 2, 2, 2, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 0, 0, 1, 1, 0, 0, 2, 2, 1, 0, 2, 2, 1, 0],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1, 0, 0, 0, 0],
+    [0, 0, 1, 2, 0, 0, 1, 2, 1, 1, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1, 0, 1, 1, 0],
+    [0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1],
+    [0, 0, 2, 2, 1, 1, 0, 2, 1, 1, 0, 2, 0, 0, 2, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 0, 0, 2, 2, 2, 2, 2],
+    [0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 0, 0, 2, 0, 0, 0, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+    [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 2, 2, 0, 2, 2, 2],
+    [0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0],
+    [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0],
+    [0, 1, 2, 0, 2, 0, 1, 2, 1, 2, 0, 1, 0, 1, 2, 0],
+    [0, 0, 1, 1, 2, 2, 0, 0, 1, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 1, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 0, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 1, 1],
+    [0, 2, 2, 0, 1, 2, 2, 1, 0, 2, 2, 0, 1, 2, 2, 1],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 0, 1, 0, 1],
+    [0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 2, 2, 2, 0, 1, 1, 1],
+    [0, 0, 0, 2, 1, 1, 1, 2, 0, 0, 0, 2, 1, 1, 1, 2],
+    [0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2],
+    [0, 0, 0, 2, 1, 1, 1, 2, 1, 1, 1, 2, 0, 0, 0, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2],
+    [0, 0, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2],
+    [0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1],
+    [0, 2, 2, 2, 1, 2, 2, 2, 0, 2, 2, 2, 1, 2, 2, 2],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 1, 2, 0, 1, 1, 2, 2, 0, 1, 2, 2, 2, 0],
+];
+/// Pixel of the second subset of each 2-subset partition whose index has
+/// its highest bit implied.
+const BC7_ANCHORS2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15, 2, 8, 2, 2, 8, 8, 15, 2, 8, 2, 2, 8, 8, 2, 2,
+    15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6,
+    6, 2, 6, 8, 15, 15, 2, 2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+/// Anchor pixels of the second and the third subsets of the 3-subset
+/// partitions.
+const BC7_ANCHORS3: [[u8; 64]; 2] = [
+    [
+        3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3,
+        3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5, 15, 15,
+        8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15,
+        3, 15, 5, 5, 5, 8, 5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+    ],
+    [
+        15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8,
+        15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6, 10, 15, 15, 10, 8,
+        15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8,
+        15, 3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+    ],
+];
+/// Interpolation weights out of 64 by the number of index bits.
+const BC7_WEIGHTS2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Reader of the bits of a block from the lowest.
+struct BitReader {
+    bits: u128,
+}
+impl BitReader {
+    fn read(&mut self, n: u32) -> u32 {
+        let rv = (self.bits & ((1 << n) - 1)) as u32;
+        self.bits >>= n;
+        rv
+    }
+}
+
+/// Decode a BC7 block. Blocks of reserved modes are transparent black.
+fn decode_bc7(block: &[u8]) -> [[u8; 4]; 16] {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(block);
+    let mut r = BitReader { bits: u128::from_le_bytes(bytes) };
+    if block[0] == 0 { return [[0; 4]; 16] }
+    let imode = block[0].trailing_zeros();
+    r.read(imode + 1);
+    let mode = &BC7_MODES[imode as usize];
+    let partition = r.read(mode.partition_bits) as usize;
+    let rotation = r.read(mode.rotation_bits);
+    let index_selection = r.read(mode.index_selection_bits);
+
+    // Endpoints of each subset, channel by channel.
+    let nend = 2 * mode.nsubset;
+    let mut ends = [[0_u32; 4]; 6];
+    for c in 0..3 {
+        for end in ends[..nend].iter_mut() {
+            end[c] = r.read(mode.color_bits);
+        }
+    }
+    for end in ends[..nend].iter_mut() {
+        end[3] = if mode.alpha_bits > 0 { r.read(mode.alpha_bits) } else { 255 };
+    }
+    let (mut color_bits, mut alpha_bits) = (mode.color_bits, mode.alpha_bits);
+    if mode.endpoint_pbits || mode.shared_pbits {
+        let mut pbits = [0; 6];
+        for (i, p) in pbits[..nend].iter_mut().enumerate() {
+            // Shared p-bits are read for the first endpoint of each subset.
+            *p = if mode.endpoint_pbits || i % 2 == 0 { r.read(1) } else { 0 };
+        }
+        for (i, end) in ends[..nend].iter_mut().enumerate() {
+            let p = if mode.endpoint_pbits { pbits[i] } else { pbits[i & !1] };
+            for x in end[..3].iter_mut() { *x = (*x << 1) | p }
+            if mode.alpha_bits > 0 { end[3] = (end[3] << 1) | p }
+        }
+        color_bits += 1;
+        if mode.alpha_bits > 0 { alpha_bits += 1 }
+    }
+    let expand = |x: u32, n: u32| if n >= 8 { x } else { (x << (8 - n)) | (x >> (2 * n - 8)) };
+    for end in ends[..nend].iter_mut() {
+        for x in end[..3].iter_mut() { *x = expand(*x, color_bits) }
+        if mode.alpha_bits > 0 { end[3] = expand(end[3], alpha_bits) }
+    }
+
+    let subset = |i: usize| match mode.nsubset {
+        2 => ((BC7_PARTITIONS2[partition] >> i) & 1) as usize,
+        3 => BC7_PARTITIONS3[partition][i] as usize,
+        _ => 0,
+    };
+    let is_anchor = |i: usize| {
+        i == 0 || match mode.nsubset {
+            2 => i == BC7_ANCHORS2[partition] as usize,
+            3 => i == BC7_ANCHORS3[0][partition] as usize || i == BC7_ANCHORS3[1][partition] as usize,
+            _ => false,
+        }
+    };
+    let mut idxs = [0; 16];
+    for (i, x) in idxs.iter_mut().enumerate() {
+        *x = r.read(mode.index_bits - is_anchor(i) as u32);
+    }
+    let mut idxs2 = [0; 16];
+    if mode.index2_bits > 0 {
+        for (i, x) in idxs2.iter_mut().enumerate() {
+            *x = r.read(mode.index2_bits - (i == 0) as u32);
+        }
+    }
+    let weights = |n: u32| -> &'static [u32] {
+        match n {
+            2 => &BC7_WEIGHTS2,
+            3 => &BC7_WEIGHTS3,
+            _ => &BC7_WEIGHTS4,
+        }
+    };
+    let lerp = |a: u32, b: u32, w: u32| ((64 - w) * a + w * b + 32) >> 6;
+
+    let mut rv = [[0; 4]; 16];
+    for (i, px) in rv.iter_mut().enumerate() {
+        let s = subset(i);
+        let (e0, e1) = (ends[2 * s], ends[2 * s + 1]);
+        // Modes indexing alpha separately swap the index sets by the index
+        // selection bit.
+        let (color_w, alpha_w) = if mode.index2_bits == 0 {
+            let w = weights(mode.index_bits)[idxs[i] as usize];
+            (w, w)
+        } else if index_selection == 0 {
+            (weights(mode.index_bits)[idxs[i] as usize], weights(mode.index2_bits)[idxs2[i] as usize])
+        } else {
+            (weights(mode.index2_bits)[idxs2[i] as usize], weights(mode.index_bits)[idxs[i] as usize])
+        };
+        let mut c = [0; 4];
+        for ch in 0..3 {
+            c[ch] = lerp(e0[ch], e1[ch], color_w) as u8;
+        }
+        c[3] = lerp(e0[3], e1[3], alpha_w) as u8;
+        match rotation {
+            1 => c.swap(0, 3),
+            2 => c.swap(1, 3),
+            3 => c.swap(2, 3),
+            _ => {},
+        }
+        *px = c;
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A legacy DDS file of a `w` by `h` texture of `nlevel` levels of
+    /// `fourcc` or, if `None`, of 32-bit RGBA pixels.
+    fn dds(w: u32, h: u32, nlevel: u32, fourcc: Option<&[u8; 4]>, pixels: &[u8]) -> Vec<u8> {
+        let mut header = [0_u32; 31];
+        header[0] = 124;
+        header[1] = 0x1007 | DDSD_MIPMAPCOUNT;
+        header[2] = h;
+        header[3] = w;
+        header[6] = nlevel;
+        header[18] = 32;
+        match fourcc {
+            Some(fourcc) => {
+                header[19] = DDPF_FOURCC;
+                header[20] = u32::from_le_bytes(*fourcc);
+            },
+            None => {
+                header[19] = DDPF_RGB | DDPF_ALPHAPIXELS;
+                header[21] = 32;
+                header[22..26].copy_from_slice(&[0xff, 0xff00, 0xff_0000, 0xff00_0000]);
+            },
+        }
+        let mut rv = DDS_MAGIC.to_vec();
+        rv.extend(header.iter().flat_map(|x| x.to_le_bytes().to_vec()));
+        rv.extend_from_slice(pixels);
+        rv
+    }
+    /// A KTX2 file of a `w` by `h` texture of `vk_format` whose levels are
+    /// `levels`, the full resolution level first.
+    fn ktx2(vk_format: u32, w: u32, h: u32, nlevel: u32, levels: &[&[u8]]) -> Vec<u8> {
+        let mut rv = KTX2_MAGIC.to_vec();
+        for x in [vk_format, 1, w, h, 0, 0, 1, nlevel, 0].iter() {
+            rv.extend_from_slice(&x.to_le_bytes());
+        }
+        // No data format descriptor, key-value data or supercompression
+        // global data.
+        rv.extend_from_slice(&[0; 32]);
+        let mut offset = KTX2_LEVEL_INDEX + KTX2_LEVEL_ENTRY_SIZE * levels.len();
+        for level in levels.iter() {
+            let len = level.len() as u64;
+            for x in [offset as u64, len, len].iter() {
+                rv.extend_from_slice(&x.to_le_bytes());
+            }
+            offset += level.len();
+        }
+        for level in levels.iter() {
+            rv.extend_from_slice(level);
+        }
+        rv
+    }
+    fn rgba8(img: &Image, x: usize, y: usize) -> [u8; 4] {
+        img.to_color_space(ColorSpace::Linear).to_alpha(Alpha::Straight).load_px(x, y).into()
+    }
+    fn assert_malformed(rv: Result<Vec<Image>, LoadError>) {
+        match rv {
+            Err(LoadError::Container(_)) => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("malformed file decoded"),
+        }
+    }
+    /// The 16 bytes of a block packing `fields` of values and bit counts from
+    /// the lowest bit.
+    fn pack(fields: &[(u128, u32)]) -> [u8; 16] {
+        let mut bits = 0;
+        let mut n = 0;
+        for &(x, nbit) in fields {
+            bits |= x << n;
+            n += nbit;
+        }
+        assert_eq!(n, 128);
+        bits.to_le_bytes()
+    }
+
+    #[test]
+    fn dds_levels() {
+        let mut pixels = Vec::new();
+        for i in 0..8 { pixels.extend_from_slice(&[i * 30, 0, 0, 255]) }
+        pixels.extend_from_slice(&[255, 255, 255, 255, 0, 0, 0, 128]);
+        pixels.extend_from_slice(&[10, 20, 30, 40]);
+        let levels = decode_dds(&dds(4, 2, 3, None, &pixels)).unwrap();
+        assert_eq!(levels.len(), 3);
+        assert_eq!((levels[1].width(), levels[1].height()), (2, 1));
+        assert_eq!((levels[2].width(), levels[2].height()), (1, 1));
+        assert_eq!(levels[0].color_space(), ColorSpace::Srgb);
+        assert_eq!(levels[0].alpha(), Alpha::Straight);
+        let img = image::RgbaImage::from(levels.into_iter().last().unwrap());
+        assert_eq!(img.get_pixel(0, 0).0, [10, 20, 30, 40]);
+    }
+    #[test]
+    fn dds_truncated() {
+        let pixels = [0; 4 * 4 * 4 - 1];
+        assert_malformed(decode_dds(&dds(4, 4, 1, None, &pixels)));
+        assert_malformed(decode_dds(&dds(4, 4, 1, None, &[])[..100]));
+    }
+    #[test]
+    fn dds_overflow() {
+        assert_malformed(decode_dds(&dds(1 << 31, 1 << 31, 1, None, &[0; 64])));
+        assert_malformed(decode_dds(&dds(4, 4, 100, None, &[0; 64])));
+    }
+    #[test]
+    fn ktx2_levels() {
+        let level0 = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let level1 = [9, 8, 7, 6];
+        let levels = decode_ktx2(&ktx2(37, 2, 2, 2, &[&level0, &level1])).unwrap();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0].color_space(), ColorSpace::Linear);
+        assert_eq!(rgba8(&levels[0], 1, 1), [13, 14, 15, 16]);
+        assert_eq!(rgba8(&levels[1], 0, 0), [9, 8, 7, 6]);
+    }
+    #[test]
+    fn ktx2_truncated() {
+        let file = ktx2(37, 2, 2, 1, &[&[0; 16]]);
+        assert_malformed(decode_ktx2(&file[..file.len() - 1]));
+        // The level index of a second level is cut off.
+        assert_malformed(decode_ktx2(&ktx2(37, 2, 2, 2, &[&[0; 16]])));
+    }
+    #[test]
+    fn ktx2_overflow() {
+        assert_malformed(decode_ktx2(&ktx2(37, 4, 4, u32::MAX, &[&[0; 64]])));
+        assert_malformed(decode_ktx2(&ktx2(109, 1 << 31, 1 << 31, 1, &[&[0; 64]])));
+    }
+
+    #[test]
+    fn bc1_block() {
+        // Red and blue endpoints in the 4-color mode, the first row indexing
+        // each color.
+        let block = [0x00, 0xf8, 0x1f, 0x00, 0b1110_0100, 0, 0, 0];
+        let px = decode_bc1(&block, true);
+        assert_eq!(px[0], [255, 0, 0, 255]);
+        assert_eq!(px[1], [0, 0, 255, 255]);
+        assert_eq!(px[2], [170, 0, 85, 255]);
+        assert_eq!(px[3], [85, 0, 170, 255]);
+        assert_eq!(px[4], [255, 0, 0, 255]);
+        // Ascending endpoints in the 3-color mode with transparent black.
+        let block = [0x1f, 0x00, 0x00, 0xf8, 0b1110_0100, 0, 0, 0];
+        let px = decode_bc1(&block, true);
+        assert_eq!(px[2], [127, 0, 127, 255]);
+        assert_eq!(px[3], [0, 0, 0, 0]);
+    }
+    #[test]
+    fn bc3_block() {
+        // Alpha from 255 to 0 in the 8-value mode indexing 255, 0 and 6/7,
+        // and colors always in the 4-color mode.
+        let block = [
+            255, 0, 0b1000_1000, 0, 0, 0, 0, 0,
+            0x1f, 0x00, 0x00, 0xf8, 0b0011_0000, 0, 0, 0,
+        ];
+        let px = decode_block(PixelFormat::Bc3, &block);
+        let px = px.iter().map(|&c| <[u8; 4]>::from(c)).collect::<Vec<_>>();
+        assert_eq!(px[0], [0, 0, 255, 255]);
+        assert_eq!(px[1], [0, 0, 255, 0]);
+        assert_eq!(px[2], [170, 0, 85, 219]);
+    }
+    #[test]
+    fn bc7_mode6_block() {
+        // Endpoints 0 and 127 with p-bits 0 and 1, i.e., 0 and 255.
+        let mut fields = vec![(1 << 6, 7)];
+        for _ in 0..4 {
+            fields.push((0, 7));
+            fields.push((127, 7));
+        }
+        fields.extend_from_slice(&[(0, 1), (1, 1), (0, 3)]);
+        for _ in 1..15 { fields.push((15, 4)) }
+        fields.push((8, 4));
+        let px = decode_bc7(&pack(&fields));
+        assert_eq!(px[0], [0, 0, 0, 0]);
+        assert_eq!(px[1], [255; 4]);
+        // Weight 34 of 64.
+        assert_eq!(px[15], [135; 4]);
+    }
+    #[test]
+    fn bc7_mode5_rotation() {
+        // White color endpoints and alpha from 0 to 200, with alpha rotated
+        // into red.
+        let mut fields = vec![(1 << 5, 6), (1, 2)];
+        for _ in 0..6 { fields.push((127, 7)) }
+        fields.extend_from_slice(&[(0, 8), (200, 8)]);
+        for i in 0..16 { fields.push((0, if i == 0 { 1 } else { 2 })) }
+        for i in 0..16 { fields.push((if i == 0 { 0 } else { 3 }, if i == 0 { 1 } else { 2 })) }
+        let px = decode_bc7(&pack(&fields));
+        assert_eq!(px[0], [0, 255, 255, 255]);
+        assert_eq!(px[5], [200, 255, 255, 255]);
+    }
+    #[test]
+    fn bc7_partition_anchors() {
+        for p in 0..64 {
+            assert_eq!(BC7_PARTITIONS2[p] & 1, 0);
+            assert_eq!((BC7_PARTITIONS2[p] >> BC7_ANCHORS2[p]) & 1, 1, "partition {}", p);
+            assert_eq!(BC7_PARTITIONS3[p][0], 0);
+            assert_eq!(BC7_PARTITIONS3[p][BC7_ANCHORS3[0][p] as usize], 1, "partition {}", p);
+            assert_eq!(BC7_PARTITIONS3[p][BC7_ANCHORS3[1][p] as usize], 2, "partition {}", p);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use crate::geom::Color;
+use crate::img::{Image, PixelSource, LoadError, load_image};
+use crate::sampler::{Sampler2D, WrapMode, FilterMode};
+use crate::trace::{self, Level};
+
+/// Provider of the tiles of a `TiledImage`.
+pub trait TileSource : Send + Sync {
+    /// Load the tile at tile coordinates `(tx, ty)`. `None` if the tile is
+    /// not available, in which case it reads transparent black, as do empty
+    /// tiles.
+    fn load_tile(&self, tx: usize, ty: usize) -> Option<Image>;
+}
+impl<F> TileSource for F
+    where F: Fn(usize, usize) -> Option<Image> + Send + Sync
+{
+    fn load_tile(&self, tx: usize, ty: usize) -> Option<Image> {
+        self(tx, ty)
+    }
+}
+
+/// Tiles stored as separate image files named `{tx}_{ty}.{ext}` in a
+/// directory.
+pub struct TileDir {
+    pub dir: PathBuf,
+    pub ext: String,
+}
+impl TileSource for TileDir {
+    fn load_tile(&self, tx: usize, ty: usize) -> Option<Image> {
+        let path = self.dir.join(format!("{}_{}.{}", tx, ty, self.ext));
+        image::open(path).ok().map(Image::from)
+    }
+}
+
+/// A huge image split into square tiles that are only loaded when a pixel in
+/// them is first accessed. Loaded tiles stay resident.
+pub struct TiledImage {
+    w: usize,
+    h: usize,
+    tile_size: usize,
+    ntile_x: usize,
+    src: Box<dyn TileSource>,
+    tiles: Vec<OnceLock<Image>>,
+}
+impl TiledImage {
+    pub fn new<S>(w: usize, h: usize, tile_size: usize, src: S) -> TiledImage
+        where S: TileSource + 'static
+    {
+        assert!(tile_size > 0, "tile size must be positive");
+        let ntile_x = w.div_ceil(tile_size);
+        let ntile_y = h.div_ceil(tile_size);
+        let tiles = (0..ntile_x * ntile_y)
+            .map(|_| OnceLock::new())
+            .collect();
+        TiledImage { w, h, tile_size, ntile_x, src: Box::new(src), tiles }
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize { self.w }
+    #[inline]
+    pub fn height(&self) -> usize { self.h }
+    #[inline]
+    pub fn tile_size(&self) -> usize { self.tile_size }
+
+    fn tile(&self, tx: usize, ty: usize) -> &Image {
+        self.tiles[tx + ty * self.ntile_x].get_or_init(|| {
+            self.src.load_tile(tx, ty)
+                .filter(|x| x.width() > 0 && x.height() > 0)
+                .unwrap_or_else(|| Image::new(self.tile_size, self.tile_size))
+        })
+    }
+    #[inline]
+    pub fn load_px(&self, x: usize, y: usize) -> Color {
+        let tile = self.tile(x / self.tile_size, y / self.tile_size);
+        let x = (x % self.tile_size).min(tile.width() - 1);
+        let y = (y % self.tile_size).min(tile.height() - 1);
+        tile.load_px(x, y)
+    }
+    /// Number of tiles loaded so far.
+    pub fn nresident(&self) -> usize {
+        self.tiles.iter()
+            .filter(|x| x.get().is_some())
+            .count()
+    }
+}
+impl PixelSource for TiledImage {
+    fn width(&self) -> usize { self.w }
+    fn height(&self) -> usize { self.h }
+    fn load_px(&self, x: usize, y: usize) -> Color { TiledImage::load_px(self, x, y) }
+    fn load_px_premultiplied(&self, x: usize, y: usize) -> Color {
+        let tile = self.tile(x / self.tile_size, y / self.tile_size);
+        let x = (x % self.tile_size).min(tile.width() - 1);
+        let y = (y % self.tile_size).min(tile.height() - 1);
+        tile.load_px_premultiplied(x, y)
+    }
+}
+
+/// Where an image is in a `TextureAtlas`, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasRegion {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+/// Pixels of an `AtlasRegion` of an image.
+struct RegionView<'a> {
+    img: &'a Image,
+    region: AtlasRegion,
+}
+impl PixelSource for RegionView<'_> {
+    fn width(&self) -> usize { self.region.w }
+    fn height(&self) -> usize { self.region.h }
+    fn load_px(&self, x: usize, y: usize) -> Color {
+        self.img.load_px(self.region.x + x, self.region.y + y)
+    }
+    fn load_px_premultiplied(&self, x: usize, y: usize) -> Color {
+        self.img.load_px_premultiplied(self.region.x + x, self.region.y + y)
+    }
+}
+
+/// Images packed side by side into a single one, e.g., many small decals or
+/// sprites kept in one texture. Each image is only sampled within its own
+/// region, so filtering doesn't bleed across neighbors.
+pub struct TextureAtlas {
+    img: Image,
+    regions: Vec<AtlasRegion>,
+}
+impl TextureAtlas {
+    /// Pack `images` in rows from the tallest into an atlas about as wide as
+    /// it's tall, of the format, alpha and color space of the first image.
+    /// `None` without images.
+    pub fn pack(images: &[Image]) -> Option<TextureAtlas> {
+        let first = images.first()?;
+        let area = images.iter().map(|x| x.width() * x.height()).sum::<usize>();
+        let widest = images.iter().map(|x| x.width()).max().unwrap_or(0);
+        let w = widest.max((area as f64).sqrt().ceil() as usize);
+        let mut order = (0..images.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse(images[i].height()));
+        // Rows are as tall as their first, tallest image.
+        let mut regions = vec![AtlasRegion { x: 0, y: 0, w: 0, h: 0 }; images.len()];
+        let (mut x, mut y, mut row_h) = (0, 0, 0);
+        for i in order {
+            let (iw, ih) = (images[i].width(), images[i].height());
+            if x + iw > w {
+                x = 0;
+                y += row_h;
+                row_h = 0;
+            }
+            regions[i] = AtlasRegion { x, y, w: iw, h: ih };
+            x += iw;
+            row_h = row_h.max(ih);
+        }
+        let mut img = Image::with_format(w, y + row_h, first.format())
+            .with_alpha(first.alpha())
+            .with_color_space(first.color_space());
+        for (src, region) in images.iter().zip(regions.iter()) {
+            let converted;
+            let src = if src.alpha() == first.alpha() {
+                src
+            } else {
+                converted = src.to_alpha(first.alpha());
+                &converted
+            };
+            for j in 0..region.h {
+                for i in 0..region.w {
+                    img.store_px(region.x + i, region.y + j, src.load_px(i, j));
+                }
+            }
+        }
+        Some(TextureAtlas { img, regions })
+    }
+    /// The packed image.
+    pub fn image(&self) -> &Image {
+        &self.img
+    }
+    /// Number of images in the atlas.
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+    /// Where the `i`-th image passed to `pack` is.
+    pub fn region(&self, i: usize) -> Option<AtlasRegion> {
+        self.regions.get(i).copied()
+    }
+    /// Sample the `i`-th image at `(u, v)` in [0, 1] squared like
+    /// `Sampler2D::sample`, clamped to its edges. Images not in the atlas
+    /// read transparent black, as do empty ones.
+    pub fn sample(&self, i: usize, u: f32, v: f32, filter: FilterMode) -> Color {
+        let region = match self.region(i) {
+            Some(x) if x.w > 0 && x.h > 0 => x,
+            _ => return Color::default(),
+        };
+        let view = RegionView { img: &self.img, region };
+        Sampler2D::new(WrapMode::Clamp, filter).sample(&view, u, v)
+    }
+}
+
+/// Token in file names of UDIM sets standing for the tile number.
+pub const UDIM_TOKEN: &str = "<UDIM>";
+
+/// UDIM tile number of texture coordinates `(u, v)`, where tile 1001 spans
+/// [0, 1) squared and numbers increase by 1 along u and by 10 along v. `None`
+/// out of the 10 tiles along u or below v = 0.
+pub fn udim(u: f32, v: f32) -> Option<u32> {
+    if !(0.0..10.0).contains(&u) || !(0.0..100_000.0).contains(&v) { return None }
+    Some(1001 + u as u32 + 10 * v as u32)
+}
+
+/// Texture of a UDIM set, i.e., an image per unit square of texture space,
+/// e.g., for film assets whose UV layouts span many tiles. Tiles are loaded
+/// when first sampled and stay resident. Texture coordinates have v pointing
+/// up within each tile, as in `bake::rasterize_uv`.
+pub struct UdimTexture {
+    tiles: BTreeMap<u32, (Option<PathBuf>, OnceLock<Image>)>,
+}
+impl UdimTexture {
+    /// UDIM set of images already in memory, by tile number.
+    pub fn from_tiles<I: IntoIterator<Item = (u32, Image)>>(tiles: I) -> UdimTexture {
+        let tiles = tiles.into_iter()
+            .map(|(i, img)| (i, (None, OnceLock::from(img))))
+            .collect();
+        UdimTexture { tiles }
+    }
+    /// UDIM set of the files matching `pattern`, whose file name has
+    /// `UDIM_TOKEN` in place of the tile number, e.g., `wood.<UDIM>.png`
+    /// for `wood.1001.png`, `wood.1002.png` and so on. Files are loaded with
+    /// `img::load_image` once first sampled; failing ones are reported as a
+    /// `trace` warning and read transparent black.
+    pub fn open<P: AsRef<Path>>(pattern: P) -> Result<UdimTexture, LoadError> {
+        let pattern = pattern.as_ref();
+        let name = pattern.file_name()
+            .and_then(|x| x.to_str())
+            .filter(|x| x.contains(UDIM_TOKEN))
+            .ok_or_else(|| LoadError::Udim(format!("`{}` has no {} in the file name",
+                pattern.display(), UDIM_TOKEN)))?;
+        let (prefix, suffix) = name.split_at(name.find(UDIM_TOKEN).unwrap());
+        let suffix = &suffix[UDIM_TOKEN.len()..];
+        let dir = match pattern.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| LoadError::Udim(format!("failed to list {}: {}", dir.display(), e)))?;
+        let tiles = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?;
+                let number = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                if number.len() != 4 || !number.bytes().all(|x| x.is_ascii_digit()) { return None }
+                let number = number.parse::<u32>().ok().filter(|&x| x >= 1001)?;
+                Some((number, (Some(path), OnceLock::new())))
+            })
+            .collect::<BTreeMap<_, _>>();
+        if tiles.is_empty() {
+            return Err(LoadError::Udim(format!("no tiles match `{}`", pattern.display())));
+        }
+        Ok(UdimTexture { tiles })
+    }
+
+    /// Numbers of the tiles in the set in increasing order.
+    pub fn tile_numbers(&self) -> impl Iterator<Item = u32> + '_ {
+        self.tiles.keys().copied()
+    }
+    /// The image of tile `number`, loading it if needed. `None` if the set
+    /// has no such tile.
+    pub fn tile(&self, number: u32) -> Option<&Image> {
+        let (path, img) = self.tiles.get(&number)?;
+        Some(img.get_or_init(|| {
+            let path = match path {
+                Some(x) => x,
+                None => return Image::new(1, 1),
+            };
+            load_image(path).unwrap_or_else(|e| {
+                trace::event(Level::Warn, &format!("failed to load UDIM tile {} from {}: {}",
+                    number, path.display(), e));
+                Image::new(1, 1)
+            })
+        }))
+    }
+    /// Number of tiles loaded so far.
+    pub fn nresident(&self) -> usize {
+        self.tiles.values()
+            .filter(|(_, x)| x.get().is_some())
+            .count()
+    }
+    /// Sample the texture at `(u, v)` with `filter`. Filtering is clamped to
+    /// the edges of each tile, so tiles don't bleed into each other. Texture
+    /// coordinates out of the tiles of the set read transparent black.
+    pub fn sample(&self, u: f32, v: f32, filter: FilterMode) -> Color {
+        let img = match udim(u, v).and_then(|x| self.tile(x)) {
+            Some(x) => x,
+            None => return Color::default(),
+        };
+        let samp = Sampler2D::new(WrapMode::Clamp, filter);
+        // Image rows go down while v goes up.
+        samp.sample(img, u.fract(), 1.0 - v.fract())
+    }
+}
@@ -0,0 +1,312 @@
+//! Non-photorealistic cel shading of the scenes of path tracers: flat bands
+//! of light instead of smooth gradients, rims of light around silhouettes,
+//! and inked edges where the object or the surface orientation changes
+//! abruptly between neighboring pixels. Edges are found by tracing the
+//! camera rays of the neighbors of each pixel again, except in wavefront
+//! draws, which look them up in the hit cache of the frame.
+use crate::geom::{Real, Ray, Vector, Triangle, Color, Barycentric, narrow};
+use crate::rt::{
+    RayTracer, WavefrontRayTracer, Intersection, HitKind, Framebuffer, HitCache, SceneChanges,
+    TILE_SIZE, morton_order,
+};
+use crate::scene::{Scene, Object, RayKind};
+use crate::accel::Accel;
+use crate::integrator::{PathTracer, diffuse_irradiance};
+use crate::post::luminance;
+
+/// Style of `ToonRayTracer`.
+#[derive(Debug, Clone, Copy)]
+pub struct ToonStyle {
+    /// Number of bands of light from the darkest to fully lit.
+    pub nband: u32,
+    /// Brightness of the darkest band, e.g., of surfaces in shadow.
+    pub shadow: f32,
+    /// Unit direction to a key light lighting scenes without lights. The
+    /// light comes from the camera by default.
+    pub key: Option<Vector>,
+    /// Color added along silhouettes.
+    pub rim_color: Color,
+    /// Width of the rims as the fraction of the view angle range from
+    /// grazing to facing the camera, 0 for no rims.
+    pub rim_width: f32,
+    /// Color of inked edges.
+    pub edge_color: Color,
+    /// Edges are inked where the normals of neighboring pixels are more than
+    /// this many radians apart.
+    pub crease_angle: Real,
+    /// Edges are inked where the depths of neighboring pixels on the same
+    /// object differ by more than this fraction, so that an object folding
+    /// over itself is outlined too.
+    pub depth_ratio: Real,
+}
+impl Default for ToonStyle {
+    fn default() -> ToonStyle {
+        ToonStyle {
+            nband: 3,
+            shadow: 0.3,
+            key: None,
+            rim_color: Color(0.3, 0.3, 0.3, 0.0),
+            rim_width: 0.15,
+            edge_color: Color(0.0, 0.0, 0.0, 1.0),
+            crease_angle: 60.0_f64.to_radians() as Real,
+            depth_ratio: 0.1,
+        }
+    }
+}
+
+/// What a camera ray sees for edge detection: the object, the unit normal
+/// facing the camera and the distance, or nothing.
+type Sight = Option<(usize, Vector, Real)>;
+
+/// Ray tracer rendering the scene of `inner` cel shaded, see the module
+/// documentation. The base color of each material is given by `albedo`.
+/// Lights are those of `inner`, and objects emitting light are drawn in
+/// their emission; the environment is seen as is but lights nothing.
+pub struct ToonRayTracer<T: RayTracer> {
+    pub inner: T,
+    pub albedo: fn(&T::Material) -> Color,
+    pub style: ToonStyle,
+}
+impl<T> ToonRayTracer<T>
+    where T: PathTracer + WavefrontRayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    pub fn new(inner: T, albedo: fn(&T::Material) -> Color) -> ToonRayTracer<T> {
+        ToonRayTracer { inner, albedo, style: ToonStyle::default() }
+    }
+
+    /// Cel shaded color where `ray` hit `tri` of the `obj`-th object.
+    fn shade(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut T::Payload,
+        mat: &T::Material,
+    ) -> Color {
+        let emit = self.inner.emitted(ray, obj, tri, intersect, mat);
+        if emit.0 > 0.0 || emit.1 > 0.0 || emit.2 > 0.0 { return emit }
+        let style = &self.style;
+        let v = ray.v.normalize();
+        let n = if intersect.kind == HitKind::Front { tri.n } else { -tri.n };
+        // Light reaching the surface, where an irradiance of 1 is fully lit.
+        let light = if self.inner.lights().is_empty() {
+            let key = style.key.unwrap_or(-v);
+            narrow(key.dot(n).max(0.0))
+        } else {
+            luminance(diffuse_irradiance(&self.inner, ray, obj, tri, intersect, payload))
+        };
+        let nband = style.nband.max(1) as f32;
+        let band = (light.clamp(0.0, 1.0) * nband).ceil().max(1.0) / nband;
+        let shade = style.shadow + (1.0 - style.shadow) * if light > 0.0 { band } else { 0.0 };
+        let mut color = (self.albedo)(mat) * shade;
+        let facing = narrow(-v.dot(n)).abs();
+        if facing < style.rim_width {
+            color = color + style.rim_color;
+        }
+        Color(color.0, color.1, color.2, 1.0)
+    }
+    /// Color of pixel `(x, y)` of a `w` by `h` frame whose camera ray `ray`
+    /// hit `tri` of the `obj`-th object if anything, inked if the pixel sees
+    /// something else than any of its 4 neighbors, as told by `sight`.
+    fn pixel<F>(
+        &self,
+        (x, y, w, h): (u32, u32, u32, u32),
+        ray: &Ray,
+        hit: Option<(usize, &Triangle, &Intersection<Barycentric>)>,
+        payload: &mut T::Payload,
+        sight: F,
+    ) -> Color
+        where F: Fn(u32, u32) -> Sight,
+    {
+        let center = hit.map(|(obj, tri, intersect)| sight_of(ray, obj, tri, intersect.t));
+        let neighbors = [
+            (x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1),
+        ];
+        let edge = neighbors.iter()
+            .filter(|&&(nx, ny)| nx < w && ny < h)
+            .any(|&(nx, ny)| self.is_edge(center, sight(nx, ny)));
+        if edge { return self.style.edge_color }
+        match hit {
+            Some((obj, tri, intersect)) => {
+                let mat = &self.scene().objs[obj].mat;
+                self.shade(ray, obj, tri, intersect, payload, mat)
+            },
+            // The background is premultiplied by its alpha like in path
+            // traced renders.
+            None => self.miss(ray, payload).premultiply(),
+        }
+    }
+    /// Whether an edge runs between pixels seeing `a` and `b`.
+    fn is_edge(&self, a: Sight, b: Sight) -> bool {
+        match (a, b) {
+            (None, None) => false,
+            (Some((ia, na, ta)), Some((ib, nb, tb))) => {
+                ia != ib
+                    || na.dot(nb) < self.style.crease_angle.cos()
+                    || (ta - tb).abs() > self.style.depth_ratio * ta.min(tb)
+            },
+            _ => true,
+        }
+    }
+}
+/// What camera ray `ray` sees hitting `tri` of the `obj`-th object at
+/// parametric distance `t`.
+fn sight_of(ray: &Ray, obj: usize, tri: &Triangle, t: Real) -> (usize, Vector, Real) {
+    let n = tri.n;
+    let n = if n.dot(ray.v) > 0.0 { -n } else { n };
+    (obj, n, t * ray.v.mag())
+}
+
+impl<T> RayTracer for ToonRayTracer<T>
+    where T: PathTracer + WavefrontRayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    type Material = T::Material;
+    type Payload = T::Payload;
+    type Ray = Ray;
+    type RayAttr = Barycentric;
+
+    /// Cel shaded color of the pixel, inked if the pixel sees something else
+    /// than any of its 4 neighbors.
+    fn ray_gen(&self, x: u32, y: u32, w: u32, h: u32) -> Color {
+        let (ray, mut payload) = self.inner.primary_ray(x, y, w, h);
+        let hit = self.inner.closest(&ray, RayKind::Camera, &mut payload);
+        let hit = hit.as_ref().map(|x| (x.obj, &x.tri, &x.intersect));
+        self.pixel((x, y, w, h), &ray, hit, &mut payload, |nx, ny| {
+            let (ray, mut payload) = self.inner.primary_ray(nx, ny, w, h);
+            let hit = self.inner.closest(&ray, RayKind::Camera, &mut payload)?;
+            Some(sight_of(&ray, hit.obj, &hit.tri, hit.intersect.t))
+        })
+    }
+    fn intersect(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        mat: &T::Material,
+    ) -> Option<Intersection<Barycentric>> {
+        self.inner.intersect(ray, tri, mat)
+    }
+    fn intersect_within(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        mat: &T::Material,
+        tmax: Real,
+    ) -> Option<Intersection<Barycentric>> {
+        self.inner.intersect_within(ray, tri, mat, tmax)
+    }
+    fn intersect_shape(
+        &self,
+        ray: &Ray,
+        obj: &Object<T::Material>,
+        tmax: Real,
+    ) -> Option<(Triangle, Intersection<Barycentric>)> {
+        self.inner.intersect_shape(ray, obj, tmax)
+    }
+    fn any_hit(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut T::Payload,
+        mat: &T::Material,
+    ) -> bool {
+        self.inner.any_hit(ray, tri, intersect, payload, mat)
+    }
+    fn miss(&self, ray: &Ray, payload: &mut T::Payload) -> Color {
+        self.inner.miss(ray, payload)
+    }
+    fn closest_hit(
+        &self,
+        ray: &Ray,
+        obj: usize,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        payload: &mut T::Payload,
+        mat: &T::Material,
+    ) -> Color {
+        self.shade(ray, obj, tri, intersect, payload, mat)
+    }
+    fn accel(&self, ray: &Ray) -> Option<(&dyn Accel, Ray)> {
+        self.inner.accel(ray)
+    }
+    fn scene(&self) -> &Scene<T::Material> {
+        self.inner.scene()
+    }
+}
+impl<T> WavefrontRayTracer for ToonRayTracer<T>
+    where T: PathTracer + WavefrontRayTracer<Ray = Ray, RayAttr = Barycentric>,
+{
+    fn primary_ray(&self, x: u32, y: u32, w: u32, h: u32) -> (Ray, T::Payload) {
+        self.inner.primary_ray(x, y, w, h)
+    }
+    fn primary_sample(&self, x: u32, y: u32, w: u32, h: u32) -> (Ray, T::Payload, Color) {
+        self.inner.primary_sample(x, y, w, h)
+    }
+    /// Same as `draw_wavefront_cached` with a cache of this frame only.
+    fn draw_wavefront<FB>(&self, framebuf: &mut FB)
+        where FB: Framebuffer,
+              Self::Ray: Send,
+              Self::Payload: Send,
+              Self::RayAttr: Send,
+              Self::Material: Sync,
+    {
+        self.draw_wavefront_cached(framebuf, &mut HitCache::new());
+    }
+    /// Edges are found from the hits of the neighbors of each pixel in
+    /// `cache`, so no more rays are traced for them.
+    fn draw_wavefront_cached<FB>(
+        &self,
+        framebuf: &mut FB,
+        cache: &mut HitCache<Ray, Barycentric>,
+    )
+        where FB: Framebuffer,
+              Self::Ray: Send + Sync,
+              Self::Payload: Send,
+              Self::RayAttr: Send + Sync,
+              Self::Material: Sync,
+    {
+        use crate::par::*;
+        let w = framebuf.width();
+        let h = framebuf.height();
+        self.fill_hit_cache(cache, w, h);
+        let cache = &*cache;
+        let order = morton_order(w, h);
+        let colors = order.par_iter()
+            .map(|&(x, y)| {
+                let (ray, hit) = cache.get(x, y).expect("the cache holds every pixel");
+                let hit = hit.map(|x| (x.obj, &x.tri, &x.intersect));
+                let (_, mut payload) = self.primary_ray(x, y, w, h);
+                self.pixel((x, y, w, h), ray, hit, &mut payload, |nx, ny| {
+                    let (ray, hit) = cache.get(nx, ny)?;
+                    hit.map(|x| sight_of(ray, x.obj, &x.tri, x.intersect.t))
+                })
+            })
+            .collect::<Vec<_>>();
+        for (&(x, y), color) in order.iter().zip(colors) {
+            framebuf.store(x, y, color);
+        }
+    }
+    /// Frames are drawn in full, since edges of pixels depend on their
+    /// neighbors across tiles.
+    fn draw_wavefront_incremental<FB>(
+        &self,
+        framebuf: &mut FB,
+        cache: &mut HitCache<Ray, Barycentric>,
+        changes: &SceneChanges,
+    ) -> usize
+        where FB: Framebuffer,
+              Self::Ray: Send + Sync,
+              Self::Payload: Send,
+              Self::RayAttr: Send + Sync,
+              Self::Material: Sync,
+    {
+        if changes.has_geometry() {
+            cache.clear();
+        }
+        self.draw_wavefront_cached(framebuf, cache);
+        let ntile_x = (framebuf.width() as usize).div_ceil(TILE_SIZE);
+        let ntile_y = (framebuf.height() as usize).div_ceil(TILE_SIZE);
+        ntile_x * ntile_y
+    }
+}
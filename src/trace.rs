@@ -0,0 +1,98 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Severity of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+}
+
+/// Receiver of renderer activity, installed with `set_subscriber`. Activities
+/// are spans with a name like `bvh_build` or `tile`, an optional detail like
+/// the tile index, and the time they took. Without a subscriber nothing is
+/// recorded and spans cost next to nothing.
+pub trait Subscriber : Send + Sync {
+    /// Whether spans named `name` are of interest. Details of spans not of
+    /// interest are never formatted.
+    fn enabled(&self, _name: &'static str) -> bool { true }
+    /// A span named `name` has ended after `elapsed`.
+    fn exit(&self, name: &'static str, detail: &str, elapsed: Duration);
+    /// A message outside of spans.
+    fn event(&self, _level: Level, _msg: &str) {}
+}
+
+static SUBSCRIBER: OnceLock<Box<dyn Subscriber>> = OnceLock::new();
+
+/// Install the process-wide subscriber. Only the first call takes effect;
+/// returns whether this call did.
+pub fn set_subscriber<S: Subscriber + 'static>(subscriber: S) -> bool {
+    SUBSCRIBER.set(Box::new(subscriber)).is_ok()
+}
+
+/// Guard of an activity in progress, reported to the subscriber on drop.
+#[must_use = "the span ends as soon as it's dropped"]
+pub struct Span {
+    name: &'static str,
+    detail: String,
+    /// `None` if no subscriber is interested.
+    start: Option<Instant>,
+}
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(start), Some(sub)) = (self.start, SUBSCRIBER.get()) {
+            sub.exit(self.name, &self.detail, start.elapsed());
+        }
+    }
+}
+
+/// Start a span named `name`, ending when the returned guard is dropped.
+pub fn span(name: &'static str) -> Span {
+    span_with(name, String::new)
+}
+/// Same as `span` with a detail, only formatted if the span is of interest.
+pub fn span_with<F: FnOnce() -> String>(name: &'static str, detail: F) -> Span {
+    match SUBSCRIBER.get() {
+        Some(sub) if sub.enabled(name) => {
+            Span { name, detail: detail(), start: Some(Instant::now()) }
+        },
+        _ => Span { name, detail: String::new(), start: None },
+    }
+}
+/// Report a message to the subscriber, if any.
+pub fn event(level: Level, msg: &str) {
+    if let Some(sub) = SUBSCRIBER.get() {
+        sub.event(level, msg);
+    }
+}
+
+/// Subscriber printing spans and events of at least `level` to standard
+/// error. Spans are printed at the debug level, except for those taking at
+/// least `slow`, which are printed at the info level.
+#[derive(Debug, Clone)]
+pub struct StderrSubscriber {
+    pub level: Level,
+    pub slow: Duration,
+}
+impl Default for StderrSubscriber {
+    fn default() -> StderrSubscriber {
+        StderrSubscriber { level: Level::Info, slow: Duration::from_secs(1) }
+    }
+}
+impl Subscriber for StderrSubscriber {
+    fn exit(&self, name: &'static str, detail: &str, elapsed: Duration) {
+        let level = if elapsed >= self.slow { Level::Info } else { Level::Debug };
+        if level < self.level { return }
+        if detail.is_empty() {
+            eprintln!("[{:?}] {} took {:.3}s", level, name, elapsed.as_secs_f64());
+        } else {
+            eprintln!("[{:?}] {} ({}) took {:.3}s", level, name, detail, elapsed.as_secs_f64());
+        }
+    }
+    fn event(&self, level: Level, msg: &str) {
+        if level >= self.level {
+            eprintln!("[{:?}] {}", level, msg);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::geom::{Real, Color};
+use crate::post::{ColorGrading, luminance};
+
+/// Unit of length of imported assets. Scenes are in meters, which physical
+/// units like `radiance_from_lumens` and the falloff of lights assume, so
+/// assets authored in other units are scaled into meters on import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthUnit {
+    #[default]
+    Meters,
+    Centimeters,
+    Millimeters,
+    Inches,
+    Feet,
+}
+impl LengthUnit {
+    /// Length of the unit in meters.
+    pub fn meters(self) -> Real {
+        match self {
+            LengthUnit::Meters => 1.0,
+            LengthUnit::Centimeters => 0.01,
+            LengthUnit::Millimeters => 0.001,
+            LengthUnit::Inches => 0.0254,
+            LengthUnit::Feet => 0.3048,
+        }
+    }
+    /// Factor converting lengths in this unit to `unit`.
+    pub fn to(self, unit: LengthUnit) -> Real {
+        self.meters() / unit.meters()
+    }
+}
+
+/// Lumens per watt of monochromatic light at 555 nm, where the eye is the most
+/// sensitive. Radiometric quantities in the renderer are converted to and
+/// from photometric ones by this factor.
+pub const LUMINOUS_EFFICACY: f32 = 683.0;
+
+/// Scale `color` so that its luminance is `lum` in photometric units, divided
+/// by `LUMINOUS_EFFICACY`. Black stays black.
+fn with_luminance(color: Color, lum: f32) -> Color {
+    let l = luminance(color);
+    if l <= 0.0 { return Color(0.0, 0.0, 0.0, color.3) }
+    let k = lum / (LUMINOUS_EFFICACY * l);
+    Color(color.0 * k, color.1 * k, color.2 * k, color.3)
+}
+
+/// Radiance of a surface of tint `color` emitting `nits` candela per square
+/// meter, e.g., for the emission of materials.
+pub fn radiance_from_nits(color: Color, nits: f32) -> Color {
+    with_luminance(color, nits)
+}
+/// Radiance of a diffuse emitter of tint `color` and area `area` in square
+/// meters emitting `lumens` in total.
+pub fn radiance_from_lumens(color: Color, lumens: f32, area: f32) -> Color {
+    use std::f32::consts::PI;
+    with_luminance(color, lumens / (PI * area))
+}
+/// Radiant intensity of a point light of tint `color` with luminous intensity
+/// `candela`.
+pub fn intensity_from_candela(color: Color, candela: f32) -> Color {
+    with_luminance(color, candela)
+}
+
+/// Exposure settings of a physical camera.
+///
+/// See: Sébastien Lagarde and Charles de Rousiers, Moving Frostbite to
+/// Physically Based Rendering.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraExposure {
+    /// Sensor sensitivity in ISO.
+    pub iso: f32,
+    /// Shutter time in seconds.
+    pub shutter: f32,
+    /// Relative aperture, i.e., the f-number.
+    pub f_number: f32,
+}
+impl Default for CameraExposure {
+    /// The sunny 16 rule: f/16 at 1/100s for ISO 100.
+    fn default() -> CameraExposure {
+        CameraExposure { iso: 100.0, shutter: 0.01, f_number: 16.0 }
+    }
+}
+impl CameraExposure {
+    /// Exposure value at ISO 100.
+    pub fn ev100(&self) -> f32 {
+        (self.f_number * self.f_number / self.shutter * 100.0 / self.iso).log2()
+    }
+    /// Factor mapping radiance to pixel values, so that the luminance
+    /// saturating the sensor maps to 1.
+    pub fn scale(&self) -> f32 {
+        let max_luminance = 1.2 * self.ev100().exp2();
+        LUMINOUS_EFFICACY / max_luminance
+    }
+    /// Color grading applying this exposure.
+    pub fn grading(&self) -> ColorGrading {
+        ColorGrading { exposure: self.scale().log2(), ..ColorGrading::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Color = Color(1.0, 1.0, 1.0, 1.0);
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-5 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn candela_to_intensity() {
+        let x = intensity_from_candela(WHITE, 1.0);
+        assert_close(x.0, 1.0 / 683.0);
+        assert_close(x.1, 1.0 / 683.0);
+        assert_close(x.2, 1.0 / 683.0);
+        assert_close(x.3, 1.0);
+    }
+    #[test]
+    fn nits_keep_tint() {
+        let tint = Color(1.0, 0.5, 0.25, 1.0);
+        let x = radiance_from_nits(tint, 500.0);
+        assert_close(luminance(x) * LUMINOUS_EFFICACY, 500.0);
+        assert_close(x.1 / x.0, 0.5);
+        assert_close(x.2 / x.0, 0.25);
+        let black = radiance_from_nits(Color(0.0, 0.0, 0.0, 1.0), 500.0);
+        assert_eq!((black.0, black.1, black.2), (0.0, 0.0, 0.0));
+    }
+    #[test]
+    fn lumens_spread_over_area() {
+        // A lambertian emitter of radiance L emits pi * L * area.
+        let lumens = std::f32::consts::PI * LUMINOUS_EFFICACY * 2.0;
+        let x = radiance_from_lumens(WHITE, lumens, 2.0);
+        assert_close(luminance(x), 1.0);
+    }
+    #[test]
+    fn sunny_16_exposure() {
+        let exposure = CameraExposure::default();
+        assert_close(exposure.ev100(), 25600.0_f32.log2());
+        assert_close(exposure.scale(), 683.0 / (1.2 * 25600.0));
+        // Doubling the sensitivity or the shutter time doubles the scale.
+        let iso = CameraExposure { iso: 200.0, ..exposure };
+        let shutter = CameraExposure { shutter: 0.02, ..exposure };
+        assert_close(iso.scale(), 2.0 * exposure.scale());
+        assert_close(shutter.scale(), 2.0 * exposure.scale());
+        assert_close(exposure.grading().exposure, exposure.scale().log2());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::geom::{Real, Point, Vector, Ray, offset_ray_origin};
+use crate::rt::RayTracer;
+use crate::par::*;
+
+/// Whether `a` and `b` see each other, i.e., no object casting shadows is
+/// between them. Points on surfaces should be lifted off them first with
+/// `offset_ray_origin`, or they are blocked by their own surfaces.
+pub fn visible<T>(rt: &T, a: Point, b: Point) -> bool
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    let d = b.rel_from(a);
+    let dist = d.mag();
+    if dist == 0.0 { return true }
+    let ray = Ray { o: a, v: d / dist };
+    !rt.occluded_within(ray, dist, &mut T::Payload::default())
+}
+
+/// Mutual visibility between two sets of points, see `visibility_matrix`.
+#[derive(Debug, Clone)]
+pub struct VisibilityMatrix {
+    nrow: usize,
+    ncol: usize,
+    /// Row-major bits, each row padded to whole words.
+    bits: Vec<u64>,
+}
+impl VisibilityMatrix {
+    fn words_per_row(ncol: usize) -> usize {
+        ncol.div_ceil(64)
+    }
+    pub fn nrow(&self) -> usize {
+        self.nrow
+    }
+    pub fn ncol(&self) -> usize {
+        self.ncol
+    }
+    /// Whether the `i`-th source point sees the `j`-th target point.
+    pub fn get(&self, i: usize, j: usize) -> bool {
+        assert!(i < self.nrow && j < self.ncol, "visibility matrix index out of range");
+        let word = self.bits[i * Self::words_per_row(self.ncol) + j / 64];
+        word & (1 << (j % 64)) != 0
+    }
+    /// Number of target points the `i`-th source point sees.
+    pub fn count_row(&self, i: usize) -> usize {
+        let nword = Self::words_per_row(self.ncol);
+        self.bits[i * nword..(i + 1) * nword].iter()
+            .map(|x| x.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Test every point in `from` against every point in `to` with `visible`,
+/// e.g., for line-of-sight queries between agents. Rows are computed in
+/// parallel.
+pub fn visibility_matrix<T>(rt: &T, from: &[Point], to: &[Point]) -> VisibilityMatrix
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+{
+    let nword = VisibilityMatrix::words_per_row(to.len());
+    let rows = from.par_iter()
+        .map(|&a| {
+            let mut row = vec![0u64; nword];
+            for (j, &b) in to.iter().enumerate() {
+                if visible(rt, a, b) {
+                    row[j / 64] |= 1 << (j % 64);
+                }
+            }
+            row
+        })
+        .collect::<Vec<_>>();
+    VisibilityMatrix {
+        nrow: from.len(),
+        ncol: to.len(),
+        bits: rows.concat(),
+    }
+}
+
+/// A parallelogram of surface facing the side of `x` cross `y`, e.g., a
+/// radiosity patch.
+#[derive(Debug, Clone)]
+pub struct Patch {
+    /// A corner of the patch.
+    pub o: Point,
+    /// First edge from `o`.
+    pub x: Vector,
+    /// Second edge from `o`.
+    pub y: Vector,
+}
+impl Patch {
+    pub fn area(&self) -> Real {
+        self.x.cross(self.y).mag()
+    }
+    /// Unit normal vector.
+    pub fn normal(&self) -> Vector {
+        self.x.cross(self.y).normalize()
+    }
+    /// Point at parametric coordinates `a` and `b` in [0..1].
+    pub fn point(&self, a: Real, b: Real) -> Point {
+        self.o.affine_add(a * self.x + b * self.y)
+    }
+}
+
+/// Estimate the form factor from patch `from` to patch `to`, the fraction of
+/// light leaving `from` diffusely that arrives at `to`, with `nsample` pairs
+/// of points on the patches. Occlusion is taken into account with `visible`.
+/// `rng` returns uniform random numbers in [0..1).
+pub fn form_factor<T, R>(rt: &T, from: &Patch, to: &Patch, nsample: u32, rng: &mut R) -> Real
+    where T: RayTracer<Ray = Ray>,
+          T::Payload: Default,
+          R: FnMut() -> Real,
+{
+    const PI: Real = std::f64::consts::PI as Real;
+    let (n1, n2) = (from.normal(), to.normal());
+    let mut sum = 0.0;
+    for _ in 0..nsample {
+        let p1 = from.point(rng(), rng());
+        let p2 = to.point(rng(), rng());
+        let d = p2.rel_from(p1);
+        let dist2 = d.dot(d);
+        if dist2 == 0.0 { continue }
+        let v = d / dist2.sqrt();
+        let (cos1, cos2) = (n1.dot(v), -n2.dot(v));
+        if cos1 <= 0.0 || cos2 <= 0.0 { continue }
+        if visible(rt, offset_ray_origin(p1, n1), offset_ray_origin(p2, n2)) {
+            sum += cos1 * cos2 / (PI * dist2);
+        }
+    }
+    sum * to.area() / nsample.max(1) as Real
+}
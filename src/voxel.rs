@@ -0,0 +1,422 @@
+use std::io::Read;
+use std::path::Path;
+use crate::geom::{Real, Point, Vector, Ray, Color, Transform, Precision};
+use crate::rt::{Intersection, HitKind};
+use crate::bvh::Aabb;
+use crate::scene::{Object, Shape, ShapeHit};
+
+/// Edge length of a brick in voxels.
+const BRICK: usize = 8;
+const BRICK_VOLUME: usize = BRICK * BRICK * BRICK;
+
+/// Attributes of a ray hitting a voxel.
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelHit {
+    /// Integer coordinates of the voxel.
+    pub cell: [usize; 3],
+    /// Unit normal of the face the ray entered through.
+    pub n: Vector,
+    /// Palette index of the voxel.
+    pub value: u8,
+}
+
+/// Sparse grid of cubic voxels. Voxels are stored in bricks of `BRICK` cubed
+/// voxels, and bricks without any solid voxel are not allocated. Each voxel is
+/// a palette index where 0 means empty. Grids can be cast to directly, and are
+/// traced with the scene as objects made by `into_object`.
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    /// Corner of voxel `[0, 0, 0]`.
+    pub origin: Point,
+    /// Edge length of a voxel.
+    pub size: Real,
+    /// Colors of palette indices. Index 0 is unused.
+    pub palette: Vec<Color>,
+    dims: [usize; 3],
+    nbrick: [usize; 3],
+    bricks: Vec<Option<Box<[u8; BRICK_VOLUME]>>>,
+}
+impl VoxelGrid {
+    pub fn new(dims: [usize; 3], origin: Point, size: Real) -> VoxelGrid {
+        let nbrick = [
+            dims[0].div_ceil(BRICK),
+            dims[1].div_ceil(BRICK),
+            dims[2].div_ceil(BRICK),
+        ];
+        let bricks = vec![None; nbrick[0] * nbrick[1] * nbrick[2]];
+        let palette = vec![Color(1.0, 1.0, 1.0, 1.0); 256];
+        VoxelGrid { origin, size, palette, dims, nbrick, bricks }
+    }
+    pub fn dims(&self) -> [usize; 3] {
+        self.dims
+    }
+    pub fn bounds(&self) -> Aabb {
+        let ext = |i: usize| self.dims[i] as Real * self.size;
+        Aabb {
+            min: self.origin,
+            max: self.origin.affine_add(Vector(ext(0), ext(1), ext(2))),
+        }
+    }
+    #[inline]
+    fn locate(&self, cell: [usize; 3]) -> (usize, usize) {
+        let [x, y, z] = cell;
+        let ibrick = ((z / BRICK) * self.nbrick[1] + y / BRICK) * self.nbrick[0] + x / BRICK;
+        let ivox = ((z % BRICK) * BRICK + y % BRICK) * BRICK + x % BRICK;
+        (ibrick, ivox)
+    }
+    /// Palette index of voxel `cell`, 0 if it's empty or out of the grid.
+    #[inline]
+    pub fn get(&self, cell: [usize; 3]) -> u8 {
+        if (0..3).any(|i| cell[i] >= self.dims[i]) { return 0 }
+        let (ibrick, ivox) = self.locate(cell);
+        self.bricks[ibrick].as_ref().map_or(0, |x| x[ivox])
+    }
+    /// Set voxel `cell` to palette index `value`. Voxels out of the grid are
+    /// ignored.
+    pub fn set(&mut self, cell: [usize; 3], value: u8) {
+        if (0..3).any(|i| cell[i] >= self.dims[i]) { return }
+        let (ibrick, ivox) = self.locate(cell);
+        match &mut self.bricks[ibrick] {
+            Some(brick) => brick[ivox] = value,
+            None if value == 0 => {},
+            brick @ None => {
+                let mut x = Box::new([0; BRICK_VOLUME]);
+                x[ivox] = value;
+                *brick = Some(x);
+            },
+        }
+    }
+
+    /// Cast a ray to the grid and return the first solid voxel it hits. Voxels
+    /// are walked along the ray with a 3D DDA, so the cost is proportional to
+    /// the number of voxels passed rather than the number of solid ones.
+    ///
+    /// See: John Amanatides and Andrew Woo, A Fast Voxel Traversal Algorithm
+    /// for Ray Tracing.
+    pub fn ray_cast(&self, ray: &Ray) -> Option<Intersection<VoxelHit>> {
+        self.ray_cast_within(ray, 0.0, Real::INFINITY)
+    }
+    /// Same as `ray_cast` but only hitting voxels entered within parametric
+    /// distances `tmin` and `tmax`, e.g., so that rays leaving a voxel skip it.
+    pub fn ray_cast_within(
+        &self,
+        ray: &Ray,
+        tmin: Real,
+        tmax: Real,
+    ) -> Option<Intersection<VoxelHit>> {
+        if self.dims.contains(&0) { return None }
+        let bounds = self.bounds();
+        let (t0, t1) = bounds.clip(ray)?;
+        let (tstart, tmax) = (t0, t1.min(tmax));
+        let o = [ray.o.0, ray.o.1, ray.o.2];
+        let v = [ray.v.0, ray.v.1, ray.v.2];
+        let min = [bounds.min.0, bounds.min.1, bounds.min.2];
+        let max = [bounds.max.0, bounds.max.1, bounds.max.2];
+        // The axis of the face the ray enters the grid through.
+        let mut axis = 0;
+        let mut tenter = -Real::INFINITY;
+        for i in 0..3 {
+            if v[i] == 0.0 { continue }
+            let t = ((if v[i] > 0.0 { min[i] } else { max[i] }) - o[i]) / v[i];
+            if t > tenter { tenter = t; axis = i; }
+        }
+        let mut cell = [0; 3];
+        let mut step = [0isize; 3];
+        let mut tnext = [Real::INFINITY; 3];
+        let mut tdelta = [Real::INFINITY; 3];
+        for i in 0..3 {
+            let p = o[i] + tstart * v[i];
+            let c = ((p - min[i]) / self.size).floor();
+            cell[i] = (c.max(0.0) as usize).min(self.dims[i] - 1);
+            if v[i] > 0.0 {
+                step[i] = 1;
+                let edge = min[i] + (cell[i] + 1) as Real * self.size;
+                tnext[i] = (edge - o[i]) / v[i];
+                tdelta[i] = self.size / v[i];
+            } else if v[i] < 0.0 {
+                step[i] = -1;
+                let edge = min[i] + cell[i] as Real * self.size;
+                tnext[i] = (edge - o[i]) / v[i];
+                tdelta[i] = -self.size / v[i];
+            }
+        }
+        let mut t = tstart;
+        loop {
+            let value = self.get(cell);
+            if value != 0 && t >= tmin {
+                let mut n = [0.0; 3];
+                n[axis] = -step[axis].signum() as Real;
+                let n = Vector(n[0], n[1], n[2]);
+                let attr = VoxelHit { cell, n, value };
+                return Some(Intersection { attr, kind: HitKind::Front, t, prim: 0 });
+            }
+            axis = if tnext[0] < tnext[1] {
+                if tnext[0] < tnext[2] { 0 } else { 2 }
+            } else if tnext[1] < tnext[2] { 1 } else { 2 };
+            t = tnext[axis];
+            if t > tmax { return None }
+            let c = cell[axis] as isize + step[axis];
+            if c < 0 || c as usize >= self.dims[axis] { return None }
+            cell[axis] = c as usize;
+            tnext[axis] += tdelta[axis];
+        }
+    }
+
+    /// Object of the voxels placed in the world by `world2obj`, traced
+    /// natively with the DDA of `ray_cast` and colored by the palette, see
+    /// `Object::colors`.
+    pub fn into_object<M>(self, mat: M, world2obj: Transform) -> Object<M> {
+        let colors = self.palette.clone();
+        Object::from_shape(self, mat, world2obj).with_colors(colors)
+    }
+}
+/// Voxels are hit on the faces rays enter them through. Hits are indexed by
+/// palette index, see `ShapeHit::prim`.
+impl Shape for VoxelGrid {
+    fn bounds(&self) -> Aabb {
+        VoxelGrid::bounds(self)
+    }
+    fn ray_cast(&self, ray: &Ray, precision: &Precision) -> Option<ShapeHit> {
+        let x = self.ray_cast_within(ray, precision.ray_epsilon, precision.max_t)?;
+        let n = x.attr.n;
+        let a = if n.0 == 0.0 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+        let tangent = n.cross(a).cross(n).normalize();
+        Some(ShapeHit { t: x.t, kind: HitKind::Front, prim: x.attr.value as usize, n, tangent })
+    }
+}
+
+/// Error loading voxel models from files.
+#[derive(Debug)]
+pub enum VoxError {
+    Io(std::io::Error),
+    /// The file is malformed.
+    Parse(String),
+}
+impl std::fmt::Display for VoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoxError::Io(e) => write!(f, "failed to read voxel model: {}", e),
+            VoxError::Parse(msg) => write!(f, "malformed vox file: {}", msg),
+        }
+    }
+}
+impl std::error::Error for VoxError {}
+impl From<std::io::Error> for VoxError {
+    fn from(e: std::io::Error) -> VoxError {
+        VoxError::Io(e)
+    }
+}
+
+/// Load the first model in a MagicaVoxel `.vox` file into a grid of voxel size
+/// `size` with its corner at `origin`. Coordinates are kept as they are in the
+/// file, where z is up. Files without a palette get a white one rather than
+/// the default palette of MagicaVoxel.
+pub fn load_vox<P: AsRef<Path>>(path: P, origin: Point, size: Real) -> Result<VoxelGrid, VoxError> {
+    let mut buf = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut buf)?;
+    parse_vox(&buf, origin, size)
+}
+fn parse_vox(buf: &[u8], origin: Point, size: Real) -> Result<VoxelGrid, VoxError> {
+    let err = |msg: &str| VoxError::Parse(msg.to_owned());
+    let u32_at = |i: usize| -> Result<u32, VoxError> {
+        let bytes = buf.get(i..i + 4).ok_or_else(|| err("unexpected end of file"))?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    if buf.get(0..4) != Some(b"VOX ") {
+        return Err(err("missing magic number"));
+    }
+    let mut dims = None;
+    let mut grid = None;
+    let mut palette = None;
+    // Chunks are flattened, children of `MAIN` simply follow its header.
+    let mut i = 8;
+    while i + 12 <= buf.len() {
+        let id = &buf[i..i + 4];
+        let len = u32_at(i + 4)? as usize;
+        let content = buf.get(i + 12..i + 12 + len)
+            .ok_or_else(|| err("truncated chunk"))?;
+        match id {
+            b"MAIN" => { i += 12; continue },
+            b"SIZE" if dims.is_none() => {
+                let d = |j: usize| u32_at(i + 12 + j * 4).map(|x| x as usize);
+                dims = Some([d(0)?, d(1)?, d(2)?]);
+            },
+            b"XYZI" if grid.is_none() => {
+                let dims = dims.ok_or_else(|| err("voxels before size"))?;
+                let mut g = VoxelGrid::new(dims, origin, size);
+                let n = u32_at(i + 12)? as usize;
+                let voxels = content.get(4..4 + n * 4)
+                    .ok_or_else(|| err("truncated voxels"))?;
+                for v in voxels.chunks_exact(4) {
+                    g.set([v[0] as usize, v[1] as usize, v[2] as usize], v[3]);
+                }
+                grid = Some(g);
+            },
+            b"RGBA" => {
+                let rgba = content.get(..1024).ok_or_else(|| err("truncated palette"))?;
+                // Color `i` of the chunk is that of palette index `i + 1`.
+                let mut colors = vec![Color::default(); 256];
+                for (j, c) in rgba.chunks_exact(4).take(255).enumerate() {
+                    colors[j + 1] = [c[0], c[1], c[2], c[3]].into();
+                }
+                palette = Some(colors);
+            },
+            _ => {},
+        }
+        let children = u32_at(i + 8)? as usize;
+        i += 12 + len + children;
+    }
+    let mut grid = grid.ok_or_else(|| err("no model"))?;
+    if let Some(palette) = palette {
+        grid.palette = palette;
+    }
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Scene;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// The closest solid voxel `ray` enters, by clipping it to every voxel.
+    fn brute_force(grid: &VoxelGrid, ray: &Ray) -> Option<Real> {
+        let [nx, ny, nz] = grid.dims();
+        let mut closest = None;
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    if grid.get([x, y, z]) == 0 { continue }
+                    let cell = Vector(x as Real, y as Real, z as Real);
+                    let min = grid.origin.affine_add(grid.size * cell);
+                    let max = min.affine_add(Vector(grid.size, grid.size, grid.size));
+                    if let Some((t, _)) = (Aabb { min, max }).clip(ray) {
+                        if closest.is_none_or(|x| t < x) { closest = Some(t) }
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    fn random_grid(rng: &mut StdRng) -> VoxelGrid {
+        let mut grid = VoxelGrid::new([10, 7, 12], Point(-1.0, -0.5, 0.25), 0.3);
+        for z in 0..12 {
+            for y in 0..7 {
+                for x in 0..10 {
+                    if rng.gen::<Real>() < 0.05 {
+                        grid.set([x, y, z], rng.gen_range(1, 256) as u8);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn assert_matches(grid: &VoxelGrid, ray: &Ray) {
+        let hit = grid.ray_cast(ray);
+        match (hit, brute_force(grid, ray)) {
+            (Some(x), Some(t)) => {
+                assert!((x.t - t).abs() < 1e-4, "{:?} {} {}", ray, x.t, t);
+                assert_eq!(grid.get(x.attr.cell), x.attr.value);
+            },
+            (None, None) => {},
+            (x, t) => panic!("{:?} {:?} {:?}", ray, x.map(|x| x.t), t),
+        }
+    }
+
+    #[test]
+    fn dda_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(0x0076_6f78);
+        let grid = random_grid(&mut rng);
+        let point = |rng: &mut StdRng| {
+            Point(rng.gen_range(-3.0, 4.0), rng.gen_range(-3.0, 4.0), rng.gen_range(-3.0, 6.0))
+        };
+        for _ in 0..2000 {
+            let o = point(&mut rng);
+            let v = point(&mut rng).rel_from(o);
+            assert_matches(&grid, &Ray { o, v });
+        }
+    }
+
+    #[test]
+    fn dda_axis_parallel_and_boundary_rays() {
+        let mut rng = StdRng::seed_from_u64(0x0062_6f78);
+        let grid = random_grid(&mut rng);
+        let axes = [Vector(1.0, 0.0, 0.0), Vector(0.0, 1.0, 0.0), Vector(0.0, 0.0, 1.0)];
+        for _ in 0..500 {
+            let axis = axes[rng.gen_range(0, 3)];
+            let v = if rng.gen::<bool>() { axis } else { -axis };
+            // Through the middle of the voxels, and along the faces between
+            // them and the faces of the grid.
+            let (a, b) = (rng.gen_range(0, 8) as Real, rng.gen_range(0, 8) as Real);
+            let (a, b) = if rng.gen::<bool>() { (a + 0.5, b + 0.5) } else { (a, b) };
+            let across = if axis.0 != 0.0 {
+                Vector(0.0, a, b)
+            } else if axis.1 != 0.0 {
+                Vector(a, 0.0, b)
+            } else {
+                Vector(a, b, 0.0)
+            };
+            let o = grid.origin.affine_add(grid.size * across).affine_sub(10.0 * v);
+            let ray = Ray { o, v };
+            let hit = grid.ray_cast(&ray);
+            if a.fract() != 0.0 {
+                assert_matches(&grid, &ray);
+            } else if let Some(x) = hit {
+                // Rays along faces graze the voxels on both sides but only
+                // walk those on one side, whichever they hit is hit on its
+                // surface.
+                let [cx, cy, cz] = x.attr.cell;
+                let cell = Vector(cx as Real, cy as Real, cz as Real);
+                let min = grid.origin.affine_add(grid.size * cell);
+                let max = min.affine_add(Vector(grid.size, grid.size, grid.size));
+                let p = ray.o.affine_add(x.t * ray.v);
+                let inside = |p: Real, min: Real, max: Real| p >= min - 1e-4 && p <= max + 1e-4;
+                assert!(inside(p.0, min.0, max.0));
+                assert!(inside(p.1, min.1, max.1));
+                assert!(inside(p.2, min.2, max.2));
+                assert_eq!(grid.get(x.attr.cell), x.attr.value);
+            }
+        }
+        // Rays starting on the grid boundary and inside the grid, where rays
+        // leaving the grid touch the voxels they start on.
+        let mut grid = VoxelGrid::new([4, 4, 4], Point(0.0, 0.0, 0.0), 1.0);
+        grid.set([3, 1, 1], 1);
+        let v = Vector(1.0, 0.0, 0.0);
+        assert_matches(&grid, &Ray { o: Point(0.0, 1.5, 1.5), v });
+        assert_matches(&grid, &Ray { o: Point(2.0, 1.5, 1.5), v });
+        let out = Ray { o: Point(4.0, 1.5, 1.5), v };
+        assert_matches(&grid, &out);
+        assert!(grid.ray_cast_within(&out, 1e-6, Real::INFINITY).is_none());
+        // Rays leaving a voxel skip it.
+        let leaving = Ray { o: Point(3.0, 1.5, 1.5), v: -v };
+        assert!(grid.ray_cast(&leaving).is_some());
+        assert!(grid.ray_cast_within(&leaving, 1e-6, Real::INFINITY).is_none());
+    }
+
+    #[test]
+    fn voxels_in_scene() {
+        let mut grid = VoxelGrid::new([3, 3, 3], Point(0.0, 0.0, 0.0), 0.5);
+        grid.palette[1] = Color(1.0, 0.0, 0.0, 1.0);
+        grid.palette[2] = Color(0.0, 1.0, 0.0, 1.0);
+        grid.set([1, 1, 1], 1);
+        grid.set([2, 1, 1], 2);
+        let scene = Scene::new(vec![grid.into_object((), Transform::eye())]);
+        let rays = [
+            (Point(0.7, 0.7, -5.0), Vector(0.0, 0.0, 1.0), 5.5, 0, Vector(0.0, 0.0, -1.0)),
+            (Point(1.2, 5.0, 0.7), Vector(0.0, -1.0, 0.0), 4.0, 1, Vector(0.0, 1.0, 0.0)),
+            (Point(5.0, 0.7, 0.7), Vector(-1.0, 0.0, 0.0), 3.5, 1, Vector(1.0, 0.0, 0.0)),
+            (Point(-5.0, 0.7, 0.8), Vector(1.0, 0.0, 0.0), 5.5, 0, Vector(-1.0, 0.0, 0.0)),
+        ];
+        for &(o, v, t, channel, n) in rays.iter() {
+            let hit = scene.raycast(&Ray { o, v }).unwrap();
+            assert!(hit.front);
+            assert!((hit.t - t).abs() < 1e-4);
+            assert!(hit.n.dot(n) > 0.999);
+            let color = scene.objs[0].color(hit.tri).unwrap();
+            assert_eq!(if channel == 0 { color.0 } else { color.1 }, 1.0);
+        }
+    }
+}
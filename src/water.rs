@@ -0,0 +1,150 @@
+//! Animated water surfaces. Flat geometry is shaded with the normals of a
+//! height field of waves moving with time, and light is split between
+//! reflection and refraction by the Fresnel reflectance at those normals.
+//! Waves are a sum of sines whose directions, lengths and phases are decided
+//! by a seed, and which travel at the speed of deep water waves of their
+//! length, so that frames rendered at increasing times line up into an
+//! animation.
+use crate::geom::{Real, Point, Vector, Ray, Triangle, Color, Barycentric, reflect, offset_ray_origin};
+use crate::rt::Intersection;
+use crate::integrator::{Scatter, Lobe};
+use crate::optics::{fresnel_dielectric, refract};
+use crate::noise::hash2;
+use crate::rng;
+
+/// Gravitational acceleration in scene units per second squared, taking
+/// scene units as meters.
+const GRAVITY: Real = 9.81;
+
+/// Parameters of an animated water surface, see the module documentation.
+#[derive(Debug, Clone, Copy)]
+pub struct Water {
+    /// Index of refraction of the water.
+    pub ior: Real,
+    /// Amplitude of the longest waves. Shorter ones are proportionally
+    /// lower.
+    pub height: Real,
+    /// Length of the longest waves. The others are down to 1/16 of it.
+    pub wavelength: Real,
+    /// Direction the waves mostly travel in, projected onto the surface.
+    pub wind: Vector,
+    /// Number of waves summed.
+    pub nwave: u32,
+    /// Time in seconds the waves are seen at.
+    pub time: Real,
+    /// Seed of the random directions and phases of the waves.
+    pub seed: u32,
+}
+impl Default for Water {
+    fn default() -> Water {
+        Water {
+            ior: 1.33,
+            height: 0.02,
+            wavelength: 1.0,
+            wind: Vector(1.0, 0.0, 0.0),
+            nwave: 16,
+            time: 0.0,
+            seed: 0,
+        }
+    }
+}
+impl Water {
+    /// Unit tangents of a surface of unit normal `n` along and across the
+    /// wind, the same for every triangle of a flat surface.
+    fn tangents(&self, n: Vector) -> (Vector, Vector) {
+        let along = self.wind - n * self.wind.dot(n);
+        let along = if along.mag() > 1e-6 {
+            along.normalize()
+        } else {
+            let up = if n.0.abs() < 0.9 { Vector(1.0, 0.0, 0.0) } else { Vector(0.0, 1.0, 0.0) };
+            up.cross(n).normalize()
+        };
+        (along, n.cross(along))
+    }
+    /// Wave number, unit direction in tangent coordinates, amplitude and
+    /// phase of the `i`-th wave.
+    fn wave(&self, i: u32) -> (Real, (Real, Real), Real, Real) {
+        const PI: Real = std::f64::consts::PI as Real;
+        let rand = |j: i32| (hash2(self.seed as i32 ^ ((i as i32) << 8), j) >> 8) as Real / (1 << 24) as Real;
+        // Wave lengths spread evenly in log scale over 4 octaves.
+        let wavelength = self.wavelength * (-4.0 * i as Real / self.nwave.max(1) as Real).exp2();
+        let k = 2.0 * PI / wavelength;
+        // Within 60 degrees of the wind.
+        let angle = (rand(0) * 2.0 - 1.0) * PI / 3.0;
+        let amplitude = self.height * wavelength / self.wavelength;
+        (k, (angle.cos(), angle.sin()), amplitude, rand(1) * 2.0 * PI)
+    }
+    /// Height of the surface of unit normal `n` at `p` along `n`.
+    pub fn height_at(&self, p: Point, n: Vector) -> Real {
+        let (t, b) = self.tangents(n);
+        let d = Vector(p.0, p.1, p.2);
+        let (x, y) = (d.dot(t), d.dot(b));
+        (0..self.nwave)
+            .map(|i| {
+                let (k, (dx, dy), a, phase) = self.wave(i);
+                let omega = (GRAVITY * k).sqrt();
+                a * (k * (dx * x + dy * y) - omega * self.time + phase).sin()
+            })
+            .sum()
+    }
+    /// Unit normal of the waves at `p` of a surface of unit normal `n`.
+    pub fn normal_at(&self, p: Point, n: Vector) -> Vector {
+        let (t, b) = self.tangents(n);
+        let d = Vector(p.0, p.1, p.2);
+        let (x, y) = (d.dot(t), d.dot(b));
+        let (mut gx, mut gy) = (0.0, 0.0);
+        for i in 0..self.nwave {
+            let (k, (dx, dy), a, phase) = self.wave(i);
+            let omega = (GRAVITY * k).sqrt();
+            let c = a * k * (k * (dx * x + dy * y) - omega * self.time + phase).cos();
+            gx += c * dx;
+            gy += c * dy;
+        }
+        (n - t * gx - b * gy).normalize()
+    }
+
+    /// Sample how a path continues where `ray` hit `tri` of the water
+    /// surface emitting `emit`. The path is reflected with the probability
+    /// of the Fresnel reflectance and refracted otherwise, so that no weight
+    /// is lost to either. Directions bent below the surface by steep waves
+    /// are mirrored back to the side they belong to.
+    pub fn scatter(
+        &self,
+        ray: &Ray,
+        tri: &Triangle,
+        intersect: &Intersection<Barycentric>,
+        emit: Color,
+    ) -> Scatter<Ray> {
+        let bary = intersect.attr;
+        let p = tri.o.affine_add(bary.u * tri.x + bary.v * tri.y);
+        let i = ray.v.normalize();
+        let ns = self.normal_at(p, tri.n);
+        // Normals on the side of the incoming ray, and the ratio of indices
+        // of refraction from that side.
+        let (ng, eta) = if i.dot(tri.n) < 0.0 {
+            (tri.n, self.ior.recip())
+        } else {
+            (-tri.n, self.ior)
+        };
+        let ns = if ns.dot(ng) < 0.0 { -ns } else { ns };
+        // Grazing rays might see the back of steep waves.
+        let ns = if i.dot(ns) < 0.0 { ns } else { ng };
+        let f = fresnel_dielectric(-i.dot(ns), eta);
+        let keep_side = |v: Vector, n: Vector| {
+            if v.dot(n) < 0.0 { v - n * (2.0 * v.dot(n)) } else { v }
+        };
+        let next = match refract(i, ns, eta) {
+            Some(t) if rng::random::<Real>() >= f => {
+                Ray { o: offset_ray_origin(p, -ng), v: keep_side(t, -ng) }
+            },
+            _ => Ray { o: offset_ray_origin(p, ng), v: keep_side(reflect(-i, ns), ng) },
+        };
+        Scatter {
+            emit,
+            direct: Color::default(),
+            next: Some((next, Color(1.0, 1.0, 1.0, 1.0))),
+            lobe: Lobe::Specular,
+            pdf: 0.0,
+        }
+    }
+}